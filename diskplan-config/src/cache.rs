@@ -1,16 +1,54 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 
 use crate::SchemaNode;
 
 /// An append-only cache of schemas ([`SchemaNode`] roots) keyed by their on-disk file path
+///
+/// Parsed schemas borrow from the text they were parsed from, so the cache keeps the text of
+/// every file it reads, including those brought in by `:include`, for as long as the cache itself
+/// lives. Neither texts nor schemas are ever removed or moved once added (each is separately
+/// allocated), which is what allows references to them, and the definitions merged from one
+/// schema into another, to share the cache's lifetime. The cache may be shared between threads.
+///
+/// Each file is parsed once, however many roots refer to it and however many threads ask for it
+/// at once. Files are keyed by their path as given, so two spellings of the same path (relative
+/// and absolute, say) are loaded separately.
+///
+/// A file edited after it was loaded can be loaded afresh with [`reload`](Self::reload), or
+/// dropped from the cache with [`invalidate`](Self::invalidate) to be loaded again when next
+/// asked for. Either way, only the mapping from the path is replaced: the old text and schema are
+/// kept until the cache itself is dropped, since references to them (and to definitions merged
+/// from them into other schemas) may still be held. Memory therefore grows with each reload, and
+/// a long-running service that reloads often should replace the cache from time to time.
 #[derive(Default)]
 pub struct SchemaCache<'a> {
     mapped: Mutex<HashMap<Utf8PathBuf, usize>>,
-    texts: elsa::FrozenVec<String>,
-    schemas: elsa::FrozenVec<Box<SchemaNode<'a>>>,
+    /// Held while loading from disk, so that a file missing from the cache is parsed only by the
+    /// first to ask for it
+    loading: Mutex<()>,
+    texts: elsa::sync::FrozenVec<String>,
+    schemas: elsa::sync::FrozenVec<Box<SchemaNode<'a>>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// Counts of the lookups made of a [`SchemaCache`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of schemas returned from the cache without loading
+    pub hits: usize,
+    /// The number of schema files read and parsed (including those brought in by `:include`)
+    pub misses: usize,
 }
 
 impl<'a> SchemaCache<'a> {
@@ -20,35 +58,284 @@ impl<'a> SchemaCache<'a> {
     }
 
     /// Parses the file at the given `path`, caches the parsed schema, and returns a reference to it
+    ///
+    /// Any `:include` within the schema is resolved relative to the directory of the file in
+    /// which it appears, and loaded through this cache in turn
     pub fn load<'s, 'r>(&'s self, path: impl AsRef<Utf8Path>) -> Result<&'r SchemaNode<'a>>
     where
         's: 'a,
     {
+        let path = path.as_ref();
+        if let Some(schema) = self.cached(path) {
+            return Ok(schema);
+        }
+        // Checked again once loading is exclusive, as another thread may have loaded it meanwhile
+        let _loading = self.loading.lock().expect("Lock poisoned");
+        self.load_included(path, &mut Vec::new())
+    }
+
+    /// Parses the file at the given `path` afresh, replacing any schema cached for it, and returns
+    /// a reference to the new schema
+    ///
+    /// If the file fails to load or parse, any schema already cached for it is kept. Schemas
+    /// included by this one are taken from the cache if present (reload them first if they have
+    /// changed too), and schemas that include this one keep the definitions they merged from the
+    /// old one until they are reloaded in turn. References to the old schema remain valid.
+    pub fn reload<'s, 'r>(&'s self, path: impl AsRef<Utf8Path>) -> Result<&'r SchemaNode<'a>>
+    where
+        's: 'a,
+    {
+        let _loading = self.loading.lock().expect("Lock poisoned");
+        self.parse(path.as_ref(), &mut Vec::new())
+    }
+
+    /// Drops the schema cached for the given `path`, if any, so it is loaded from disk when next
+    /// asked for, returning whether one was cached
+    ///
+    /// References to the dropped schema remain valid (see [`reload`](Self::reload) for what this
+    /// means for schemas that include it).
+    pub fn invalidate(&self, path: impl AsRef<Utf8Path>) -> bool {
         let mut locked = self.mapped.lock().expect("Lock poisoned");
+        locked.remove(path.as_ref()).is_some()
+    }
 
+    /// Returns the number of cache hits and misses so far
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the schema cached for `path`, if any, counting the hit
+    fn cached<'s, 'r>(&'s self, path: &Utf8Path) -> Option<&'r SchemaNode<'a>>
+    where
+        's: 'a,
+    {
+        let index = *self.mapped.lock().expect("Lock poisoned").get(path)?;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(self.schemas.get(index).expect("Mapped schema"))
+    }
+
+    /// Loads the schema at `path`, where `chain` lists the files (outermost first) that are
+    /// currently being loaded because each includes the next
+    fn load_included<'s, 'r>(
+        &'s self,
+        path: &Utf8Path,
+        chain: &mut Vec<Utf8PathBuf>,
+    ) -> Result<&'r SchemaNode<'a>>
+    where
+        's: 'a,
+    {
         // Early return for cache hit
-        if let Some(index) = locked.get(path.as_ref()) {
-            return Ok(&self.schemas[*index]);
+        if let Some(schema) = self.cached(path) {
+            return Ok(schema);
+        }
+        if chain.iter().any(|p| p == path) {
+            let mut cycle = String::new();
+            for p in chain.iter() {
+                write!(cycle, "{p} -> ")?;
+            }
+            bail!("Include cycle: {}{}", cycle, path);
         }
+        self.parse(path, chain)
+    }
 
+    /// Loads and parses the schema at `path` (and any it includes, if not cached), mapping the
+    /// path to it in the cache
+    fn parse<'s, 'r>(
+        &'s self,
+        path: &Utf8Path,
+        chain: &mut Vec<Utf8PathBuf>,
+    ) -> Result<&'r SchemaNode<'a>>
+    where
+        's: 'a,
+    {
         // Cache miss; load text from file and parse it
+        self.misses.fetch_add(1, Ordering::Relaxed);
         let text = self.texts.push_get(
-            std::fs::read_to_string(path.as_ref())
-                .with_context(|| format!("Failed to load config from: {}", path.as_ref()))?,
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to load config from: {path}"))?,
         );
-        let schema = diskplan_schema::parse_schema(text)
+        let mut schema = diskplan_schema::parse_schema_with_path(text, path)
             // ParseError lifetime is tricky, flattern
-            .map_err(|e| anyhow!("{}", e))?;
-        locked.insert(path.as_ref().to_owned(), self.schemas.len());
-        Ok(self.schemas.push_get(Box::new(schema)))
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(|| format!("Failed to parse schema: {path}"))?;
+
+        // Schemas included by this one are loaded with the lock released, as they may in turn
+        // include others
+        let directory = path.parent().unwrap_or(path);
+        chain.push(path.to_owned());
+        let resolved = schema.resolve_includes(&mut |include| {
+            let included = directory.join(include.path);
+            self.load_included(&included, chain)
+                .with_context(|| format!("Included from {} (line {})", path, include.line_number))
+        });
+        chain.pop();
+        resolved?;
+
+        let index = self.schemas.push_get_index(Box::new(schema));
+        let mut locked = self.mapped.lock().expect("Lock poisoned");
+        locked.insert(path.to_owned(), index);
+        Ok(self.schemas.get(index).expect("Pushed schema"))
     }
 
     /// Injects a path to schema mapping into the cache without loading from disk
     ///
-    /// This is primarily used for tests
+    /// This is primarily used for tests. The schema is cached as given; any `:include` within it
+    /// is not resolved
     pub fn inject(&self, path: impl AsRef<Utf8Path>, schema: SchemaNode<'a>) {
+        let index = self.schemas.push_get_index(Box::new(schema));
         let mut locked = self.mapped.lock().expect("Lock poisoned");
-        locked.insert(path.as_ref().to_owned(), self.schemas.len());
-        self.schemas.push(Box::new(schema));
+        locked.insert(path.as_ref().to_owned(), index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+    use camino::Utf8PathBuf;
+    use diskplan_schema::Identifier;
+
+    use super::SchemaCache;
+
+    fn temp_dir(name: &str) -> Result<Utf8PathBuf> {
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir())?.join(format!(
+            "diskplan-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib"))?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn include_merges_defs_and_lets() -> Result<()> {
+        let dir = temp_dir("include")?;
+        fs::write(
+            dir.join("main.diskplan"),
+            ":include lib/shared.diskplan\nproject/\n    :use shared\n",
+        )?;
+        fs::write(
+            dir.join("lib/shared.diskplan"),
+            ":include more.diskplan\n:let where = here\n:def shared/\n    inner/\n",
+        )?;
+        fs::write(dir.join("lib/more.diskplan"), ":def more/\n")?;
+
+        let cache = SchemaCache::new();
+        let schema = cache.load(dir.join("main.diskplan"))?;
+        let directory = schema.schema.as_directory().unwrap();
+        assert!(directory.get_def(&Identifier::new("shared")).is_some());
+        assert!(directory.get_def(&Identifier::new("more")).is_some());
+        assert!(directory.get_var(&Identifier::new("where")).is_some());
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn include_shared_by_two_includes() -> Result<()> {
+        let dir = temp_dir("include-diamond")?;
+        fs::write(
+            dir.join("main.diskplan"),
+            ":include lib/b.diskplan\n:include lib/c.diskplan\n",
+        )?;
+        fs::write(
+            dir.join("lib/b.diskplan"),
+            ":include common.diskplan\n:def b/\n",
+        )?;
+        fs::write(
+            dir.join("lib/c.diskplan"),
+            ":include common.diskplan\n:def c/\n",
+        )?;
+        fs::write(
+            dir.join("lib/common.diskplan"),
+            ":let where = here\n:def common/\n    inner/\n",
+        )?;
+        fs::write(
+            dir.join("clash.diskplan"),
+            ":include lib/b.diskplan\n:def common/\n",
+        )?;
+
+        let cache = SchemaCache::new();
+        let schema = cache.load(dir.join("main.diskplan"))?;
+        let directory = schema.schema.as_directory().unwrap();
+        for def in ["b", "c", "common"] {
+            assert!(directory.get_def(&Identifier::new(def)).is_some(), "{def}");
+        }
+        assert!(directory.get_var(&Identifier::new("where")).is_some());
+
+        // A different definition of the same name is still an error
+        let error = format!("{:?}", cache.load(dir.join("clash.diskplan")).unwrap_err());
+        assert!(error.contains(":def common occurs twice"), "{error}");
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn include_errors_report_the_chain() -> Result<()> {
+        let dir = temp_dir("include-errors")?;
+        fs::write(dir.join("cycle.diskplan"), ":include lib/a.diskplan\n")?;
+        fs::write(dir.join("lib/a.diskplan"), ":include b.diskplan\n")?;
+        fs::write(dir.join("lib/b.diskplan"), ":include a.diskplan\n")?;
+        fs::write(dir.join("broken.diskplan"), ":include lib/bad.diskplan\n")?;
+        fs::write(dir.join("lib/bad.diskplan"), ":def ok/\n:nonsense\n")?;
+
+        let cache = SchemaCache::new();
+        let error = format!("{:?}", cache.load(dir.join("cycle.diskplan")).unwrap_err());
+        assert!(error.contains(&format!(
+            "Include cycle: {0}/cycle.diskplan -> {0}/lib/a.diskplan -> {0}/lib/b.diskplan -> {0}/lib/a.diskplan",
+            dir
+        )));
+
+        let error = format!("{:?}", cache.load(dir.join("broken.diskplan")).unwrap_err());
+        assert!(error.contains(&format!("Included from {dir}/broken.diskplan (line 1)")));
+        assert!(error.contains(&format!("Failed to parse schema: {dir}/lib/bad.diskplan")));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reload_and_invalidate_after_edits() -> Result<()> {
+        let dir = temp_dir("reload")?;
+        let path = dir.join("schema.diskplan");
+        let entry_names = |schema: &crate::SchemaNode| -> Vec<String> {
+            let directory = schema.schema.as_directory().unwrap();
+            directory
+                .entries()
+                .iter()
+                .map(|(b, _)| b.to_string())
+                .collect()
+        };
+        fs::write(&path, "old/\n")?;
+
+        let cache = SchemaCache::new();
+        let old = cache.load(&path)?;
+        fs::write(&path, "new/\n")?;
+        assert_eq!(entry_names(cache.load(&path)?), ["old"]);
+
+        // The old schema is still usable once replaced
+        assert_eq!(entry_names(cache.reload(&path)?), ["new"]);
+        assert_eq!(entry_names(cache.load(&path)?), ["new"]);
+        assert_eq!(entry_names(old), ["old"]);
+
+        // A failed reload keeps what was cached
+        fs::write(&path, ":nonsense\n")?;
+        assert!(cache.reload(&path).is_err());
+        assert_eq!(entry_names(cache.load(&path)?), ["new"]);
+
+        fs::write(&path, "newer/\n")?;
+        assert!(cache.invalidate(&path));
+        assert!(!cache.invalidate(&path));
+        assert_eq!(entry_names(cache.load(&path)?), ["newer"]);
+        assert_eq!(cache.stats().misses, 4);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
     }
 }
@@ -3,7 +3,7 @@ use anyhow::Result;
 use regex::Regex;
 
 use diskplan_config::Config;
-use diskplan_filesystem::{Filesystem, MemoryFilesystem, SetAttrs};
+use diskplan_filesystem::{Filesystem, MemoryFilesystem};
 use diskplan_schema::{Root, SchemaNode};
 use diskplan_traversal::{traverse, StackFrame};
 
@@ -22,23 +22,11 @@ where
         .format_timestamp(None)
         .init();
 
-    let mut fs = MemoryFilesystem::new();
-
     // Create initial filesystem from input tree
-    for entry in parse_tree(in_tree)? {
-        if let Some(target) = entry.link_target {
-            fs.create_symlink(&entry.path, target)?;
-        } else if entry.is_dir {
-            if !fs.exists(&entry.path) {
-                fs.create_directory(&entry.path, SetAttrs::default())?;
-            }
-        } else {
-            fs.create_file(&entry.path, SetAttrs::default(), "".to_owned())?;
-        }
-    }
+    let mut fs = MemoryFilesystem::from_tree(in_tree)?;
 
     // Apply schema
-    let stack = StackFrame::stack(&config, Default::default(), "root", "root", 0o755.into());
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", Some(0o755.into()));
     traverse(target, &stack, &mut fs)?;
 
     // Check tree matches expected output tree
@@ -14,6 +14,22 @@ pub struct ConfigFile {
 
     /// Schema directory (defaults to directory containing config)
     pub schema_directory: Option<Utf8PathBuf>,
+
+    /// Whether relative `:source` paths are resolved against the schema directory
+    #[serde(default)]
+    pub relative_sources: bool,
+
+    /// Map of user names to the names to use in their place
+    #[serde(default)]
+    pub usermap: HashMap<String, String>,
+
+    /// Map of group names to the names to use in their place
+    #[serde(default)]
+    pub groupmap: HashMap<String, String>,
+
+    /// Variables that may be used by the schemas
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
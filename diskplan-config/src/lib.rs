@@ -18,13 +18,13 @@ use std::{collections::HashMap, fmt::Write as _, ops::Deref};
 use anyhow::{anyhow, Context as _, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 
-use diskplan_filesystem::Root;
+use diskplan_filesystem::{PlantedPath, Root};
 use diskplan_schema::SchemaNode;
 
 mod cache;
 mod file;
 pub use self::{
-    cache::SchemaCache,
+    cache::{CacheStats, SchemaCache},
     file::{ConfigFile, ConfigStem},
 };
 
@@ -39,12 +39,40 @@ pub struct Config<'t> {
     /// Directory to search for schemas
     schema_directory: Utf8PathBuf,
 
+    /// Whether relative `:source` paths are resolved against the schema directory
+    relative_sources: bool,
+
+    /// The maximum depth of directories to traverse beneath a root, if limited
+    max_depth: Option<usize>,
+
+    /// Whether to explain how names in each directory were matched against the schema
+    explain: bool,
+
+    /// Whether names that match no entry of their directory's schema are an error
+    fail_on_unmatched: bool,
+
+    /// Whether errors carry a dump of the traversal stack at the point they occurred
+    dump_stack_on_error: bool,
+
+    /// The number of threads with which to traverse independent subtrees
+    jobs: usize,
+
     /// Map user names, for example "root:admin,janine:jfu"
     usermap: HashMap<String, String>,
 
     /// Map groups names
     groupmap: HashMap<String, String>,
 
+    /// Variables that may be used by the schemas, for example "site:north,zone:a"
+    vars: HashMap<String, String>,
+
+    /// Whether variables given for the run (such as by `--vars`) take precedence over the schema's
+    /// `:let` variables of the same name
+    vars_override_lets: bool,
+
+    /// Environment variables given in place of those of the process, if any
+    environment: Option<HashMap<String, String>>,
+
     stems: Stems<'t>,
 }
 
@@ -60,18 +88,38 @@ impl<'t> Config<'t> {
             target: target.as_ref().to_owned(),
             apply,
             schema_directory: Utf8PathBuf::from("/"),
+            relative_sources: false,
+            max_depth: None,
+            explain: false,
+            fail_on_unmatched: false,
+            dump_stack_on_error: false,
+            jobs: 1,
             usermap: Default::default(),
             groupmap: Default::default(),
+            vars: Default::default(),
+            vars_override_lets: false,
+            environment: None,
             stems: Default::default(),
         }
     }
 
     /// Loads configuation options from the given `path`
+    ///
+    /// Any user map, group map and variables given by the file are added to those already held,
+    /// so maps and variables applied after loading take precedence over those from the file
     pub fn load(&mut self, path: impl AsRef<Utf8Path>) -> Result<()> {
         let ConfigFile {
             stems,
             schema_directory,
+            relative_sources,
+            usermap,
+            groupmap,
+            vars,
         } = ConfigFile::load(path.as_ref())?;
+        self.relative_sources = relative_sources;
+        self.apply_user_map(usermap);
+        self.apply_group_map(groupmap);
+        self.apply_vars(vars);
         self.schema_directory = schema_directory.unwrap_or_else(|| {
             path.as_ref()
                 .parent()
@@ -82,17 +130,146 @@ impl<'t> Config<'t> {
             let schema_path = self.schema_directory.join(stem.schema());
             self.stems.add(stem.root().to_owned(), schema_path)
         }
+        for (outer, inner) in self.validate_roots() {
+            tracing::warn!(
+                "Root {} lies within root {}, so beneath it, its own schema applies to targets \
+                 given directly, but not when traversing from the outer root",
+                inner.path(),
+                outer.path(),
+            );
+        }
         Ok(())
     }
 
+    /// Returns each pair of configured roots where one lies within the other, as `(outer, inner)`
+    ///
+    /// The schema for a path is taken from the longest root containing it, so an inner root
+    /// shadows the outer root's schema for targets beneath it (which may not be intended).
+    pub fn validate_roots(&self) -> Vec<(&Root, &Root)> {
+        self.stems.overlaps()
+    }
+
     /// Updates this configuration's user name map with the one provided
     pub fn apply_user_map(&mut self, usermap: HashMap<String, String>) {
-        self.usermap.extend(usermap.into_iter())
+        self.usermap.extend(usermap)
     }
 
     /// Updates this configuration's group name map with the one provided
     pub fn apply_group_map(&mut self, groupmap: HashMap<String, String>) {
-        self.groupmap.extend(groupmap.into_iter())
+        self.groupmap.extend(groupmap)
+    }
+
+    /// Updates this configuration's variables with those provided
+    pub fn apply_vars(&mut self, vars: HashMap<String, String>) {
+        self.vars.extend(vars)
+    }
+
+    /// The variables given by this configuration, for use by the schemas
+    pub fn vars(&self) -> &HashMap<String, String> {
+        &self.vars
+    }
+
+    /// Sets whether variables given for the run (those at the base of the traversal's stack, such
+    /// as from `--vars`) take precedence over the schema's `:let` variables of the same name
+    ///
+    /// By default, a `:let` takes precedence, so these only provide values the schema leaves
+    /// unset. Either way, a name bound by a `$variable` entry is never overridden.
+    pub fn set_vars_override_lets(&mut self, vars_override_lets: bool) {
+        self.vars_override_lets = vars_override_lets;
+    }
+
+    /// Whether variables given for the run take precedence over the schema's `:let` variables
+    pub fn vars_override_lets(&self) -> bool {
+        self.vars_override_lets
+    }
+
+    /// Gives the environment variables to be read by schemas (as `$ENV{NAME}`), in place of those
+    /// of the process
+    pub fn set_environment(&mut self, environment: HashMap<String, String>) {
+        self.environment = Some(environment);
+    }
+
+    /// Returns the value of the named environment variable, if it is set (and valid UTF-8)
+    pub fn env_var(&self, name: &str) -> Option<String> {
+        match &self.environment {
+            Some(environment) => environment.get(name).cloned(),
+            None => std::env::var(name).ok(),
+        }
+    }
+
+    /// Sets the directory in which schemas (and, optionally, relative sources) are found
+    pub fn set_schema_directory(&mut self, schema_directory: impl AsRef<Utf8Path>) {
+        self.schema_directory = schema_directory.as_ref().to_owned();
+    }
+
+    /// Sets whether relative `:source` paths are resolved against the schema directory
+    pub fn set_relative_sources(&mut self, relative_sources: bool) {
+        self.relative_sources = relative_sources;
+    }
+
+    /// Resolves a `:source` path, joining it onto the schema directory if it is relative and
+    /// relative sources are enabled; otherwise it is returned unchanged
+    pub fn resolve_source(&self, source: impl AsRef<Utf8Path>) -> Utf8PathBuf {
+        let source = source.as_ref();
+        if self.relative_sources && source.is_relative() {
+            self.schema_directory.join(source)
+        } else {
+            source.to_owned()
+        }
+    }
+
+    /// Limits the depth of directories traversed beneath a root (or `None` for no limit)
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.max_depth = max_depth;
+    }
+
+    /// The maximum depth of directories to traverse beneath a root, if limited
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// Sets whether to log an explanation of how names in each directory were matched (or not)
+    /// against the entries of its schema
+    pub fn set_explain(&mut self, explain: bool) {
+        self.explain = explain;
+    }
+
+    /// Whether to log an explanation of how names in each directory were matched
+    pub fn explain(&self) -> bool {
+        self.explain
+    }
+
+    /// Sets whether an error in traversal carries a dump of every frame of the stack at the point
+    /// it occurred (see `StackFrame::dump` in `diskplan-traversal`)
+    pub fn set_dump_stack_on_error(&mut self, dump_stack_on_error: bool) {
+        self.dump_stack_on_error = dump_stack_on_error;
+    }
+
+    /// Whether errors carry a dump of the traversal stack at the point they occurred
+    pub fn dump_stack_on_error(&self) -> bool {
+        self.dump_stack_on_error
+    }
+
+    /// Sets whether names that match no entry of their directory's schema are an error, rather
+    /// than only being logged as a warning
+    pub fn set_fail_on_unmatched(&mut self, fail_on_unmatched: bool) {
+        self.fail_on_unmatched = fail_on_unmatched;
+    }
+
+    /// Whether names that match no entry of their directory's schema are an error
+    pub fn fail_on_unmatched(&self) -> bool {
+        self.fail_on_unmatched
+    }
+
+    /// Sets the number of threads with which to traverse independent subtrees (where the
+    /// filesystem supports it, otherwise the traversal is serial)
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
+
+    /// The number of threads with which to traverse independent subtrees
+    pub fn jobs(&self) -> usize {
+        self.jobs
     }
 
     /// The path intended to be constructed
@@ -127,15 +304,61 @@ impl<'t> Config<'t> {
         self.stems.roots()
     }
 
+    /// Returns each configured root that contains the given `path` or lies beneath it, along
+    /// with the path of its schema file (see [`Stems::stems_for_prefix`] for the ordering)
+    pub fn stems_for_prefix(&self, path: impl AsRef<Utf8Path>) -> Vec<(&Root, &Utf8Path)> {
+        self.stems.stems_for_prefix(path.as_ref())
+    }
+
     /// Returns the schema for a given path, loaded on demand, or an error if the schema cannot be
     /// found, has a syntax error, or otherwise fails to load
-    pub fn schema_for<'s, 'p>(&'s self, path: &'p Utf8Path) -> Result<(&SchemaNode<'t>, &Root)>
+    pub fn schema_for<'s, 'p>(
+        &'s self,
+        path: &'p Utf8Path,
+    ) -> Result<(&'s SchemaNode<'t>, &'s Root)>
     where
         's: 't,
     {
         self.stems.schema_for(path)
     }
 
+    /// Returns the number of schema loads answered from the cache, and of schema files parsed
+    pub fn cache_stats(&self) -> CacheStats {
+        self.stems.cache_stats()
+    }
+
+    /// Parses the schema file at `schema_path` afresh, as after it has been edited, replacing the
+    /// schema cached for it (see [`SchemaCache::reload`])
+    pub fn reload_schema<'s>(
+        &'s self,
+        schema_path: impl AsRef<Utf8Path>,
+    ) -> Result<&'s SchemaNode<'t>>
+    where
+        's: 't,
+    {
+        self.stems.reload(schema_path)
+    }
+
+    /// Drops the schema cached for the file at `schema_path`, so it is loaded afresh when next
+    /// needed, returning whether one was cached (see [`SchemaCache::invalidate`])
+    pub fn invalidate_schema(&self, schema_path: impl AsRef<Utf8Path>) -> bool {
+        self.stems.invalidate(schema_path)
+    }
+
+    /// Returns the schema for a given path, as [`schema_for`](Self::schema_for) does, along with
+    /// the path planted in the root to which the schema applies (so its part relative to the
+    /// root is readily available)
+    pub fn planted_schema_for<'s>(
+        &'s self,
+        path: &Utf8Path,
+    ) -> Result<(&'s SchemaNode<'t>, PlantedPath)>
+    where
+        's: 't,
+    {
+        let (schema, root) = self.schema_for(path)?;
+        Ok((schema, PlantedPath::new(root, Some(path))?))
+    }
+
     /// Applies the user map to the given user name, returning itself if no mapping exists for
     /// this name
     pub fn map_user<'a>(&'a self, name: &'a str) -> &'a str {
@@ -185,13 +408,65 @@ impl<'t> Stems<'t> {
         self.add(root, schema_path);
     }
 
+    /// Returns the number of schema loads answered from the cache, and of schema files parsed
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Parses the schema file at `schema_path` afresh, replacing the schema cached for it
+    pub fn reload<'s>(&'s self, schema_path: impl AsRef<Utf8Path>) -> Result<&'s SchemaNode<'t>>
+    where
+        's: 't,
+    {
+        self.cache.reload(schema_path)
+    }
+
+    /// Drops the schema cached for the file at `schema_path`, returning whether one was cached
+    pub fn invalidate(&self, schema_path: impl AsRef<Utf8Path>) -> bool {
+        self.cache.invalidate(schema_path)
+    }
+
     /// Returns an iterator over the roots configures in this map
     pub fn roots(&self) -> impl Iterator<Item = &Root> {
         self.path_map.keys()
     }
 
+    /// Returns each configured root that contains the given `path` (or is it), or lies beneath
+    /// it, along with the path of its schema file
+    ///
+    /// Roots are ordered by the length of their path (then by path), so any containing `path`
+    /// come first, from the outermost, followed by those beneath it. The schemas are not loaded.
+    pub fn stems_for_prefix(&self, path: &Utf8Path) -> Vec<(&Root, &Utf8Path)> {
+        let mut related: Vec<_> = self
+            .path_map
+            .iter()
+            .filter(|(root, _)| path.starts_with(root.path()) || root.path().starts_with(path))
+            .map(|(root, schema_path)| (root, schema_path.as_path()))
+            .collect();
+        related.sort_by_key(|(root, _)| (root.path().as_str().len(), root.path()));
+        related
+    }
+
+    /// Returns each pair of roots where one lies within the other, as `(outer, inner)`, ordered
+    /// by path
+    pub fn overlaps(&self) -> Vec<(&Root, &Root)> {
+        let mut overlaps = vec![];
+        for outer in self.roots() {
+            for inner in self.roots() {
+                if inner != outer && inner.path().starts_with(outer.path()) {
+                    overlaps.push((outer, inner));
+                }
+            }
+        }
+        overlaps.sort_by_key(|(outer, inner)| (outer.path(), inner.path()));
+        overlaps
+    }
+
     /// Looks up the schema associated with the root of a given `path` within this root
-    pub fn schema_for<'s, 'p>(&'s self, path: &'p Utf8Path) -> Result<(&SchemaNode<'t>, &Root)>
+    pub fn schema_for<'s, 'p>(
+        &'s self,
+        path: &'p Utf8Path,
+    ) -> Result<(&'s SchemaNode<'t>, &'s Root)>
     where
         's: 't,
     {
@@ -238,3 +513,177 @@ impl<'t> Stems<'t> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs};
+
+    use anyhow::Result;
+    use camino::Utf8PathBuf;
+
+    use diskplan_filesystem::Root;
+
+    use super::{Config, ConfigFile};
+
+    #[test]
+    fn maps_and_vars_from_file() -> Result<()> {
+        let text = r#"
+            [stems.main]
+            root = "/local"
+            schema = "schema.diskplan"
+
+            [usermap]
+            root = "admin"
+            janine = "jfu"
+
+            [groupmap]
+            staff = "users"
+
+            [vars]
+            site = "north"
+            zone = "a"
+        "#;
+        let file: ConfigFile = text.try_into()?;
+        assert_eq!(file.usermap["janine"], "jfu");
+        assert_eq!(file.groupmap["staff"], "users");
+        assert_eq!(file.vars["site"], "north");
+        assert!(ConfigFile::try_from("[stems]\n[vars]\nsite = 1").is_err());
+
+        // Those given after loading (as on the command line) take precedence
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir())?
+            .join(format!("diskplan-config-maps-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("diskplan.toml"), text)?;
+        let mut config = Config::new("/local", false);
+        config.load(dir.join("diskplan.toml"))?;
+        config.apply_user_map(HashMap::from([("root".into(), "superuser".into())]));
+        config.apply_vars(HashMap::from([("zone".into(), "b".into())]));
+        assert_eq!(config.map_user("root"), "superuser");
+        assert_eq!(config.map_user("janine"), "jfu");
+        assert_eq!(config.map_group("staff"), "users");
+        assert_eq!(config.vars()["site"], "north");
+        assert_eq!(config.vars()["zone"], "b");
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn overlapping_roots() -> Result<()> {
+        let mut config = Config::new("/local", false);
+        for root in [
+            "/local",
+            "/local/special",
+            "/local/special/more",
+            "/localish",
+            "/other",
+        ] {
+            config.add_stem(Root::try_from(root)?, "schema.diskplan");
+        }
+        let overlaps: Vec<_> = config
+            .validate_roots()
+            .into_iter()
+            .map(|(outer, inner)| (outer.path().as_str(), inner.path().as_str()))
+            .collect();
+        assert_eq!(
+            overlaps,
+            vec![
+                ("/local", "/local/special"),
+                ("/local", "/local/special/more"),
+                ("/local/special", "/local/special/more"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stems_for_prefix() -> Result<()> {
+        let mut config = Config::new("/local", false);
+        for root in [
+            "/local/special/more",
+            "/local",
+            "/local/special",
+            "/localish",
+            "/other",
+            "/",
+        ] {
+            config.add_stem(Root::try_from(root)?, format!("/schemas{root}.diskplan"));
+        }
+        let related = |path| -> Vec<_> {
+            config
+                .stems_for_prefix(path)
+                .into_iter()
+                .map(|(root, _)| root.path().as_str())
+                .collect()
+        };
+        assert_eq!(
+            related("/local/special"),
+            vec!["/", "/local", "/local/special", "/local/special/more"]
+        );
+        assert_eq!(related("/local/other/deeper"), vec!["/", "/local"]);
+        assert_eq!(
+            related("/"),
+            vec![
+                "/",
+                "/local",
+                "/other",
+                "/localish",
+                "/local/special",
+                "/local/special/more"
+            ]
+        );
+        let (_, schema) = config.stems_for_prefix("/other")[1];
+        assert_eq!(schema, "/schemas/other.diskplan");
+        Ok(())
+    }
+
+    #[test]
+    fn equivalent_root_spellings() -> Result<()> {
+        for spelling in [
+            "/net/remote",
+            "/net/remote/",
+            "/net/remote/.",
+            "/net//./remote",
+        ] {
+            let mut config = Config::new("/net/remote/zone", false);
+            let schema = diskplan_schema::parse_schema("zone/")?;
+            config.add_precached_stem(Root::try_from(spelling)?, "/schema.diskplan", schema);
+            let (_, root) = config.schema_for("/net/remote/zone".into())?;
+            assert_eq!(root, &Root::try_from("/net/remote")?, "{spelling}");
+            assert!(config.schema_for("/net/remote2".into()).is_err());
+
+            let (_, planted) = config.planted_schema_for("/net/remote/zone".into())?;
+            assert_eq!(planted.root(), "/net/remote");
+            assert_eq!(planted.relative(), "zone");
+            assert_eq!(planted.to_root().absolute(), "/net/remote");
+        }
+        assert_eq!(Root::try_from("/")?.path(), "/");
+        assert!(Root::try_from("/net/../remote").is_err());
+        assert!(Root::try_from("net/remote").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn shared_schema_parsed_once() -> Result<()> {
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir())?
+            .join(format!("diskplan-config-shared-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let schema_path = dir.join("remote.diskplan");
+        fs::write(&schema_path, "zone/\n")?;
+
+        let roots = ["/net/one", "/net/two", "/net/three", "/net/four"];
+        let mut config = Config::new("/net/one", false);
+        for root in roots {
+            config.add_stem(Root::try_from(root)?, &schema_path);
+        }
+        let first = config.schema_for("/net/one/zone".into())?.0;
+        for root in roots {
+            let (schema, _) = config.schema_for(Utf8PathBuf::from(root).join("zone").as_path())?;
+            assert!(std::ptr::eq(schema, first));
+        }
+        let stats = config.cache_stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, roots.len());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,23 +1,139 @@
-use std::{borrow::Cow, fmt::Debug};
+use std::{
+    borrow::Cow,
+    fmt::{Debug, Display},
+    time::SystemTime,
+};
 
 /// The default mode for directories (`0o755` or `rwxr-xr-x`)
 pub const DEFAULT_DIRECTORY_MODE: Mode = Mode(0o755);
 /// The default mode for files (`0o644` or `rw-r--r--`)
 pub const DEFAULT_FILE_MODE: Mode = Mode(0o644);
 
+/// The last access and modification times of a file or directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Times {
+    /// When the content was last read
+    pub accessed: SystemTime,
+    /// When the content was last changed
+    pub modified: SystemTime,
+}
+
+impl Times {
+    /// Returns times of access and modification that are both now
+    pub fn now() -> Self {
+        let now = SystemTime::now();
+        Times {
+            accessed: now,
+            modified: now,
+        }
+    }
+}
+
 /// Optional owner, group and UNIX permissions to be set
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+///
+/// These display as only the attributes given, such as `owner=root mode=0750`, or `(none)`.
+#[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SetAttrs<'a> {
-    /// An optional owner to set given by name
+    /// An optional owner to set given by name (or numeric ID, such as `1000` or `#1000`)
     pub owner: Option<&'a str>,
-    /// An optional group to set given by name
+    /// An optional group to set given by name (or numeric ID, such as `1000` or `#1000`)
     pub group: Option<&'a str>,
     /// An optional [`Mode`] to set
     pub mode: Option<Mode>,
 }
 
+impl<'a> SetAttrs<'a> {
+    /// Returns a builder to construct `SetAttrs` with fluent setters
+    ///
+    /// ```
+    /// use diskplan_filesystem::SetAttrs;
+    ///
+    /// let attrs = SetAttrs::builder().owner("root").mode(0o750).build();
+    /// assert_eq!(attrs.owner, Some("root"));
+    /// assert_eq!(attrs.group, None);
+    /// assert_eq!(attrs.mode, Some(0o750.into()));
+    ///
+    /// assert_eq!(attrs, SetAttrs::default().with_owner("root").with_mode(0o750));
+    /// ```
+    pub fn builder() -> SetAttrsBuilder<'a> {
+        SetAttrsBuilder::default()
+    }
+
+    /// Returns these attributes with the owner set
+    pub fn with_owner(self, owner: &'a str) -> Self {
+        SetAttrs {
+            owner: Some(owner),
+            ..self
+        }
+    }
+
+    /// Returns these attributes with the group set
+    pub fn with_group(self, group: &'a str) -> Self {
+        SetAttrs {
+            group: Some(group),
+            ..self
+        }
+    }
+
+    /// Returns these attributes with the [`Mode`] set
+    pub fn with_mode(self, mode: impl Into<Mode>) -> Self {
+        SetAttrs {
+            mode: Some(mode.into()),
+            ..self
+        }
+    }
+}
+
+impl Display for SetAttrs<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_attrs(f, self.owner, self.group, self.mode)
+    }
+}
+
+impl Debug for SetAttrs<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SetAttrs({self})")
+    }
+}
+
+/// A builder for [`SetAttrs`], see [`SetAttrs::builder`]
+#[derive(Debug, Default, Clone)]
+pub struct SetAttrsBuilder<'a> {
+    attrs: SetAttrs<'a>,
+}
+
+impl<'a> SetAttrsBuilder<'a> {
+    /// Sets the owner to be given by name
+    pub fn owner(self, owner: &'a str) -> Self {
+        SetAttrsBuilder {
+            attrs: self.attrs.with_owner(owner),
+        }
+    }
+
+    /// Sets the group to be given by name
+    pub fn group(self, group: &'a str) -> Self {
+        SetAttrsBuilder {
+            attrs: self.attrs.with_group(group),
+        }
+    }
+
+    /// Sets the UNIX permissions
+    pub fn mode(self, mode: impl Into<Mode>) -> Self {
+        SetAttrsBuilder {
+            attrs: self.attrs.with_mode(mode),
+        }
+    }
+
+    /// Returns the constructed [`SetAttrs`]
+    pub fn build(self) -> SetAttrs<'a> {
+        self.attrs
+    }
+}
+
 /// Owner, group and UNIX permissions
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// These display as, for example, `owner=root group=root mode=0750`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Attrs<'a> {
     /// The owner of the file or directory
     pub owner: Cow<'a, str>,
@@ -27,8 +143,107 @@ pub struct Attrs<'a> {
     pub mode: Mode,
 }
 
+impl Attrs<'_> {
+    /// Compares these (existing) attributes with those to be set, returning only the ones that
+    /// differ
+    ///
+    /// ```
+    /// use diskplan_filesystem::{Attrs, SetAttrs};
+    ///
+    /// let existing = Attrs { owner: "root".into(), group: "root".into(), mode: 0o755.into() };
+    /// let diff = existing.diff(&SetAttrs::default().with_owner("root").with_mode(0o750));
+    /// assert_eq!(diff.owner, None);
+    /// assert_eq!(diff.mode, Some(0o750.into()));
+    /// ```
+    pub fn diff<'a>(&self, attrs: &SetAttrs<'a>) -> AttrDiff<'a> {
+        AttrDiff {
+            owner: attrs.owner.filter(|&owner| owner != self.owner),
+            group: attrs.group.filter(|&group| group != self.group),
+            mode: attrs.mode.filter(|&mode| mode != self.mode),
+        }
+    }
+}
+
+impl Display for Attrs<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_attrs(f, Some(&self.owner), Some(&self.group), Some(self.mode))
+    }
+}
+
+impl Debug for Attrs<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Attrs({self})")
+    }
+}
+
+/// The attributes to be set that differ from those existing, see [`Attrs::diff`]
+///
+/// These display as [`SetAttrs`] do, listing only those that differ.
+#[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AttrDiff<'a> {
+    /// The owner to set, if it differs
+    pub owner: Option<&'a str>,
+    /// The group to set, if it differs
+    pub group: Option<&'a str>,
+    /// The [`Mode`] to set, if it differs
+    pub mode: Option<Mode>,
+}
+
+impl AttrDiff<'_> {
+    /// Returns true if no attribute differs
+    pub fn is_empty(&self) -> bool {
+        self == &AttrDiff::default()
+    }
+}
+
+impl Display for AttrDiff<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_attrs(f, self.owner, self.group, self.mode)
+    }
+}
+
+impl Debug for AttrDiff<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AttrDiff({self})")
+    }
+}
+
+/// Writes each attribute given as `name=value`, separated by spaces, or `(none)` if none are
+fn write_attrs(
+    f: &mut std::fmt::Formatter<'_>,
+    owner: Option<&str>,
+    group: Option<&str>,
+    mode: Option<Mode>,
+) -> std::fmt::Result {
+    let mut separator = "";
+    for (name, value) in [
+        ("owner", owner.map(Cow::Borrowed)),
+        ("group", group.map(Cow::Borrowed)),
+        ("mode", mode.map(|mode| Cow::Owned(mode.to_string()))),
+    ] {
+        if let Some(value) = value {
+            write!(f, "{separator}{name}={value}")?;
+            separator = " ";
+        }
+    }
+    if separator.is_empty() {
+        write!(f, "(none)")?;
+    }
+    Ok(())
+}
+
+impl<'a> From<AttrDiff<'a>> for SetAttrs<'a> {
+    fn from(diff: AttrDiff<'a>) -> Self {
+        let AttrDiff { owner, group, mode } = diff;
+        SetAttrs { owner, group, mode }
+    }
+}
+
 /// UNIX permissions
-#[derive(Clone, Copy, Eq, PartialEq)]
+///
+/// These display in octal with four digits, such as `0750`, so that the setuid, setgid and
+/// sticky bits are always shown.
+#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
 pub struct Mode(u16);
 
 impl Mode {
@@ -40,7 +255,14 @@ impl Mode {
 
 impl Debug for Mode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Mode(0o{:03o})", self.0)
+        write!(f, "Mode({self})")
+    }
+}
+
+/// Modes display as four octal digits, such as `0755` or `4750` (where the setuid bit is set)
+impl Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04o}", self.0)
     }
 }
 
@@ -61,3 +283,95 @@ impl From<Mode> for u32 {
         mode.0 as u32
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AttrDiff, Attrs, SetAttrs};
+
+    #[test]
+    fn diff_each_field() {
+        let existing = Attrs {
+            owner: "root".into(),
+            group: "wheel".into(),
+            mode: 0o755.into(),
+        };
+        assert!(existing.diff(&SetAttrs::default()).is_empty());
+        let same = SetAttrs::default()
+            .with_owner("root")
+            .with_group("wheel")
+            .with_mode(0o755);
+        assert!(existing.diff(&same).is_empty());
+
+        let diff = existing.diff(&same.clone().with_owner("daemon"));
+        assert_eq!(
+            diff,
+            AttrDiff {
+                owner: Some("daemon"),
+                ..Default::default()
+            }
+        );
+        let diff = existing.diff(&same.clone().with_group("staff"));
+        assert_eq!(
+            diff,
+            AttrDiff {
+                group: Some("staff"),
+                ..Default::default()
+            }
+        );
+        let diff = existing.diff(&same.with_mode(0o700));
+        assert_eq!(
+            diff,
+            AttrDiff {
+                mode: Some(0o700.into()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(SetAttrs::from(diff), SetAttrs::default().with_mode(0o700));
+    }
+
+    #[test]
+    fn display_and_order() {
+        let attrs = Attrs {
+            owner: "root".into(),
+            group: "wheel".into(),
+            mode: 0o750.into(),
+        };
+        assert_eq!(attrs.to_string(), "owner=root group=wheel mode=0750");
+        assert_eq!(
+            format!("{attrs:?}"),
+            "Attrs(owner=root group=wheel mode=0750)"
+        );
+        assert_eq!(
+            SetAttrs::default()
+                .with_owner("root")
+                .with_mode(0o4755)
+                .to_string(),
+            "owner=root mode=4755"
+        );
+        assert_eq!(SetAttrs::default().to_string(), "(none)");
+        assert_eq!(
+            attrs
+                .diff(&SetAttrs::default().with_mode(0o700))
+                .to_string(),
+            "mode=0700"
+        );
+
+        let mut sorted = [
+            SetAttrs::default().with_owner("root").with_mode(0o755),
+            SetAttrs::default().with_owner("daemon"),
+            SetAttrs::default().with_owner("root").with_mode(0o700),
+            SetAttrs::default(),
+        ];
+        sorted.sort();
+        let sorted: Vec<_> = sorted.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            sorted,
+            [
+                "(none)",
+                "owner=daemon",
+                "owner=root mode=0700",
+                "owner=root mode=0755"
+            ]
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use std::{borrow::Cow, collections::BTreeMap, fmt::Display};
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+
+use super::{Attrs, Filesystem, NodeKind};
+
+/// A way in which the same subtree differs between two filesystems, as found by
+/// [`compare_subtrees`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// The path exists only in the first filesystem (and so does everything beneath it)
+    OnlyInFirst(Utf8PathBuf),
+    /// The path exists only in the second filesystem (and so does everything beneath it)
+    OnlyInSecond(Utf8PathBuf),
+    /// The path is a different kind of entry in each
+    Kind {
+        /// The path of the entry
+        path: Utf8PathBuf,
+        /// Its kind in the first filesystem
+        first: NodeKind,
+        /// Its kind in the second filesystem
+        second: NodeKind,
+    },
+    /// The owner, group or mode of the file or directory differ
+    Attributes {
+        /// The path of the file or directory
+        path: Utf8PathBuf,
+        /// Its attributes in the first filesystem
+        first: Attrs<'static>,
+        /// Its attributes in the second filesystem
+        second: Attrs<'static>,
+    },
+    /// The content of the file differs
+    Content(Utf8PathBuf),
+    /// The symlink points to a different target in each
+    LinkTarget {
+        /// The path of the symlink
+        path: Utf8PathBuf,
+        /// Its target in the first filesystem
+        first: Utf8PathBuf,
+        /// Its target in the second filesystem
+        second: Utf8PathBuf,
+    },
+}
+
+impl Difference {
+    /// The path at which the difference was found
+    pub fn path(&self) -> &Utf8Path {
+        match self {
+            Difference::OnlyInFirst(path)
+            | Difference::OnlyInSecond(path)
+            | Difference::Content(path) => path,
+            Difference::Kind { path, .. }
+            | Difference::Attributes { path, .. }
+            | Difference::LinkTarget { path, .. } => path,
+        }
+    }
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let attrs = |attrs: &Attrs| format!("{}:{} {}", attrs.owner, attrs.group, attrs.mode);
+        match self {
+            Difference::OnlyInFirst(path) => write!(f, "Only in first: {path}"),
+            Difference::OnlyInSecond(path) => write!(f, "Only in second: {path}"),
+            Difference::Kind {
+                path,
+                first,
+                second,
+            } => write!(f, "Kind of {path} differs: {first:?} vs {second:?}"),
+            Difference::Attributes {
+                path,
+                first,
+                second,
+            } => write!(
+                f,
+                "Attributes of {path} differ: {} vs {}",
+                attrs(first),
+                attrs(second)
+            ),
+            Difference::Content(path) => write!(f, "Content of {path} differs"),
+            Difference::LinkTarget {
+                path,
+                first,
+                second,
+            } => write!(f, "Target of {path} differs: {first} vs {second}"),
+        }
+    }
+}
+
+/// Walks the subtree at `path` in two filesystems, listing (in path order) every way in which
+/// they differ
+///
+/// Entries are compared by kind, then by owner, group and mode (files and directories), content
+/// (files) or target (symlinks). An entry missing from either filesystem is reported once, not
+/// the entries beneath it. This allows a tree on disk to be checked against one simulated in
+/// memory, for example.
+///
+/// ```
+/// use diskplan_filesystem::{compare_subtrees, Difference, Filesystem, MemoryFilesystem};
+///
+/// let mut expected = MemoryFilesystem::new();
+/// expected.create_directory("/tree", Default::default()).unwrap();
+/// expected.create_file("/tree/file", Default::default(), "content".into()).unwrap();
+/// let mut actual = MemoryFilesystem::new();
+/// actual.create_directory("/tree", Default::default()).unwrap();
+///
+/// let differences = compare_subtrees(&expected, &actual, "/tree").unwrap();
+/// assert_eq!(differences, [Difference::OnlyInFirst("/tree/file".into())]);
+/// ```
+pub fn compare_subtrees<A, B>(
+    first: &A,
+    second: &B,
+    path: impl AsRef<Utf8Path>,
+) -> Result<Vec<Difference>>
+where
+    A: Filesystem,
+    B: Filesystem,
+{
+    let path = path.as_ref();
+    let first_entries = entries(first, path)?;
+    let second_entries = entries(second, path)?;
+
+    let mut paths: Vec<&Utf8PathBuf> = first_entries.keys().chain(second_entries.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut differences = Vec::new();
+    let mut missing: Option<&Utf8Path> = None;
+    for path in paths {
+        if missing.map(|missing| path.starts_with(missing)) == Some(true) {
+            continue;
+        }
+        let found = match (first_entries.get(path), second_entries.get(path)) {
+            (Some(_), None) => vec![Difference::OnlyInFirst(path.clone())],
+            (None, Some(_)) => vec![Difference::OnlyInSecond(path.clone())],
+            (Some((first_kind, first_attrs)), Some((second_kind, second_attrs))) => compare_entry(
+                (first, *first_kind, first_attrs),
+                (second, *second_kind, second_attrs),
+                path,
+            )?,
+            (None, None) => unreachable!("Path is from one or the other"),
+        };
+        // Anything beneath an entry missing from one side, or of a different kind, is missing
+        // from one side too
+        if let Some(
+            Difference::OnlyInFirst(_) | Difference::OnlyInSecond(_) | Difference::Kind { .. },
+        ) = found.first()
+        {
+            missing = Some(path);
+        }
+        differences.extend(found);
+    }
+    Ok(differences)
+}
+
+/// Compares an entry present in both filesystems, returning any differences found (a file may
+/// differ in both attributes and content)
+fn compare_entry<A, B>(
+    (first, first_kind, first_attrs): (&A, NodeKind, &Attrs),
+    (second, second_kind, second_attrs): (&B, NodeKind, &Attrs),
+    path: &Utf8Path,
+) -> Result<Vec<Difference>>
+where
+    A: Filesystem,
+    B: Filesystem,
+{
+    let mut differences = Vec::new();
+    if first_kind != second_kind {
+        differences.push(Difference::Kind {
+            path: path.to_owned(),
+            first: first_kind,
+            second: second_kind,
+        });
+    } else if first_kind == NodeKind::Symlink {
+        let (first_target, second_target) = (first.read_link(path)?, second.read_link(path)?);
+        if first_target != second_target {
+            differences.push(Difference::LinkTarget {
+                path: path.to_owned(),
+                first: first_target,
+                second: second_target,
+            });
+        }
+    } else {
+        if first_attrs != second_attrs {
+            differences.push(Difference::Attributes {
+                path: path.to_owned(),
+                first: owned(first_attrs),
+                second: owned(second_attrs),
+            });
+        }
+        if first_kind == NodeKind::File
+            && first.read_file_bytes(path)? != second.read_file_bytes(path)?
+        {
+            differences.push(Difference::Content(path.to_owned()));
+        }
+    }
+    Ok(differences)
+}
+
+/// Collects the kind and attributes of every entry at or beneath `path`, if it exists
+fn entries<FS>(
+    filesystem: &FS,
+    path: &Utf8Path,
+) -> Result<BTreeMap<Utf8PathBuf, (NodeKind, Attrs<'static>)>>
+where
+    FS: Filesystem,
+{
+    if !filesystem.exists(path) && !filesystem.is_link(path) {
+        return Ok(BTreeMap::new());
+    }
+    filesystem
+        .walk(path)
+        .map(|entry| entry.map(|(path, kind, attrs)| (path, (kind, owned(&attrs)))))
+        .collect()
+}
+
+fn owned(attrs: &Attrs) -> Attrs<'static> {
+    Attrs {
+        owner: Cow::Owned(attrs.owner.to_string()),
+        group: Cow::Owned(attrs.group.to_string()),
+        mode: attrs.mode,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{Filesystem, MemoryFilesystem, NodeKind, SetAttrs};
+
+    use super::{compare_subtrees, Difference};
+
+    #[test]
+    fn reports_each_kind_of_difference() -> Result<()> {
+        let mut first = MemoryFilesystem::new();
+        let mut second = MemoryFilesystem::new();
+        for fs in [&mut first, &mut second] {
+            fs.create_directory("/tree", SetAttrs::default())?;
+            fs.create_file("/tree/same", SetAttrs::default(), "same".into())?;
+            fs.create_symlink("/tree/link", "/tree/same")?;
+        }
+        first.create_directory("/tree/only_first", SetAttrs::default())?;
+        first.create_file("/tree/only_first/inner", SetAttrs::default(), "".into())?;
+        second.create_file("/tree/only_second", SetAttrs::default(), "".into())?;
+        first.create_directory("/tree/kind", SetAttrs::default())?;
+        first.create_file("/tree/kind/inner", SetAttrs::default(), "".into())?;
+        second.create_file("/tree/kind", SetAttrs::default(), "".into())?;
+        first.create_file(
+            "/tree/mode",
+            SetAttrs::default().with_mode(0o600),
+            "".into(),
+        )?;
+        second.create_file(
+            "/tree/mode",
+            SetAttrs::default().with_mode(0o640),
+            "".into(),
+        )?;
+        first.create_file("/tree/content", SetAttrs::default(), "one".into())?;
+        second.create_file("/tree/content", SetAttrs::default(), "two".into())?;
+        first.create_symlink("/tree/target", "/tree/same")?;
+        second.create_symlink("/tree/target", "/tree/content")?;
+
+        let differences = compare_subtrees(&first, &second, "/tree")?;
+        let paths: Vec<_> = differences.iter().map(|d| d.path().as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/tree/content",
+                "/tree/kind",
+                "/tree/mode",
+                "/tree/only_first",
+                "/tree/only_second",
+                "/tree/target",
+            ]
+        );
+        assert_eq!(differences[0], Difference::Content("/tree/content".into()));
+        assert_eq!(
+            differences[1],
+            Difference::Kind {
+                path: "/tree/kind".into(),
+                first: NodeKind::Directory,
+                second: NodeKind::File,
+            }
+        );
+        assert!(matches!(differences[2], Difference::Attributes { .. }));
+        assert_eq!(
+            differences[2].to_string(),
+            "Attributes of /tree/mode differ: root:root 0600 vs root:root 0640"
+        );
+        assert_eq!(
+            differences[5],
+            Difference::LinkTarget {
+                path: "/tree/target".into(),
+                first: "/tree/same".into(),
+                second: "/tree/content".into(),
+            }
+        );
+
+        assert!(compare_subtrees(&first, &first, "/tree")?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+//! Mapping between user/group names and their numeric IDs
+//!
+//! Owners and groups may be given by name, or numerically (as `1000` or `#1000`) for IDs that
+//! have no name entry, as is common in containers.
+
+use anyhow::{anyhow, Result};
+use users::{Groups, Users, UsersCache};
+
+/// Returns the user ID for the given owner name or numeric ID
+pub(crate) fn uid_for(users: &UsersCache, owner: &str) -> Result<u32> {
+    match numeric(owner) {
+        Some(uid) => Ok(uid),
+        None => Ok(users
+            .get_user_by_name(owner)
+            .ok_or_else(|| anyhow!("No such user: {}", owner))?
+            .uid()),
+    }
+}
+
+/// Returns the group ID for the given group name or numeric ID
+pub(crate) fn gid_for(users: &UsersCache, group: &str) -> Result<u32> {
+    match numeric(group) {
+        Some(gid) => Ok(gid),
+        None => Ok(users
+            .get_group_by_name(group)
+            .ok_or_else(|| anyhow!("No such group: {}", group))?
+            .gid()),
+    }
+}
+
+/// Returns the name of the user with the given ID, or the ID itself if it has no name
+pub(crate) fn user_name(users: &UsersCache, uid: u32) -> String {
+    users
+        .get_user_by_uid(uid)
+        .map(|user| user.name().to_string_lossy().into_owned())
+        .unwrap_or_else(|| uid.to_string())
+}
+
+/// Returns the name of the group with the given ID, or the ID itself if it has no name
+pub(crate) fn group_name(users: &UsersCache, gid: u32) -> String {
+    users
+        .get_group_by_gid(gid)
+        .map(|group| group.name().to_string_lossy().into_owned())
+        .unwrap_or_else(|| gid.to_string())
+}
+
+fn numeric(name: &str) -> Option<u32> {
+    let digits = name.strip_prefix('#').unwrap_or(name);
+    if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
@@ -1,34 +1,54 @@
 //! Provides an abstract [`Filesystem`] trait, together with a physical ([`DiskFilesystem`])
-//! and virtual ([`MemoryFilesystem`]) implementation.
+//! and virtual ([`MemoryFilesystem`]) implementation, and a [`RecordingFilesystem`] that reads
+//! from another but only records the changes made to it.
 #![warn(missing_docs)]
 
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    io::{Cursor, Read},
+};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 
 mod attributes;
+mod compare;
+mod ids;
 mod memory;
 mod physical;
+mod recording;
 mod root;
+mod walk;
 
 pub use self::{
-    attributes::{Attrs, Mode, SetAttrs, DEFAULT_DIRECTORY_MODE, DEFAULT_FILE_MODE},
-    memory::MemoryFilesystem,
+    attributes::{
+        AttrDiff, Attrs, Mode, SetAttrs, SetAttrsBuilder, Times, DEFAULT_DIRECTORY_MODE,
+        DEFAULT_FILE_MODE,
+    },
+    compare::{compare_subtrees, Difference},
+    memory::{Change, MemoryFilesystem, Snapshot, SnapshotEntry, Stats},
     physical::DiskFilesystem,
-    root::Root,
+    recording::{ChangeKind, Operation, RecordingFilesystem},
+    root::{expand_home, Root},
+    walk::{NodeKind, Walk},
 };
 
 impl SetAttrs<'_> {
     /// Returns true if this `SetAttrs` matches the given, existing `attrs`
     pub fn matches(&self, attrs: &Attrs) -> bool {
-        let SetAttrs { owner, group, mode } = self;
-        owner.map(|owner| owner == attrs.owner).unwrap_or(true)
-            && group.map(|group| group == attrs.group).unwrap_or(true)
-            && mode.map(|mode| mode == attrs.mode).unwrap_or(true)
+        attrs.diff(self).is_empty()
     }
 }
 
+/// The type and attributes of a directory or file, as returned by [`Filesystem::metadata`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata<'a> {
+    /// Whether this is a directory or a file (never a symlink, as these are dereferenced)
+    pub kind: NodeKind,
+    /// The owner, group and mode
+    pub attrs: Attrs<'a>,
+}
+
 /// Operations of a file system
 pub trait Filesystem {
     /// Create a directory at the given path, with any number of attributes set
@@ -54,8 +74,107 @@ pub trait Filesystem {
         path: impl AsRef<Utf8Path>,
         attrs: SetAttrs,
         content: String,
+    ) -> Result<()> {
+        self.create_file_bytes(path, attrs, content.into_bytes())
+    }
+
+    /// Create a file with the given binary content and any number of attributes set
+    fn create_file_bytes(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+        content: Vec<u8>,
+    ) -> Result<()>;
+
+    /// Create a file with the given binary content and any number of attributes set, unless a file
+    /// exists at the path already, returning whether it was created
+    ///
+    /// The check and creation are made as one step (where the filesystem allows), so that a file
+    /// created by another process in the meantime is neither overwritten nor reported as an
+    /// error. Anything other than a file at the path (or a symlink to one) is an error.
+    fn create_file_if_absent(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+        content: Vec<u8>,
+    ) -> Result<bool>;
+
+    /// Copy the file at `from` to a new file at `to`, with any number of attributes set
+    ///
+    /// The content is copied as is, without interpretation (it need not be valid UTF-8)
+    fn copy_within(
+        &mut self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
     ) -> Result<()>;
 
+    /// Copy the file at `from` to a new file at `to`, with any number of attributes set, unless a
+    /// file exists at `to` already, returning whether it was copied
+    ///
+    /// As with [`create_file_if_absent`](Filesystem::create_file_if_absent), the check and
+    /// creation are made as one step where the filesystem allows, and anything other than a file
+    /// at `to` (or a symlink to one) is an error.
+    fn copy_if_absent(
+        &mut self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+    ) -> Result<bool> {
+        let to = to.as_ref();
+        if self.exists(to) || self.is_link(to) {
+            if !self.is_file(to) {
+                bail!("Exists, but not as a file: {}", to);
+            }
+            return Ok(false);
+        }
+        self.copy_within(from, to, attrs)?;
+        Ok(true)
+    }
+
+    /// Replace the content of the existing file at `path`, keeping its attributes
+    fn write_file_bytes(&mut self, path: impl AsRef<Utf8Path>, content: Vec<u8>) -> Result<()>;
+
+    /// Copy the content of the directory at `from` into the existing directory at `to`,
+    /// recursively
+    ///
+    /// Everything copied is given the owner and group of `attrs` (where set), and the mode of the
+    /// entry it was copied from. Symlinks are copied as they are, not followed. It is an error if
+    /// any entry being copied already exists at its destination.
+    fn copy_tree(
+        &mut self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if !self.is_directory(from) {
+            bail!("Not a directory: {}", from);
+        }
+        for name in self.list_directory_sorted(from)? {
+            let (source, target) = (from.join(&name), to.join(&name));
+            if self.is_link(&source) {
+                let link = self.read_link(&source)?;
+                self.create_symlink(&target, link)?;
+                continue;
+            }
+            let attrs = SetAttrs {
+                mode: Some(self.attributes(&source)?.mode),
+                ..attrs.clone()
+            };
+            if self.is_directory(&source) {
+                self.create_directory(&target, attrs.clone())?;
+                self.copy_tree(&source, &target, attrs)?;
+            } else {
+                self.copy_within(&source, &target, attrs)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Create a symlink pointing to the given target
     fn create_symlink(
         &mut self,
@@ -63,6 +182,12 @@ pub trait Filesystem {
         target: impl AsRef<Utf8Path>,
     ) -> Result<()>;
 
+    /// Move the file, directory or symlink at `from` (with all its content) to `to`
+    ///
+    /// The final component of `from` is not dereferenced, so a symlink is itself moved. It is an
+    /// error if anything exists at `to`.
+    fn rename(&mut self, from: impl AsRef<Utf8Path>, to: impl AsRef<Utf8Path>) -> Result<()>;
+
     /// Returns true if the path exists
     fn exists(&self, path: impl AsRef<Utf8Path>) -> bool;
 
@@ -78,8 +203,40 @@ pub trait Filesystem {
     /// Lists the contents of the given directory
     fn list_directory(&self, path: impl AsRef<Utf8Path>) -> Result<Vec<String>>;
 
-    /// Reads the contents of the given file
-    fn read_file(&self, path: impl AsRef<Utf8Path>) -> Result<String>;
+    /// Lists the contents of the given directory, sorted by name (for consistent reporting)
+    fn list_directory_sorted(&self, path: impl AsRef<Utf8Path>) -> Result<Vec<String>> {
+        let mut listing = self.list_directory(path)?;
+        listing.sort();
+        Ok(listing)
+    }
+
+    /// Iterates over `root` and everything beneath it, with the type and attributes of each
+    ///
+    /// See [`Walk`] for the order in which entries are visited.
+    fn walk(&self, root: impl AsRef<Utf8Path>) -> Walk<'_, Self>
+    where
+        Self: Sized,
+    {
+        Walk::new(self, root)
+    }
+
+    /// Reads the contents of the given file, which must be valid UTF-8
+    fn read_file(&self, path: impl AsRef<Utf8Path>) -> Result<String> {
+        let path = path.as_ref();
+        String::from_utf8(self.read_file_bytes(path)?)
+            .with_context(|| format!("File content is not valid UTF-8: {path}"))
+    }
+
+    /// Reads the binary contents of the given file
+    fn read_file_bytes(&self, path: impl AsRef<Utf8Path>) -> Result<Vec<u8>>;
+
+    /// Opens the given file to read its binary contents as a stream
+    ///
+    /// By default, the whole file is read into memory up front. The [`DiskFilesystem`] instead
+    /// reads from disk as the stream is consumed, so large files need not be held in memory.
+    fn open_file(&self, path: impl AsRef<Utf8Path>) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(Cursor::new(self.read_file_bytes(path)?)))
+    }
 
     /// Reads the path pointed to by the given symbolic link
     fn read_link(&self, path: impl AsRef<Utf8Path>) -> Result<Utf8PathBuf>;
@@ -88,42 +245,121 @@ pub trait Filesystem {
     ///
     /// If the path is a symlink, the file/directory pointed to by the symlink will be checked
     /// and its attributes returned (i.e. paths are dereferenced)
-    fn attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs>;
+    fn attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs<'_>>;
+
+    /// Returns whether the given path is a directory or a file, along with its attributes, or
+    /// `None` if it is neither
+    ///
+    /// Symlinks are dereferenced, as by [`Filesystem::is_directory`], [`Filesystem::is_file`] and
+    /// [`Filesystem::attributes`], whose answers together this gives. Checking an existing entry
+    /// this way takes a single `stat` on disk, where those would take one each.
+    fn metadata(&self, path: impl AsRef<Utf8Path>) -> Option<Metadata<'_>> {
+        let path = path.as_ref();
+        let kind = if self.is_directory(path) {
+            NodeKind::Directory
+        } else if self.is_file(path) {
+            NodeKind::File
+        } else {
+            return None;
+        };
+        let attrs = self.attributes(path).ok()?;
+        Some(Metadata { kind, attrs })
+    }
 
     /// Sets the attributes of the given file or directory
     ///
-    /// If the path is a symlink, the file/directory pointed to by the symlink will be updated
-    /// with the given attributes (i.e. paths are dereferenced)
+    /// Only those attributes given are changed; the rest are left as they are. If the path is a
+    /// symlink, the file/directory pointed to by the symlink will be updated with the given
+    /// attributes (i.e. paths are dereferenced)
     fn set_attributes(&mut self, path: impl AsRef<Utf8Path>, attrs: SetAttrs) -> Result<()>;
 
+    /// Returns the last access and modification times of the given file or directory
+    ///
+    /// If the path is a symlink, the times of the file/directory pointed to are given
+    fn times(&self, path: impl AsRef<Utf8Path>) -> Result<Times>;
+
+    /// Sets the last access and modification times of the given file or directory
+    ///
+    /// If the path is a symlink, the file/directory pointed to is updated
+    fn set_times(&mut self, path: impl AsRef<Utf8Path>, times: Times) -> Result<()>;
+
+    /// Returns the attributes of the symlink at the given path itself, rather than those of what
+    /// it points to (as [`Filesystem::attributes`] would)
+    ///
+    /// It is an error if the path is not a symlink. The mode of a symlink is not meaningful on
+    /// Linux, where every link is given `777` and its permissions are never checked.
+    fn link_attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs<'_>>;
+
+    /// Sets the owner and group of the symlink at the given path itself, rather than those of what
+    /// it points to (as [`Filesystem::set_attributes`] would)
+    ///
+    /// Only those attributes given are changed. It is an error if the path is not a symlink, or
+    /// if a mode is given, since the mode of a symlink cannot be changed on Linux.
+    fn set_link_attributes(&mut self, path: impl AsRef<Utf8Path>, attrs: SetAttrs) -> Result<()>;
+
+    /// Returns another handle to this same filesystem, to be used from another thread, if this
+    /// is supported
+    ///
+    /// Independent subtrees are only traversed in parallel on filesystems that return a handle
+    /// here. Of those provided, only the [`DiskFilesystem`] does so (it holds no state of its
+    /// own); the [`MemoryFilesystem`] and [`RecordingFilesystem`] keep their state in a single
+    /// place, and so are always traversed serially.
+    fn fork(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
     /// Returns the path after following all symlinks, normalized and absolute
+    ///
+    /// It is an error if more than [`MAX_SYMLINKS`] symlinks are followed, as happens when links
+    /// point to one another in a loop.
     fn canonicalize(&self, path: impl AsRef<Utf8Path>) -> Result<Utf8PathBuf> {
         let path = path.as_ref();
-        if !path.is_absolute() {
-            // TODO: Keep a current_directory to provide relative path support
-            bail!("Only absolute paths supported");
+        canonicalize_following(self, path, &mut 0)
+            .with_context(|| format!("Failed to canonicalize {path}"))
+    }
+}
+
+/// The most symlinks followed by [`Filesystem::canonicalize`] before giving up, as Linux does
+/// (where the limit is also 40) with the error `ELOOP`
+pub const MAX_SYMLINKS: usize = 40;
+
+/// Canonicalizes `path` as [`Filesystem::canonicalize`] does, counting each symlink followed in
+/// `followed` (including those followed by any outer call)
+fn canonicalize_following<FS>(fs: &FS, path: &Utf8Path, followed: &mut usize) -> Result<Utf8PathBuf>
+where
+    FS: Filesystem + ?Sized,
+{
+    if !path.is_absolute() {
+        // TODO: Keep a current_directory to provide relative path support
+        bail!("Only absolute paths supported");
+    }
+    let mut canon = Utf8PathBuf::with_capacity(path.as_str().len());
+    for part in path.components() {
+        if part == Utf8Component::ParentDir {
+            let pop = canon.pop();
+            assert!(pop);
+            continue;
         }
-        let mut canon = Utf8PathBuf::with_capacity(path.as_str().len());
-        for part in path.components() {
-            if part == Utf8Component::ParentDir {
-                let pop = canon.pop();
-                assert!(pop);
-                continue;
+        canon.push(part);
+        if fs.is_link(Utf8Path::new(&canon)) {
+            *followed += 1;
+            if *followed > MAX_SYMLINKS {
+                bail!("Too many levels of symbolic links (at {canon})");
             }
-            canon.push(part);
-            if self.is_link(Utf8Path::new(&canon)) {
-                let link = self.read_link(&canon)?;
-                if link.is_absolute() {
-                    canon.clear();
-                } else {
-                    canon.pop();
-                }
-                canon.push(link);
-                canon = self.canonicalize(canon)?;
+            let link = fs.read_link(&canon)?;
+            if link.is_absolute() {
+                canon.clear();
+            } else {
+                canon.pop();
             }
+            canon.push(link);
+            canon = canonicalize_following(fs, &canon, followed)?;
         }
-        Ok(canon)
     }
+    Ok(canon)
 }
 
 /// Splits the dirname and basename of the path if possible to do so
@@ -139,6 +375,7 @@ fn split(path: &Utf8Path) -> Option<(&Utf8Path, &str)> {
 }
 
 /// An absolute path that can be split easily into its [`Root`] and relative path parts
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PlantedPath {
     root_len: usize,
     full: Utf8PathBuf,
@@ -170,6 +407,14 @@ impl PlantedPath {
         self.full.as_str()[..self.root_len].into()
     }
 
+    /// Produces the planted path of the root itself
+    pub fn to_root(&self) -> Self {
+        PlantedPath {
+            root_len: self.root_len,
+            full: self.root().to_owned(),
+        }
+    }
+
     /// The full, absolute path
     pub fn absolute(&self) -> &Utf8Path {
         &self.full
@@ -182,8 +427,30 @@ impl PlantedPath {
             .into()
     }
 
-    /// Produces a new planted path with the given path part appended
-    pub fn join(&self, name: impl AsRef<str>) -> Result<Self> {
+    /// Produces a new planted path with the given relative path appended
+    ///
+    /// The path may have any number of components (such as `resources/assets`), each of which is
+    /// checked with the same rules as [`PlantedPath::join_component`]
+    pub fn join(&self, path: impl AsRef<str>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.starts_with('/') {
+            bail!(
+                "Only relative paths can be joined to a planted path: {}",
+                path
+            );
+        }
+        let mut joined = self.clone();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            joined = joined.join_component(name)?;
+        }
+        Ok(joined)
+    }
+
+    /// Produces a new planted path with a single path component (a name) appended
+    ///
+    /// The name must not contain a `/`, nor be `.` or `..`, which would take the path somewhere
+    /// other than an entry within this one
+    pub fn join_component(&self, name: impl AsRef<str>) -> Result<Self> {
         let name = name.as_ref();
         if name.contains('/') {
             bail!(
@@ -191,6 +458,9 @@ impl PlantedPath {
                 name
             );
         }
+        if name.is_empty() || name == "." || name == ".." {
+            bail!("Invalid path component: {:?}", name);
+        }
         Ok(PlantedPath {
             root_len: self.root_len,
             full: self.full.join(name),
@@ -210,6 +480,50 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn expand_home_directories() -> Result<()> {
+        assert_eq!(expand_home("/no/tilde")?, "/no/tilde");
+        assert_eq!(expand_home("relative/~")?, "relative/~");
+
+        let root_home = expand_home("~root")?;
+        assert!(root_home.is_absolute());
+        assert_eq!(expand_home("~root/sub/dir")?, root_home.join("sub/dir"));
+        assert!(expand_home("~")?.is_absolute());
+        assert!(expand_home("~no-such-user-exists/dir").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn root_display_round_trips() -> Result<()> {
+        // Every combination of components and spellings that normalize away
+        let components = [
+            "",
+            "net",
+            "remote",
+            "with space",
+            "ünïcode",
+            ".hidden",
+            "a.b",
+        ];
+        let separators = ["/", "//", "/./"];
+        for first in components {
+            for second in components {
+                for separator in separators {
+                    for trailing in ["", "/", "/."] {
+                        let text = format!("/{first}{separator}{second}{trailing}");
+                        let root = Root::try_from(text.as_str())?;
+                        assert_eq!(Root::try_from(root.to_string().as_str())?, root, "{text}");
+                        assert_eq!(root.to_string().parse::<Root>()?, root, "{text}");
+                        assert_eq!(root.to_string(), root.path().as_str());
+                    }
+                }
+            }
+        }
+        assert!("relative".parse::<Root>().is_err());
+        assert!("/parent/..".parse::<Root>().is_err());
+        Ok(())
+    }
+
     #[test]
     fn check_relative() {
         let path = PlantedPath::new(
@@ -220,6 +534,22 @@ mod tests {
         assert_eq!(path.relative(), "path");
     }
 
+    #[test]
+    fn join_planted_paths() -> Result<()> {
+        let path = PlantedPath::new(&Root::try_from("/example")?, None)?;
+        let joined = path.join("resources//assets/")?;
+        assert_eq!(joined.absolute(), "/example/resources/assets");
+        assert_eq!(joined.relative(), "resources/assets");
+        assert_eq!(path.join_component("name")?.relative(), "name");
+
+        assert!(path.join("/absolute").is_err());
+        assert!(path.join("resources/../elsewhere").is_err());
+        assert!(path.join("./resources").is_err());
+        assert!(path.join_component("resources/assets").is_err());
+        assert!(path.join_component("..").is_err());
+        Ok(())
+    }
+
     #[test]
     fn canonicalize() -> Result<()> {
         let path = Utf8Path::new("/");
@@ -254,4 +584,34 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn canonicalize_symlink_loop() -> Result<()> {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/dir", Default::default())?;
+        fs.create_symlink("/dir/a", "b")?;
+        fs.create_symlink("/dir/b", "/dir/a")?;
+        fs.create_symlink("/dir/self", "self/deeper")?;
+
+        for path in ["/dir/a", "/dir/b/file", "/dir/self"] {
+            let error = format!("{:#}", fs.canonicalize(path).unwrap_err());
+            assert!(error.starts_with(&format!("Failed to canonicalize {path}: ")));
+            assert!(error.contains("Too many levels of symbolic links"));
+        }
+        assert!(!fs.exists("/dir/a"));
+        assert!(fs
+            .create_file("/dir/a/file", Default::default(), "".into())
+            .is_err());
+
+        // A long chain within the limit is followed to its end
+        fs.create_directory("/chain", Default::default())?;
+        for n in 0..MAX_SYMLINKS {
+            fs.create_symlink(format!("/chain/{n}"), format!("{}", n + 1))?;
+        }
+        assert_eq!(
+            fs.canonicalize("/chain/0")?,
+            format!("/chain/{MAX_SYMLINKS}")
+        );
+        Ok(())
+    }
 }
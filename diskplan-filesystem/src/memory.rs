@@ -6,12 +6,16 @@ use std::{
 use anyhow::{anyhow, bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use nix::unistd;
-use users::{Groups, Users, UsersCache};
+use users::UsersCache;
 
 use super::{
-    attributes::Mode, Attrs, Filesystem, SetAttrs, DEFAULT_DIRECTORY_MODE, DEFAULT_FILE_MODE,
+    attributes::Mode, ids, Attrs, Filesystem, Metadata, NodeKind, SetAttrs, Times,
+    DEFAULT_DIRECTORY_MODE, DEFAULT_FILE_MODE,
 };
 
+mod snapshot;
+pub use snapshot::{Change, Snapshot, SnapshotEntry};
+
 /// An in-memory representation of a file system
 pub struct MemoryFilesystem {
     map: HashMap<Utf8PathBuf, Node>,
@@ -19,34 +23,59 @@ pub struct MemoryFilesystem {
 
     uid: u32,
     gid: u32,
+
+    /// Whether unknown owners and groups are given made-up IDs, rather than being an error
+    fake_ids: bool,
+    /// Names of the unknown owners given made-up IDs, indexed from [`MemoryFilesystem::FAKE_ID`]
+    fake_users: Vec<String>,
+    /// Names of the unknown groups given made-up IDs, indexed from [`MemoryFilesystem::FAKE_ID`]
+    fake_groups: Vec<String>,
+}
+
+/// Counts of the entries held by a [`MemoryFilesystem`], and the size of their content
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of files
+    pub files: usize,
+    /// The number of directories (including the root)
+    pub directories: usize,
+    /// The number of symlinks
+    pub symlinks: usize,
+    /// The total size of the content of all files, in bytes
+    pub content_bytes: usize,
 }
 
 #[derive(Debug)]
 enum Node {
     File {
         attrs: FSAttrs,
-        content: String,
+        content: Vec<u8>,
     },
     Directory {
         attrs: FSAttrs,
         children: Vec<String>,
     },
     Symlink {
+        /// The owner and group of the link itself (its mode is always `777`)
+        attrs: FSAttrs,
         target: Utf8PathBuf,
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct FSAttrs {
     uid: u32,
     gid: u32,
     mode: u16,
+    times: Times,
 }
 
 impl MemoryFilesystem {
     const ROOT: u32 = 0;
     const DEFAULT_OWNER: u32 = Self::ROOT;
     const DEFAULT_GROUP: u32 = Self::ROOT;
+    /// The first of the made-up IDs, chosen to be well clear of any real user or group
+    const FAKE_ID: u32 = 0xF000_0000;
 
     /// Constructs a new in-memory filesystem
     pub fn new() -> Self {
@@ -58,6 +87,7 @@ impl MemoryFilesystem {
                     uid: Self::DEFAULT_OWNER,
                     gid: Self::DEFAULT_GROUP,
                     mode: DEFAULT_DIRECTORY_MODE.into(),
+                    times: Times::now(),
                 },
                 children: vec![],
             },
@@ -67,13 +97,170 @@ impl MemoryFilesystem {
             users: UsersCache::new(),
             uid: unistd::getuid().as_raw(),
             gid: unistd::getgid().as_raw(),
+            fake_ids: false,
+            fake_users: vec![],
+            fake_groups: vec![],
         }
     }
 
+    /// Sets whether owners and groups unknown to this machine are accepted
+    ///
+    /// By default, an owner or group with no entry in the local user database is an error, just
+    /// as it is on disk. With fake IDs, each such name is given a made-up ID instead, so that
+    /// schemas referring to accounts that only exist elsewhere can still be simulated. The
+    /// original name is reported back by [`Filesystem::attributes`].
+    pub fn with_fake_ids(mut self, fake_ids: bool) -> Self {
+        self.fake_ids = fake_ids;
+        self
+    }
+
+    /// Constructs an in-memory filesystem from a tree drawn in the style of the `tree` command
+    ///
+    /// Each unindented line gives an absolute directory path (created along with its parents),
+    /// beneath which entries are drawn with `├── ` and `└── `, indented by `│   ` or four spaces
+    /// per level. A name ending in `/` is a directory, `name -> target` is a symlink, and anything
+    /// else is an empty file. Any indentation common to the whole tree is ignored. For example:
+    /// ```
+    /// # use diskplan_filesystem::{Filesystem, MemoryFilesystem};
+    /// let fs = MemoryFilesystem::from_tree(
+    ///     "
+    ///     /home/
+    ///     ├── alice/
+    ///     │   └── notes.txt
+    ///     └── latest -> alice
+    ///     ",
+    /// )
+    /// .unwrap();
+    /// assert!(fs.is_file("/home/alice/notes.txt"));
+    /// assert!(fs.is_file("/home/latest/notes.txt"));
+    /// ```
+    pub fn from_tree(tree: &str) -> Result<Self> {
+        let mut fs = MemoryFilesystem::new();
+        // The path of the most recent entry at each depth, if it is a directory
+        let mut parents: Vec<Option<Utf8PathBuf>> = vec![];
+        let mut margin = None;
+        for (index, line) in tree.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // `tree` itself draws with non-breaking spaces
+            let line = line.trim_end().replace('\u{a0}', " ");
+            let margin = *margin.get_or_insert_with(|| line.len() - line.trim_start().len());
+            fs.add_tree_line(&line, margin, &mut parents)
+                .with_context(|| format!("Invalid tree at line {}: {:?}", index + 1, line))?;
+        }
+        Ok(fs)
+    }
+
+    fn add_tree_line(
+        &mut self,
+        line: &str,
+        margin: usize,
+        parents: &mut Vec<Option<Utf8PathBuf>>,
+    ) -> Result<()> {
+        let mut rest = match line.get(margin..) {
+            Some(rest) if line[..margin].trim().is_empty() => rest,
+            _ => bail!("Line is indented less than the first"),
+        };
+        let mut depth = 0;
+        while let Some(inner) = rest
+            .strip_prefix("│   ")
+            .or_else(|| rest.strip_prefix("    "))
+        {
+            depth += 1;
+            rest = inner;
+        }
+        if let Some(entry) = rest
+            .strip_prefix("├── ")
+            .or_else(|| rest.strip_prefix("└── "))
+        {
+            depth += 1;
+            rest = entry;
+        } else if depth > 0 || rest.starts_with(['│', '├', '└', ' ']) {
+            bail!("Expected an entry beginning \"├── \" or \"└── \"");
+        }
+
+        if depth == 0 {
+            let path = Utf8Path::new(rest);
+            if !path.is_absolute() {
+                bail!("Top level entry must be an absolute path");
+            }
+            if path.as_str().contains(" -> ") {
+                bail!("Top level entry must be a directory");
+            }
+            self.create_directory_all(path, SetAttrs::default())?;
+            parents.clear();
+            parents.push(Some(path.to_owned()));
+            return Ok(());
+        }
+
+        if parents.len() < depth {
+            bail!("Entry is indented too far (no parent at the level above)");
+        }
+        parents.truncate(depth);
+        let parent = match &parents[depth - 1] {
+            Some(parent) => parent.clone(),
+            None => bail!("Entry is beneath a file or symlink"),
+        };
+        let (name, target) = match rest.split_once(" -> ") {
+            Some((name, target)) => (name, Some(target)),
+            None => (rest, None),
+        };
+        let (name, is_dir) = match name.strip_suffix('/') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        if name.is_empty() || name.contains('/') {
+            bail!("Invalid name: {:?}", name);
+        }
+        let path = parent.join(name);
+        match target {
+            Some(target) => self.create_symlink(&path, target)?,
+            None if is_dir => self.create_directory(&path, SetAttrs::default())?,
+            None => self.create_file(&path, SetAttrs::default(), String::new())?,
+        }
+        parents.push(if is_dir && target.is_none() {
+            Some(path)
+        } else {
+            None
+        });
+        Ok(())
+    }
+
     /// For use by tests to compare with expected results
     pub fn to_path_set(&self) -> HashSet<&Utf8Path> {
         self.map.keys().map(|i| i.as_ref()).collect()
     }
+
+    /// Returns the path of every entry at or beneath `root`, in the order [`Filesystem::walk`]
+    /// visits them, or nothing if `root` does not exist
+    pub fn paths_under(&self, root: impl AsRef<Utf8Path>) -> impl Iterator<Item = &Utf8Path> {
+        // Each walked path is looked up again, to borrow it from the filesystem
+        self.walk(root).filter_map(|entry| {
+            let (path, _, _) = entry.ok()?;
+            self.map
+                .get_key_value(&path)
+                .map(|(path, _)| path.as_path())
+        })
+    }
+
+    /// Counts the entries of each kind held in memory, along with the size of their content
+    ///
+    /// This allows tests to guard against a schema producing far more than expected (such as
+    /// dynamic bindings multiplying across a large tree).
+    pub fn stats(&self) -> Stats {
+        self.map.values().fold(Stats::default(), |mut stats, node| {
+            match node {
+                Node::File { content, .. } => {
+                    stats.files += 1;
+                    stats.content_bytes += content.len();
+                }
+                Node::Directory { .. } => stats.directories += 1,
+                Node::Symlink { .. } => stats.symlinks += 1,
+            }
+            stats
+        })
+    }
 }
 
 impl Default for MemoryFilesystem {
@@ -94,11 +281,11 @@ impl Filesystem for MemoryFilesystem {
             .with_context(|| format!("Creating directory: {path}"))
     }
 
-    fn create_file(
+    fn create_file_bytes(
         &mut self,
         path: impl AsRef<Utf8Path>,
         attrs: SetAttrs,
-        content: String,
+        content: Vec<u8>,
     ) -> Result<()> {
         let path = path.as_ref();
         let (parent, name) = self.canonical_split(path)?;
@@ -107,6 +294,60 @@ impl Filesystem for MemoryFilesystem {
             .with_context(|| format!("Creating file: {path}"))
     }
 
+    fn create_file_if_absent(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+        content: Vec<u8>,
+    ) -> Result<bool> {
+        let path = path.as_ref();
+        let (parent, name) = self.canonical_split(path)?;
+        let existing = parent.join(name);
+        if self.map.contains_key(&existing) {
+            if !self.is_file(&existing) {
+                bail!("Exists, but not as a file: {}", path);
+            }
+            return Ok(false);
+        }
+        let attrs = self.internal_attrs(attrs, DEFAULT_FILE_MODE)?;
+        self.insert_node(&parent, name, Node::File { attrs, content })
+            .with_context(|| format!("Creating file: {path}"))?;
+        Ok(true)
+    }
+
+    fn copy_within(
+        &mut self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+    ) -> Result<()> {
+        let from = self.canonicalize(from)?;
+        let to = to.as_ref();
+        let content = match self.node_from_path(&from)? {
+            Node::File { content, .. } => content.clone(),
+            Node::Directory { .. } => bail!("Tried to copy directory as a file: {}", from),
+            Node::Symlink { .. } => unreachable!("Non-canonical path: {}", from),
+        };
+        let (parent, name) = self.canonical_split(to)?;
+        let attrs = self.internal_attrs(attrs, DEFAULT_FILE_MODE)?;
+        self.insert_node(&parent, name, Node::File { attrs, content })
+            .with_context(|| format!("Copying file: {from} to {to}"))
+    }
+
+    fn write_file_bytes(&mut self, path: impl AsRef<Utf8Path>, content: Vec<u8>) -> Result<()> {
+        let path = self.canonicalize(path)?;
+        match self.map.get_mut(&path) {
+            Some(Node::File {
+                content: existing, ..
+            }) => {
+                *existing = content;
+                Ok(())
+            }
+            Some(_) => Err(anyhow!("Not a file: {}", path)),
+            None => Err(anyhow!("No such file or directory: {}", path)),
+        }
+    }
+
     fn create_symlink(
         &mut self,
         path: impl AsRef<Utf8Path>,
@@ -119,12 +360,58 @@ impl Filesystem for MemoryFilesystem {
             &parent,
             name,
             Node::Symlink {
+                attrs: FSAttrs {
+                    uid: self.uid,
+                    gid: self.gid,
+                    mode: 0o777,
+                    times: Times::now(),
+                },
                 target: target.to_owned(),
             },
         )
         .with_context(|| format!("Creating symlink: {path} -> {target}"))
     }
 
+    fn rename(&mut self, from: impl AsRef<Utf8Path>, to: impl AsRef<Utf8Path>) -> Result<()> {
+        let (from_parent, from_name) = self.canonical_split(from.as_ref())?;
+        let (to_parent, to_name) = self.canonical_split(to.as_ref())?;
+        let from = from_parent.join(from_name);
+        let to = to_parent.join(to_name);
+        self.node_from_path(&from)?;
+        if to.starts_with(&from) {
+            bail!("Cannot move {} inside itself ({})", from, to);
+        }
+        if !matches!(self.map.get(&to_parent), Some(Node::Directory { .. })) {
+            bail!("Parent directory not found: {}", to_parent);
+        }
+        if self.map.contains_key(&to) {
+            bail!("File exists: {}", to);
+        }
+        // Detach from the old parent and attach to the new
+        if let Some(Node::Directory { children, .. }) = self.map.get_mut(&from_parent) {
+            children.retain(|child| child != from_name);
+        }
+        if let Some(Node::Directory { children, .. }) = self.map.get_mut(&to_parent) {
+            children.push(to_name.into());
+        }
+        // Move the entry and everything beneath it
+        let moving: Vec<Utf8PathBuf> = self
+            .map
+            .keys()
+            .filter(|path| path.starts_with(&from))
+            .cloned()
+            .collect();
+        for old in moving {
+            let node = self.map.remove(&old).expect("Path listed from map");
+            let new = match old.strip_prefix(&from).expect("Path under prefix") {
+                rest if rest == "" => to.clone(),
+                rest => to.join(rest),
+            };
+            self.map.insert(new, node);
+        }
+        Ok(())
+    }
+
     fn exists(&self, path: impl AsRef<Utf8Path>) -> bool {
         match self.canonicalize(path) {
             Ok(path) => self.map.contains_key(&path),
@@ -159,7 +446,7 @@ impl Filesystem for MemoryFilesystem {
         })
     }
 
-    fn read_file(&self, path: impl AsRef<Utf8Path>) -> Result<String> {
+    fn read_file_bytes(&self, path: impl AsRef<Utf8Path>) -> Result<Vec<u8>> {
         let path = self.canonicalize(path)?;
         Ok(match self.node_from_path(&path)? {
             Node::File { content, .. } => content.clone(),
@@ -170,64 +457,99 @@ impl Filesystem for MemoryFilesystem {
 
     fn read_link(&self, path: impl AsRef<Utf8Path>) -> Result<Utf8PathBuf> {
         Ok(match self.node_from_path(&path)? {
-            Node::Symlink { target } => target.clone(),
+            Node::Symlink { target, .. } => target.clone(),
             _ => bail!("Not a symlink: {}", path.as_ref()),
         })
     }
 
-    fn attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs> {
+    fn attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs<'_>> {
         let path = self.canonicalize(path)?;
         let node = self.node_from_path(&path)?;
-        let attrs = match node {
-            Node::Directory { attrs, .. } | Node::File { attrs, .. } => attrs,
+        match node {
+            Node::Directory { attrs, .. } | Node::File { attrs, .. } => Ok(self.named(attrs)),
             Node::Symlink { .. } => panic!("Non-canonical path: {path}"),
-        };
-        let owner = Cow::Owned(
-            self.users
-                .get_user_by_uid(attrs.uid)
-                .ok_or_else(|| anyhow!("Failed to get user from UID: {}", attrs.uid))?
-                .name()
-                .to_string_lossy()
-                .into_owned(),
-        );
-        let group = Cow::Owned(
-            self.users
-                .get_group_by_gid(attrs.gid)
-                .ok_or_else(|| anyhow!("Failed to get group from GID: {}", attrs.gid))?
-                .name()
-                .to_string_lossy()
-                .into_owned(),
-        );
-        let mode = attrs.mode.into();
-        Ok(Attrs { owner, group, mode })
+        }
+    }
+
+    fn metadata(&self, path: impl AsRef<Utf8Path>) -> Option<Metadata<'_>> {
+        let path = self.canonicalize(path).ok()?;
+        match self.map.get(&path)? {
+            Node::Directory { attrs, .. } => Some(Metadata {
+                kind: NodeKind::Directory,
+                attrs: self.named(attrs),
+            }),
+            Node::File { attrs, .. } => Some(Metadata {
+                kind: NodeKind::File,
+                attrs: self.named(attrs),
+            }),
+            Node::Symlink { .. } => unreachable!("Non-canonical path: {}", path),
+        }
     }
 
     fn set_attributes(&mut self, path: impl AsRef<Utf8Path>, set_attrs: SetAttrs) -> Result<()> {
-        let use_default = set_attrs.mode.is_none();
-        let mut fs_attrs = self.internal_attrs(set_attrs, 0.into())?;
         let path = self.canonicalize(path)?;
-        let node = self
-            .map
-            .get_mut(&path)
-            .ok_or_else(|| anyhow!("No such file or directory: {}", path))?;
-        match node {
-            Node::Directory { attrs, .. } => {
-                if use_default {
-                    fs_attrs.mode = DEFAULT_DIRECTORY_MODE.into();
-                }
-                *attrs = fs_attrs;
-                Ok(())
-            }
-            Node::File { attrs, .. } => {
-                if use_default {
-                    fs_attrs.mode = DEFAULT_FILE_MODE.into();
-                }
-                *attrs = fs_attrs;
+        let current = match self.map.get(&path) {
+            Some(Node::Directory { attrs, .. } | Node::File { attrs, .. }) => attrs.clone(),
+            Some(Node::Symlink { .. }) => bail!("Non-canonical path: {}", path),
+            None => bail!("No such file or directory: {}", path),
+        };
+        let fs_attrs = self.updated_attrs(&current, set_attrs)?;
+        if let Some(Node::Directory { attrs, .. } | Node::File { attrs, .. }) =
+            self.map.get_mut(&path)
+        {
+            *attrs = fs_attrs;
+        }
+        Ok(())
+    }
+
+    fn times(&self, path: impl AsRef<Utf8Path>) -> Result<Times> {
+        let path = self.canonicalize(path)?;
+        match self.node_from_path(&path)? {
+            Node::Directory { attrs, .. } | Node::File { attrs, .. } => Ok(attrs.times),
+            Node::Symlink { .. } => unreachable!("Non-canonical path: {}", path),
+        }
+    }
+
+    fn set_times(&mut self, path: impl AsRef<Utf8Path>, times: Times) -> Result<()> {
+        let path = self.canonicalize(path)?;
+        match self.map.get_mut(&path) {
+            Some(Node::Directory { attrs, .. } | Node::File { attrs, .. }) => {
+                attrs.times = times;
                 Ok(())
             }
-            Node::Symlink { .. } => Err(anyhow!("Non-canonical path: {}", path)),
+            Some(Node::Symlink { .. }) => unreachable!("Non-canonical path: {}", path),
+            None => Err(anyhow!("No such file or directory: {}", path)),
         }
     }
+
+    fn link_attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs<'_>> {
+        let (parent, name) = self.canonical_split(path.as_ref())?;
+        match self.map.get(&parent.join(name)) {
+            Some(Node::Symlink { attrs, .. }) => Ok(self.named(attrs)),
+            _ => bail!("Not a symlink: {}", path.as_ref()),
+        }
+    }
+
+    fn set_link_attributes(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        set_attrs: SetAttrs,
+    ) -> Result<()> {
+        let (parent, name) = self.canonical_split(path.as_ref())?;
+        let link = parent.join(name);
+        let current = match self.map.get(&link) {
+            Some(Node::Symlink { attrs, .. }) => attrs.clone(),
+            _ => bail!("Not a symlink: {}", path.as_ref()),
+        };
+        if set_attrs.mode.is_some() {
+            bail!("The mode of a symlink cannot be set: {}", link);
+        }
+        let fs_attrs = self.updated_attrs(&current, set_attrs)?;
+        if let Some(Node::Symlink { attrs, .. }) = self.map.get_mut(&link) {
+            *attrs = fs_attrs;
+        }
+        Ok(())
+    }
 }
 
 impl MemoryFilesystem {
@@ -238,25 +560,75 @@ impl MemoryFilesystem {
         }
     }
 
-    fn internal_attrs(&self, attrs: SetAttrs, default_mode: Mode) -> Result<FSAttrs> {
+    /// Returns the given attributes with their IDs given as names
+    fn named(&self, attrs: &FSAttrs) -> Attrs<'_> {
+        let owner = Cow::Owned(match Self::fake_name(&self.fake_users, attrs.uid) {
+            Some(name) => name.to_owned(),
+            None => ids::user_name(&self.users, attrs.uid),
+        });
+        let group = Cow::Owned(match Self::fake_name(&self.fake_groups, attrs.gid) {
+            Some(name) => name.to_owned(),
+            None => ids::group_name(&self.users, attrs.gid),
+        });
+        let mode = attrs.mode.into();
+        Attrs { owner, group, mode }
+    }
+
+    /// Returns the `current` attributes with those given by `set_attrs` changed, and the rest
+    /// left as they are
+    fn updated_attrs(&mut self, current: &FSAttrs, set_attrs: SetAttrs) -> Result<FSAttrs> {
+        let (keep_owner, keep_group) = (set_attrs.owner.is_none(), set_attrs.group.is_none());
+        let mut fs_attrs = self.internal_attrs(set_attrs, current.mode.into())?;
+        if keep_owner {
+            fs_attrs.uid = current.uid;
+        }
+        if keep_group {
+            fs_attrs.gid = current.gid;
+        }
+        fs_attrs.times = current.times;
+        Ok(fs_attrs)
+    }
+
+    fn internal_attrs(&mut self, attrs: SetAttrs, default_mode: Mode) -> Result<FSAttrs> {
         let uid = match attrs.owner {
-            Some(owner) => self
-                .users
-                .get_user_by_name(owner)
-                .ok_or_else(|| anyhow!("No such user: {}", owner))?
-                .uid(),
+            Some(owner) => match ids::uid_for(&self.users, owner) {
+                Err(_) if self.fake_ids => Self::fake_id(&mut self.fake_users, owner),
+                uid => uid?,
+            },
             None => self.uid,
         };
         let gid = match attrs.group {
-            Some(group) => self
-                .users
-                .get_group_by_name(group)
-                .ok_or_else(|| anyhow!("No such group: {}", group))?
-                .gid(),
+            Some(group) => match ids::gid_for(&self.users, group) {
+                Err(_) if self.fake_ids => Self::fake_id(&mut self.fake_groups, group),
+                gid => gid?,
+            },
             None => self.gid,
         };
         let mode = attrs.mode.unwrap_or(default_mode).into();
-        Ok(FSAttrs { uid, gid, mode })
+        Ok(FSAttrs {
+            uid,
+            gid,
+            mode,
+            times: Times::now(),
+        })
+    }
+
+    /// Returns the made-up ID for an unknown name, allocating the next one if it has none yet
+    fn fake_id(names: &mut Vec<String>, name: &str) -> u32 {
+        let index = match names.iter().position(|known| known == name) {
+            Some(index) => index,
+            None => {
+                names.push(name.to_owned());
+                names.len() - 1
+            }
+        };
+        Self::FAKE_ID + index as u32
+    }
+
+    /// Returns the unknown name for which the given ID was made up, if it was
+    fn fake_name(names: &[String], id: u32) -> Option<&str> {
+        let index = id.checked_sub(Self::FAKE_ID)?;
+        names.get(index as usize).map(String::as_str)
     }
 
     /// Inserts a new entry into the filesystem, under the given *canonical* parent
@@ -300,9 +672,13 @@ impl MemoryFilesystem {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Filesystem, SetAttrs};
+    use std::time::{Duration, SystemTime};
 
-    use super::MemoryFilesystem;
+    use camino::Utf8Path;
+
+    use crate::{Filesystem, NodeKind, SetAttrs, Times};
+
+    use super::{Change, MemoryFilesystem, Stats};
 
     #[test]
     fn exists() {
@@ -313,6 +689,410 @@ mod tests {
         assert!(fs.exists("/entry"));
     }
 
+    #[test]
+    fn stats() {
+        let mut fs = MemoryFilesystem::new();
+        assert_eq!(
+            fs.stats(),
+            Stats {
+                directories: 1,
+                ..Default::default()
+            }
+        );
+        fs.create_directory("/dir", SetAttrs::default()).unwrap();
+        fs.create_file("/dir/a", SetAttrs::default(), "four".into())
+            .unwrap();
+        fs.create_file("/dir/b", SetAttrs::default(), "six...".into())
+            .unwrap();
+        fs.create_symlink("/link", "/dir").unwrap();
+        assert_eq!(
+            fs.stats(),
+            Stats {
+                files: 2,
+                directories: 2,
+                symlinks: 1,
+                content_bytes: 10,
+            }
+        );
+    }
+
+    #[test]
+    fn rename() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/old", SetAttrs::default()).unwrap();
+        fs.create_directory("/old/sub", SetAttrs::default())
+            .unwrap();
+        fs.create_file("/old/sub/file", SetAttrs::default(), "content".into())
+            .unwrap();
+        fs.create_directory("/dest", SetAttrs::default()).unwrap();
+
+        fs.rename("/old", "/dest/new").unwrap();
+        assert!(!fs.exists("/old"));
+        assert!(!fs.list_directory("/").unwrap().contains(&"old".to_owned()));
+        assert_eq!(fs.list_directory("/dest").unwrap(), ["new"]);
+        assert_eq!(fs.read_file("/dest/new/sub/file").unwrap(), "content");
+
+        // Destination must not exist, nor be within the source
+        fs.create_directory("/other", SetAttrs::default()).unwrap();
+        assert!(fs.rename("/other", "/dest/new").is_err());
+        assert!(fs.rename("/dest", "/dest/new/inner").is_err());
+        assert!(fs.rename("/missing", "/elsewhere").is_err());
+    }
+
+    #[test]
+    fn walk() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/b", SetAttrs::default()).unwrap();
+        fs.create_file("/b/file", SetAttrs::default().with_mode(0o600), "".into())
+            .unwrap();
+        fs.create_directory("/a", SetAttrs::default()).unwrap();
+        fs.create_symlink("/a/link", "/b").unwrap();
+        fs.create_symlink("/a/dangling", "/missing").unwrap();
+
+        let walked: Vec<_> = fs
+            .walk("/")
+            .map(|entry| {
+                let (path, kind, attrs) = entry.unwrap();
+                (path.to_string(), kind, attrs.mode.value())
+            })
+            .collect();
+        assert_eq!(
+            walked,
+            [
+                ("/".to_owned(), NodeKind::Directory, 0o755),
+                ("/a".to_owned(), NodeKind::Directory, 0o755),
+                ("/a/dangling".to_owned(), NodeKind::Symlink, 0o777),
+                ("/a/link".to_owned(), NodeKind::Symlink, 0o777),
+                ("/b".to_owned(), NodeKind::Directory, 0o755),
+                ("/b/file".to_owned(), NodeKind::File, 0o600),
+            ]
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/dir", SetAttrs::default().with_mode(0o700))
+            .unwrap();
+        fs.create_file("/dir/file", SetAttrs::default(), "".into())
+            .unwrap();
+        fs.create_symlink("/link", "/dir").unwrap();
+        fs.create_symlink("/dangling", "/missing").unwrap();
+
+        for path in ["/dir", "/link", "/dir/file", "/link/file"] {
+            let metadata = fs.metadata(path).unwrap();
+            assert_eq!(metadata.attrs, fs.attributes(path).unwrap());
+            let kind = match fs.is_directory(path) {
+                true => NodeKind::Directory,
+                false => NodeKind::File,
+            };
+            assert_eq!(metadata.kind, kind);
+        }
+        assert_eq!(fs.metadata("/link").unwrap().attrs.mode.value(), 0o700);
+        assert!(fs.metadata("/missing").is_none());
+        assert!(fs.metadata("/dangling").is_none());
+        assert!(fs.metadata("/dir/file/below").is_none());
+    }
+
+    #[test]
+    fn paths_under() {
+        let mut fs = MemoryFilesystem::new();
+        for path in [
+            "/one",
+            "/one/b",
+            "/one/a",
+            "/one/a/deep",
+            "/one-two",
+            "/two",
+        ] {
+            fs.create_directory(path, SetAttrs::default()).unwrap();
+        }
+        fs.create_symlink("/one/link", "/two").unwrap();
+        let paths: Vec<_> = fs.paths_under("/one").map(Utf8Path::as_str).collect();
+        assert_eq!(
+            paths,
+            ["/one", "/one/a", "/one/a/deep", "/one/b", "/one/link"]
+        );
+        assert_eq!(fs.paths_under("/").count(), fs.to_path_set().len());
+        assert_eq!(fs.paths_under("/missing").count(), 0);
+    }
+
+    #[test]
+    fn list_directory_sorted() {
+        let mut fs = MemoryFilesystem::new();
+        for name in ["/zeta", "/alpha", "/mid"] {
+            fs.create_directory(name, SetAttrs::default()).unwrap();
+        }
+        assert_eq!(fs.list_directory("/").unwrap(), ["zeta", "alpha", "mid"]);
+        assert_eq!(
+            fs.list_directory_sorted("/").unwrap(),
+            ["alpha", "mid", "zeta"]
+        );
+    }
+
+    #[test]
+    fn copy_within() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_file("/source", SetAttrs::default(), "content".into())
+            .unwrap();
+        fs.copy_within("/source", "/copy", SetAttrs::default().with_mode(0o600))
+            .unwrap();
+        assert_eq!(fs.read_file("/copy").unwrap(), "content");
+        assert_eq!(fs.attributes("/copy").unwrap().mode, 0o600.into());
+        assert!(fs
+            .copy_within("/source", "/copy", SetAttrs::default())
+            .is_err());
+    }
+
+    #[test]
+    fn copy_if_absent() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_file("/source", SetAttrs::default(), "content".into())
+            .unwrap();
+        let attrs = SetAttrs::default().with_mode(0o600);
+        assert!(fs
+            .copy_if_absent("/source", "/copy", attrs.clone())
+            .unwrap());
+        fs.write_file_bytes("/copy", b"changed".to_vec()).unwrap();
+        assert!(!fs
+            .copy_if_absent("/source", "/copy", attrs.clone())
+            .unwrap());
+        assert_eq!(fs.read_file("/copy").unwrap(), "changed");
+        assert_eq!(fs.attributes("/copy").unwrap().mode, 0o600.into());
+        fs.create_directory("/dir", SetAttrs::default()).unwrap();
+        assert!(fs.copy_if_absent("/source", "/dir", attrs).is_err());
+    }
+
+    #[test]
+    fn create_file_if_absent() {
+        let mut fs = MemoryFilesystem::new();
+        let attrs = SetAttrs::default().with_mode(0o600);
+        assert!(fs
+            .create_file_if_absent("/file", attrs.clone(), b"first".to_vec())
+            .unwrap());
+        assert!(!fs
+            .create_file_if_absent("/file", SetAttrs::default(), b"second".to_vec())
+            .unwrap());
+        assert_eq!(fs.read_file("/file").unwrap(), "first");
+        assert_eq!(fs.attributes("/file").unwrap().mode, 0o600.into());
+        fs.create_symlink("/link", "/file").unwrap();
+        assert!(!fs
+            .create_file_if_absent("/link", attrs.clone(), vec![])
+            .unwrap());
+        fs.create_directory("/dir", SetAttrs::default()).unwrap();
+        assert!(fs.create_file_if_absent("/dir", attrs, vec![]).is_err());
+    }
+
+    #[test]
+    fn set_times() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_file("/file", SetAttrs::default(), "content".into())
+            .unwrap();
+        fs.create_symlink("/link", "/file").unwrap();
+        let times = Times {
+            accessed: SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000),
+        };
+        fs.set_times("/link", times).unwrap();
+        assert_eq!(fs.times("/file").unwrap(), times);
+        // Times are kept when other attributes change
+        fs.set_attributes("/file", SetAttrs::default().with_mode(0o600))
+            .unwrap();
+        assert_eq!(fs.times("/link").unwrap(), times);
+        assert!(fs.set_times("/missing", times).is_err());
+        assert!(fs.times("/missing").is_err());
+    }
+
+    #[test]
+    fn write_file_bytes() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_file("/file", SetAttrs::default().with_mode(0o600), "old".into())
+            .unwrap();
+        fs.write_file_bytes("/file", b"new".to_vec()).unwrap();
+        assert_eq!(fs.read_file("/file").unwrap(), "new");
+        assert_eq!(fs.attributes("/file").unwrap().mode, 0o600.into());
+        assert!(fs.write_file_bytes("/missing", vec![]).is_err());
+        fs.create_directory("/dir", SetAttrs::default()).unwrap();
+        assert!(fs.write_file_bytes("/dir", vec![]).is_err());
+    }
+
+    #[test]
+    fn binary_content() {
+        let mut fs = MemoryFilesystem::new();
+        let content = vec![b'a', 0xFF, 0x00, 0xFE, b'z'];
+        fs.create_file_bytes("/binary", SetAttrs::default(), content.clone())
+            .unwrap();
+        assert_eq!(fs.read_file_bytes("/binary").unwrap(), content);
+        assert!(fs.read_file("/binary").is_err());
+
+        fs.copy_within("/binary", "/copy", SetAttrs::default())
+            .unwrap();
+        assert_eq!(fs.read_file_bytes("/copy").unwrap(), content);
+    }
+
+    #[test]
+    fn numeric_owner_and_group() {
+        let mut fs = MemoryFilesystem::new();
+        let attrs = SetAttrs::default().with_owner("#0").with_group("0");
+        fs.create_directory("/by_id", attrs).unwrap();
+        let attrs = fs.attributes("/by_id").unwrap();
+        assert_eq!(
+            (attrs.owner.as_ref(), attrs.group.as_ref()),
+            ("root", "root")
+        );
+
+        // IDs without names are reported numerically
+        let attrs = SetAttrs::default().with_owner("54321").with_group("#54321");
+        fs.create_directory("/unnamed", attrs).unwrap();
+        let attrs = fs.attributes("/unnamed").unwrap();
+        assert_eq!(
+            (attrs.owner.as_ref(), attrs.group.as_ref()),
+            ("54321", "54321")
+        );
+    }
+
+    #[test]
+    fn fake_ids() {
+        let attrs = || {
+            SetAttrs::default()
+                .with_owner("no-such-user")
+                .with_group("no-such-group")
+        };
+        let mut fs = MemoryFilesystem::new();
+        assert!(fs.create_directory("/strict", attrs()).is_err());
+
+        let mut fs = MemoryFilesystem::new().with_fake_ids(true);
+        fs.create_directory("/service", attrs()).unwrap();
+        fs.create_file("/service/file", attrs(), "".into()).unwrap();
+        fs.create_directory("/other", SetAttrs::default().with_owner("another-user"))
+            .unwrap();
+        fs.set_attributes("/other", SetAttrs::default().with_group("root"))
+            .unwrap();
+        for path in ["/service", "/service/file"] {
+            let attrs = fs.attributes(path).unwrap();
+            assert_eq!(
+                (attrs.owner.as_ref(), attrs.group.as_ref()),
+                ("no-such-user", "no-such-group")
+            );
+        }
+        // Setting the group leaves the owner as it was
+        assert_eq!(fs.attributes("/other").unwrap().group, "root");
+        assert_eq!(fs.attributes("/other").unwrap().owner, "another-user");
+        let attrs = SetAttrs::default().with_owner("another-user");
+        fs.set_attributes("/other", attrs).unwrap();
+        assert_eq!(fs.attributes("/other").unwrap().owner, "another-user");
+    }
+
+    #[test]
+    fn link_attributes() {
+        let mut fs = MemoryFilesystem::new().with_fake_ids(true);
+        fs.create_file("/target", SetAttrs::default(), "".into())
+            .unwrap();
+        fs.create_symlink("/link", "/target").unwrap();
+        assert!(fs.link_attributes("/target").is_err());
+
+        // The link and its target are owned separately
+        let attrs = SetAttrs::default()
+            .with_owner("link-user")
+            .with_group("link-group");
+        fs.set_link_attributes("/link", attrs).unwrap();
+        let attrs = fs.link_attributes("/link").unwrap();
+        assert_eq!(
+            (attrs.owner.as_ref(), attrs.group.as_ref()),
+            ("link-user", "link-group")
+        );
+        assert_eq!(fs.attributes("/link").unwrap().owner, "root");
+
+        fs.set_attributes("/link", SetAttrs::default().with_owner("target-user"))
+            .unwrap();
+        assert_eq!(fs.attributes("/target").unwrap().owner, "target-user");
+        assert_eq!(fs.link_attributes("/link").unwrap().owner, "link-user");
+
+        assert!(fs
+            .set_link_attributes("/link", SetAttrs::default().with_mode(0o700))
+            .is_err());
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/kept", SetAttrs::default()).unwrap();
+        fs.create_file("/changed", SetAttrs::default(), "before".into())
+            .unwrap();
+        let before = fs.snapshot();
+        assert!(fs.diff(&before).is_empty());
+
+        fs.create_directory("/kept/added", SetAttrs::default())
+            .unwrap();
+        fs.set_attributes("/changed", SetAttrs::default().with_mode(0o600))
+            .unwrap();
+        assert_eq!(
+            fs.diff(&before),
+            vec![
+                Change::Modified("/changed".into()),
+                Change::Added("/kept/added".into()),
+            ]
+        );
+        assert_eq!(
+            before.diff(&fs.snapshot()),
+            vec![
+                Change::Modified("/changed".into()),
+                Change::Removed("/kept/added".into()),
+            ]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshot_round_trip() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/dir", SetAttrs::default().with_mode(0o700))
+            .unwrap();
+        fs.create_file("/dir/file", SetAttrs::default(), "content".into())
+            .unwrap();
+        fs.create_symlink("/link", "/dir").unwrap();
+        let before = fs.snapshot();
+
+        let json = serde_json::to_string(&before).unwrap();
+        let restored: super::Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, before);
+        assert!(fs.diff(&restored).is_empty());
+    }
+
+    #[test]
+    fn from_tree() {
+        let fs = MemoryFilesystem::from_tree(
+            "
+            /srv/
+            ├── data/
+            │   ├── empty/
+            │   └── file.txt
+            ├── link -> /srv/data
+            └── other/
+                └── nested/
+            /tmp/
+            └── scratch
+            ",
+        )
+        .unwrap();
+        assert!(fs.is_directory("/srv/data/empty"));
+        assert!(fs.is_file("/srv/data/file.txt"));
+        assert_eq!(fs.read_file("/srv/data/file.txt").unwrap(), "");
+        assert_eq!(fs.read_link("/srv/link").unwrap(), "/srv/data");
+        assert!(fs.is_directory("/srv/other/nested"));
+        assert!(fs.is_file("/tmp/scratch"));
+
+        let error = |tree| format!("{:#}", MemoryFilesystem::from_tree(tree).err().unwrap());
+        assert_eq!(
+            error("relative/"),
+            "Invalid tree at line 1: \"relative/\": Top level entry must be an absolute path"
+        );
+        assert!(error("/\n└── file\n    └── child").contains("beneath a file or symlink"));
+        assert!(error("/\n└── a/\n        └── deep").contains("indented too far"));
+        assert!(error("/\n└─ bad").contains("Expected an entry"));
+        assert!(error("/\n├── same\n└── same").starts_with("Invalid tree at line 3"));
+    }
+
     #[test]
     fn symlink_make_sub_directory() {
         let mut fs = MemoryFilesystem::new();
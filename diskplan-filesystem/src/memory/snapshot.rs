@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use super::{MemoryFilesystem, Node};
+
+/// A copy of every entry in a [`MemoryFilesystem`] at a point in time, ordered by path
+///
+/// With the `serde` feature enabled, snapshots can be serialized and deserialized, so that one
+/// taken in an earlier run may be compared with the filesystem later.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    entries: BTreeMap<Utf8PathBuf, SnapshotEntry>,
+}
+
+/// The type, attributes and content of a single entry in a [`Snapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SnapshotEntry {
+    /// A directory, with its owner and group IDs, and permissions
+    Directory {
+        /// The owner's user ID
+        uid: u32,
+        /// The group ID
+        gid: u32,
+        /// The UNIX permissions
+        mode: u16,
+    },
+    /// A file, with its owner and group IDs, permissions and content
+    File {
+        /// The owner's user ID
+        uid: u32,
+        /// The group ID
+        gid: u32,
+        /// The UNIX permissions
+        mode: u16,
+        /// The content of the file
+        content: Vec<u8>,
+    },
+    /// A symbolic link, with its own owner and group IDs, and the path to which it points
+    Symlink {
+        /// The owner's user ID
+        uid: u32,
+        /// The group ID
+        gid: u32,
+        /// The target of the link, as given when it was created
+        target: Utf8PathBuf,
+    },
+}
+
+/// A difference in a single path between two [`Snapshot`]s
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The path exists only in the later snapshot
+    Added(Utf8PathBuf),
+    /// The path exists only in the earlier snapshot
+    Removed(Utf8PathBuf),
+    /// The path exists in both, but its type, attributes or content differ
+    Modified(Utf8PathBuf),
+}
+
+impl Change {
+    /// The path that was added, removed or modified
+    pub fn path(&self) -> &Utf8Path {
+        match self {
+            Change::Added(path) | Change::Removed(path) | Change::Modified(path) => path,
+        }
+    }
+}
+
+impl Snapshot {
+    /// Returns the entries of this snapshot, ordered by path
+    pub fn entries(&self) -> &BTreeMap<Utf8PathBuf, SnapshotEntry> {
+        &self.entries
+    }
+
+    /// Returns the entry at the given path, if there was one when the snapshot was taken
+    pub fn get(&self, path: impl AsRef<Utf8Path>) -> Option<&SnapshotEntry> {
+        self.entries.get(path.as_ref())
+    }
+
+    /// Compares this (later) snapshot with an earlier one, listing changes in path order
+    pub fn diff(&self, before: &Snapshot) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (path, entry) in &self.entries {
+            match before.entries.get(path) {
+                None => changes.push(Change::Added(path.clone())),
+                Some(previous) if previous != entry => changes.push(Change::Modified(path.clone())),
+                Some(_) => {}
+            }
+        }
+        for path in before.entries.keys() {
+            if !self.entries.contains_key(path) {
+                changes.push(Change::Removed(path.clone()));
+            }
+        }
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        changes
+    }
+}
+
+impl MemoryFilesystem {
+    /// Takes a copy of all entries in this filesystem, with their types and attributes
+    pub fn snapshot(&self) -> Snapshot {
+        let entries = self
+            .map
+            .iter()
+            .map(|(path, node)| {
+                let entry = match node {
+                    Node::Directory { attrs, .. } => SnapshotEntry::Directory {
+                        uid: attrs.uid,
+                        gid: attrs.gid,
+                        mode: attrs.mode,
+                    },
+                    Node::File { attrs, content } => SnapshotEntry::File {
+                        uid: attrs.uid,
+                        gid: attrs.gid,
+                        mode: attrs.mode,
+                        content: content.clone(),
+                    },
+                    Node::Symlink { attrs, target } => SnapshotEntry::Symlink {
+                        uid: attrs.uid,
+                        gid: attrs.gid,
+                        target: target.clone(),
+                    },
+                };
+                (path.clone(), entry)
+            })
+            .collect();
+        Snapshot { entries }
+    }
+
+    /// Lists the changes made to this filesystem since the `before` snapshot was taken
+    pub fn diff(&self, before: &Snapshot) -> Vec<Change> {
+        self.snapshot().diff(before)
+    }
+}
@@ -1,15 +1,21 @@
-use std::{borrow::Cow, fs, io::Write, os::unix::fs::PermissionsExt};
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, Read, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use nix::{
     sys::stat,
     unistd::{Gid, Uid},
 };
-use users::{Groups, Users, UsersCache};
+use users::UsersCache;
 
 use super::{
-    attributes::Mode, Attrs, Filesystem, SetAttrs, DEFAULT_DIRECTORY_MODE, DEFAULT_FILE_MODE,
+    attributes::Mode, ids, Attrs, Filesystem, Metadata, NodeKind, SetAttrs, Times,
+    DEFAULT_DIRECTORY_MODE, DEFAULT_FILE_MODE,
 };
 
 /// Access to a real file system
@@ -24,17 +30,56 @@ impl Filesystem for DiskFilesystem {
         self.apply_attrs(path, attrs, DEFAULT_DIRECTORY_MODE)
     }
 
-    fn create_file(
+    fn create_file_bytes(
         &mut self,
         path: impl AsRef<Utf8Path>,
         attrs: SetAttrs,
-        content: String,
+        content: Vec<u8>,
     ) -> Result<()> {
         let mut file = fs::File::create(path.as_ref())?;
-        file.write_all(content.as_bytes())?;
+        file.write_all(&content)?;
         self.apply_attrs(path, attrs, DEFAULT_FILE_MODE)
     }
 
+    fn create_file_if_absent(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+        content: Vec<u8>,
+    ) -> Result<bool> {
+        self.create_new_with(path.as_ref(), attrs, |file| Ok(file.write_all(&content)?))
+    }
+
+    fn copy_within(
+        &mut self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+    ) -> Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if !self.copy_file(from, to, attrs)? {
+            bail!("File exists: {}", to);
+        }
+        Ok(())
+    }
+
+    fn copy_if_absent(
+        &mut self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+    ) -> Result<bool> {
+        self.copy_file(from, to, attrs)
+    }
+
+    fn write_file_bytes(&mut self, path: impl AsRef<Utf8Path>, content: Vec<u8>) -> Result<()> {
+        let path = path.as_ref();
+        if !self.is_file(path) {
+            bail!("Not a file: {}", path);
+        }
+        fs::write(path, content).with_context(|| format!("Writing {path}"))
+    }
+
     fn create_symlink(
         &mut self,
         path: impl AsRef<Utf8Path>,
@@ -43,6 +88,14 @@ impl Filesystem for DiskFilesystem {
         Ok(std::os::unix::fs::symlink(target.as_ref(), path.as_ref())?)
     }
 
+    fn rename(&mut self, from: impl AsRef<Utf8Path>, to: impl AsRef<Utf8Path>) -> Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if fs::symlink_metadata(to).is_ok() {
+            bail!("File exists: {}", to);
+        }
+        fs::rename(from, to).with_context(|| format!("Renaming {from} to {to}"))
+    }
+
     fn exists(&self, path: impl AsRef<Utf8Path>) -> bool {
         fs::metadata(path.as_ref()).is_ok()
     }
@@ -75,47 +128,106 @@ impl Filesystem for DiskFilesystem {
         Ok(listing)
     }
 
-    fn read_file(&self, path: impl AsRef<Utf8Path>) -> Result<String> {
-        fs::read_to_string(path.as_ref()).map_err(Into::into)
+    fn read_file_bytes(&self, path: impl AsRef<Utf8Path>) -> Result<Vec<u8>> {
+        fs::read(path.as_ref()).map_err(Into::into)
+    }
+
+    fn open_file(&self, path: impl AsRef<Utf8Path>) -> Result<Box<dyn Read + '_>> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).with_context(|| format!("Opening {path}"))?;
+        Ok(Box::new(file))
     }
 
     fn read_link(&self, path: impl AsRef<Utf8Path>) -> Result<Utf8PathBuf> {
         Ok(fs::read_link(path.as_ref())?.try_into()?)
     }
 
-    fn attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs> {
+    fn attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs<'_>> {
         let stat = stat::stat(path.as_ref().as_std_path())?;
-        let owner = Cow::Owned(
-            self.users
-                .get_user_by_uid(stat.st_uid)
-                .ok_or_else(|| anyhow!("Failed to get user from UID: {}", stat.st_uid))?
-                .name()
-                .to_string_lossy()
-                .into_owned(),
-        );
-        let group = Cow::Owned(
-            self.users
-                .get_group_by_gid(stat.st_gid)
-                .ok_or_else(|| anyhow!("Failed to get group from GID: {}", stat.st_gid))?
-                .name()
-                .to_string_lossy()
-                .into_owned(),
-        );
-        let mode = (stat.st_mode as u16).into();
+        let owner = Cow::Owned(ids::user_name(&self.users, stat.st_uid));
+        let group = Cow::Owned(ids::group_name(&self.users, stat.st_gid));
+        let mode = ((stat.st_mode & 0o7777) as u16).into();
         Ok(Attrs { owner, group, mode })
     }
 
+    fn metadata(&self, path: impl AsRef<Utf8Path>) -> Option<Metadata<'_>> {
+        let metadata = fs::metadata(path.as_ref()).ok()?;
+        let kind = if metadata.is_dir() {
+            NodeKind::Directory
+        } else if metadata.is_file() {
+            NodeKind::File
+        } else {
+            return None;
+        };
+        let owner = Cow::Owned(ids::user_name(&self.users, metadata.uid()));
+        let group = Cow::Owned(ids::group_name(&self.users, metadata.gid()));
+        let mode = ((metadata.mode() & 0o7777) as u16).into();
+        Some(Metadata {
+            kind,
+            attrs: Attrs { owner, group, mode },
+        })
+    }
+
     fn set_attributes(&mut self, path: impl AsRef<Utf8Path>, attrs: SetAttrs) -> Result<()> {
+        // Anything not given is left as it is
+        let current = self.attributes(path.as_ref())?.mode;
+        self.apply_attrs(path, attrs, current)
+    }
+
+    fn times(&self, path: impl AsRef<Utf8Path>) -> Result<Times> {
+        let metadata = fs::metadata(path.as_ref())?;
+        Ok(Times {
+            accessed: metadata.accessed()?,
+            modified: metadata.modified()?,
+        })
+    }
+
+    fn set_times(&mut self, path: impl AsRef<Utf8Path>, times: Times) -> Result<()> {
         let path = path.as_ref();
-        self.apply_attrs(
-            path,
-            attrs,
-            if self.is_directory(path) {
-                DEFAULT_DIRECTORY_MODE
-            } else {
-                DEFAULT_FILE_MODE
-            },
+        let file = fs::File::open(path).with_context(|| format!("Opening {path}"))?;
+        file.set_times(
+            fs::FileTimes::new()
+                .set_accessed(times.accessed)
+                .set_modified(times.modified),
         )
+        .with_context(|| format!("Setting times of {path}"))
+    }
+
+    fn link_attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs<'_>> {
+        let path = path.as_ref();
+        if !self.is_link(path) {
+            bail!("Not a symlink: {}", path);
+        }
+        let stat = stat::lstat(path.as_std_path())?;
+        let owner = Cow::Owned(ids::user_name(&self.users, stat.st_uid));
+        let group = Cow::Owned(ids::group_name(&self.users, stat.st_gid));
+        let mode = ((stat.st_mode & 0o7777) as u16).into();
+        Ok(Attrs { owner, group, mode })
+    }
+
+    fn set_link_attributes(&mut self, path: impl AsRef<Utf8Path>, attrs: SetAttrs) -> Result<()> {
+        let path = path.as_ref();
+        if !self.is_link(path) {
+            bail!("Not a symlink: {}", path);
+        }
+        if attrs.mode.is_some() {
+            bail!("The mode of a symlink cannot be set: {}", path);
+        }
+        let uid = match attrs.owner {
+            Some(owner) => Some(ids::uid_for(&self.users, owner)?),
+            None => None,
+        };
+        let gid = match attrs.group {
+            Some(group) => Some(ids::gid_for(&self.users, group)?),
+            None => None,
+        };
+        tracing::trace!("lchown {:?} {:?}:{:?}", path, uid, gid);
+        std::os::unix::fs::lchown(path, uid, gid)
+            .with_context(|| format!("Changing ownership of symlink {path}"))
+    }
+
+    fn fork(&self) -> Option<Self> {
+        Some(DiskFilesystem::new())
     }
 }
 
@@ -127,6 +239,63 @@ impl DiskFilesystem {
         }
     }
 
+    /// Copies the file at `from` to a new file at `to`, streaming its content rather than
+    /// reading it into memory, unless a file exists at `to` already, returning whether it was
+    /// copied
+    ///
+    /// Anything other than a file at `to` (or a symlink to one) is an error.
+    pub fn copy_file(
+        &self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+    ) -> Result<bool> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let mut source = fs::File::open(from).with_context(|| format!("Opening {from}"))?;
+        self.create_new_with(to, attrs, |file| {
+            io::copy(&mut source, file).with_context(|| format!("Copying {from} to {to}"))?;
+            Ok(())
+        })
+    }
+
+    /// Creates a new file at `path`, unless a file exists there already, filling it with `write`
+    /// and setting its attributes, returning whether it was created
+    ///
+    /// The file is opened with O_CREAT | O_EXCL, failing if anything (even a dangling symlink) is
+    /// there. Should filling it or setting its attributes fail, it is removed again, so that it
+    /// is not taken to be complete by a later run.
+    fn create_new_with(
+        &self,
+        path: &Utf8Path,
+        attrs: SetAttrs,
+        write: impl FnOnce(&mut fs::File) -> Result<()>,
+    ) -> Result<bool> {
+        let mut file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                if !self.is_file(path) {
+                    bail!("Exists, but not as a file: {}", path);
+                }
+                return Ok(false);
+            }
+            Err(error) => return Err(error).with_context(|| format!("Creating {path}")),
+        };
+        let filled =
+            write(&mut file).and_then(|()| self.apply_attrs(path, attrs, DEFAULT_FILE_MODE));
+        if let Err(error) = filled {
+            drop(file);
+            if let Err(removal) = fs::remove_file(path) {
+                tracing::warn!("Failed to remove incomplete file {}: {}", path, removal);
+            }
+            return Err(error);
+        }
+        Ok(true)
+    }
+
     fn apply_attrs(
         &self,
         path: impl AsRef<Utf8Path>,
@@ -134,21 +303,11 @@ impl DiskFilesystem {
         default_mode: Mode,
     ) -> Result<()> {
         let uid = match attrs.owner {
-            Some(owner) => Some(Uid::from_raw(
-                self.users
-                    .get_user_by_name(owner)
-                    .ok_or_else(|| anyhow!("No such user: {}", owner))?
-                    .uid(),
-            )),
+            Some(owner) => Some(Uid::from_raw(ids::uid_for(&self.users, owner)?)),
             None => None,
         };
         let gid = match attrs.group {
-            Some(group) => Some(Gid::from_raw(
-                self.users
-                    .get_group_by_name(group)
-                    .ok_or_else(|| anyhow!("No such group: {}", group))?
-                    .gid(),
-            )),
+            Some(group) => Some(Gid::from_raw(ids::gid_for(&self.users, group)?)),
             None => None,
         };
         let mode = PermissionsExt::from_mode(attrs.mode.unwrap_or(default_mode).into());
@@ -160,3 +319,118 @@ impl DiskFilesystem {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory for the test to work in, removed (with its content) when dropped
+    struct Scratch(Utf8PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("diskplan-physical-{}-{name}", std::process::id()));
+            let path = Utf8PathBuf::try_from(path).unwrap();
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir(&path).unwrap();
+            Scratch(path)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn set_times() -> Result<()> {
+        let scratch = Scratch::new("set_times");
+        let (file, link) = (scratch.0.join("file"), scratch.0.join("link"));
+        fs::write(&file, "content")?;
+
+        let mut disk = DiskFilesystem::new();
+        disk.create_symlink(&link, &file)?;
+        let times = Times {
+            accessed: std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000),
+            modified: std::time::UNIX_EPOCH + std::time::Duration::from_secs(2_000_000),
+        };
+        for path in [&link, &scratch.0] {
+            disk.set_times(path, times)?;
+            assert_eq!(disk.times(path)?, times);
+        }
+        assert_eq!(disk.times(&file)?, times);
+        assert!(disk.set_times(scratch.0.join("missing"), times).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn walk_reports_dangling_links() -> Result<()> {
+        let scratch = Scratch::new("walk");
+        let link = scratch.0.join("dangling");
+        let disk = DiskFilesystem::new();
+        std::os::unix::fs::symlink(scratch.0.join("missing"), &link)?;
+
+        let walked = disk.walk(&scratch.0).collect::<Result<Vec<_>>>()?;
+        assert_eq!(walked.len(), 2);
+        let (path, kind, attrs) = &walked[1];
+        assert_eq!((path, *kind), (&link, NodeKind::Symlink));
+        assert_eq!(attrs, &disk.link_attributes(&link)?);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_within_streams_content() -> Result<()> {
+        let scratch = Scratch::new("copy_within");
+        let (source, copy) = (scratch.0.join("source"), scratch.0.join("copy"));
+        let content: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        fs::write(&source, &content)?;
+
+        let mut disk = DiskFilesystem::new();
+        disk.copy_within(&source, &copy, SetAttrs::default().with_mode(0o600))?;
+        assert_eq!(fs::read(&copy)?, content);
+        assert_eq!(disk.attributes(&copy)?.mode, 0o600.into());
+        assert!(disk
+            .copy_within(&source, &copy, SetAttrs::default())
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn copy_if_absent_keeps_existing() -> Result<()> {
+        let scratch = Scratch::new("copy_if_absent");
+        let (source, copy) = (scratch.0.join("source"), scratch.0.join("copy"));
+        fs::write(&source, "new")?;
+
+        let mut disk = DiskFilesystem::new();
+        assert!(disk.copy_if_absent(&source, &copy, SetAttrs::default())?);
+        fs::write(&copy, "changed")?;
+        assert!(!disk.copy_if_absent(&source, &copy, SetAttrs::default())?);
+        assert_eq!(fs::read_to_string(&copy)?, "changed");
+        fs::create_dir(scratch.0.join("dir"))?;
+        assert!(disk
+            .copy_if_absent(&source, scratch.0.join("dir"), SetAttrs::default())
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn incomplete_file_removed() -> Result<()> {
+        let scratch = Scratch::new("incomplete");
+        let (source, path) = (scratch.0.join("source"), scratch.0.join("file"));
+        fs::write(&source, "content")?;
+        let unknown = SetAttrs::default().with_owner("no-such-user-for-diskplan");
+
+        // Setting the attributes fails after the content is written
+        let mut disk = DiskFilesystem::new();
+        assert!(disk
+            .create_file_if_absent(&path, unknown.clone(), b"content".to_vec())
+            .is_err());
+        assert!(fs::symlink_metadata(&path).is_err());
+        assert!(disk.copy_if_absent(&source, &path, unknown).is_err());
+        assert!(fs::symlink_metadata(&path).is_err());
+        assert!(disk.create_file_if_absent(&path, SetAttrs::default(), vec![])?);
+        Ok(())
+    }
+}
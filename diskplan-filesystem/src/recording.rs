@@ -0,0 +1,804 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
+
+use anyhow::{anyhow, bail, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use nix::unistd;
+use users::UsersCache;
+
+use super::{
+    attributes::Mode, ids, Attrs, Filesystem, SetAttrs, Times, DEFAULT_DIRECTORY_MODE,
+    DEFAULT_FILE_MODE,
+};
+
+/// A filesystem that reads from an inner filesystem (typically a [`DiskFilesystem`]), but
+/// records changes instead of making them
+///
+/// Each change is kept in an overlay, so later reads see the filesystem as it would be had the
+/// changes been made, and listed in order as an [`Operation`]. The inner filesystem is never
+/// modified.
+///
+/// [`DiskFilesystem`]: super::DiskFilesystem
+pub struct RecordingFilesystem<FS> {
+    inner: FS,
+    planned: HashMap<Utf8PathBuf, Planned>,
+    added: HashMap<Utf8PathBuf, Vec<String>>,
+    times: HashMap<Utf8PathBuf, Times>,
+    operations: Vec<Operation>,
+    users: UsersCache,
+}
+
+/// A change that a [`RecordingFilesystem`] was asked to make, with the attributes that would
+/// result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// A directory would be created
+    CreateDirectory {
+        /// The path of the new directory
+        path: Utf8PathBuf,
+        /// The attributes it would be given
+        attrs: Attrs<'static>,
+    },
+    /// A file would be created with the given content
+    CreateFile {
+        /// The path of the new file
+        path: Utf8PathBuf,
+        /// The attributes it would be given
+        attrs: Attrs<'static>,
+    },
+    /// A file would be copied
+    CopyFile {
+        /// The path of the file copied
+        from: Utf8PathBuf,
+        /// The path of the new file
+        to: Utf8PathBuf,
+        /// The attributes it would be given
+        attrs: Attrs<'static>,
+    },
+    /// The content of an existing file would be replaced
+    WriteFile {
+        /// The path of the file
+        path: Utf8PathBuf,
+    },
+    /// A symlink would be created
+    CreateSymlink {
+        /// The path of the new symlink
+        path: Utf8PathBuf,
+        /// The path to which it would point
+        target: Utf8PathBuf,
+    },
+    /// A file, directory or symlink would be moved
+    Rename {
+        /// The path it would be moved from
+        from: Utf8PathBuf,
+        /// The path it would be moved to
+        to: Utf8PathBuf,
+    },
+    /// The attributes of an existing file or directory would be set
+    SetAttributes {
+        /// The path of the file or directory
+        path: Utf8PathBuf,
+        /// The attributes it would have afterwards
+        attrs: Attrs<'static>,
+    },
+    /// The owner and group of a symlink itself would be set
+    SetLinkAttributes {
+        /// The path of the symlink
+        path: Utf8PathBuf,
+        /// The attributes it would have afterwards
+        attrs: Attrs<'static>,
+    },
+    /// The access and modification times of an existing file or directory would be set
+    SetTimes {
+        /// The path of the file or directory
+        path: Utf8PathBuf,
+        /// The times it would have afterwards
+        times: Times,
+    },
+}
+
+/// Whether an [`Operation`] brings an entry into being or changes one that was already there
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    /// The content or attributes of an existing entry would change
+    Modified,
+    /// The entry would be created (or moved into place)
+    Created,
+}
+
+impl Operation {
+    /// Returns the path of the entry this operation would create or modify (the destination of
+    /// a copy or rename) and which of the two it would do
+    pub fn change(&self) -> (&Utf8Path, ChangeKind) {
+        match self {
+            Operation::CreateDirectory { path, .. }
+            | Operation::CreateFile { path, .. }
+            | Operation::CreateSymlink { path, .. } => (path, ChangeKind::Created),
+            Operation::CopyFile { to, .. } | Operation::Rename { to, .. } => {
+                (to, ChangeKind::Created)
+            }
+            Operation::WriteFile { path }
+            | Operation::SetAttributes { path, .. }
+            | Operation::SetLinkAttributes { path, .. }
+            | Operation::SetTimes { path, .. } => (path, ChangeKind::Modified),
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let attrs = |f: &mut std::fmt::Formatter<'_>, attrs: &Attrs| {
+            write!(f, " ({}:{} {})", attrs.owner, attrs.group, attrs.mode)
+        };
+        match self {
+            Operation::CreateDirectory { path, attrs: a } => {
+                write!(f, "Create directory {path}")?;
+                attrs(f, a)
+            }
+            Operation::CreateFile { path, attrs: a } => {
+                write!(f, "Create file {path}")?;
+                attrs(f, a)
+            }
+            Operation::CopyFile { from, to, attrs: a } => {
+                write!(f, "Copy file {from} to {to}")?;
+                attrs(f, a)
+            }
+            Operation::WriteFile { path } => write!(f, "Write file {path}"),
+            Operation::CreateSymlink { path, target } => {
+                write!(f, "Create symlink {path} -> {target}")
+            }
+            Operation::Rename { from, to } => write!(f, "Rename {from} to {to}"),
+            Operation::SetAttributes { path, attrs: a } => {
+                write!(f, "Set attributes of {path}")?;
+                attrs(f, a)
+            }
+            Operation::SetLinkAttributes { path, attrs: a } => {
+                write!(f, "Set attributes of symlink {path}")?;
+                attrs(f, a)
+            }
+            Operation::SetTimes { path, .. } => write!(f, "Set times of {path}"),
+        }
+    }
+}
+
+/// The state of a path, as changed by the recorded operations
+#[derive(Debug)]
+enum Planned {
+    Directory(Attrs<'static>),
+    File(Attrs<'static>, Vec<u8>),
+    Symlink(Utf8PathBuf, Attrs<'static>),
+    /// The entry at `from` on the inner filesystem (which may be this same path, or where it was
+    /// moved from), with changed attributes if any have been set
+    Inner {
+        from: Utf8PathBuf,
+        attrs: Option<Attrs<'static>>,
+    },
+    /// The entry on the inner filesystem has been moved away
+    Removed,
+}
+
+/// Where to find what is at a path
+enum Location<'a> {
+    Planned(&'a Planned),
+    Inner(Utf8PathBuf, Option<&'a Attrs<'static>>),
+    Missing,
+}
+
+impl<FS: Filesystem> RecordingFilesystem<FS> {
+    /// Wraps the given filesystem, which will be read from but never modified
+    pub fn new(inner: FS) -> Self {
+        RecordingFilesystem {
+            inner,
+            planned: HashMap::new(),
+            added: HashMap::new(),
+            times: HashMap::new(),
+            operations: Vec::new(),
+            users: UsersCache::new(),
+        }
+    }
+
+    /// The operations recorded so far, in the order they were requested
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Returns each path that the recorded operations would create or modify, in path order
+    ///
+    /// A path that would be created and then modified is reported as created.
+    pub fn changes(&self) -> BTreeMap<Utf8PathBuf, ChangeKind> {
+        let mut changes = BTreeMap::new();
+        for operation in &self.operations {
+            let (path, kind) = operation.change();
+            let entry = changes.entry(path.to_owned()).or_insert(kind);
+            *entry = kind.max(*entry);
+        }
+        changes
+    }
+
+    /// Provides access to the (unmodified) inner filesystem
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// Unwraps the inner filesystem, discarding the recorded operations
+    pub fn into_inner(self) -> FS {
+        self.inner
+    }
+
+    fn locate(&self, path: &Utf8Path) -> Location<'_> {
+        for ancestor in path.ancestors() {
+            let Some(planned) = self.planned.get(ancestor) else {
+                continue;
+            };
+            return match planned {
+                Planned::Removed => Location::Missing,
+                Planned::Inner { from, attrs } if ancestor == path => {
+                    Location::Inner(from.clone(), attrs.as_ref())
+                }
+                Planned::Inner { from, .. } => Location::Inner(
+                    from.join(path.strip_prefix(ancestor).expect("Ancestor of path")),
+                    None,
+                ),
+                planned if ancestor == path => Location::Planned(planned),
+                // Nothing has yet been planned beneath a newly created entry
+                _ => Location::Missing,
+            };
+        }
+        Location::Inner(path.to_owned(), None)
+    }
+
+    fn canonical_split<'s>(&self, path: &'s Utf8Path) -> Result<(Utf8PathBuf, &'s str)> {
+        match super::split(path) {
+            None => Err(anyhow!("Cannot create {}", path)),
+            Some((parent, name)) => Ok((self.canonicalize(parent)?, name)),
+        }
+    }
+
+    /// Checks the path is free to be created, returning it with its canonical parent
+    fn new_path(&self, path: &Utf8Path) -> Result<Utf8PathBuf> {
+        let (parent, name) = self.canonical_split(path)?;
+        if !self.is_directory(&parent) {
+            bail!("Parent directory not found: {}", parent);
+        }
+        let path = parent.join(name);
+        if self.exists(&path) || self.is_link(&path) {
+            bail!("File exists: {}", path);
+        }
+        Ok(path)
+    }
+
+    fn insert(&mut self, path: Utf8PathBuf, planned: Planned) {
+        if let Some((parent, name)) = super::split(&path) {
+            let added = self.added.entry(parent.to_owned()).or_default();
+            if !added.iter().any(|added| added == name) {
+                added.push(name.to_owned());
+            }
+        }
+        if let Planned::Directory(_) | Planned::File(..) = planned {
+            self.times.entry(path.clone()).or_insert_with(Times::now);
+        }
+        self.planned.insert(path, planned);
+    }
+
+    fn new_attrs(&self, attrs: SetAttrs, default_mode: Mode) -> Attrs<'static> {
+        let owner = match attrs.owner {
+            Some(owner) => owner.to_owned(),
+            None => ids::user_name(&self.users, unistd::getuid().as_raw()),
+        };
+        let group = match attrs.group {
+            Some(group) => group.to_owned(),
+            None => ids::group_name(&self.users, unistd::getgid().as_raw()),
+        };
+        Attrs {
+            owner: Cow::Owned(owner),
+            group: Cow::Owned(group),
+            mode: attrs.mode.unwrap_or(default_mode),
+        }
+    }
+}
+
+fn updated(current: &Attrs, attrs: SetAttrs) -> Attrs<'static> {
+    Attrs {
+        owner: Cow::Owned(attrs.owner.unwrap_or(&current.owner).to_owned()),
+        group: Cow::Owned(attrs.group.unwrap_or(&current.group).to_owned()),
+        mode: attrs.mode.unwrap_or(current.mode),
+    }
+}
+
+impl<FS: Filesystem> Filesystem for RecordingFilesystem<FS> {
+    fn create_directory(&mut self, path: impl AsRef<Utf8Path>, attrs: SetAttrs) -> Result<()> {
+        let path = self.new_path(path.as_ref())?;
+        let attrs = self.new_attrs(attrs, DEFAULT_DIRECTORY_MODE);
+        self.insert(path.clone(), Planned::Directory(attrs.clone()));
+        self.operations
+            .push(Operation::CreateDirectory { path, attrs });
+        Ok(())
+    }
+
+    fn create_file_bytes(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+        content: Vec<u8>,
+    ) -> Result<()> {
+        let path = self.new_path(path.as_ref())?;
+        let attrs = self.new_attrs(attrs, DEFAULT_FILE_MODE);
+        self.insert(path.clone(), Planned::File(attrs.clone(), content));
+        self.operations.push(Operation::CreateFile { path, attrs });
+        Ok(())
+    }
+
+    fn create_file_if_absent(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+        content: Vec<u8>,
+    ) -> Result<bool> {
+        let path = path.as_ref();
+        if self.exists(path) || self.is_link(path) {
+            if !self.is_file(path) {
+                bail!("Exists, but not as a file: {}", path);
+            }
+            return Ok(false);
+        }
+        self.create_file_bytes(path, attrs, content)?;
+        Ok(true)
+    }
+
+    fn copy_within(
+        &mut self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+        attrs: SetAttrs,
+    ) -> Result<()> {
+        let content = self.read_file_bytes(from.as_ref())?;
+        let to = self.new_path(to.as_ref())?;
+        let attrs = self.new_attrs(attrs, DEFAULT_FILE_MODE);
+        self.insert(to.clone(), Planned::File(attrs.clone(), content));
+        self.operations.push(Operation::CopyFile {
+            from: from.as_ref().to_owned(),
+            to,
+            attrs,
+        });
+        Ok(())
+    }
+
+    fn write_file_bytes(&mut self, path: impl AsRef<Utf8Path>, content: Vec<u8>) -> Result<()> {
+        let path = self.canonicalize(path)?;
+        if !self.is_file(&path) {
+            bail!("Not a file: {}", path);
+        }
+        let current = self.attributes(&path)?;
+        let attrs = updated(&current, SetAttrs::default());
+        self.planned
+            .insert(path.clone(), Planned::File(attrs, content));
+        self.operations.push(Operation::WriteFile { path });
+        Ok(())
+    }
+
+    fn create_symlink(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+        target: impl AsRef<Utf8Path>,
+    ) -> Result<()> {
+        let path = self.new_path(path.as_ref())?;
+        let target = target.as_ref().to_owned();
+        let attrs = self.new_attrs(SetAttrs::default(), 0o777.into());
+        self.insert(path.clone(), Planned::Symlink(target.clone(), attrs));
+        self.operations
+            .push(Operation::CreateSymlink { path, target });
+        Ok(())
+    }
+
+    fn rename(&mut self, from: impl AsRef<Utf8Path>, to: impl AsRef<Utf8Path>) -> Result<()> {
+        let (from_parent, from_name) = self.canonical_split(from.as_ref())?;
+        let from = from_parent.join(from_name);
+        if !self.exists(&from) && !self.is_link(&from) {
+            bail!("No such file or directory: {}", from);
+        }
+        let to = self.new_path(to.as_ref())?;
+        if to.starts_with(&from) {
+            bail!("Cannot move {} inside itself ({})", from, to);
+        }
+        let inner = match self.locate(&from) {
+            Location::Inner(path, attrs) => Some((path, attrs.cloned())),
+            _ => None,
+        };
+        // Move everything planned at and beneath the old path
+        let moved = |old: &Utf8Path| match old.strip_prefix(&from).expect("Path under prefix") {
+            rest if rest == "" => to.clone(),
+            rest => to.join(rest),
+        };
+        let moving: Vec<Utf8PathBuf> = self
+            .planned
+            .keys()
+            .filter(|path| path.starts_with(&from))
+            .cloned()
+            .collect();
+        for old in moving {
+            let planned = self.planned.remove(&old).expect("Path listed from map");
+            self.planned.insert(moved(&old), planned);
+        }
+        let moving: Vec<Utf8PathBuf> = self
+            .added
+            .keys()
+            .filter(|path| path.starts_with(&from))
+            .cloned()
+            .collect();
+        for old in moving {
+            let added = self.added.remove(&old).expect("Path listed from map");
+            self.added.insert(moved(&old), added);
+        }
+        let moving: Vec<Utf8PathBuf> = self
+            .times
+            .keys()
+            .filter(|path| path.starts_with(&from))
+            .cloned()
+            .collect();
+        for old in moving {
+            let times = self.times.remove(&old).expect("Path listed from map");
+            self.times.insert(moved(&old), times);
+        }
+        match inner {
+            // What was on the inner filesystem now appears at the new path, and not the old
+            Some((path, attrs)) => {
+                self.insert(to.clone(), Planned::Inner { from: path, attrs });
+                self.planned.insert(from.clone(), Planned::Removed);
+            }
+            None => {
+                if let Some(added) = self.added.get_mut(&from_parent) {
+                    added.retain(|name| name != from_name);
+                }
+                let planned = self.planned.remove(&to).expect("Moved entry");
+                self.insert(to.clone(), planned);
+            }
+        }
+        self.operations.push(Operation::Rename { from, to });
+        Ok(())
+    }
+
+    fn exists(&self, path: impl AsRef<Utf8Path>) -> bool {
+        match self.canonicalize(path) {
+            Ok(path) => match self.locate(&path) {
+                Location::Planned(_) => true,
+                Location::Inner(path, _) => self.inner.exists(path),
+                Location::Missing => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn is_directory(&self, path: impl AsRef<Utf8Path>) -> bool {
+        match self.canonicalize(path) {
+            Ok(path) => match self.locate(&path) {
+                Location::Planned(planned) => matches!(planned, Planned::Directory(_)),
+                Location::Inner(path, _) => self.inner.is_directory(path),
+                Location::Missing => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn is_file(&self, path: impl AsRef<Utf8Path>) -> bool {
+        match self.canonicalize(path) {
+            Ok(path) => match self.locate(&path) {
+                Location::Planned(planned) => matches!(planned, Planned::File(..)),
+                Location::Inner(path, _) => self.inner.is_file(path),
+                Location::Missing => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn is_link(&self, path: impl AsRef<Utf8Path>) -> bool {
+        match self.locate(path.as_ref()) {
+            Location::Planned(planned) => matches!(planned, Planned::Symlink(..)),
+            Location::Inner(path, _) => self.inner.is_link(path),
+            Location::Missing => false,
+        }
+    }
+
+    fn list_directory(&self, path: impl AsRef<Utf8Path>) -> Result<Vec<String>> {
+        let path = self.canonicalize(path)?;
+        let mut listing = match self.locate(&path) {
+            Location::Planned(Planned::Directory(_)) => Vec::new(),
+            Location::Inner(inner_path, _) => self
+                .inner
+                .list_directory(inner_path)?
+                .into_iter()
+                .filter(|name| !matches!(self.locate(&path.join(name)), Location::Missing))
+                .collect(),
+            _ => bail!("Not a directory: {}", path),
+        };
+        for name in self.added.get(&path).into_iter().flatten() {
+            if !listing.contains(name)
+                && !matches!(self.locate(&path.join(name)), Location::Missing)
+            {
+                listing.push(name.clone());
+            }
+        }
+        Ok(listing)
+    }
+
+    fn read_file_bytes(&self, path: impl AsRef<Utf8Path>) -> Result<Vec<u8>> {
+        let path = self.canonicalize(path)?;
+        match self.locate(&path) {
+            Location::Planned(Planned::File(_, content)) => Ok(content.clone()),
+            Location::Inner(path, _) => self.inner.read_file_bytes(path),
+            _ => bail!("Not a file: {}", path),
+        }
+    }
+
+    fn read_link(&self, path: impl AsRef<Utf8Path>) -> Result<Utf8PathBuf> {
+        match self.locate(path.as_ref()) {
+            Location::Planned(Planned::Symlink(target, _)) => Ok(target.clone()),
+            Location::Inner(path, _) => self.inner.read_link(path),
+            _ => bail!("Not a symlink: {}", path.as_ref()),
+        }
+    }
+
+    fn attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs<'_>> {
+        let path = self.canonicalize(path)?;
+        match self.locate(&path) {
+            Location::Planned(Planned::Directory(attrs) | Planned::File(attrs, _)) => {
+                Ok(attrs.clone())
+            }
+            Location::Inner(_, Some(attrs)) => Ok(attrs.clone()),
+            Location::Inner(path, None) => self.inner.attributes(path),
+            _ => bail!("No such file or directory: {}", path),
+        }
+    }
+
+    fn set_attributes(&mut self, path: impl AsRef<Utf8Path>, attrs: SetAttrs) -> Result<()> {
+        let path = self.canonicalize(path)?;
+        let new_attrs = match self.locate(&path) {
+            Location::Planned(Planned::Directory(current) | Planned::File(current, _)) => {
+                updated(current, attrs)
+            }
+            Location::Inner(from, current) => {
+                let new_attrs = match current {
+                    Some(current) => updated(current, attrs),
+                    None => updated(&self.inner.attributes(&from)?, attrs),
+                };
+                self.planned.insert(
+                    path.clone(),
+                    Planned::Inner {
+                        from,
+                        attrs: Some(new_attrs.clone()),
+                    },
+                );
+                new_attrs
+            }
+            _ => bail!("No such file or directory: {}", path),
+        };
+        if let Some(Planned::Directory(attrs) | Planned::File(attrs, _)) =
+            self.planned.get_mut(&path)
+        {
+            *attrs = new_attrs.clone();
+        }
+        self.operations.push(Operation::SetAttributes {
+            path,
+            attrs: new_attrs,
+        });
+        Ok(())
+    }
+
+    fn times(&self, path: impl AsRef<Utf8Path>) -> Result<Times> {
+        let path = self.canonicalize(path)?;
+        if let Some(times) = self.times.get(&path) {
+            return Ok(*times);
+        }
+        match self.locate(&path) {
+            Location::Inner(path, _) => self.inner.times(path),
+            _ => bail!("No such file or directory: {}", path),
+        }
+    }
+
+    fn set_times(&mut self, path: impl AsRef<Utf8Path>, times: Times) -> Result<()> {
+        let path = self.canonicalize(path)?;
+        if !self.exists(&path) {
+            bail!("No such file or directory: {}", path);
+        }
+        self.times.insert(path.clone(), times);
+        self.operations.push(Operation::SetTimes { path, times });
+        Ok(())
+    }
+
+    fn link_attributes(&self, path: impl AsRef<Utf8Path>) -> Result<Attrs<'_>> {
+        let (parent, name) = self.canonical_split(path.as_ref())?;
+        let path = parent.join(name);
+        if !self.is_link(&path) {
+            bail!("Not a symlink: {}", path);
+        }
+        match self.locate(&path) {
+            Location::Planned(Planned::Symlink(_, attrs)) | Location::Inner(_, Some(attrs)) => {
+                Ok(attrs.clone())
+            }
+            Location::Inner(from, None) => self.inner.link_attributes(from),
+            _ => bail!("Not a symlink: {}", path),
+        }
+    }
+
+    fn set_link_attributes(&mut self, path: impl AsRef<Utf8Path>, attrs: SetAttrs) -> Result<()> {
+        let (parent, name) = self.canonical_split(path.as_ref())?;
+        let path = parent.join(name);
+        if !self.is_link(&path) {
+            bail!("Not a symlink: {}", path);
+        }
+        if attrs.mode.is_some() {
+            bail!("The mode of a symlink cannot be set: {}", path);
+        }
+        // An inner link's own attributes are kept against its path, which (being a link) is never
+        // that of a file or directory whose attributes are kept
+        let new_attrs = updated(&self.link_attributes(&path)?, attrs);
+        match self.planned.get_mut(&path) {
+            Some(Planned::Symlink(_, attrs)) => *attrs = new_attrs.clone(),
+            _ => {
+                let from = match self.locate(&path) {
+                    Location::Inner(from, _) => from,
+                    _ => bail!("Not a symlink: {}", path),
+                };
+                self.planned.insert(
+                    path.clone(),
+                    Planned::Inner {
+                        from,
+                        attrs: Some(new_attrs.clone()),
+                    },
+                );
+            }
+        }
+        self.operations.push(Operation::SetLinkAttributes {
+            path,
+            attrs: new_attrs,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use anyhow::Result;
+
+    use crate::{Filesystem, MemoryFilesystem, SetAttrs, Times};
+
+    use super::{ChangeKind, Operation, RecordingFilesystem};
+
+    #[test]
+    fn changes_are_recorded_not_made() -> Result<()> {
+        let mut inner = MemoryFilesystem::new();
+        inner.create_directory("/existing", Default::default())?;
+        inner.create_file("/existing/file", Default::default(), "old".into())?;
+        let before = inner.snapshot();
+
+        let mut fs = RecordingFilesystem::new(inner);
+        fs.create_directory("/existing/new", SetAttrs::default().with_mode(0o700))?;
+        fs.create_file("/existing/new/file", Default::default(), "new".into())?;
+        fs.set_attributes("/existing/file", SetAttrs::default().with_mode(0o600))?;
+        fs.rename("/existing", "/moved")?;
+
+        // Reads see the planned state
+        assert!(!fs.exists("/existing"));
+        assert_eq!(fs.list_directory("/moved")?, vec!["file", "new"]);
+        assert_eq!(fs.read_file("/moved/file")?, "old");
+        assert_eq!(fs.read_file("/moved/new/file")?, "new");
+        assert_eq!(fs.attributes("/moved/file")?.mode.value(), 0o600);
+        assert_eq!(fs.attributes("/moved/new")?.mode.value(), 0o700);
+        assert!(fs
+            .create_directory("/moved/new", Default::default())
+            .is_err());
+        assert!(fs
+            .create_directory("/missing/new", Default::default())
+            .is_err());
+
+        // Operations are listed in order, and the inner filesystem is untouched
+        let operations: Vec<_> = fs.operations().iter().map(|op| op.to_string()).collect();
+        assert_eq!(operations.len(), 4);
+        assert!(operations[0].starts_with("Create directory /existing/new ("));
+        assert!(operations[0].ends_with(" 0700)"));
+        assert!(matches!(
+            &fs.operations()[3],
+            Operation::Rename { from, to } if from == "/existing" && to == "/moved"
+        ));
+        assert_eq!(fs.into_inner().snapshot(), before);
+        Ok(())
+    }
+
+    #[test]
+    fn link_attributes_are_recorded() -> Result<()> {
+        let mut inner = MemoryFilesystem::new().with_fake_ids(true);
+        inner.create_file("/target", Default::default(), "".into())?;
+        inner.create_symlink("/existing", "/target")?;
+        let before = inner.snapshot();
+
+        let mut fs = RecordingFilesystem::new(inner);
+        fs.create_symlink("/new", "/target")?;
+        for link in ["/existing", "/new"] {
+            fs.set_link_attributes(link, SetAttrs::default().with_owner("link-user"))?;
+            assert_eq!(fs.link_attributes(link)?.owner, "link-user");
+        }
+        assert_eq!(fs.attributes("/existing")?.owner, "root");
+        assert!(fs.link_attributes("/target").is_err());
+
+        let operations: Vec<_> = fs.operations().iter().map(|op| op.to_string()).collect();
+        assert_eq!(operations.len(), 3);
+        assert!(operations[1].starts_with("Set attributes of symlink /existing"));
+        assert_eq!(fs.into_inner().snapshot(), before);
+        Ok(())
+    }
+
+    #[test]
+    fn create_file_if_absent_is_recorded_once() -> Result<()> {
+        let mut inner = MemoryFilesystem::new();
+        inner.create_file("/existing", Default::default(), "old".into())?;
+        let mut fs = RecordingFilesystem::new(inner);
+        assert!(!fs.create_file_if_absent("/existing", Default::default(), b"new".to_vec())?);
+        assert!(fs.create_file_if_absent("/new", Default::default(), b"new".to_vec())?);
+        assert!(!fs.create_file_if_absent("/new", Default::default(), b"again".to_vec())?);
+        assert_eq!(fs.read_file("/existing")?, "old");
+        assert_eq!(fs.read_file("/new")?, "new");
+        assert_eq!(fs.operations().len(), 1);
+        assert!(!fs.into_inner().exists("/new"));
+        Ok(())
+    }
+
+    #[test]
+    fn times_are_recorded() -> Result<()> {
+        let mut inner = MemoryFilesystem::new();
+        inner.create_directory("/existing", Default::default())?;
+        inner.create_file("/existing/file", Default::default(), "".into())?;
+        let before = inner.snapshot();
+        let inner_times = inner.times("/existing/file")?;
+
+        let mut fs = RecordingFilesystem::new(inner);
+        let times = Times {
+            accessed: SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000),
+        };
+        assert_eq!(fs.times("/existing/file")?, inner_times);
+        fs.set_times("/existing/file", times)?;
+        fs.create_file("/existing/new", Default::default(), "".into())?;
+        fs.set_times("/existing/new", times)?;
+        fs.rename("/existing", "/moved")?;
+        assert_eq!(fs.times("/moved/file")?, times);
+        assert_eq!(fs.times("/moved/new")?, times);
+        assert!(fs.times("/existing/file").is_err());
+        assert!(fs.set_times("/missing", times).is_err());
+
+        let operations: Vec<_> = fs.operations().iter().map(|op| op.to_string()).collect();
+        assert_eq!(operations[0], "Set times of /existing/file");
+        assert_eq!(operations.len(), 4);
+        assert_eq!(fs.into_inner().snapshot(), before);
+        Ok(())
+    }
+
+    #[test]
+    fn changes_are_classified() -> Result<()> {
+        let mut inner = MemoryFilesystem::new();
+        inner.create_directory("/existing", Default::default())?;
+        inner.create_file("/existing/file", Default::default(), "old".into())?;
+
+        let mut fs = RecordingFilesystem::new(inner);
+        fs.create_directory("/existing/new", Default::default())?;
+        fs.set_attributes("/existing/new", SetAttrs::default().with_mode(0o700))?;
+        fs.write_file_bytes("/existing/file", b"new".to_vec())?;
+        fs.set_attributes("/existing/file", SetAttrs::default().with_mode(0o600))?;
+        let changes: Vec<_> = fs
+            .changes()
+            .into_iter()
+            .map(|(path, kind)| (path.into_string(), kind))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("/existing/file".to_owned(), ChangeKind::Modified),
+                ("/existing/new".to_owned(), ChangeKind::Created),
+            ]
+        );
+        Ok(())
+    }
+}
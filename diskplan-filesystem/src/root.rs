@@ -1,7 +1,22 @@
-use anyhow::{bail, Result};
-use camino::{Utf8Path, Utf8PathBuf};
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use users::os::unix::UserExt;
 
 /// An absolute path to a configured location on disk
+///
+/// Roots are normalized on construction, so equivalent spellings (such as `/net/remote`,
+/// `/net/remote/` and `/net/remote/.`) give the same root. Parent (`..`) components are rejected.
+///
+/// A root is displayed as its normalized path, which parses back to the same root:
+/// ```
+/// use diskplan_filesystem::Root;
+///
+/// let root: Root = "/net//remote/".parse().unwrap();
+/// assert_eq!(root.to_string(), "/net/remote");
+/// assert_eq!(root.to_string().parse::<Root>().unwrap(), root);
+/// ```
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Root(Utf8PathBuf);
 
@@ -26,13 +41,11 @@ impl TryFrom<Utf8PathBuf> for Root {
     type Error = anyhow::Error;
 
     fn try_from(value: Utf8PathBuf) -> Result<Self, Self::Error> {
-        if !is_normalized(value.as_str()) {
-            bail!("Root must be a normalized path: {}", value);
-        }
+        let value = expand_home(value)?;
         if !value.is_absolute() {
             bail!("Invalid root; path must be absolute: {}", value);
         }
-        Ok(Root(value))
+        Ok(Root(normalize(&value)?))
     }
 }
 
@@ -52,7 +65,55 @@ impl TryFrom<&str> for Root {
     }
 }
 
-fn is_normalized(path: impl AsRef<Utf8Path>) -> bool {
-    let path = path.as_ref().as_str();
-    !((path.ends_with('/') && path != "/") || path.contains("//") || path.contains("/./"))
+impl FromStr for Root {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.try_into()
+    }
+}
+
+impl Display for Root {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Expands a leading `~` (the current user's home directory) or `~user` (the home directory of the
+/// given user) in `path`. Paths not beginning with `~` are returned unchanged.
+pub fn expand_home(path: impl AsRef<Utf8Path>) -> Result<Utf8PathBuf> {
+    let path = path.as_ref();
+    let tilde_user = match path.as_str().strip_prefix('~') {
+        Some(tilde_user) => tilde_user,
+        None => return Ok(path.to_owned()),
+    };
+    let (name, rest) = tilde_user.split_once('/').unwrap_or((tilde_user, ""));
+    let home = match (name, std::env::var("HOME")) {
+        ("", Ok(home)) if !home.is_empty() => Utf8PathBuf::from(home),
+        _ => {
+            let user = match name {
+                "" => users::get_user_by_uid(users::get_current_uid()),
+                name => users::get_user_by_name(name),
+            }
+            .ok_or_else(|| anyhow!("No such user to find home directory: {}", path))?;
+            Utf8PathBuf::try_from(user.home_dir().to_owned())?
+        }
+    };
+    Ok(match rest {
+        "" => home,
+        rest => home.join(rest),
+    })
+}
+
+/// Rebuilds the path from its components, dropping any repeated or trailing separators and `.`
+fn normalize(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let mut normalized = Utf8PathBuf::new();
+    for component in path.components() {
+        match component {
+            Utf8Component::CurDir => {}
+            Utf8Component::ParentDir => bail!("Root must not contain '..': {}", path),
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
 }
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+
+use super::{Attrs, Filesystem};
+
+/// The type of an entry found by [`Filesystem::walk`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A regular file
+    File,
+    /// A directory
+    Directory,
+    /// A symbolic link (which is not followed when walking)
+    Symlink,
+}
+
+/// An iterator over a directory tree, created by [`Filesystem::walk`]
+///
+/// Entries are visited depth first, with the contents of each directory in name order, so the
+/// order is the same from one walk to the next. Symbolic links are reported (with the attributes
+/// of the link itself) but not followed.
+pub struct Walk<'a, FS> {
+    filesystem: &'a FS,
+    pending: Vec<Utf8PathBuf>,
+}
+
+impl<'a, FS> Walk<'a, FS>
+where
+    FS: Filesystem,
+{
+    pub(crate) fn new(filesystem: &'a FS, root: impl AsRef<Utf8Path>) -> Self {
+        Walk {
+            filesystem,
+            pending: vec![root.as_ref().to_owned()],
+        }
+    }
+
+    fn visit(&mut self, path: Utf8PathBuf) -> Result<(Utf8PathBuf, NodeKind, Attrs<'a>)> {
+        let fs = self.filesystem;
+        let kind = if fs.is_link(&path) {
+            NodeKind::Symlink
+        } else if fs.is_directory(&path) {
+            NodeKind::Directory
+        } else {
+            NodeKind::File
+        };
+        if kind == NodeKind::Directory {
+            let children = fs.list_directory_sorted(&path)?;
+            // Reversed onto the stack so they come off in order
+            self.pending
+                .extend(children.iter().rev().map(|child| path.join(child)));
+        }
+        // A link's own attributes, so one whose target is missing is reported all the same
+        let attrs = match kind {
+            NodeKind::Symlink => fs.link_attributes(&path)?,
+            _ => fs.attributes(&path)?,
+        };
+        Ok((path, kind, attrs))
+    }
+}
+
+impl<'a, FS> Iterator for Walk<'a, FS>
+where
+    FS: Filesystem,
+{
+    type Item = Result<(Utf8PathBuf, NodeKind, Attrs<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let path = self.pending.pop()?;
+        Some(self.visit(path))
+    }
+}
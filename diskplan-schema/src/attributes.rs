@@ -1,16 +1,23 @@
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 
 use super::Expression;
 
 /// Owner, group and UNIX permissions
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+///
+/// These display as the attributes given, named by their tags, such as
+/// `owner=${user} group=inherit mode-initial=0640`, or `(none)`.
+#[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Attributes<'t> {
     /// The owner to be set, if given
-    pub owner: Option<Expression<'t>>,
+    pub owner: Option<AttributeValue<Expression<'t>>>,
     /// The group to be set, if given
-    pub group: Option<Expression<'t>>,
+    pub group: Option<AttributeValue<Expression<'t>>>,
     /// The UNIX permissions to be set, if given
-    pub mode: Option<u16>,
+    pub mode: Option<AttributeValue<u16>>,
+    /// Whether the mode is set only on creation (`:mode-initial`), leaving that of an existing
+    /// entry as it is found rather than correcting it
+    pub mode_initial: bool,
 }
 
 impl<'t> Attributes<'t> {
@@ -22,7 +29,81 @@ impl<'t> Attributes<'t> {
                 owner: None,
                 group: None,
                 mode: None,
+                ..
             }
         )
     }
+
+    /// Returns true if this entry sets no attributes of its own; each is either not given or
+    /// given as `inherit`
+    pub fn is_inherited(&self) -> bool {
+        matches!(self.owner, None | Some(AttributeValue::Inherit))
+            && matches!(self.group, None | Some(AttributeValue::Inherit))
+            && matches!(self.mode, None | Some(AttributeValue::Inherit))
+    }
+}
+
+impl Display for Attributes<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn value<T>(value: &AttributeValue<T>, explicit: impl Fn(&T) -> String) -> String {
+            match value {
+                AttributeValue::Inherit => "inherit".into(),
+                AttributeValue::Explicit(value) => explicit(value),
+            }
+        }
+        let mode_tag = match self.mode_initial {
+            true => "mode-initial",
+            false => "mode",
+        };
+        let mut separator = "";
+        for (tag, given) in [
+            (
+                "owner",
+                self.owner.as_ref().map(|v| value(v, ToString::to_string)),
+            ),
+            (
+                "group",
+                self.group.as_ref().map(|v| value(v, ToString::to_string)),
+            ),
+            (
+                mode_tag,
+                self.mode.as_ref().map(|v| value(v, |m| format!("{m:04o}"))),
+            ),
+        ] {
+            if let Some(given) = given {
+                write!(f, "{separator}{tag}={given}")?;
+                separator = " ";
+            }
+        }
+        if separator.is_empty() {
+            write!(f, "(none)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Attributes<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Attributes({self})")
+    }
+}
+
+/// A single attribute given by a schema node, either explicitly or as `inherit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum AttributeValue<T> {
+    /// Take the value from the parent, even if a `:use`d definition gives one
+    Inherit,
+    /// Use the given value
+    Explicit(T),
+}
+
+impl<T> AttributeValue<T> {
+    /// Returns the explicitly given value, or `None` if the value is to be inherited
+    pub fn explicit(&self) -> Option<&T> {
+        match self {
+            AttributeValue::Inherit => None,
+            AttributeValue::Explicit(value) => Some(value),
+        }
+    }
 }
@@ -0,0 +1,50 @@
+use std::fmt::Display;
+
+use super::Expression;
+
+/// A comparison of two expressions, given by a `:when` tag, deciding whether a node applies
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Condition<'t> {
+    /// The expression on the left of the operator
+    pub left: Expression<'t>,
+    /// How the two sides are compared
+    pub comparison: Comparison,
+    /// The expression on the right of the operator
+    pub right: Expression<'t>,
+}
+
+impl Condition<'_> {
+    /// Compares the values to which the left and right expressions evaluated
+    pub fn holds(&self, left: &str, right: &str) -> bool {
+        match self.comparison {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+        }
+    }
+}
+
+impl Display for Condition<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.left, self.comparison, self.right)
+    }
+}
+
+/// An operator comparing the two sides of a [`Condition`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Comparison {
+    /// The sides must evaluate to the same text (`==`)
+    Equal,
+    /// The sides must evaluate to different text (`!=`)
+    NotEqual,
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+        })
+    }
+}
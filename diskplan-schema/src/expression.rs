@@ -1,31 +1,126 @@
-use std::{fmt::Display, vec};
+use std::{cmp::Ordering, fmt::Display, vec};
 
 /// A string expression made from one or more [`Token`]s
-#[derive(Debug, Clone, PartialEq, Eq, Ord, PartialOrd)]
-pub struct Expression<'t>(Vec<Token<'t>>);
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
+pub struct Expression<'t> {
+    tokens: Vec<Token<'t>>,
+    /// The whole schema text this expression was parsed from, if any, used to locate its tokens
+    #[cfg_attr(feature = "serde", serde(skip))]
+    text: Option<&'t str>,
+}
 
 impl<'t> Expression<'t> {
     /// Provides access to the slice of tokens that make up this expression
     pub fn tokens(&self) -> &[Token<'t>] {
-        &self.0[..]
+        &self.tokens[..]
+    }
+
+    /// Returns this expression, noting the schema text from which it was parsed
+    ///
+    /// This allows [`position`](Self::position) to locate its tokens within that text.
+    pub fn with_text(mut self, text: &'t str) -> Self {
+        self.text = Some(text);
+        self
+    }
+
+    /// Locates the token at `index` within the schema text this expression was parsed from
+    ///
+    /// Only variables (including environment variables) can be located, as other tokens do not
+    /// keep a reference to their text.
+    pub fn position(&self, index: usize) -> Option<Position<'t>> {
+        let text = self.text?;
+        let Some(Token::Variable(var) | Token::Environment(var)) = self.tokens.get(index) else {
+            return None;
+        };
+        let start = text.as_ptr() as usize;
+        let pos = var.value().as_ptr() as usize;
+        if pos < start || pos > start + text.len() {
+            return None;
+        }
+        // Step back to the dollar sign (and any brace) introducing the variable
+        let before = &text[..pos - start];
+        let offset = before.len()
+            - if before.ends_with("$ENV{") {
+                5
+            } else if before.ends_with("${") {
+                2
+            } else {
+                1
+            };
+        let line_start = text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = text[offset..]
+            .find('\n')
+            .map(|i| offset + i)
+            .unwrap_or(text.len());
+        Some(Position {
+            line: &text[line_start..line_end],
+            line_number: text[..offset].matches('\n').count() + 1,
+            column: offset - line_start,
+        })
+    }
+}
+
+impl std::fmt::Debug for Expression<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Expression").field(&self.tokens).finish()
+    }
+}
+
+// Expressions compare by their tokens alone, wherever they were parsed from
+impl PartialEq for Expression<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.tokens == other.tokens
+    }
+}
+
+impl Eq for Expression<'_> {}
+
+impl PartialOrd for Expression<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Expression<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.tokens.cmp(&other.tokens)
     }
 }
 
 impl<'t> From<Vec<Token<'t>>> for Expression<'t> {
     fn from(tokens: Vec<Token<'t>>) -> Self {
-        Expression(tokens)
+        Expression { tokens, text: None }
     }
 }
 
 impl<'t> From<&[Token<'t>]> for Expression<'t> {
     fn from(tokens: &[Token<'t>]) -> Self {
-        Expression(tokens.into())
+        Expression::from(tokens.to_vec())
+    }
+}
+
+/// The location of a token within a line of schema text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position<'t> {
+    /// The whole line of text containing the token
+    pub line: &'t str,
+    /// The (1-based) number of the line
+    pub line_number: usize,
+    /// The byte offset of the start of the token within the line
+    pub column: usize,
+}
+
+impl Display for Position<'_> {
+    /// Shows the line with a caret beneath the token, as for a [`ParseError`](crate::ParseError)
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        crate::text::write_snippet(f, self.line_number, self.line, self.column)
     }
 }
 
 impl Display for Expression<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for token in self.0.iter() {
+        for token in self.tokens.iter() {
             write!(f, "{token}")?;
         }
         Ok(())
@@ -36,7 +131,7 @@ impl PartialEq<&str> for Expression<'_> {
     fn eq(&self, other: &&str) -> bool {
         // Expression is equal to a string only if it is a single text token
         // with the same inner value
-        match &self.0[..] {
+        match &self.tokens[..] {
             [Token::Text(text)] => *text == *other,
             _ => false,
         }
@@ -45,6 +140,7 @@ impl PartialEq<&str> for Expression<'_> {
 
 /// Part of an [`Expression`]; a constant string, or a variable for later expansion to a string
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Token<'t> {
     /// A constant string of plain text
     Text(&'t str),
@@ -52,20 +148,25 @@ pub enum Token<'t> {
     Variable(Identifier<'t>),
     /// A special variable whose value is provided by the current scope
     Special(Special),
+    /// The name of an environment variable (written `$ENV{NAME}`), read when evaluated
+    Environment(Identifier<'t>),
 }
 
 impl Display for Token<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Token::Text(s) => f.write_str(s),
+            // Dollar signs are escaped, so the text may be parsed back again
+            Token::Text(s) => f.write_str(&s.replace('$', "$$")),
             Token::Variable(v) => write!(f, "${{{v}}}"),
             Token::Special(sp) => write!(f, "${{{sp}}}"),
+            Token::Environment(name) => write!(f, "$ENV{{{name}}}"),
         }
     }
 }
 
 /// A choice of built-in variables that are used to provide context information during traversal
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Special {
     /// The current path relative to the active root
     PathRelative,
@@ -81,6 +182,14 @@ pub enum Special {
     ParentNameOnly,
     /// The absolute path of the active root
     RootPath,
+    /// The final component of the path of the active root
+    RootName,
+    /// The name bound by the nearest enclosing dynamic (`$variable`) entry
+    MatchedName,
+    /// The owner of the current entry, after any user map is applied
+    MappedOwner,
+    /// The group of the current entry, after any group map is applied
+    MappedGroup,
 }
 
 impl Special {
@@ -98,6 +207,14 @@ impl Special {
     pub const PARENT_PATH_NAME: &'static str = "PARENT_NAME";
     /// The absolute path of the active root
     pub const ROOT_PATH: &'static str = "ROOT_PATH";
+    /// The final component of the path of the active root
+    pub const ROOT_NAME: &'static str = "ROOT_NAME";
+    /// The name bound by the nearest enclosing dynamic (`$variable`) entry
+    pub const MATCHED_NAME: &'static str = "MATCHED_NAME";
+    /// The owner of the current entry, after any user map is applied
+    pub const MAPPED_OWNER: &'static str = "MAPPED_OWNER";
+    /// The group of the current entry, after any group map is applied
+    pub const MAPPED_GROUP: &'static str = "MAPPED_GROUP";
 }
 
 impl Display for Special {
@@ -110,12 +227,17 @@ impl Display for Special {
             Special::ParentAbsolute => Special::PARENT_PATH_ABSOLUTE,
             Special::ParentNameOnly => Special::PARENT_PATH_NAME,
             Special::RootPath => Special::ROOT_PATH,
+            Special::RootName => Special::ROOT_NAME,
+            Special::MatchedName => Special::MATCHED_NAME,
+            Special::MappedOwner => Special::MAPPED_OWNER,
+            Special::MappedGroup => Special::MAPPED_GROUP,
         })
     }
 }
 
 /// The name given to a variable
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Identifier<'t>(&'t str);
 
 impl<'t> Identifier<'t> {
@@ -144,7 +266,7 @@ impl<'a> From<&'a str> for Identifier<'a> {
 
 impl<'a> From<Identifier<'a>> for Expression<'a> {
     fn from(identifier: Identifier<'a>) -> Self {
-        Expression(vec![Token::Variable(identifier)])
+        Expression::from(vec![Token::Variable(identifier)])
     }
 }
 
@@ -153,7 +275,7 @@ mod tests {
     use super::*;
 
     fn test_expression() -> Expression<'static> {
-        Expression(vec![
+        Expression::from(vec![
             Token::Text("normal text/"),
             Token::Variable(Identifier("a_variable")),
             Token::Text("/"),
@@ -182,6 +304,12 @@ mod tests {
         );
     }
 
+    #[test]
+    fn format_environment() {
+        let home = Identifier("HOME");
+        assert_eq!(&format!("{}", Token::Environment(home)), "$ENV{HOME}");
+    }
+
     #[test]
     fn formatted_expression_is_valid_schema_expression() {
         let expr = test_expression();
@@ -193,4 +321,20 @@ mod tests {
 
         assert_eq!(*symlink_expression, expr);
     }
+
+    #[test]
+    fn formatted_environment_is_valid_schema_expression() {
+        let expr = Expression::from(vec![
+            Token::Text("/home/"),
+            Token::Environment(Identifier("USER")),
+            Token::Text("_"),
+            Token::Variable(Identifier("suffix")),
+        ]);
+        let schema_text = format!("symlink/ -> {expr}");
+        let schema_node = crate::parse_schema(&schema_text).unwrap();
+        let directory_schema = schema_node.schema.as_directory().unwrap();
+        let (_, symlink_node) = directory_schema.entries().first().unwrap();
+
+        assert_eq!(*symlink_node.symlink.as_ref().unwrap(), expr);
+    }
 }
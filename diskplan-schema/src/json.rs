@@ -0,0 +1,28 @@
+//! Serialization of parsed schemas, available with the `serde` feature
+//!
+//! Schema types only implement [`serde::Serialize`], borrowing their strings directly from the
+//! schema text. Since a serialized schema is an output of parsing (for use by other tooling)
+//! rather than an alternative input, no owned shadow types are needed for deserialization.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+use serde::{Serialize, Serializer};
+
+use crate::parse_schema;
+
+/// Parses the given text representation and serializes the resulting tree as pretty-printed JSON
+pub fn parse_schema_to_json(text: &str) -> Result<String> {
+    let schema = parse_schema(text).map_err(|e| anyhow!("{}", e))?;
+    Ok(serde_json::to_string_pretty(&schema)?)
+}
+
+/// Serializes a map in key order, so that output is stable from one run to the next
+pub(crate) fn sorted<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Serialize + Ord,
+    V: Serialize,
+    S: Serializer,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
@@ -13,18 +13,111 @@
 //! | _str_ `->` _expr_     | A symlink to a file
 //! | _str_/ `->` _expr_    | A symlink to a directory
 //!
-//! Properties of a given node are set using the following tags:
+//! Comments begin with `#`, either on a line of their own or following the significant part of a
+//! line (in which case at least one space must come before the `#`).
+//!
+//! A schema may begin with a line declaring the version of the format it is written for, such as
+//! `#!diskplan 1`. This is not a comment; a version this parser does not support (see
+//! [`SCHEMA_VERSION`]) is an error, rather than risking the rest of the file being misread.
+//!
+//! Properties of a given node are set using the following tags (owners and groups may be given
+//! by name or by numeric ID, such as `1000` or `#1000`):
 //!
 //! | Tag                       | Types     | Description
 //! |---------------------------|-----------|---------------------------
 //! |`:owner` _expr_            | All       | Sets the owner of this file/directory/symlink target
 //! |`:group` _expr_            | All       | Sets the group of this file, directory or symlink target
 //! |`:mode` _octal_            | All       | Sets the permissions of this file/directory/symlink target
-//! |`:source` _expr_           | File      | Copies content into this file from the path given by _expr_
+//! |`:mode-initial` _octal_    | All       | Sets the permissions on creation only, not correcting them
+//! |`:source` _expr_           | All       | Copies content into this file/directory from _expr_
+//! |`:source` _expr_`,` _expr_…| File      | Copies content from the first of several paths to exist
+//! |`:source-glob` _expr_      | Directory | Creates a file here from each file matching _expr_
+//! |`:sha256` _hex_            | File      | Verifies the file's content, repairing it from `:source`
+//! |`:link-owner` _expr_       | Symlink   | Sets the owner of the symlink itself, not its target
+//! |`:link-group` _expr_       | Symlink   | Sets the group of the symlink itself, not its target
+//! |`:rename-from` _expr_      | Non-link  | Moves an existing file/directory here from _expr_ if found
+//! |`:when` _expr_ _op_ _expr_ | All       | Applies this entry (and all beneath it) only if true
+//! |`:description` _text_      | All       | Describes this entry, for documentation only
 //! |`:let` _ident_ `=` _expr_  | Directory | Sets a variable at this level to be used by deeper levels
+//! |`:ignore-case`             | Directory | Matches the names of child items without regard to case
+//! |`:recursive`               | Directory | Makes this directory's mode the default for everything beneath
 //! |`:def` _ident_             | Directory | Defines a sub-schema that can be reused by `:use`
+//! |`:def` _ident_`(`_params_`)`| Directory | Defines a sub-schema taking parameters
 //! |`:use` _ident_             | Directory | Reuses a sub-schema defined by `:def`
+//! |`:use` _ident_`(`_args_`)`  | Directory | Reuses a sub-schema, passing arguments to its parameters
+//! |`:include` _path_          | Directory | Brings in the top-level `:def`s and `:let`s of another file
+//!
+//! Since `:owner`, `:group` and `:mode` on a symlink apply to its target, `:link-owner` and
+//! `:link-group` are given to set the ownership of the link itself (as `lchown` would). There is
+//! no equivalent for the mode, as the permissions of a symlink are not used (and cannot be
+//! changed on Linux).
+//!
+//! A `:description` takes the rest of the line as free text (with no variables substituted). It
+//! has no effect on what is created, but is kept in the parsed tree (and written out by
+//! [`format_schema`] and the JSON serialization) so that schemas can document themselves.
+//!
+//! When restructuring an existing tree, `:rename-from` gives a previous location (absolute, or
+//! relative to the parent directory) of a file or directory. If that exists and the new location
+//! does not, it is moved rather than created afresh, keeping its content. It is an error for both
+//! to exist, or for the previous location to lie outside the root of the new one.
+//!
+//! A `:when` condition compares two expressions with `==` (equal) or `!=` (not equal), which must
+//! be separated from each side by spaces. The left side is a single word, such as `$env` or
+//! `${NAME}`. Where the condition is false, the entry is skipped: neither it nor anything beneath
+//! it is created or changed. A condition that refers to a variable which is not set is false,
+//! whichever the operator. For example, to create `logs/` only when `$env` is `prod`:
+//! ```text
+//! logs/
+//!     :when $env == prod
+//! ```
+//! A `:when` given in a definition applies wherever that definition is used. A symlink whose target
+//! is skipped this way is an error.
+//!
+//! An entry is created with every attribute set, taking any not given from its parent (owner
+//! and group) or the default for its type (mode). An entry that exists already has only the
+//! attributes the schema gives it corrected: those of its node (itself or through `:use`), an
+//! owner or group given by an ancestor, and a mode given by a `:recursive` ancestor. So where
+//! nothing above gives an owner or mode, a node giving just `:group` changes the group of an
+//! existing file or directory, and leaves its owner and mode as found.
+//!
+//! A mode given by `:mode-initial` rather than `:mode` is set when the entry is created, but an
+//! existing entry keeps whatever mode it is found with. This lets operators loosen (or tighten)
+//! it deliberately without it being reset on the next run. Only one of the two may be given.
 //!
+//! A directory given a `:source` is a copy of the directory at that path when first created (its
+//! content, recursively, keeping the modes found there but taking this directory's owner and
+//! group). The copy is made before the directory's own entries are applied, so these may then
+//! refine or add to what was copied. Nothing is copied into a directory that already exists.
+//!
+//! A directory given a `:source-glob`, such as `:source-glob /templates/conf/*.conf`, has a file
+//! created in it for each file matching the pattern, of the same name and with the content of
+//! the match. Only the last component of the pattern may contain wildcards: `*` for any run of
+//! characters and `?` for any one (neither matching a leading `.`, so hidden files are left
+//! out). The files take the owner, group and mode that a file entry without tags would take
+//! here. A name claimed by one of the directory's own entries, static or dynamic, is left to that
+//! entry, so a template can be overridden by listing it. Like a `:source`, files are only
+//! created where missing, and existing files are left as they are.
+//!
+//! A file's `:source` may list several candidate paths, separated by commas (so a path cannot
+//! itself contain one, though it may contain spaces), such as
+//! `:source /sites/$site/motd, /default/motd`. Each is evaluated in turn and the first that
+//! exists is used; it is an error if none do.
+//!
+//! A source is read as soon as its entry is created, so it may lie within a root only if it is
+//! already present by then. A missing source within a root is reported as such, naming both
+//! paths, since it may be something diskplan has still to create.
+//!
+//! A file with a `:sha256` digest is checked whenever the schema is applied. Its source must
+//! match the digest before it is copied, and an existing file that does not match is rewritten
+//! from its source (which is an error if the source does not match either).
+//!
+//! An `:include` names another schema file (absolute, or relative to the including file). That
+//! file may contain only `:def`s, `:let`s and further `:include`s at its top level, and these are
+//! merged into the scope of the directory where the `:include` appears, as if written there.
+//! A file included more than once into the same scope (say, by two files that each include it)
+//! is merged only once, but a `:def` or `:let` of the same name that differs is an error.
+//! Includes are recorded by [parse_schema] and merged by [SchemaNode::resolve_includes], which is
+//! left to the loader of the files (such as the `SchemaCache` of `diskplan-config`).
 //!
 //! # Simple Schema
 //!
@@ -39,12 +132,16 @@
 //! ")?;
 //!
 //! assert!(matches!(schema_root.schema, SchemaType::Directory(_)));
-//! assert_eq!(schema_root.attributes.owner.unwrap(), "person");
-//! assert_eq!(schema_root.attributes.group.unwrap(), "user");
-//! assert_eq!(schema_root.attributes.mode.unwrap(), 0o777);
+//! assert_eq!(schema_root.attributes.owner.unwrap().explicit().unwrap(), &"person");
+//! assert_eq!(schema_root.attributes.group.unwrap().explicit().unwrap(), &"user");
+//! assert_eq!(schema_root.attributes.mode.unwrap(), AttributeValue::Explicit(0o777));
 //! # Ok::<(), anyhow::Error>(())
 //! ```
 //!
+//! An owner or group carries down to everything beneath, unless set again further down. A mode
+//! applies only where it is given, unless the directory is marked `:recursive`, in which case it
+//! also becomes the default for everything beneath (where no `:mode` of their own is given).
+//!
 //! A [DirectorySchema] may contain sub-directories and files:
 //! ```
 //! # use diskplan_schema::*;
@@ -112,6 +209,11 @@
 //! # Ok::<(), anyhow::Error>(())
 //! ```
 //!
+//! A symlink is only ever created where nothing exists. Finding a symlink to the same target
+//! already in its place, diskplan leaves it be; finding a symlink to any other target, or a
+//! file or directory, it stops with an error rather than replace what is there. Such conflicts
+//! are left for the operator to resolve (by removing or moving the existing entry).
+//!
 //! ## Variable Substitution
 //!
 //! Variables can be used to drive construction, for example:
@@ -129,6 +231,21 @@
 //! # ).unwrap();
 //! ```
 //!
+//! The value of a `:let` may itself refer to other variables, or to special variables such as
+//! `${NAME}`, and is evaluated where it is used. Variables set closer to the point of use take
+//! precedence over those set further out, and those given for the whole run (such as by the
+//! `--vars` option of the command line tool) are furthest out of all, unless the run asks for them
+//! to override the schema's `:let` variables instead. A variable whose value refers back to
+//! itself (directly or through others) is an error.
+//!
+//! The environment in which diskplan runs is kept apart from these variables. Its variables are
+//! written `$ENV{NAME}` (always with braces), so `$ENV{HOME}` is the value of `HOME` in the
+//! environment, while `$HOME` is a schema variable. It is an error if the environment variable
+//! is not set.
+//!
+//! Wherever variables may be used, a literal dollar sign is written `$$`. For example,
+//! `:source /data/price_$$5` copies from `/data/price_$5`, and `$$var` is the text `$var`.
+//!
 //! Variables will also pick up on names already on disk (even if a `:let` provides a different
 //! value). For example, if we had `assets/prop/Banana` on disk already, `$asset_type` would match
 //! against and take the value "prop" (as well as "character") and `$asset` would take the value
@@ -153,7 +270,12 @@
 //! can help restrict the pattern matching and ensure proper partitioning.
 //!
 //! Static names (without variables) always take precedence and do not need to be unique with
-//! respect to variable patterns (and vice versa).
+//! respect to variable patterns (and vice versa). A variable never takes the name of a static
+//! sibling, so no `:avoid` is needed for them. Here, `$project` binds any name except `admin`:
+//! ```text
+//! admin/
+//! $project/
+//! ```
 //!
 //! For example, this is legal in the schema but will always error in practice:
 //! ```text
@@ -173,6 +295,71 @@
 //!     :match [^A-Z].*
 //! ```
 //!
+//! A `:match` pattern must match the _whole_ name, as if it began with `^` and ended with `$`, so
+//! `:match foo` matches only `foo` itself, not `foobar` or `seafood`. To match only the start of
+//! a name, use `:match-prefix` instead, or to match anywhere within it, `:match-contains` (only
+//! one of the three may be given for a node). These two are equivalent:
+//! ```text
+//! $draft/
+//!     :match-prefix draft_
+//! $also_draft/
+//!     :match draft_.*
+//! ```
+//!
+//! Variables in `:match` and `:avoid` patterns are substituted before the pattern is compiled,
+//! taking the values they have in the directory whose children are being matched: the names
+//! bound by it and its ancestors, and any `:let` in scope there. Special variables such as
+//! `${NAME}` likewise refer to that directory, as the name being matched is not yet known. Values
+//! are substituted as they are, so any characters special to regular expressions keep their
+//! meaning. Here, each release must be named after the project it is in:
+//! ```text
+//! $project/
+//!     $release/
+//!         :match ${project}_[0-9]+
+//! ```
+//!
+//! Multiple `:avoid` tags may be given for a single node, in which case a name is excluded if it
+//! matches any one of them:
+//! ```text
+//! $anything/
+//!     :avoid \..*
+//!     :avoid .*~
+//!     :avoid .*\.tmp
+//! ```
+//!
+//! To exclude hidden names (such as `.git` or `.DS_Store`) found on disk, a variable may be given
+//! `:avoid-hidden` instead of `:avoid \..*`, alongside any other `:avoid` tags. This is off by
+//! default, and has no effect on static names, so a `.config/` entry is still created as given:
+//! ```text
+//! .config/
+//! $project/
+//!     :avoid-hidden
+//! ```
+//!
+//! A variable given `:match-path` has its `:match` and `:avoid` patterns tested against its whole
+//! path relative to the root (as `$PATH` would give it) rather than its name alone, so whether a
+//! name matches may depend on those of its ancestors. Here, any name is accepted under `public`,
+//! but only those starting `secret_` under `private`:
+//! ```text
+//! $area/
+//!     $item/
+//!         :match-path
+//!         :match public/.*|private/secret_.*
+//! ```
+//!
+//! On case-insensitive filesystems, a directory may be given the `:ignore-case` tag so that its
+//! children's static names and `:match`/`:avoid` patterns are compared without regard to case.
+//! Names found on disk then take precedence over the spelling given in the schema. Two names that
+//! differ only by case (for example `Zone_A` and `zone_a`, both on disk) become a conflict, and
+//! an error occurs if either is matched:
+//! ```text
+//! zones/
+//!     :ignore-case
+//!     zone_a/
+//!     $other/
+//!         :match zone_.*
+//! ```
+//!
 //! ## Schema Reuse
 //!
 //! Portions of a schema can be built from reusable definitions.
@@ -200,42 +387,169 @@
 //!     ## Subsequent :use lines take lower precedence
 //!     :use two
 //! ```
+//! Giving `inherit` (or `-` for short) in place of a value (as in `:owner inherit`, `:group -` or
+//! `:mode inherit`) resolves that attribute as if it had not been set (owner and group from the
+//! parent directory, mode to the default), even where a `:use`d definition sets it:
+//! ```text
+//! :def private/
+//!     :mode 700
+//!
+//! shared/
+//!     :mode inherit
+//!     :use private
+//! ```
+//!
+//! The owner and group an entry is given, after any user or group map the run applies (so `admin`
+//! where `root` is mapped to it), may be referred to in its `:source` and in anything beneath it
+//! as `${MAPPED_OWNER}` and `${MAPPED_GROUP}`:
+//! ```text
+//! home/
+//!     :owner root
+//!     .profile
+//!         :source /templates/${MAPPED_OWNER}/profile
+//! ```
+//! A definition may itself `:use` other definitions, whose attributes take lower precedence than
+//! its own. A definition must not use itself, whether directly or through others.
+//!
+//! A definition may declare parameters, which are bound as variables within its body. Arguments
+//! are given at each `:use`, may themselves contain variables (evaluated at the point of use), and
+//! must match the number of parameters:
+//! ```text
+//! :def project(owner, quota_dir)/
+//!     :owner $owner
+//!     $quota_dir/
+//!
+//! alice_project/
+//!     :use project(alice, scratch)
+//! ```
+//!
+//! With the `serde` feature enabled, the parsed tree implements `serde::Serialize` and can be
+//! written out as JSON by `parse_schema_to_json` for use by other tooling.
+//!
+//! Tools that inspect a parsed tree (to collect every `:source`, for example) can implement
+//! [`Visitor`] and pass it to [`visit`], rather than matching on each [`SchemaType`] themselves.
 #![warn(missing_docs)]
 
 use std::{collections::HashMap, fmt::Display};
 
+use anyhow::{bail, Context as _};
+
 mod attributes;
-pub use attributes::Attributes;
+pub use attributes::{AttributeValue, Attributes};
+
+mod condition;
+pub use condition::{Comparison, Condition};
 
 mod expression;
-pub use expression::{Expression, Identifier, Special, Token};
+pub use expression::{Expression, Identifier, Position, Special, Token};
 
 mod text;
-pub use text::{parse_schema, ParseError};
+pub use text::{format_schema, parse_schema, parse_schema_with_path, ParseError, SCHEMA_VERSION};
+
+mod visit;
+pub use visit::{visit, Step, Visitor};
+
+#[cfg(feature = "serde")]
+mod json;
+#[cfg(feature = "serde")]
+pub use json::parse_schema_to_json;
 
 /// A node in an abstract directory hierarchy
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct SchemaNode<'t> {
     /// A reference to the line in the text representation where this node was defined
     pub line: &'t str,
 
+    /// The (1-based) number of the line in the text representation where this node was defined,
+    /// or zero for the root node
+    pub line_number: usize,
+
     /// Condition against which to match file/directory names
     pub match_pattern: Option<Expression<'t>>,
 
-    /// Condition against which file/directory names must not match
-    pub avoid_pattern: Option<Expression<'t>>,
+    /// How much of a name the `match_pattern` must match (the whole name unless given by
+    /// `:match-prefix` or `:match-contains`)
+    pub match_anchoring: Anchoring,
+
+    /// Conditions against which file/directory names must not match (any one match excludes)
+    pub avoid_patterns: Vec<Expression<'t>>,
+
+    /// Whether hidden file/directory names (those starting with a dot) are excluded, as if by an
+    /// additional `:avoid` pattern (`:avoid-hidden`)
+    pub avoid_hidden: bool,
+
+    /// Whether `:match` and `:avoid` patterns are tested against the path relative to the root
+    /// rather than the name alone (`:match-path`)
+    pub match_path: bool,
 
     /// Symlink target - if this produces a symbolic link. Operates on the target end.
     pub symlink: Option<Expression<'t>>,
 
+    /// The owner of the symlink itself, rather than its target (`:link-owner`)
+    pub link_owner: Option<Expression<'t>>,
+
+    /// The group of the symlink itself, rather than its target (`:link-group`)
+    pub link_group: Option<Expression<'t>>,
+
+    /// A previous location of this file/directory, to be moved here if found (`:rename-from`)
+    pub rename_from: Option<Expression<'t>>,
+
+    /// A condition under which this file/directory applies at all (`:when`)
+    pub when: Option<Condition<'t>>,
+
+    /// A description of this file/directory, for documentation only (`:description`)
+    pub description: Option<&'t str>,
+
+    /// Parameters of this node, if it is a definition (bound to arguments at each `:use`)
+    pub params: Vec<Identifier<'t>>,
+
     /// Links to other schemas `:use`d by this one (found in parent [`DirectorySchema`] definitions)
-    pub uses: Vec<Identifier<'t>>,
+    pub uses: Vec<Usage<'t>>,
 
     /// Properties of this file/directory
     pub attributes: Attributes<'t>,
 
     /// Properties specific to the underlying (file or directory) type
     pub schema: SchemaType<'t>,
+
+    /// The version of the schema format declared by a `#!diskplan` line at the start of the text,
+    /// if any (only ever given for the root node)
+    pub version: Option<u32>,
+}
+
+impl<'t> SchemaNode<'t> {
+    /// Merges the schema of each `:include` found within this tree into the directory containing
+    /// it, using `load` to obtain the (already resolved) schema for each [`Include`]
+    ///
+    /// Resolved includes are removed from each directory, so that the tree only describes what
+    /// remains to be done
+    pub fn resolve_includes<'s, F>(&mut self, load: &mut F) -> anyhow::Result<()>
+    where
+        't: 's,
+        F: FnMut(&Include<'t>) -> anyhow::Result<&'s SchemaNode<'t>>,
+    {
+        if let SchemaType::Directory(directory) = &mut self.schema {
+            for include in directory.includes.clone() {
+                let included = load(&include)?;
+                directory.merge(included).with_context(|| {
+                    format!(
+                        "Failed to include {} (line {})",
+                        include.path, include.line_number
+                    )
+                })?;
+            }
+            // Once merged, the includes have been fully accounted for
+            directory.includes.clear();
+            for def in directory.defs.values_mut() {
+                def.resolve_includes(load)?;
+            }
+            for (_, entry) in directory.entries.iter_mut() {
+                entry.resolve_includes(load)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'t> std::fmt::Display for SchemaNode<'t> {
@@ -243,10 +557,21 @@ impl<'t> std::fmt::Display for SchemaNode<'t> {
         write!(f, "Schema node \"{}\"", self.line)?;
         if let Some(ref match_pattern) = self.match_pattern {
             write!(f, ", matching \"{match_pattern}\"")?;
+            match self.match_anchoring {
+                Anchoring::Full => {}
+                Anchoring::Prefix => write!(f, " as a prefix")?,
+                Anchoring::Contains => write!(f, " anywhere")?,
+            }
         }
-        if let Some(ref avoid_pattern) = self.avoid_pattern {
+        for avoid_pattern in &self.avoid_patterns {
             write!(f, ", avoiding \"{avoid_pattern}\"")?;
         }
+        if self.avoid_hidden {
+            write!(f, ", avoiding hidden names")?;
+        }
+        if self.match_path {
+            write!(f, ", against the whole path")?;
+        }
 
         match &self.schema {
             SchemaType::Directory(ds) => {
@@ -264,8 +589,60 @@ impl<'t> std::fmt::Display for SchemaNode<'t> {
     }
 }
 
+/// How much of a name a `:match` pattern must match
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Anchoring {
+    /// The pattern must match the whole name (`:match`)
+    #[default]
+    Full,
+    /// The pattern must match the start of the name (`:match-prefix`)
+    Prefix,
+    /// The pattern may match anywhere within the name (`:match-contains`)
+    Contains,
+}
+
+impl Anchoring {
+    /// The tag by which a `:match` pattern is given this anchoring
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Anchoring::Full => "match",
+            Anchoring::Prefix => "match-prefix",
+            Anchoring::Contains => "match-contains",
+        }
+    }
+}
+
+/// A reference to a definition from a `:use` tag, along with any arguments passed to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Usage<'t> {
+    /// The name of the definition being used
+    pub name: Identifier<'t>,
+    /// Arguments to bind, in order, to the parameters of the definition
+    pub args: Vec<Expression<'t>>,
+}
+
+impl Display for Usage<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.args.is_empty() {
+            write!(f, "(")?;
+            for (i, arg) in self.args.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{arg}")?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
 /// File/directory specific aspects of a node in the tree
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum SchemaType<'t> {
     /// Indicates that this node describes a directory
     Directory(DirectorySchema<'t>),
@@ -291,17 +668,45 @@ impl<'t> SchemaType<'t> {
     }
 }
 
+/// A reference to another schema file, whose definitions and variables are brought into scope
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Include<'t> {
+    /// The path of the file to include, as written
+    pub path: &'t str,
+    /// The (1-based) number of the line on which the `:include` was given
+    pub line_number: usize,
+}
+
 /// A DirectorySchema is a container of variables, definitions (named schemas) and a directory listing
-#[derive(Debug, Default, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DirectorySchema<'t> {
     /// Text replacement variables
+    #[cfg_attr(feature = "serde", serde(serialize_with = "json::sorted"))]
     vars: HashMap<Identifier<'t>, Expression<'t>>,
 
     /// Definitions of sub-schemas
+    #[cfg_attr(feature = "serde", serde(serialize_with = "json::sorted"))]
     defs: HashMap<Identifier<'t>, SchemaNode<'t>>,
 
     /// Disk entries to be created within this directory
     entries: Vec<(Binding<'t>, SchemaNode<'t>)>,
+
+    /// Whether names within this directory are matched without regard to case
+    ignore_case: bool,
+
+    /// Whether this directory's mode is the default for all of its descendants
+    recursive: bool,
+
+    /// Other schema files whose definitions and variables are merged into this directory
+    includes: Vec<Include<'t>>,
+
+    /// Path to a directory whose content is copied into this one when it is created
+    source: Option<Expression<'t>>,
+
+    /// Pattern of the files from which to create a file entry of the same name in this directory
+    source_glob: Option<Expression<'t>>,
 }
 
 impl<'t> DirectorySchema<'t> {
@@ -317,8 +722,111 @@ impl<'t> DirectorySchema<'t> {
             vars,
             defs,
             entries,
+            ignore_case: false,
+            recursive: false,
+            includes: Vec::new(),
+            source: None,
+            source_glob: None,
+        }
+    }
+
+    /// Sets whether names within this directory are matched without regard to case
+    pub fn with_ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    /// Whether names within this directory are matched without regard to case
+    pub fn ignore_case(&self) -> bool {
+        self.ignore_case
+    }
+
+    /// Sets whether this directory's mode is the default for all of its descendants
+    pub fn with_recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Whether this directory's mode is the default for all of its descendants
+    pub fn recursive(&self) -> bool {
+        self.recursive
+    }
+
+    /// Sets the other schema files to be included into this directory
+    pub fn with_includes(mut self, includes: Vec<Include<'t>>) -> Self {
+        self.includes = includes;
+        self
+    }
+
+    /// The other schema files included into this directory, in the order given (and not yet
+    /// resolved)
+    pub fn includes(&self) -> &[Include<'t>] {
+        &self.includes
+    }
+
+    /// Sets the path to a directory whose content is copied into this one when it is created
+    pub fn with_source(mut self, source: Option<Expression<'t>>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Returns the expression of the path to a directory whose content is copied into this one
+    /// when it is created, if any
+    pub fn source(&self) -> Option<&Expression<'t>> {
+        self.source.as_ref()
+    }
+
+    /// Sets the pattern (a path whose last component may contain `*` and `?` wildcards) of the
+    /// files from which to create a file entry of the same name in this directory
+    pub fn with_source_glob(mut self, source_glob: Option<Expression<'t>>) -> Self {
+        self.source_glob = source_glob;
+        self
+    }
+
+    /// Returns the expression of the pattern of files from which to create file entries in this
+    /// directory, if any
+    pub fn source_glob(&self) -> Option<&Expression<'t>> {
+        self.source_glob.as_ref()
+    }
+
+    /// Merges the top-level definitions and variables of an included schema into this directory
+    fn merge(&mut self, included: &SchemaNode<'t>) -> anyhow::Result<()> {
+        let directory = match &included.schema {
+            SchemaType::Directory(directory)
+                if directory.entries.is_empty()
+                    && included.uses.is_empty()
+                    && included.attributes.is_empty() =>
+            {
+                directory
+            }
+            _ => {
+                bail!("An included file may only contain :def, :let and :include at its top level")
+            }
+        };
+        // The same file may be reached by more than one chain of includes (as when two included
+        // files each include a third), so a variable or definition identical to one present
+        // already is accepted
+        for (id, expr) in &directory.vars {
+            match self.vars.get(id) {
+                Some(existing) if existing != expr => bail!(":let {} occurs twice", id),
+                Some(_) => {}
+                None => {
+                    self.vars.insert(*id, expr.clone());
+                }
+            }
         }
+        for (id, def) in &directory.defs {
+            match self.defs.get(id) {
+                Some(existing) if existing != def => bail!(":def {} occurs twice", id),
+                Some(_) => {}
+                None => {
+                    self.defs.insert(*id, def.clone());
+                }
+            }
+        }
+        Ok(())
     }
+
     /// Provides access to the variables defined in this node
     pub fn vars(&self) -> &HashMap<Identifier<'t>, Expression<'t>> {
         &self.vars
@@ -329,7 +837,7 @@ impl<'t> DirectorySchema<'t> {
     }
 
     /// Provides access to the sub-schema definitions defined in this node
-    pub fn defs(&self) -> &HashMap<Identifier, SchemaNode> {
+    pub fn defs(&self) -> &HashMap<Identifier<'t>, SchemaNode<'t>> {
         &self.defs
     }
     /// Returns the sub-schema associated with the given definition, if any was set in the schema
@@ -345,6 +853,7 @@ impl<'t> DirectorySchema<'t> {
 
 /// How an entry is bound in a schema, either to a static fixed name or to a variable
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Binding<'t> {
     /// A static, fixed name
     Static(&'t str), // Static is ordered first
@@ -363,20 +872,52 @@ impl Display for Binding<'_> {
 
 /// A description of a file
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct FileSchema<'t> {
-    /// Path to the resource to be copied as file content
+    /// Paths to the resource to be copied as file content, the first of which to exist being used
     // TODO: Make source enum: Enforce(...), Default(...) latter only creates if missing
-    source: Expression<'t>,
+    sources: Vec<Expression<'t>>,
+    /// The expected SHA-256 digest of the file's content, as hexadecimal, if declared
+    sha256: Option<&'t str>,
 }
 
 impl<'t> FileSchema<'t> {
     /// Constructs a new description of a file
     pub fn new(source: Expression<'t>) -> Self {
-        FileSchema { source }
+        FileSchema {
+            sources: vec![source],
+            sha256: None,
+        }
     }
-    /// Returns the expression of the path from where the file will inherit its content
+
+    /// Adds further candidate paths from which to copy the file's content, to be tried in order
+    /// should those before them not exist
+    pub fn with_fallbacks(mut self, fallbacks: Vec<Expression<'t>>) -> Self {
+        self.sources.extend(fallbacks);
+        self
+    }
+
+    /// Declares the expected SHA-256 digest (as hexadecimal) of the file's content
+    pub fn with_sha256(mut self, sha256: Option<&'t str>) -> Self {
+        self.sha256 = sha256;
+        self
+    }
+
+    /// Returns the expression of the path from where the file will inherit its content (the first
+    /// candidate, if there are several)
     pub fn source(&self) -> &Expression<'t> {
-        &self.source
+        &self.sources[0]
+    }
+
+    /// Returns the expressions of every candidate path from where the file may inherit its
+    /// content, in the order they are tried
+    pub fn sources(&self) -> &[Expression<'t>] {
+        &self.sources
+    }
+
+    /// Returns the expected SHA-256 digest (as hexadecimal) of the file's content, if declared
+    pub fn sha256(&self) -> Option<&'t str> {
+        self.sha256
     }
 }
 
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 
 use super::{
-    parse_schema, Attributes, Binding, DirectorySchema, Identifier, SchemaNode, SchemaType,
+    format_schema, parse_schema, Anchoring, AttributeValue, Attributes, Binding, DirectorySchema,
+    Identifier, SchemaNode, SchemaType, Step, Visitor,
 };
 
 #[test]
@@ -33,7 +34,7 @@ fn def_and_use_compare_equal() {
     let sub = &root_directory.entries[0].1;
     assert_eq!(sub.uses.len(), 1);
     let mut defs = root_directory.defs().keys();
-    assert_eq!(defs.next(), Some(&sub.uses[0]));
+    assert_eq!(defs.next(), Some(&sub.uses[0].name));
     assert_eq!(defs.next(), None);
     assert!(root_directory.get_def(&"empty".into()).is_some());
     assert!(root_directory.get_def(&"none".into()).is_none());
@@ -44,12 +45,23 @@ fn directory_binding_sort_order() {
     let empty_subdirectory = SchemaType::Directory(DirectorySchema::default());
     let empty_directory_node = SchemaNode {
         line: "N/A",
+        line_number: 0,
         schema: empty_subdirectory,
         match_pattern: None,
-        avoid_pattern: None,
+        match_anchoring: Default::default(),
+        avoid_patterns: vec![],
+        avoid_hidden: false,
+        match_path: false,
         attributes: Attributes::default(),
         symlink: None,
+        link_owner: None,
+        link_group: None,
+        rename_from: None,
+        when: None,
+        description: None,
+        params: vec![],
         uses: vec![],
+        version: None,
     };
 
     // Variable then static should re-order (so static is first)
@@ -78,3 +90,464 @@ fn directory_binding_sort_order() {
     assert!(matches!(entries[0].0, Binding::Static(_)));
     assert!(matches!(entries[1].0, Binding::Dynamic(_)));
 }
+
+#[test]
+fn multiple_avoids_are_recorded() {
+    let root = parse_schema("$var/\n    :avoid \\..*\n    :avoid .*~").unwrap();
+    let root_directory = root.schema.as_directory().unwrap();
+    let var = &root_directory.entries[0].1;
+    assert_eq!(var.avoid_patterns.len(), 2);
+    assert_eq!(var.avoid_patterns[0], "\\..*");
+    assert_eq!(var.avoid_patterns[1], ".*~");
+}
+
+#[test]
+fn line_numbers_are_recorded() {
+    let root = parse_schema("\n# Comment\nfirst/\n\n    inner/\nsecond/").unwrap();
+    assert_eq!(root.line_number, 0);
+    let root_directory = root.schema.as_directory().unwrap();
+    let first = &root_directory.entries[0].1;
+    assert_eq!((first.line, first.line_number), ("first/", 3));
+    let inner = &first.schema.as_directory().unwrap().entries[0].1;
+    assert_eq!((inner.line, inner.line_number), ("inner/", 5));
+    let second = &root_directory.entries[1].1;
+    assert_eq!((second.line, second.line_number), ("second/", 6));
+}
+
+#[test]
+fn def_params_and_use_args_are_recorded() {
+    let root = parse_schema(
+        "
+        :def project(owner, quota_dir)/
+            :owner $owner
+        here/
+            :use project(alice, ${area}_scratch)
+        ",
+    )
+    .unwrap();
+    let root_directory = root.schema.as_directory().unwrap();
+    let project = root_directory.get_def(&Identifier::new("project")).unwrap();
+    assert_eq!(
+        project.params,
+        vec![Identifier::new("owner"), Identifier::new("quota_dir")]
+    );
+    let here = &root_directory.entries[0].1;
+    assert_eq!(here.uses.len(), 1);
+    assert_eq!(here.uses[0].name, Identifier::new("project"));
+    assert_eq!(here.uses[0].args.len(), 2);
+    assert_eq!(here.uses[0].args[0], "alice");
+    assert_eq!(here.uses[0].args[1].to_string(), "${area}_scratch");
+}
+
+#[test]
+fn use_args_with_escaped_dollar() {
+    let text = "here/\n    :use tmpl(a$$b, $$${var})\n";
+    let root = parse_schema(text).unwrap();
+    let here = &root.schema.as_directory().unwrap().entries[0].1;
+    assert_eq!(here.uses[0].args.len(), 2);
+    assert_eq!(here.uses[0].args[0].to_string(), "a$$b");
+    assert_eq!(here.uses[0].args[1].to_string(), "$$${var}");
+    assert_eq!(super::format_schema(&root), text);
+}
+
+#[test]
+fn use_with_empty_args() {
+    let root = parse_schema(
+        "
+        :def plain()/
+            inner/
+        here/
+            :use plain()
+        there/
+            :use plain( )
+        ",
+    )
+    .unwrap();
+    let root_directory = root.schema.as_directory().unwrap();
+    let plain = root_directory.get_def(&Identifier::new("plain")).unwrap();
+    assert!(plain.params.is_empty());
+    for (_, node) in &root_directory.entries {
+        assert_eq!(node.uses.len(), 1);
+        assert_eq!(node.uses[0].name, Identifier::new("plain"));
+        assert!(node.uses[0].args.is_empty());
+    }
+}
+
+#[test]
+fn def_params_must_be_unique() {
+    assert!(parse_schema(":def project(owner, owner)/").is_err());
+}
+
+#[test]
+fn inherit_is_distinct_from_unset_and_explicit() {
+    let root = parse_schema(
+        "
+        a/
+            :owner inherit
+            :mode inherit
+        b/
+            :owner inheritor
+            :mode 700
+        c/
+            :owner -
+            :group -
+            :mode -
+        d/
+            :owner -x
+        ",
+    )
+    .unwrap();
+    let entries = root.schema.as_directory().unwrap().entries();
+    let a = &entries[0].1.attributes;
+    assert_eq!(a.owner, Some(AttributeValue::Inherit));
+    assert_eq!(a.group, None);
+    assert_eq!(a.mode, Some(AttributeValue::Inherit));
+    let b = &entries[1].1.attributes;
+    assert_eq!(b.owner.clone().unwrap().explicit().unwrap(), &"inheritor");
+    assert_eq!(b.mode, Some(AttributeValue::Explicit(0o700)));
+    let c = &entries[2].1.attributes;
+    assert_eq!(c.owner, Some(AttributeValue::Inherit));
+    assert_eq!(c.group, Some(AttributeValue::Inherit));
+    assert_eq!(c.mode, Some(AttributeValue::Inherit));
+    let d = &entries[3].1.attributes;
+    assert_eq!(d.owner.clone().unwrap().explicit().unwrap(), &"-x");
+}
+
+#[test]
+fn mode_is_limited_to_permission_bits() {
+    let mode = |text: &str| {
+        let root = parse_schema(text).unwrap();
+        root.schema.as_directory().unwrap().entries()[0]
+            .1
+            .attributes
+            .mode
+    };
+    assert_eq!(mode("dir/\n    :mode 0"), Some(AttributeValue::Explicit(0)));
+    assert_eq!(
+        mode("dir/\n    :mode 7777"),
+        Some(AttributeValue::Explicit(0o7777))
+    );
+
+    for over in ["10000", "77777", "7777777777777777777777"] {
+        let text = format!("dir/\n    :mode {over}");
+        let error = parse_schema(&text).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains(&format!(":mode must be at most 7777 (octal): {over}")));
+    }
+    assert!(parse_schema("dir/\n    :mode").is_err());
+    assert!(parse_schema("dir/\n    :mode 8").is_err());
+}
+
+#[test]
+fn link_owner_and_group_are_recorded() {
+    let root =
+        parse_schema("link/ -> /target\n    :link-owner $user\n    :link-group staff").unwrap();
+    let entries = root.schema.as_directory().unwrap().entries();
+    let node = &entries[0].1;
+    assert_eq!(node.link_owner.as_ref().unwrap().to_string(), "${user}");
+    assert_eq!(node.link_group.as_ref().unwrap().to_string(), "staff");
+
+    assert!(parse_schema("link/ -> /target\n    :link-owner a\n    :link-owner b").is_err());
+    assert!(parse_schema("dir/\n    :link-owner root").is_err());
+    assert!(parse_schema("dir/\n    :link-group root").is_err());
+}
+
+#[test]
+fn mode_initial_is_recorded() {
+    let root = parse_schema("dir/\n    :mode-initial 700").unwrap();
+    let attributes = &root.schema.as_directory().unwrap().entries()[0]
+        .1
+        .attributes;
+    assert_eq!(attributes.mode, Some(AttributeValue::Explicit(0o700)));
+    assert!(attributes.mode_initial);
+    assert!(format_schema(&root).contains(":mode-initial 0700\n"));
+
+    assert!(parse_schema("dir/\n    :mode 700\n    :mode-initial 700").is_err());
+    assert!(parse_schema("dir/\n    :mode-initial 700\n    :mode 700").is_err());
+    assert!(parse_schema("dir/\n    :mode-initial 700\n    :mode-initial 700").is_err());
+    assert!(parse_schema("dir/\n    :mode-initial inherit").is_err());
+    assert!(parse_schema("dir/\n    :mode-initial 17777").is_err());
+}
+
+#[test]
+fn attributes_display() {
+    let root = parse_schema(
+        "a/\n    :owner $user\n    :group inherit\n    :mode 750\nb/\n    :mode-initial 4755\nc/\n",
+    )
+    .unwrap();
+    let attributes: Vec<_> = root
+        .schema
+        .as_directory()
+        .unwrap()
+        .entries()
+        .iter()
+        .map(|(_, node)| node.attributes.to_string())
+        .collect();
+    assert_eq!(
+        attributes,
+        [
+            "owner=${user} group=inherit mode=0750",
+            "mode-initial=4755",
+            "(none)"
+        ]
+    );
+    assert_eq!(format!("{:?}", Attributes::default()), "Attributes((none))");
+}
+
+#[test]
+fn rename_from_is_recorded() {
+    let root = parse_schema("new/\n    :rename-from old_$x").unwrap();
+    let entries = root.schema.as_directory().unwrap().entries();
+    assert_eq!(
+        entries[0].1.rename_from.as_ref().unwrap().to_string(),
+        "old_${x}"
+    );
+
+    assert!(parse_schema("new/\n    :rename-from a\n    :rename-from b").is_err());
+    assert!(parse_schema("link/ -> /target\n    :rename-from old").is_err());
+}
+
+#[test]
+fn ignore_case_is_recorded() {
+    let root = parse_schema("zones/\n    :ignore-case\nother/").unwrap();
+    let root_directory = root.schema.as_directory().unwrap();
+    assert!(!root_directory.ignore_case());
+    let zones = root_directory.entries()[1].1.schema.as_directory().unwrap();
+    assert!(zones.ignore_case());
+    let other = root_directory.entries()[0].1.schema.as_directory().unwrap();
+    assert!(!other.ignore_case());
+
+    assert!(parse_schema("file\n    :source x\n    :ignore-case").is_err());
+}
+
+#[test]
+fn recursive_is_recorded() {
+    let root = parse_schema("private/\n    :mode 700\n    :recursive").unwrap();
+    let root_directory = root.schema.as_directory().unwrap();
+    assert!(!root_directory.recursive());
+    let private = root_directory.entries()[0].1.schema.as_directory().unwrap();
+    assert!(private.recursive());
+
+    assert!(parse_schema("dir/\n    :recursive\n    :recursive").is_err());
+    assert!(parse_schema("file\n    :source x\n    :recursive").is_err());
+}
+
+#[test]
+fn includes_are_recorded_and_resolved() {
+    let library = parse_schema(":let area = scratch\n:def shared/\n    inner/").unwrap();
+    let mut root = parse_schema(
+        "
+        :include lib/common.diskplan  # shared definitions
+        project/
+            :include /etc/diskplan/other.diskplan
+        ",
+    )
+    .unwrap();
+    let root_directory = root.schema.as_directory().unwrap();
+    assert_eq!(root_directory.includes()[0].path, "lib/common.diskplan");
+    assert_eq!(root_directory.includes()[0].line_number, 2);
+    let project = root_directory.entries()[0].1.schema.as_directory().unwrap();
+    assert_eq!(project.includes()[0].path, "/etc/diskplan/other.diskplan");
+
+    let mut loaded = Vec::new();
+    root.resolve_includes(&mut |include| {
+        loaded.push(include.path);
+        Ok(&library)
+    })
+    .unwrap();
+    assert_eq!(
+        loaded,
+        vec!["lib/common.diskplan", "/etc/diskplan/other.diskplan"]
+    );
+    let root_directory = root.schema.as_directory().unwrap();
+    assert!(root_directory.get_def(&Identifier::new("shared")).is_some());
+    assert!(root_directory.get_var(&Identifier::new("area")).is_some());
+    assert!(root_directory.includes().is_empty());
+
+    // Included files may not bring entries, and may not redefine what is already in scope
+    let entries = parse_schema("dir/").unwrap();
+    let mut root = parse_schema(":include x").unwrap();
+    assert!(root.resolve_includes(&mut |_| Ok(&entries)).is_err());
+    let mut root = parse_schema(":include x\n:def shared/").unwrap();
+    assert!(root.resolve_includes(&mut |_| Ok(&library)).is_err());
+
+    assert!(parse_schema(":include x\n:include x").is_err());
+    assert!(parse_schema("file\n    :source x\n    :include y").is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn schema_serializes_to_json() {
+    let json = super::parse_schema_to_json(
+        "
+        :let b = two
+        :let a = one
+        $x/
+            :mode 750
+        file
+            :source ${a}.txt
+        ",
+    )
+    .unwrap();
+    assert!(json.find("\"a\"").unwrap() < json.find("\"b\"").unwrap());
+    assert!(json.contains("\"Explicit\": 488"));
+    assert!(json.contains("\"Dynamic\": \"x\""));
+    assert!(json.contains("\"Variable\": \"a\""));
+    let json = super::parse_schema_to_json("dir/\n    :description Shared space\n").unwrap();
+    assert!(json.contains("\"description\": \"Shared space\""));
+    assert!(super::parse_schema_to_json("file\n    :ignore-case").is_err());
+}
+
+#[test]
+fn format_schema_is_canonical() {
+    let text = "
+        # Comments and blank lines are dropped
+
+        $user/
+            :use home(${user})
+            :avoid \\..*
+            :match [a-z]+
+            :mode 750
+        :let b = two
+        :def home(owner)/
+            :owner $owner
+            :group inherit
+            :recursive
+            latest/ -> ${ROOT_PATH}/archive
+        :let a = price_$$5
+        :include lib/common.diskplan
+        archive/
+            :source /templates/archive
+            :ignore-case
+        checked
+            :sha256 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+            :source /data/${b}.txt
+        ";
+    let root = parse_schema(text).unwrap();
+    let formatted = super::format_schema(&root);
+    assert_eq!(
+        formatted,
+        ":include lib/common.diskplan
+:let a = price_$$5
+:let b = two
+:def home(owner)/
+    :owner ${owner}
+    :group inherit
+    :recursive
+    latest/ -> ${ROOT_PATH}/archive
+archive/
+    :source /templates/archive
+    :ignore-case
+checked
+    :source /data/${b}.txt
+    :sha256 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+$user/
+    :match [a-z]+
+    :avoid \\..*
+    :mode 0750
+    :use home(${user})
+"
+    );
+    // Formatting is stable once canonical
+    let reparsed = parse_schema(&formatted).unwrap();
+    assert_eq!(super::format_schema(&reparsed), formatted);
+}
+
+#[test]
+fn when_is_recorded() {
+    let root = parse_schema("dir/\n    :when ${env}_x != $$prod # comment").unwrap();
+    let dir = &root.schema.as_directory().unwrap().entries()[0].1;
+    let condition = dir.when.as_ref().unwrap();
+    assert_eq!(condition.comparison, super::Comparison::NotEqual);
+    assert_eq!(condition.to_string(), "${env}_x != $$prod");
+    assert!(condition.holds("a", "b"));
+
+    assert!(parse_schema("dir/\n    :when $a == b\n    :when $a == c").is_err());
+    assert!(parse_schema("dir/\n    :when $a==b").is_err());
+    assert!(parse_schema("dir/\n    :when $a = b").is_err());
+}
+
+#[test]
+fn avoid_hidden_is_recorded() {
+    let root = parse_schema("$var/\n    :avoid-hidden\n    :avoid x").unwrap();
+    let node = &root.schema.as_directory().unwrap().entries()[0].1;
+    assert!(node.avoid_hidden);
+    assert_eq!(node.avoid_patterns.len(), 1);
+    assert!(!root.avoid_hidden);
+
+    assert!(parse_schema("$var/\n    :avoid-hidden\n    :avoid-hidden").is_err());
+    assert!(parse_schema(":def d/\n    :avoid-hidden").is_err());
+}
+
+#[test]
+fn match_anchoring_is_recorded() {
+    let root =
+        parse_schema("$a/\n    :match x\n$b/\n    :match-prefix y\n$c/\n    :match-contains z")
+            .unwrap();
+    let entries = root.schema.as_directory().unwrap().entries();
+    let anchoring: Vec<_> = entries.iter().map(|(_, n)| n.match_anchoring).collect();
+    assert_eq!(
+        anchoring,
+        [Anchoring::Full, Anchoring::Prefix, Anchoring::Contains]
+    );
+    assert_eq!(
+        format_schema(&root),
+        "$a/\n    :match x\n$b/\n    :match-prefix y\n$c/\n    :match-contains z\n"
+    );
+
+    assert!(parse_schema("$a/\n    :match x\n    :match-prefix y").is_err());
+    assert!(parse_schema(":def d/\n    :match-contains z").is_err());
+}
+
+#[test]
+fn match_path_is_recorded() {
+    let root = parse_schema("$var/\n    :match-path\n    :match a/.*").unwrap();
+    let node = &root.schema.as_directory().unwrap().entries()[0].1;
+    assert!(node.match_path);
+    assert!(node.match_pattern.is_some());
+    assert!(!root.match_path);
+
+    assert!(parse_schema("$var/\n    :match-path\n    :match-path").is_err());
+    assert!(parse_schema(":def d/\n    :match-path").is_err());
+}
+
+#[test]
+fn visit_reports_paths() {
+    struct Paths(Vec<String>);
+    impl<'t> Visitor<'t> for Paths {
+        fn node(&mut self, path: &[Step<'t>], node: &SchemaNode<'t>) {
+            let path: Vec<_> = path.iter().map(ToString::to_string).collect();
+            self.0
+                .push(format!("{} ({})", path.join("/"), node.line_number));
+        }
+        fn def(&mut self, path: &[Step<'t>], name: Identifier<'t>, _: &SchemaNode<'t>) {
+            self.0.push(format!("def {name} in {}", path.len()));
+        }
+    }
+
+    let root = parse_schema(
+        "
+        :def shared/
+            inner/
+        b/
+            $var/
+        a/
+        ",
+    )
+    .unwrap();
+    let mut paths = Paths(vec![]);
+    super::visit(&root, &mut paths);
+    assert_eq!(
+        paths.0,
+        [
+            " (0)",
+            "def shared in 0",
+            ":def shared (2)",
+            ":def shared/inner (3)",
+            "a (6)",
+            "b (4)",
+            "b/$var (5)",
+        ]
+    );
+}
@@ -1,17 +1,20 @@
 use nom::{
     branch::alt,
     bytes::complete::{is_a, is_not, tag},
-    character::complete::{alpha1, alphanumeric1, char, line_ending, space0, space1},
-    combinator::{all_consuming, consumed, eof, map, opt, recognize, value},
+    character::complete::{alpha1, alphanumeric1, char, line_ending, one_of, space0, space1},
+    combinator::{all_consuming, consumed, eof, map, not, opt, peek, recognize, value},
     error::{context, VerboseError, VerboseErrorKind},
-    multi::{count, many0, many1},
+    multi::{count, many0, many1, separated_list0, separated_list1},
     sequence::{delimited, pair, preceded, terminated, tuple},
     IResult, Parser,
 };
 use tracing::{span, Level};
 
-use super::{Binding, SchemaNode};
-use crate::{Expression, Identifier, Special, Token};
+use super::{Binding, SchemaNode, SchemaType};
+use crate::{
+    Anchoring, AttributeValue, Comparison, Condition, Expression, Identifier, Include, Special,
+    Token, Usage,
+};
 
 type Res<T, U> = IResult<T, U, VerboseError<T>>;
 
@@ -19,19 +22,38 @@ mod builder;
 use builder::SchemaNodeBuilder;
 
 mod error;
+pub(crate) use error::write_snippet;
 pub use error::ParseError;
 
+mod format;
+pub use format::format_schema;
+
 #[derive(Debug)]
 pub enum NodeType {
     Directory,
     File,
 }
 
+/// Parses the given text representation into a tree of [`SchemaNode`]s, naming the file it was
+/// read from (`path`) in any error
+pub fn parse_schema_with_path(
+    text: &str,
+    path: impl AsRef<str>,
+) -> std::result::Result<SchemaNode<'_>, ParseError<'_>> {
+    parse_schema(text).map_err(|e| e.with_path(path))
+}
+
+/// The latest version of the schema format, the highest a schema may declare (by beginning with
+/// a line such as `#!diskplan 1`) to be parsed
+pub const SCHEMA_VERSION: u32 = 1;
+
 /// Parses the given text representation into a tree of [`SchemaNode`]s
-pub fn parse_schema(text: &str) -> std::result::Result<SchemaNode, ParseError> {
+pub fn parse_schema(text: &str) -> std::result::Result<SchemaNode<'_>, ParseError<'_>> {
     let span = span!(Level::INFO, "parse_schema");
     let _enter = span.enter();
 
+    let (body, version) = version_directive(text)?;
+
     // Strip several levels of initial indentation to help with indented literal schemas
     let any_indent = |s| {
         opt(alt((
@@ -43,7 +65,7 @@ pub fn parse_schema(text: &str) -> std::result::Result<SchemaNode, ParseError> {
         )))(s)
     };
     // Parse and process entire schema and handle any errors that arise
-    let (_, ops) = all_consuming(preceded(many0(blank_line), any_indent))(text).map_err(|e| {
+    let (_, ops) = all_consuming(preceded(many0(blank_line), any_indent))(body).map_err(|e| {
         let e = match e {
             nom::Err::Error(e) | nom::Err::Failure(e) => e,
             nom::Err::Incomplete(_) => unreachable!(),
@@ -65,7 +87,9 @@ pub fn parse_schema(text: &str) -> std::result::Result<SchemaNode, ParseError> {
         error.unwrap()
     })?;
     let ops = ops.unwrap_or_default();
-    let schema_node = schema_node("root", text, text, false, NodeType::Directory, None, ops)?;
+    let mut schema_node = schema_node("root", text, text, None, NodeType::Directory, None, ops)?;
+    attach_text(&mut schema_node, text);
+    schema_node.version = version;
     if schema_node.match_pattern.is_some() {
         return Err(ParseError::new(
             "Top level :match is not allowed".into(),
@@ -80,11 +104,40 @@ pub fn parse_schema(text: &str) -> std::result::Result<SchemaNode, ParseError> {
     Ok(schema_node)
 }
 
+/// Splits any `#!diskplan <version>` line (after blank lines and indentation) from the start of
+/// the text, returning the text that follows it and the version declared, if any
+fn version_directive(text: &str) -> std::result::Result<(&str, Option<u32>), ParseError<'_>> {
+    let start = &text[text.len() - text.trim_start().len()..];
+    if !start.starts_with("#!") {
+        return Ok((text, None));
+    }
+    let end = start.find('\n').map_or(start.len(), |pos| pos + 1);
+    let line = start[..end].trim_end();
+    let error = |message: String| ParseError::new(message, text, line, None);
+    let mut words = line[2..].split_whitespace();
+    let version = match (words.next(), words.next(), words.next()) {
+        (Some("diskplan"), Some(version), None) => version
+            .parse::<u32>()
+            .map_err(|_| error(format!("Invalid schema version: {version}")))?,
+        _ => {
+            return Err(error(
+                "Expected a version directive of the form \"#!diskplan <version>\"".into(),
+            ))
+        }
+    };
+    if !(1..=SCHEMA_VERSION).contains(&version) {
+        return Err(error(format!(
+            "Unsupported schema version {version} (this parser supports versions 1 to {SCHEMA_VERSION})"
+        )));
+    }
+    Ok((&start[end..], Some(version)))
+}
+
 fn schema_node<'t>(
     line: &'t str,
     whole: &'t str,
     part: &'t str,
-    is_def: bool,
+    def_params: Option<Vec<Identifier<'t>>>,
     item_type: NodeType,
     symlink: Option<Expression<'t>>,
     ops: Vec<(&'t str, Operator<'t>)>,
@@ -92,7 +145,8 @@ fn schema_node<'t>(
     let part_parse_error = |e: anyhow::Error| ParseError::new(e.to_string(), whole, part, None);
     let mut builder = SchemaNodeBuilder::new(
         line,
-        is_def,
+        line_number(whole, line),
+        def_params,
         match item_type {
             NodeType::Directory => NodeType::Directory,
             NodeType::File => NodeType::File,
@@ -102,19 +156,41 @@ fn schema_node<'t>(
     for (span, op) in ops {
         match op {
             // Operators that affect the parent (when looking up this item)
-            Operator::Match(expr) => builder.match_pattern(expr),
+            Operator::Match(expr) => builder.match_pattern(expr, Anchoring::Full),
+            Operator::MatchPrefix(expr) => builder.match_pattern(expr, Anchoring::Prefix),
+            Operator::MatchContains(expr) => builder.match_pattern(expr, Anchoring::Contains),
             Operator::Avoid(expr) => builder.avoid_pattern(expr),
+            Operator::AvoidHidden => builder.avoid_hidden(),
+            Operator::MatchPath => builder.match_path(),
 
             // Operators that apply to this item
-            Operator::Use { name } => builder.use_definition(name),
+            Operator::Use { name, args } => builder.use_definition(Usage { name, args }),
             Operator::Mode(mode) => builder.mode(mode),
+            Operator::ModeInitial(mode) => builder.mode_initial(mode),
             Operator::Owner(owner) => builder.owner(owner),
             Operator::Group(group) => builder.group(group),
             Operator::Source(source) => builder.source(source),
+            Operator::SourceGlob(pattern) => builder.source_glob(pattern),
             Operator::Target(target) => builder.target(target),
+            Operator::LinkOwner(owner) => builder.link_owner(owner),
+            Operator::LinkGroup(group) => builder.link_group(group),
+            Operator::RenameFrom(old) => builder.rename_from(old),
+            Operator::When(condition) => builder.when(condition),
+            Operator::Description(text) => builder.description(text),
+            Operator::Sha256(digest) => builder.sha256(digest),
+
+            // Operators that affect how child items are matched
+            Operator::IgnoreCase => builder.ignore_case(),
+
+            // Operators that affect child items' defaults
+            Operator::Recursive => builder.recursive(),
 
             // Operators that apply to child items
             Operator::Let { name, expr } => builder.let_var(name, expr),
+            Operator::Include(path) => builder.include(Include {
+                path,
+                line_number: line_number(whole, span),
+            }),
             Operator::Item {
                 line,
                 binding,
@@ -122,26 +198,29 @@ fn schema_node<'t>(
                 link,
                 children,
             } => {
+                if let Binding::Static(name) = binding {
+                    check_static_name(name)
+                        .map_err(|e| ParseError::new(e.to_string(), whole, name, None))?;
+                }
                 let sub_item_type = match is_directory {
                     false => NodeType::File,
                     true => NodeType::Directory,
                 };
-                let item_node =
-                    schema_node(line, whole, span, false, sub_item_type, link, children).map_err(
-                        |e| {
-                            ParseError::new(
-                                format!(r#"Problem within "{binding}""#),
-                                whole,
-                                span,
-                                Some(Box::new(e)),
-                            )
-                        },
-                    )?;
+                let item_node = schema_node(line, whole, span, None, sub_item_type, link, children)
+                    .map_err(|e| {
+                        ParseError::new(
+                            format!(r#"Problem within "{binding}""#),
+                            whole,
+                            span,
+                            Some(Box::new(e)),
+                        )
+                    })?;
                 builder.add_entry(binding, item_node)
             }
             Operator::Def {
                 line,
                 name,
+                params,
                 is_directory,
                 link,
                 children,
@@ -158,17 +237,23 @@ fn schema_node<'t>(
                     false => NodeType::File,
                     true => NodeType::Directory,
                 };
-                let properties =
-                    schema_node(line, whole, span, true, sub_item_type, link, children).map_err(
-                        |e| {
-                            ParseError::new(
-                                format!(r#"Error within definition "{name}""#),
-                                whole,
-                                span,
-                                Some(Box::new(e)),
-                            )
-                        },
-                    )?;
+                let properties = schema_node(
+                    line,
+                    whole,
+                    span,
+                    Some(params),
+                    sub_item_type,
+                    link,
+                    children,
+                )
+                .map_err(|e| {
+                    ParseError::new(
+                        format!(r#"Error within definition "{name}""#),
+                        whole,
+                        span,
+                        Some(Box::new(e)),
+                    )
+                })?;
 
                 if properties.match_pattern.is_some() {
                     return Err(ParseError::new(
@@ -187,6 +272,73 @@ fn schema_node<'t>(
     builder.build().map_err(part_parse_error)
 }
 
+/// Notes the schema text on every expression in the tree, so errors evaluating them later can
+/// point to where they were written
+fn attach_text<'t>(node: &mut SchemaNode<'t>, text: &'t str) {
+    let attach = |expr: &mut Expression<'t>| *expr = std::mem::take(expr).with_text(text);
+    node.match_pattern.iter_mut().for_each(attach);
+    node.avoid_patterns.iter_mut().for_each(attach);
+    node.symlink.iter_mut().for_each(attach);
+    node.link_owner.iter_mut().for_each(attach);
+    node.link_group.iter_mut().for_each(attach);
+    node.rename_from.iter_mut().for_each(attach);
+    for condition in node.when.iter_mut() {
+        attach(&mut condition.left);
+        attach(&mut condition.right);
+    }
+    for usage in node.uses.iter_mut() {
+        usage.args.iter_mut().for_each(attach);
+    }
+    for attr in [&mut node.attributes.owner, &mut node.attributes.group] {
+        if let Some(AttributeValue::Explicit(expr)) = attr {
+            attach(expr);
+        }
+    }
+    match &mut node.schema {
+        SchemaType::Directory(directory) => {
+            directory.vars.values_mut().for_each(attach);
+            directory.source_glob.iter_mut().for_each(attach);
+            for def in directory.defs.values_mut() {
+                attach_text(def, text);
+            }
+            for (_, entry) in directory.entries.iter_mut() {
+                attach_text(entry, text);
+            }
+        }
+        SchemaType::File(file) => file.sources.iter_mut().for_each(attach),
+    }
+}
+
+/// Ensures a static name can be safely joined onto a path as a single component
+///
+/// Dynamic names have no statically known text (the binding is a lone variable) and so are
+/// checked when joined during traversal.
+fn check_static_name(name: &str) -> anyhow::Result<()> {
+    if name == "." || name == ".." {
+        anyhow::bail!(
+            r#"Invalid name "{}" (refers to a directory, not an entry in it)"#,
+            name
+        );
+    }
+    if name.contains('/') || name.contains('\0') {
+        anyhow::bail!(
+            r#"Invalid name "{}" (must not contain a path separator or NUL)"#,
+            name.escape_default()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the (1-based) line number of `line` within `whole`, or zero if it is not a part of it
+fn line_number(whole: &str, line: &str) -> usize {
+    let start = whole.as_ptr() as usize;
+    let pos = line.as_ptr() as usize;
+    if pos < start || pos > start + whole.len() {
+        return 0;
+    }
+    whole[..pos - start].chars().filter(|&c| c == '\n').count() + 1
+}
+
 fn indentation(level: usize) -> impl Fn(&str) -> Res<&str, &str> {
     move |s: &str| recognize(count(tag("    "), level))(s)
 }
@@ -204,28 +356,60 @@ fn operator(level: usize) -> impl Fn(&str) -> Res<&str, (&str, Operator)> {
         let sep = |ch, second| preceded(delimited(space0, char(ch), space0), second);
 
         let let_op = tuple((op("let", identifier), sep('=', expression)));
-        let use_op = op("use", identifier);
+        let use_op = op("use", tuple((identifier, opt(arguments))));
         let match_op = op("match", expression);
+        let match_prefix_op = op("match-prefix", expression);
+        let match_contains_op = op("match-contains", expression);
         let avoid_op = op("avoid", expression);
-        let mode_op = op("mode", octal);
-        let owner_op = op("owner", expression);
-        let group_op = op("group", expression);
-        let source_op = op("source", expression);
+        let mode_op = op("mode", inheritable(octal));
+        let mode_initial_op = op("mode-initial", octal);
+        let owner_op = op("owner", inheritable(expression));
+        let group_op = op("group", inheritable(expression));
+        let source_op = op("source", source_candidates);
+        let source_glob_op = op("source-glob", expression);
         let target_op = op("target", expression);
+        let link_owner_op = op("link-owner", expression);
+        let link_group_op = op("link-group", expression);
+        let rename_from_op = op("rename-from", expression);
+        let when_op = op("when", condition);
+        let description_op = op("description", is_not("\r\n"));
+        let include_op = op("include", is_not(" \t\r\n"));
+        let sha256_op = op("sha256", is_not(" \t\r\n"));
 
         consumed(alt((
             delimited(
                 tuple((indentation(level), char(':'))),
                 alt((
                     map(let_op, |(name, expr)| Operator::Let { name, expr }),
-                    map(use_op, |name| Operator::Use { name }),
+                    map(use_op, |(name, args)| Operator::Use {
+                        name,
+                        args: args.unwrap_or_default(),
+                    }),
                     map(match_op, Operator::Match),
+                    map(match_prefix_op, Operator::MatchPrefix),
+                    map(match_contains_op, Operator::MatchContains),
                     map(avoid_op, Operator::Avoid),
                     map(mode_op, Operator::Mode),
+                    map(mode_initial_op, Operator::ModeInitial),
                     map(owner_op, Operator::Owner),
                     map(group_op, Operator::Group),
                     map(source_op, Operator::Source),
+                    map(source_glob_op, Operator::SourceGlob),
                     map(target_op, Operator::Target),
+                    map(link_owner_op, Operator::LinkOwner),
+                    map(link_group_op, Operator::LinkGroup),
+                    map(rename_from_op, Operator::RenameFrom),
+                    map(when_op, Operator::When),
+                    map(description_op, Operator::Description),
+                    map(include_op, Operator::Include),
+                    map(sha256_op, Operator::Sha256),
+                    // Tags without values
+                    alt((
+                        value(Operator::IgnoreCase, tag("ignore-case")),
+                        value(Operator::Recursive, tag("recursive")),
+                        value(Operator::AvoidHidden, tag("avoid-hidden")),
+                        value(Operator::MatchPath, tag("match-path")),
+                    )),
                 )),
                 end_of_lines,
             ),
@@ -249,9 +433,10 @@ fn operator(level: usize) -> impl Fn(&str) -> Res<&str, (&str, Operator)> {
                     delimited(indentation(level), consumed(def_header), end_of_lines),
                     many0(operator(level + 1)),
                 )),
-                |((line, (name, is_directory, link)), children)| Operator::Def {
+                |((line, (name, params, is_directory, link)), children)| Operator::Def {
                     line,
                     name,
+                    params,
                     is_directory,
                     link,
                     children,
@@ -277,20 +462,37 @@ enum Operator<'t> {
     Def {
         line: &'t str,
         name: Identifier<'t>,
+        params: Vec<Identifier<'t>>,
         is_directory: bool,
         link: Option<Expression<'t>>,
         children: Vec<(&'t str, Operator<'t>)>,
     },
     Use {
         name: Identifier<'t>,
+        args: Vec<Expression<'t>>,
     },
     Match(Expression<'t>),
+    MatchPrefix(Expression<'t>),
+    MatchContains(Expression<'t>),
     Avoid(Expression<'t>),
-    Mode(u16),
-    Owner(Expression<'t>),
-    Group(Expression<'t>),
-    Source(Expression<'t>),
+    AvoidHidden,
+    MatchPath,
+    Mode(AttributeValue<&'t str>),
+    ModeInitial(&'t str),
+    Owner(AttributeValue<Expression<'t>>),
+    Group(AttributeValue<Expression<'t>>),
+    Source(Vec<Expression<'t>>),
+    SourceGlob(Expression<'t>),
     Target(Expression<'t>),
+    LinkOwner(Expression<'t>),
+    LinkGroup(Expression<'t>),
+    RenameFrom(Expression<'t>),
+    When(Condition<'t>),
+    Description(&'t str),
+    Include(&'t str),
+    Sha256(&'t str),
+    IgnoreCase,
+    Recursive,
 }
 
 fn blank_line(s: &str) -> Res<&str, &str> {
@@ -309,9 +511,17 @@ fn comment(s: &str) -> Res<&str, &str> {
     ))(s)
 }
 
-/// Match and consume line endings and any following blank lines, or EOF
+/// A comment following the significant part of a line, which must be preceded by whitespace
+fn inline_comment(s: &str) -> Res<&str, &str> {
+    recognize(tuple((space1, char('#'), opt(is_not("\r\n")))))(s)
+}
+
+/// Match and consume (any inline comment and) line endings and any following blank lines, or EOF
 fn end_of_lines(s: &str) -> Res<&str, &str> {
-    alt((recognize(tuple((line_ending, many0(blank_line)))), eof))(s)
+    preceded(
+        opt(inline_comment),
+        alt((recognize(tuple((line_ending, many0(blank_line)))), eof)),
+    )(s)
 }
 
 fn binding(s: &str) -> Res<&str, Binding<'_>> {
@@ -327,7 +537,7 @@ fn filename(s: &str) -> Res<&str, &str> {
 
 // $name/ -> link
 // name
-fn item_header(s: &str) -> Res<&str, (Binding, bool, Option<Expression>)> {
+fn item_header(s: &str) -> Res<&str, (Binding<'_>, bool, Option<Expression<'_>>)> {
     tuple((
         binding,
         map(opt(char('/')), |o| o.is_some()),
@@ -335,26 +545,81 @@ fn item_header(s: &str) -> Res<&str, (Binding, bool, Option<Expression>)> {
     ))(s)
 }
 
+type DefHeader<'t> = (
+    Identifier<'t>,
+    Vec<Identifier<'t>>,
+    bool,
+    Option<Expression<'t>>,
+);
+
 // :def name/
+// :def name(param1, param2)/
 // :def name -> link
-fn def_header(s: &str) -> Res<&str, (Identifier, bool, Option<Expression>)> {
+fn def_header(s: &str) -> Res<&str, DefHeader<'_>> {
     preceded(
         tuple((tag(":def"), space1)),
         tuple((
             identifier,
+            map(opt(parameters), Option::unwrap_or_default),
             map(opt(char('/')), |o| o.is_some()),
             opt(preceded(tuple((space0, tag("->"), space0)), expression)),
         )),
     )(s)
 }
 
-fn octal(s: &str) -> Res<&str, u16> {
-    map(is_a("01234567"), |mode| {
-        u16::from_str_radix(mode, 8).unwrap()
-    })(s)
+/// A parenthesised list of parameter names, such as "(owner, quota_dir)"
+fn parameters(s: &str) -> Res<&str, Vec<Identifier<'_>>> {
+    delimited(
+        pair(char('('), space0),
+        separated_list0(delimited(space0, char(','), space0), identifier),
+        pair(space0, char(')')),
+    )(s)
+}
+
+/// A parenthesised list of argument expressions, such as "(alice, ${area}_scratch)"
+fn arguments(s: &str) -> Res<&str, Vec<Expression<'_>>> {
+    delimited(
+        pair(char('('), space0),
+        separated_list0(delimited(space0, char(','), space0), argument),
+        pair(space0, char(')')),
+    )(s)
+}
+
+/// An expression used as an argument, which ends at any separating comma or closing parenthesis
+/// (where, as in any expression, "$$" is a literal "$")
+fn argument(s: &str) -> Res<&str, Expression<'_>> {
+    map(
+        many1(alt((
+            escaped_dollar,
+            map(is_not("$\n,() \t"), Token::Text),
+            variable,
+        ))),
+        Expression::from,
+    )(s)
+}
+
+/// Either the keyword `inherit` or its shorthand `-` (alone on the line), or a value parsed by
+/// `explicit`
+fn inheritable<'a, O, P>(explicit: P) -> impl FnMut(&'a str) -> Res<&'a str, AttributeValue<O>>
+where
+    O: Clone,
+    P: Parser<&'a str, O, VerboseError<&'a str>>,
+{
+    alt((
+        value(
+            AttributeValue::Inherit,
+            terminated(alt((tag("inherit"), tag("-"))), peek(end_of_lines)),
+        ),
+        map(explicit, AttributeValue::Explicit),
+    ))
+}
+
+/// Octal digits, left as text so that the builder can report any that are out of range
+fn octal(s: &str) -> Res<&str, &str> {
+    is_a("01234567")(s)
 }
 
-fn identifier(s: &str) -> Res<&str, Identifier> {
+fn identifier(s: &str) -> Res<&str, Identifier<'_>> {
     map(
         recognize(pair(
             alt((alpha1, tag("_"))),
@@ -364,20 +629,74 @@ fn identifier(s: &str) -> Res<&str, Identifier> {
     )(s)
 }
 
-/// Expression, such as "static/$varA/${varB}v2/${NAME}"
-fn expression(s: &str) -> Res<&str, Expression> {
-    map(many1(alt((non_variable, variable))), |tokens| {
-        Expression::from(tokens)
-    })(s)
+/// Expression, such as "static/$varA/${varB}v2/${NAME}" (where "$$" is a literal "$")
+fn expression(s: &str) -> Res<&str, Expression<'_>> {
+    map(
+        many1(alt((escaped_dollar, non_variable, variable))),
+        Expression::from,
+    )(s)
 }
 
-/// A sequence of characters that are not part of any variable
-fn non_variable(s: &str) -> Res<&str, Token> {
-    map(is_not("$\n"), Token::Text)(s)
+/// One or more `:source` paths, separated by commas, such as "/site/$site/motd, /default/motd"
+fn source_candidates(s: &str) -> Res<&str, Vec<Expression<'_>>> {
+    // As in any expression, a path may contain spaces, except before a comma or inline comment
+    let text = recognize(many1(alt((
+        is_not("$\n \t,"),
+        terminated(space1, not(one_of("#,"))),
+    ))));
+    let candidate = preceded(
+        not(char('#')),
+        map(
+            many1(alt((escaped_dollar, map(text, Token::Text), variable))),
+            Expression::from,
+        ),
+    );
+    separated_list1(delimited(space0, tag(","), space0), candidate)(s)
 }
 
-/// A variable name, optionally braced, prefixed by a dollar sign, such as `${example}`
-fn variable(s: &str) -> Res<&str, Token> {
+/// A comparison such as "$env == prod", where the left side is a single word (without spaces)
+fn condition(s: &str) -> Res<&str, Condition<'_>> {
+    let word = map(
+        many1(alt((
+            escaped_dollar,
+            map(is_not("$\n \t"), Token::Text),
+            variable,
+        ))),
+        Expression::from,
+    );
+    let comparison = alt((
+        value(Comparison::Equal, tag("==")),
+        value(Comparison::NotEqual, tag("!=")),
+    ));
+    map(
+        tuple((word, delimited(space1, comparison, space1), expression)),
+        |(left, comparison, right)| Condition {
+            left,
+            comparison,
+            right,
+        },
+    )(s)
+}
+
+/// A doubled dollar sign, standing for a single literal one (so "$$var" is the text "$var")
+fn escaped_dollar(s: &str) -> Res<&str, Token<'_>> {
+    map(tag("$$"), |escaped: &str| Token::Text(&escaped[..1]))(s)
+}
+
+/// A sequence of characters that are not part of any variable (nor any inline comment)
+fn non_variable(s: &str) -> Res<&str, Token<'_>> {
+    map(
+        recognize(many1(alt((
+            is_not("$\n \t"),
+            terminated(space1, not(char('#'))),
+        )))),
+        Token::Text,
+    )(s)
+}
+
+/// A variable name, optionally braced, prefixed by a dollar sign, such as `${example}`, or an
+/// environment variable name, such as `$ENV{HOME}`
+fn variable(s: &str) -> Res<&str, Token<'_>> {
     let braced = |parser| alt((delimited(char('{'), parser, char('}')), parser));
     let vars = |s| {
         alt((
@@ -406,10 +725,29 @@ fn variable(s: &str) -> Res<&str, Token> {
                 tag(Special::PARENT_PATH_NAME),
             ),
             value(Token::Special(Special::RootPath), tag(Special::ROOT_PATH)),
+            value(Token::Special(Special::RootName), tag(Special::ROOT_NAME)),
+            value(
+                Token::Special(Special::MatchedName),
+                tag(Special::MATCHED_NAME),
+            ),
+            value(
+                Token::Special(Special::MappedOwner),
+                tag(Special::MAPPED_OWNER),
+            ),
+            value(
+                Token::Special(Special::MappedGroup),
+                tag(Special::MAPPED_GROUP),
+            ),
             map(identifier, Token::Variable),
         ))(s)
     };
-    preceded(char('$'), braced(vars))(s)
+    alt((
+        map(
+            delimited(tag("$ENV{"), identifier, char('}')),
+            Token::Environment,
+        ),
+        preceded(char('$'), braced(vars)),
+    ))(s)
 }
 
 #[cfg(test)]
@@ -3,8 +3,8 @@ use std::collections::{hash_map::Entry, HashMap};
 use anyhow::{anyhow, bail, Result};
 
 use crate::{
-    Attributes, Binding, DirectorySchema, Expression, FileSchema, Identifier, SchemaNode,
-    SchemaType,
+    Anchoring, AttributeValue, Attributes, Binding, Condition, DirectorySchema, Expression,
+    FileSchema, Identifier, Include, SchemaNode, SchemaType, Usage,
 };
 
 use super::NodeType;
@@ -12,11 +12,21 @@ use super::NodeType;
 #[derive(Debug)]
 pub struct SchemaNodeBuilder<'t> {
     line: &'t str,
+    line_number: usize,
     is_def: bool,
+    params: Vec<Identifier<'t>>,
     match_pattern: Option<Expression<'t>>,
-    avoid_pattern: Option<Expression<'t>>,
+    match_anchoring: Anchoring,
+    avoid_patterns: Vec<Expression<'t>>,
+    avoid_hidden: bool,
+    match_path: bool,
     symlink: Option<Expression<'t>>,
-    uses: Vec<Identifier<'t>>,
+    link_owner: Option<Expression<'t>>,
+    link_group: Option<Expression<'t>>,
+    rename_from: Option<Expression<'t>>,
+    when: Option<Condition<'t>>,
+    description: Option<&'t str>,
+    uses: Vec<Usage<'t>>,
     attributes: Attributes<'t>,
     type_specific: TypeSpecific<'t>,
 }
@@ -27,25 +37,42 @@ enum TypeSpecific<'t> {
         vars: HashMap<Identifier<'t>, Expression<'t>>,
         defs: HashMap<Identifier<'t>, SchemaNode<'t>>,
         entries: Vec<(Binding<'t>, SchemaNode<'t>)>,
+        ignore_case: bool,
+        recursive: bool,
+        includes: Vec<Include<'t>>,
+        source: Option<Expression<'t>>,
+        source_glob: Option<Expression<'t>>,
     },
     File {
-        source: Option<Expression<'t>>,
+        sources: Vec<Expression<'t>>,
+        sha256: Option<&'t str>,
     },
 }
 
 impl<'t> SchemaNodeBuilder<'t> {
     pub fn new(
         line: &'t str,
-        is_def: bool,
+        line_number: usize,
+        def_params: Option<Vec<Identifier<'t>>>,
         node_type: NodeType,
         symlink: Option<Expression<'t>>,
     ) -> Self {
         SchemaNodeBuilder {
             line,
-            is_def,
+            line_number,
+            is_def: def_params.is_some(),
+            params: def_params.unwrap_or_default(),
             match_pattern: None,
-            avoid_pattern: None,
+            match_anchoring: Anchoring::Full,
+            avoid_patterns: Vec::new(),
+            avoid_hidden: false,
+            match_path: false,
             symlink,
+            link_owner: None,
+            link_group: None,
+            rename_from: None,
+            when: None,
+            description: None,
             uses: Vec::new(),
             attributes: Attributes::default(),
 
@@ -54,31 +81,59 @@ impl<'t> SchemaNodeBuilder<'t> {
                     vars: HashMap::new(),
                     defs: HashMap::new(),
                     entries: Vec::new(),
+                    ignore_case: false,
+                    recursive: false,
+                    includes: Vec::new(),
+                    source: None,
+                    source_glob: None,
+                },
+                NodeType::File => TypeSpecific::File {
+                    sources: Vec::new(),
+                    sha256: None,
                 },
-                NodeType::File => TypeSpecific::File { source: None },
             },
         }
     }
 
-    pub fn match_pattern(&mut self, pattern: Expression<'t>) -> Result<()> {
+    pub fn match_pattern(&mut self, pattern: Expression<'t>, anchoring: Anchoring) -> Result<()> {
         if self.match_pattern.is_some() {
-            bail!(":match occurs twice");
+            bail!(":{} occurs twice", anchoring.tag());
         }
         if self.is_def {
-            bail!(":match cannot be used in definition");
+            bail!(":{} cannot be used in definition", anchoring.tag());
         }
         self.match_pattern = Some(pattern);
+        self.match_anchoring = anchoring;
         Ok(())
     }
 
     pub fn avoid_pattern(&mut self, pattern: Expression<'t>) -> Result<()> {
-        if self.avoid_pattern.is_some() {
-            bail!(":avoid occurs twice");
-        }
         if self.is_def {
             bail!(":avoid cannot be used in definition");
         }
-        self.avoid_pattern = Some(pattern);
+        self.avoid_patterns.push(pattern);
+        Ok(())
+    }
+
+    pub fn avoid_hidden(&mut self) -> Result<()> {
+        if self.avoid_hidden {
+            bail!(":avoid-hidden occurs twice");
+        }
+        if self.is_def {
+            bail!(":avoid-hidden cannot be used in definition");
+        }
+        self.avoid_hidden = true;
+        Ok(())
+    }
+
+    pub fn match_path(&mut self) -> Result<()> {
+        if self.match_path {
+            bail!(":match-path occurs twice");
+        }
+        if self.is_def {
+            bail!(":match-path cannot be used in definition");
+        }
+        self.match_path = true;
         Ok(())
     }
 
@@ -98,6 +153,21 @@ impl<'t> SchemaNodeBuilder<'t> {
         }
     }
 
+    pub fn include(&mut self, include: Include<'t>) -> Result<()> {
+        match &mut self.type_specific {
+            TypeSpecific::File { .. } => Err(anyhow!(
+                "Cannot :include other schemas inside files (add a '/' to make it a directory)"
+            )),
+            TypeSpecific::Directory { includes, .. } => {
+                if includes.iter().any(|other| other.path == include.path) {
+                    bail!(":include {} occurs twice", include.path);
+                }
+                includes.push(include);
+                Ok(())
+            }
+        }
+    }
+
     pub fn define(&mut self, id: Identifier<'t>, definition: SchemaNode<'t>) -> Result<()> {
         match &mut self.type_specific {
             TypeSpecific::File { .. } => Err(anyhow!(
@@ -114,17 +184,17 @@ impl<'t> SchemaNodeBuilder<'t> {
         }
     }
 
-    pub fn use_definition(&mut self, id: Identifier<'t>) -> Result<()> {
-        if let TypeSpecific::File { source, .. } = &self.type_specific {
-            if source.is_some() {
+    pub fn use_definition(&mut self, usage: Usage<'t>) -> Result<()> {
+        if let TypeSpecific::File { sources, .. } = &self.type_specific {
+            if !sources.is_empty() {
                 bail!(":use cannot be used in conjunction with :source");
             }
         }
-        self.uses.push(id);
+        self.uses.push(usage);
         Ok(())
     }
 
-    pub fn owner(&mut self, owner: Expression<'t>) -> Result<()> {
+    pub fn owner(&mut self, owner: AttributeValue<Expression<'t>>) -> Result<()> {
         if self.attributes.owner.is_some() {
             bail!(":owner occurs twice");
         }
@@ -132,7 +202,7 @@ impl<'t> SchemaNodeBuilder<'t> {
         Ok(())
     }
 
-    pub fn group(&mut self, group: Expression<'t>) -> Result<()> {
+    pub fn group(&mut self, group: AttributeValue<Expression<'t>>) -> Result<()> {
         if self.attributes.group.is_some() {
             bail!(":group occurs twice");
         }
@@ -140,34 +210,100 @@ impl<'t> SchemaNodeBuilder<'t> {
         Ok(())
     }
 
-    pub fn mode(&mut self, mode: u16) -> Result<()> {
+    pub fn mode(&mut self, mode: AttributeValue<&'t str>) -> Result<()> {
+        if self.attributes.mode_initial {
+            bail!(":mode cannot be used in conjunction with :mode-initial");
+        }
         if self.attributes.mode.is_some() {
             bail!(":mode occurs twice");
         }
+        let mode = match mode {
+            AttributeValue::Inherit => AttributeValue::Inherit,
+            // Only the permission bits may be given, not those of the file type
+            AttributeValue::Explicit(digits) => match u16::from_str_radix(digits, 8) {
+                Ok(mode) if mode <= 0o7777 => AttributeValue::Explicit(mode),
+                _ => bail!(":mode must be at most 7777 (octal): {}", digits),
+            },
+        };
         self.attributes.mode = Some(mode);
         Ok(())
     }
 
-    pub fn source(&mut self, source: Expression<'t>) -> Result<()> {
+    pub fn mode_initial(&mut self, digits: &'t str) -> Result<()> {
+        match (self.attributes.mode, self.attributes.mode_initial) {
+            (Some(_), true) => bail!(":mode-initial occurs twice"),
+            (Some(_), false) => bail!(":mode-initial cannot be used in conjunction with :mode"),
+            (None, _) => {}
+        }
+        match u16::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o7777 => {
+                self.attributes.mode = Some(AttributeValue::Explicit(mode));
+                self.attributes.mode_initial = true;
+                Ok(())
+            }
+            _ => bail!(":mode-initial must be at most 7777 (octal): {}", digits),
+        }
+    }
+
+    pub fn source(&mut self, mut candidates: Vec<Expression<'t>>) -> Result<()> {
         match self.type_specific {
-            TypeSpecific::Directory { .. } => Err(anyhow!(
-                ":source can only be used for files, not directories"
-            )),
-            TypeSpecific::File {
+            TypeSpecific::Directory {
                 source: ref mut src,
+                ref source_glob,
+                ..
+            } => {
+                if src.is_some() {
+                    Err(anyhow!(":source occurs twice"))
+                } else if source_glob.is_some() {
+                    Err(anyhow!(
+                        ":source cannot be used in conjunction with :source-glob"
+                    ))
+                } else if candidates.len() > 1 {
+                    Err(anyhow!(
+                        ":source can only give several candidates for files, not directories"
+                    ))
+                } else {
+                    *src = candidates.pop();
+                    Ok(())
+                }
+            }
+            TypeSpecific::File {
+                ref mut sources, ..
             } => {
                 if !self.uses.is_empty() {
                     Err(anyhow!(":source cannot be used in conjunction with :use"))
-                } else if src.is_some() {
+                } else if !sources.is_empty() {
                     Err(anyhow!(":source occurs twice"))
                 } else {
-                    *src = Some(source);
+                    *sources = candidates;
                     Ok(())
                 }
             }
         }
     }
 
+    pub fn source_glob(&mut self, pattern: Expression<'t>) -> Result<()> {
+        match &mut self.type_specific {
+            TypeSpecific::File { .. } => Err(anyhow!(
+                ":source-glob can only be used for directories, not files"
+            )),
+            TypeSpecific::Directory {
+                source,
+                source_glob,
+                ..
+            } => {
+                if source_glob.is_some() {
+                    bail!(":source-glob occurs twice");
+                }
+                if source.is_some() {
+                    bail!(":source-glob cannot be used in conjunction with :source");
+                }
+                *source_glob = Some(pattern);
+                Ok(())
+            }
+        }
+    }
+
     pub fn target(&mut self, target: Expression<'t>) -> Result<()> {
         if self.symlink.is_some() {
             bail!(":target occurs twice");
@@ -176,6 +312,94 @@ impl<'t> SchemaNodeBuilder<'t> {
         Ok(())
     }
 
+    pub fn sha256(&mut self, digest: &'t str) -> Result<()> {
+        match &mut self.type_specific {
+            TypeSpecific::Directory { .. } => Err(anyhow!(
+                ":sha256 can only be used for files, not directories"
+            )),
+            TypeSpecific::File { sha256, .. } => {
+                if sha256.is_some() {
+                    bail!(":sha256 occurs twice");
+                }
+                if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                    bail!(":sha256 must be 64 hexadecimal digits: {}", digest);
+                }
+                *sha256 = Some(digest);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn link_owner(&mut self, owner: Expression<'t>) -> Result<()> {
+        if self.link_owner.is_some() {
+            bail!(":link-owner occurs twice");
+        }
+        self.link_owner = Some(owner);
+        Ok(())
+    }
+
+    pub fn link_group(&mut self, group: Expression<'t>) -> Result<()> {
+        if self.link_group.is_some() {
+            bail!(":link-group occurs twice");
+        }
+        self.link_group = Some(group);
+        Ok(())
+    }
+
+    pub fn rename_from(&mut self, old: Expression<'t>) -> Result<()> {
+        if self.rename_from.is_some() {
+            bail!(":rename-from occurs twice");
+        }
+        self.rename_from = Some(old);
+        Ok(())
+    }
+
+    pub fn when(&mut self, condition: Condition<'t>) -> Result<()> {
+        if self.when.is_some() {
+            bail!(":when occurs twice");
+        }
+        self.when = Some(condition);
+        Ok(())
+    }
+
+    pub fn description(&mut self, text: &'t str) -> Result<()> {
+        if self.description.is_some() {
+            bail!(":description occurs twice");
+        }
+        self.description = Some(text.trim_end());
+        Ok(())
+    }
+
+    pub fn ignore_case(&mut self) -> Result<()> {
+        match &mut self.type_specific {
+            TypeSpecific::File { .. } => Err(anyhow!(
+                ":ignore-case can only be used for directories, not files"
+            )),
+            TypeSpecific::Directory { ignore_case, .. } => {
+                if *ignore_case {
+                    bail!(":ignore-case occurs twice");
+                }
+                *ignore_case = true;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn recursive(&mut self) -> Result<()> {
+        match &mut self.type_specific {
+            TypeSpecific::File { .. } => Err(anyhow!(
+                ":recursive can only be used for directories, not files"
+            )),
+            TypeSpecific::Directory { recursive, .. } => {
+                if *recursive {
+                    bail!(":recursive occurs twice");
+                }
+                *recursive = true;
+                Ok(())
+            }
+        }
+    }
+
     pub fn add_entry(&mut self, binding: Binding<'t>, entry: SchemaNode<'t>) -> Result<()> {
         match &mut self.type_specific {
             TypeSpecific::File { .. } => Err(anyhow!(
@@ -192,35 +416,89 @@ impl<'t> SchemaNodeBuilder<'t> {
     pub fn build(self) -> Result<SchemaNode<'t>> {
         let SchemaNodeBuilder {
             line,
+            line_number,
             is_def: _,
+            params,
             match_pattern,
-            avoid_pattern,
+            match_anchoring,
+            avoid_patterns,
+            avoid_hidden,
+            match_path,
             symlink,
+            link_owner,
+            link_group,
+            rename_from,
+            when,
+            description,
             uses,
             attributes,
             type_specific,
         } = self;
+        if symlink.is_some() && rename_from.is_some() {
+            bail!(":rename-from cannot be used for symlinks");
+        }
+        if symlink.is_none() {
+            if link_owner.is_some() {
+                bail!(":link-owner can only be used for symlinks");
+            }
+            if link_group.is_some() {
+                bail!(":link-group can only be used for symlinks");
+            }
+        }
+        for (i, param) in params.iter().enumerate() {
+            if params[..i].contains(param) {
+                bail!("Parameter {} occurs twice", param);
+            }
+        }
         let schema = match type_specific {
             TypeSpecific::Directory {
                 vars,
                 defs,
                 entries,
-            } => SchemaType::Directory(DirectorySchema::new(vars, defs, entries)),
-            TypeSpecific::File { source } => {
-                let source = source.ok_or_else(|| {
+                ignore_case,
+                recursive,
+                includes,
+                source,
+                source_glob,
+            } => SchemaType::Directory(
+                DirectorySchema::new(vars, defs, entries)
+                    .with_ignore_case(ignore_case)
+                    .with_recursive(recursive)
+                    .with_includes(includes)
+                    .with_source(source)
+                    .with_source_glob(source_glob),
+            ),
+            TypeSpecific::File { sources, sha256 } => {
+                let mut sources = sources.into_iter();
+                let source = sources.next().ok_or_else(|| {
                     anyhow!("File must have a :source (or add a '/' to make it a directory)")
                 })?;
-                SchemaType::File(FileSchema::new(source))
+                SchemaType::File(
+                    FileSchema::new(source)
+                        .with_fallbacks(sources.collect())
+                        .with_sha256(sha256),
+                )
             }
         };
         Ok(SchemaNode {
             line,
+            line_number,
             match_pattern,
-            avoid_pattern,
+            match_anchoring,
+            avoid_patterns,
+            avoid_hidden,
+            match_path,
             symlink,
+            link_owner,
+            link_group,
+            rename_from,
+            when,
+            description,
+            params,
             uses,
             attributes,
             schema,
+            version: None,
         })
     }
 }
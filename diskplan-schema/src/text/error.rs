@@ -6,6 +6,7 @@ pub struct ParseError<'a> {
     error: String,
     text: &'a str,
     span: &'a str,
+    path: Option<String>,
     next: Option<Box<ParseError<'a>>>,
 }
 
@@ -14,14 +15,11 @@ impl Display for ParseError<'_> {
         let lineno = self.line_number();
         let line = self.text.lines().nth(lineno - 1).unwrap_or("<EOF>");
         let column = self.span.as_ptr() as usize - line.as_ptr() as usize;
-        writeln!(f, "Error: {}", self.error)?;
-        writeln!(f, "     |")?;
-        writeln!(f, "{lineno:4} | {line}")?;
-        if column == 0 {
-            writeln!(f, "     |")?;
-        } else {
-            writeln!(f, "     | {0:1$}^", "", column)?;
+        match &self.path {
+            Some(path) => writeln!(f, "Error: {}:{}: {}", path, lineno, self.error)?,
+            None => writeln!(f, "Error: {}", self.error)?,
         }
+        write_snippet(f, lineno, line, column)?;
         if let Some(next) = &self.next {
             write!(f, "{next}")?;
         }
@@ -31,6 +29,22 @@ impl Display for ParseError<'_> {
 
 impl std::error::Error for ParseError<'_> {}
 
+/// Writes a numbered line of schema text, with a caret beneath the given (byte) column
+pub(crate) fn write_snippet(
+    f: &mut std::fmt::Formatter<'_>,
+    line_number: usize,
+    line: &str,
+    column: usize,
+) -> std::fmt::Result {
+    writeln!(f, "     |")?;
+    writeln!(f, "{line_number:4} | {line}")?;
+    if column == 0 {
+        writeln!(f, "     |")
+    } else {
+        writeln!(f, "     | {0:1$}^", "", column)
+    }
+}
+
 impl<'a> ParseError<'a> {
     /// Constructs a detailed error for an issue encountered during parsing
     pub fn new(
@@ -43,14 +57,63 @@ impl<'a> ParseError<'a> {
             error,
             text,
             span,
+            path: None,
             next,
         }
     }
 
+    /// Attaches the path of the file from which the text was read, to be named when displayed
+    pub fn with_path(mut self, path: impl AsRef<str>) -> ParseError<'a> {
+        let path = path.as_ref();
+        let mut error = Some(&mut self);
+        while let Some(e) = error {
+            e.path = Some(path.to_owned());
+            error = e.next.as_deref_mut();
+        }
+        self
+    }
+
+    /// The path of the file from which the text was read, if known
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
     /// Returns the calculated line number of the span within the text
     pub fn line_number(&self) -> usize {
-        let pos = self.span.as_ptr() as usize - self.text.as_ptr() as usize;
-        self.text[..pos].chars().filter(|&c| c == '\n').count() + 1
+        self.text[..self.offset()]
+            .chars()
+            .filter(|&c| c == '\n')
+            .count()
+            + 1
+    }
+
+    /// Returns the line (counting from 1) on which the error's span begins, as
+    /// [`line_number`](ParseError::line_number)
+    pub fn line(&self) -> usize {
+        self.line_number()
+    }
+
+    /// Returns the column (counting from 1, in characters) at which the error's span begins
+    pub fn column(&self) -> usize {
+        let offset = self.offset();
+        let line_start = self.text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        self.text[line_start..offset].chars().count() + 1
+    }
+
+    /// Returns the length (in characters) of the error's span, which may be zero (at the end of
+    /// the text, for example)
+    pub fn len(&self) -> usize {
+        self.span.chars().count()
+    }
+
+    /// Returns true if the error's span is empty
+    pub fn is_empty(&self) -> bool {
+        self.span.is_empty()
+    }
+
+    /// The byte offset of the span within the text
+    fn offset(&self) -> usize {
+        self.span.as_ptr() as usize - self.text.as_ptr() as usize
     }
 }
 
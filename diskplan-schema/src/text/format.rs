@@ -0,0 +1,141 @@
+use std::fmt::{Display, Result, Write as _};
+
+use crate::{AttributeValue, DirectorySchema, SchemaNode, SchemaType};
+
+/// Renders a tree of [`SchemaNode`]s back into the text representation, such that parsing the
+/// result yields an equivalent tree
+///
+/// Nothing is expanded; the output only differs from the original text in layout. Indentation is
+/// normalised, comments and blank lines are dropped, and tags are written in a canonical order
+/// (variables and definitions sorted by name) before the entries of a directory.
+pub fn format_schema(schema: &SchemaNode) -> String {
+    let mut text = String::new();
+    if let Some(version) = schema.version {
+        text.push_str(&format!("#!diskplan {version}\n"));
+    }
+    write_body(&mut text, schema, 0).expect("Writing to a String cannot fail");
+    text
+}
+
+/// Writes the tags and children of `node`, each line indented to the given `level`
+fn write_body(f: &mut String, node: &SchemaNode, level: usize) -> Result {
+    let indent = "    ".repeat(level);
+    let mut tag = |name: &str, value: &dyn Display| writeln!(f, "{indent}:{name} {value}");
+
+    if let Some(description) = node.description {
+        tag("description", &description)?;
+    }
+    let directory = node.schema.as_directory();
+    if let Some(directory) = directory {
+        for include in directory.includes() {
+            tag("include", &include.path)?;
+        }
+        let mut vars: Vec<_> = directory.vars().iter().collect();
+        vars.sort_by_key(|(id, _)| **id);
+        for (id, expr) in vars {
+            tag("let", &format_args!("{id} = {expr}"))?;
+        }
+    }
+    if let Some(pattern) = &node.match_pattern {
+        tag(node.match_anchoring.tag(), pattern)?;
+    }
+    for pattern in &node.avoid_patterns {
+        tag("avoid", pattern)?;
+    }
+    if let Some(owner) = &node.link_owner {
+        tag("link-owner", owner)?;
+    }
+    if let Some(group) = &node.link_group {
+        tag("link-group", group)?;
+    }
+    if let Some(old) = &node.rename_from {
+        tag("rename-from", old)?;
+    }
+    if let Some(condition) = &node.when {
+        tag("when", condition)?;
+    }
+    match &node.schema {
+        SchemaType::Directory(directory) => {
+            if let Some(source) = directory.source() {
+                tag("source", source)?;
+            }
+            if let Some(pattern) = directory.source_glob() {
+                tag("source-glob", pattern)?;
+            }
+        }
+        SchemaType::File(file) => {
+            let sources: Vec<_> = file.sources().iter().map(|s| s.to_string()).collect();
+            tag("source", &sources.join(", "))?;
+            if let Some(sha256) = file.sha256() {
+                tag("sha256", &sha256)?;
+            }
+        }
+    }
+    if let Some(owner) = &node.attributes.owner {
+        tag("owner", &inheritable(owner, ToString::to_string))?;
+    }
+    if let Some(group) = &node.attributes.group {
+        tag("group", &inheritable(group, ToString::to_string))?;
+    }
+    if let Some(mode) = &node.attributes.mode {
+        let name = match node.attributes.mode_initial {
+            true => "mode-initial",
+            false => "mode",
+        };
+        tag(name, &inheritable(mode, |mode| format!("{mode:04o}")))?;
+    }
+    if node.avoid_hidden {
+        writeln!(f, "{indent}:avoid-hidden")?;
+    }
+    if node.match_path {
+        writeln!(f, "{indent}:match-path")?;
+    }
+    if directory.map(DirectorySchema::ignore_case) == Some(true) {
+        writeln!(f, "{indent}:ignore-case")?;
+    }
+    if directory.map(DirectorySchema::recursive) == Some(true) {
+        writeln!(f, "{indent}:recursive")?;
+    }
+    for usage in &node.uses {
+        writeln!(f, "{indent}:use {usage}")?;
+    }
+
+    if let Some(directory) = directory {
+        let mut defs: Vec<_> = directory.defs().iter().collect();
+        defs.sort_by_key(|(id, _)| **id);
+        for (id, def) in defs {
+            write!(f, "{indent}:def {id}")?;
+            if !def.params.is_empty() {
+                let params: Vec<_> = def.params.iter().map(ToString::to_string).collect();
+                write!(f, "({})", params.join(", "))?;
+            }
+            write_header_end(f, def)?;
+            write_body(f, def, level + 1)?;
+        }
+        for (binding, entry) in directory.entries() {
+            write!(f, "{indent}{binding}")?;
+            write_header_end(f, entry)?;
+            write_body(f, entry, level + 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Completes the header line of an entry or definition with its type and any symlink target
+fn write_header_end(f: &mut String, node: &SchemaNode) -> Result {
+    if node.schema.as_directory().is_some() {
+        f.push('/');
+    }
+    if let Some(target) = &node.symlink {
+        write!(f, " -> {target}")?;
+    }
+    writeln!(f)
+}
+
+/// Renders an attribute value given by the schema, or the `inherit` keyword
+fn inheritable<T>(value: &AttributeValue<T>, explicit: impl Fn(&T) -> String) -> String {
+    match value {
+        AttributeValue::Inherit => "inherit".to_owned(),
+        AttributeValue::Explicit(value) => explicit(value),
+    }
+}
@@ -11,10 +11,10 @@ use nom::{
 use crate::{
     expression::{Expression, Identifier, Token},
     text::{
-        blank_line, comment, def_header, end_of_lines, expression, indentation, operator,
-        parse_schema, Operator,
+        blank_line, check_static_name, comment, def_header, end_of_lines, expression, indentation,
+        operator, parse_schema, parse_schema_with_path, Operator, SCHEMA_VERSION,
     },
-    Binding, DirectorySchema, FileSchema, SchemaNode, SchemaType,
+    AttributeValue, Binding, DirectorySchema, FileSchema, SchemaNode, SchemaType,
 };
 
 #[test]
@@ -189,11 +189,11 @@ fn let_statements() {
 fn def_headers() {
     assert_eq!(
         def_header(":def something"),
-        Ok(("", (Identifier::new("something"), false, None)))
+        Ok(("", (Identifier::new("something"), vec![], false, None)))
     );
     assert_eq!(
         def_header(":def something/"),
-        Ok(("", (Identifier::new("something"), true, None,)))
+        Ok(("", (Identifier::new("something"), vec![], true, None,)))
     );
 }
 
@@ -206,7 +206,7 @@ fn def_op_no_children() {
         alt((line_ending, eof)),
     )(s0)
     .unwrap();
-    assert_eq!(o1, (Identifier::new("something_"), false, None));
+    assert_eq!(o1, (Identifier::new("something_"), vec![], false, None));
     let (s2, o2) = many0(operator(level + 1))(s1).unwrap();
     assert_eq!(o2, vec![]);
     assert_eq!(s2, "");
@@ -221,6 +221,7 @@ fn def_op_no_children() {
                 Operator::Def {
                     line: ":def something_",
                     name: Identifier::new("something_"),
+                    params: vec![],
                     is_directory: false,
                     link: None,
                     children: vec![],
@@ -244,6 +245,7 @@ fn def_op_no_children() {
                 Operator::Def {
                     line: ":def something -> /somewhere/else",
                     name: Identifier::new("something"),
+                    params: vec![],
                     is_directory: false,
                     link: Some(Expression::from(vec![Token::Text("/somewhere/else")])),
                     children: vec![],
@@ -265,6 +267,7 @@ fn def_op_with_children() {
                 Operator::Def {
                     line: s,
                     name: Identifier::new("something"),
+                    params: vec![],
                     is_directory: false,
                     link: Some(Expression::from(vec![
                         Token::Text("/some"),
@@ -289,6 +292,41 @@ fn dynamic_binding() {
     );
 }
 
+#[test]
+fn escaped_dollar() {
+    let var = |name| Token::Variable(Identifier::new(name));
+    assert_eq!(
+        expression("price_$$5"),
+        Ok((
+            "",
+            Expression::from(vec![
+                Token::Text("price_"),
+                Token::Text("$"),
+                Token::Text("5")
+            ])
+        ))
+    );
+    // A literal dollar followed by text, not a dollar followed by a variable
+    assert_eq!(
+        expression("$$var"),
+        Ok((
+            "",
+            Expression::from(vec![Token::Text("$"), Token::Text("var")])
+        ))
+    );
+    assert_eq!(
+        expression("$$$var"),
+        Ok(("", Expression::from(vec![Token::Text("$"), var("var")])))
+    );
+
+    // Displayed with the escape, so as to parse back to the same expression
+    for text in ["price_$$5", "$$var", "$$${var}$$"] {
+        let (_, parsed) = expression(text).unwrap();
+        assert_eq!(parsed.to_string(), text);
+        assert_eq!(expression(&parsed.to_string()).unwrap().1, parsed);
+    }
+}
+
 /// Line ending may be a newline or the EOF
 #[test]
 fn line_end() {
@@ -300,15 +338,18 @@ fn line_end() {
 #[test]
 fn no_trailing_whitespace() {
     let s = "\n    \n\n    \n\n";
-    assert!(matches!(end_of_lines(s), Ok(_)));
+    assert!(end_of_lines(s).is_ok());
     let s = "    \n\n    \n\n";
-    assert!(matches!(end_of_lines(s), Err(_)));
+    assert!(end_of_lines(s).is_err());
 }
 
 #[test]
 fn single_line_mode_op() {
     let s = ":mode 777";
-    assert_eq!(operator(0)(s), Ok(("", (s, Operator::Mode(0o777)))));
+    assert_eq!(
+        operator(0)(s),
+        Ok(("", (s, Operator::Mode(AttributeValue::Explicit("777")))))
+    );
 }
 
 #[test]
@@ -346,7 +387,13 @@ fn multiline_meta_ops() {
     let t = &s[end..];
     assert_eq!(
         operator(2)(s),
-        Ok((t, (&s[pos..end], Operator::Mode(0o777))))
+        Ok((
+            t,
+            (
+                &s[pos..end],
+                Operator::Mode(AttributeValue::Explicit("777"))
+            )
+        ))
     );
 
     let line = "        :owner usr-1\n";
@@ -357,13 +404,25 @@ fn multiline_meta_ops() {
     let group_expr = Expression::from(vec![Token::Text("grpX")]);
     assert_eq!(
         operator(2)(t),
-        Ok((u, (&s[pos..end], Operator::Owner(owner_expr))))
+        Ok((
+            u,
+            (
+                &s[pos..end],
+                Operator::Owner(AttributeValue::Explicit(owner_expr))
+            )
+        ))
     );
     let line = "        :group grpX\n";
     let pos = s.find(line).unwrap();
     assert_eq!(
         operator(2)(u),
-        Ok(("", (&s[pos..], Operator::Group(group_expr))))
+        Ok((
+            "",
+            (
+                &s[pos..],
+                Operator::Group(AttributeValue::Explicit(group_expr))
+            )
+        ))
     );
 }
 
@@ -391,7 +450,7 @@ fn source_pattern() {
             "",
             (
                 s,
-                Operator::Source(Expression::from(vec![Token::Text("/a/file/path")]))
+                Operator::Source(vec![Expression::from(vec![Token::Text("/a/file/path")])])
             )
         ))
     )
@@ -409,6 +468,7 @@ fn def_with_newline() {
                 Operator::Def {
                     line: ":def defined/",
                     name: Identifier::new("defined"),
+                    params: vec![],
                     is_directory: true,
                     link: None,
                     children: vec![]
@@ -434,6 +494,7 @@ fn def_with_block() {
                 Operator::Def {
                     line: ":def defined/",
                     name: Identifier::new("defined"),
+                    params: vec![],
                     is_directory: true,
                     link: None,
                     children: vec![
@@ -493,6 +554,7 @@ fn usage() {
                     Operator::Def {
                         line: ":def defined/",
                         name: Identifier::new("defined"),
+                        params: vec![],
                         is_directory: true,
                         link: None,
                         children: vec![(
@@ -504,9 +566,9 @@ fn usage() {
                                 link: None,
                                 children: vec![(
                                     &s[source_pos..usage_pos],
-                                    Operator::Source(Expression::from(vec![Token::Variable(
-                                        Identifier::new("emptyfile")
-                                    )]))
+                                    Operator::Source(vec![Expression::from(vec![
+                                        Token::Variable(Identifier::new("emptyfile"))
+                                    ])])
                                 )],
                             }
                         )],
@@ -522,7 +584,8 @@ fn usage() {
                         children: vec![(
                             &s[use_pos..],
                             Operator::Use {
-                                name: Identifier::new("defined")
+                                name: Identifier::new("defined"),
+                                args: vec![],
                             }
                         )]
                     }
@@ -615,3 +678,234 @@ fn symlink_file() {
         &Some(Expression::from(vec![Token::Text("/another/place")]))
     );
 }
+
+#[test]
+fn inline_comments() {
+    assert_eq!(
+        expression("some value  # comment"),
+        Ok((
+            "  # comment",
+            Expression::from(vec![Token::Text("some value")])
+        ))
+    );
+    assert_eq!(
+        expression("no#comment"),
+        Ok(("", Expression::from(vec![Token::Text("no#comment")])))
+    );
+    assert_eq!(end_of_lines(" # comment\n"), Ok(("", "\n")));
+    assert_eq!(end_of_lines(" #"), Ok(("", "")));
+
+    let root = parse_schema(
+        "
+        config/  # per-project settings
+            :owner admin  # the admin user
+            :mode 750 #
+            file # a file
+                :source /path/#not_a_comment    # but this is
+        ",
+    )
+    .unwrap();
+    let (binding, config) = &root.schema.as_directory().unwrap().entries()[0];
+    assert_eq!(binding, &Binding::Static("config"));
+    assert_eq!(config.line, "config/");
+    assert_eq!(
+        config.attributes.owner.clone().unwrap().explicit().unwrap(),
+        &"admin"
+    );
+    assert_eq!(
+        config.attributes.mode,
+        Some(AttributeValue::Explicit(0o750))
+    );
+    let (binding, file) = &config.schema.as_directory().unwrap().entries()[0];
+    assert_eq!(binding, &Binding::Static("file"));
+    assert_eq!(
+        *file.schema.as_file().unwrap().source(),
+        "/path/#not_a_comment"
+    );
+
+    // A comment must be separated from what precedes it
+    assert!(parse_schema("config/# comment").is_err());
+}
+
+#[test]
+fn static_names_are_checked() {
+    let text = "ok/\n    ../\n";
+    let error = parse_schema(text).unwrap_err();
+    let innermost = error.into_iter().last().unwrap();
+    assert_eq!(innermost.line_number(), 2);
+    assert_eq!(
+        (innermost.line(), innermost.column(), innermost.len()),
+        (2, 5, 2)
+    );
+    assert!(!innermost.is_empty());
+    assert!(innermost.to_string().contains(r#"Invalid name "..""#));
+    assert!(innermost.to_string().contains("     |     ^"));
+
+    assert!(parse_schema(".\n    :source x").is_err());
+    assert!(parse_schema("..a/\n.hidden/\n").is_ok());
+    assert!(check_static_name("a/b").is_err());
+    assert!(check_static_name("a\0b").is_err());
+}
+
+#[test]
+fn parse_errors_name_the_file() {
+    let text = "ok/\n    ../\n";
+    let error = parse_schema_with_path(text, "dir/file.diskplan").unwrap_err();
+    for error in &error {
+        assert_eq!(error.path(), Some("dir/file.diskplan"));
+    }
+    let innermost = error.into_iter().last().unwrap();
+    assert!(innermost
+        .to_string()
+        .starts_with(r#"Error: dir/file.diskplan:2: Invalid name "..""#));
+
+    let error = parse_schema(text).unwrap_err();
+    assert_eq!(error.path(), None);
+}
+
+#[test]
+fn sha256_is_checked() {
+    let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    let text = format!("file\n    :source /src\n    :sha256 {digest}\n");
+    let schema = parse_schema(&text).unwrap();
+    let directory = schema.schema.as_directory().unwrap();
+    let (_, file) = &directory.entries()[0];
+    assert_eq!(file.schema.as_file().unwrap().sha256(), Some(digest));
+
+    assert!(parse_schema("file\n    :source /src\n    :sha256 abc\n").is_err());
+    assert!(parse_schema(&format!("dir/\n    :sha256 {digest}\n")).is_err());
+    let twice = format!("file\n    :source /src\n    :sha256 {digest}\n    :sha256 {digest}\n");
+    assert!(parse_schema(&twice).is_err());
+}
+
+#[test]
+fn directory_source() {
+    let schema = parse_schema("dir/\n    :source /templates/dir\n    child/\n").unwrap();
+    let directory = schema.schema.as_directory().unwrap();
+    let (_, dir) = &directory.entries()[0];
+    let dir = dir.schema.as_directory().unwrap();
+    assert_eq!(dir.source().unwrap().to_string(), "/templates/dir");
+    assert_eq!(dir.entries().len(), 1);
+
+    assert!(parse_schema("dir/\n    :source /a\n    :source /b\n").is_err());
+}
+
+#[test]
+fn source_candidates() {
+    let schema =
+        parse_schema("file\n    :source /a/$x, /b,/c    # comment\ndir/\n    :source /d\n")
+            .unwrap();
+    let entries = schema.schema.as_directory().unwrap().entries();
+    let file = entries.iter().find(|(b, _)| *b == Binding::Static("file"));
+    let sources: Vec<_> = file
+        .unwrap()
+        .1
+        .schema
+        .as_file()
+        .unwrap()
+        .sources()
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(sources, ["/a/${x}", "/b", "/c"]);
+    assert!(crate::format_schema(&schema).contains(":source /a/${x}, /b, /c\n"));
+
+    // Directories are copied from a single source
+    assert!(parse_schema("dir/\n    :source /a, /b\n").is_err());
+}
+
+#[test]
+fn source_with_spaces() {
+    let schema = parse_schema(
+        "
+        file
+            :source /My Assets/$x file, /Default Assets/file    # comment
+        dir/
+            :source /My Templates/dir
+        ",
+    )
+    .unwrap();
+    let entries = schema.schema.as_directory().unwrap().entries();
+    let file = entries.iter().find(|(b, _)| *b == Binding::Static("file"));
+    let sources: Vec<_> = file
+        .unwrap()
+        .1
+        .schema
+        .as_file()
+        .unwrap()
+        .sources()
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(sources, ["/My Assets/${x} file", "/Default Assets/file"]);
+    let dir = entries.iter().find(|(b, _)| *b == Binding::Static("dir"));
+    let dir = dir.unwrap().1.schema.as_directory().unwrap();
+    assert_eq!(dir.source().unwrap().to_string(), "/My Templates/dir");
+}
+
+#[test]
+fn source_glob() {
+    let schema = parse_schema("conf/\n    :source-glob /templates/$kind/*.conf\n").unwrap();
+    let (_, conf) = &schema.schema.as_directory().unwrap().entries()[0];
+    let pattern = conf.schema.as_directory().unwrap().source_glob().unwrap();
+    assert_eq!(pattern.to_string(), "/templates/${kind}/*.conf");
+    assert!(crate::format_schema(&schema).contains(":source-glob /templates/${kind}/*.conf\n"));
+
+    assert!(parse_schema("file\n    :source /a\n    :source-glob /b/*\n").is_err());
+    assert!(parse_schema("dir/\n    :source /a\n    :source-glob /b/*\n").is_err());
+    assert!(parse_schema("dir/\n    :source-glob /b/*\n    :source /a\n").is_err());
+    assert!(parse_schema("dir/\n    :source-glob /a/*\n    :source-glob /b/*\n").is_err());
+}
+
+#[test]
+fn version_directive() {
+    let schema = parse_schema("#!diskplan 1\ndir/\n").unwrap();
+    assert_eq!(schema.version, Some(SCHEMA_VERSION));
+    assert_eq!(schema.schema.as_directory().unwrap().entries().len(), 1);
+    assert_eq!(crate::format_schema(&schema), "#!diskplan 1\ndir/\n");
+    assert_eq!(parse_schema("dir/\n").unwrap().version, None);
+
+    // Indented literal schemas may declare a version too
+    let schema = parse_schema("\n    #!diskplan 1\n    dir/\n        sub/\n    ").unwrap();
+    assert_eq!(schema.version, Some(1));
+
+    let error = parse_schema("#!diskplan 2\ndir/\n").unwrap_err();
+    assert_eq!(error.line(), 1);
+    assert!(error
+        .to_string()
+        .contains("Unsupported schema version 2 (this parser supports versions 1 to 1)"));
+    assert!(parse_schema("#!diskplan 0\n").is_err());
+    assert!(parse_schema("#!diskplan one\n").is_err());
+    assert!(parse_schema("#!diskplan\n").is_err());
+    assert!(parse_schema("#!other 1\n").is_err());
+
+    // Only the first line may declare the version
+    let error = parse_schema("dir/\n#!diskplan 1\n").unwrap_err();
+    assert_eq!(error.into_iter().last().unwrap().line(), 2);
+}
+
+#[test]
+fn description() {
+    let schema = parse_schema(
+        "
+        :description Project area (see #42)
+        $project/
+            :description Owned by ${project}, not substituted
+            :match [a-z]+
+        ",
+    )
+    .unwrap();
+    assert_eq!(schema.description, Some("Project area (see #42)"));
+    let (_, project) = &schema.schema.as_directory().unwrap().entries()[0];
+    assert_eq!(
+        project.description,
+        Some("Owned by ${project}, not substituted")
+    );
+    let text = crate::format_schema(&schema);
+    assert!(text.starts_with(":description Project area (see #42)\n"));
+    assert!(text.contains("    :description Owned by ${project}, not substituted\n"));
+    assert_eq!(crate::format_schema(&parse_schema(&text).unwrap()), text);
+
+    assert!(parse_schema("dir/\n    :description\n").is_err());
+    assert!(parse_schema(":description One\n:description Two\n").is_err());
+}
@@ -0,0 +1,107 @@
+use std::fmt::Display;
+
+use crate::{Binding, Identifier, SchemaNode};
+
+/// One step along the path from the root of a schema to a node within it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step<'t> {
+    /// Into the entry of a directory with the given binding
+    Entry(Binding<'t>),
+    /// Into the body of a `:def` with the given name
+    Def(Identifier<'t>),
+}
+
+impl Display for Step<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Entry(binding) => write!(f, "{binding}"),
+            Step::Def(name) => write!(f, ":def {name}"),
+        }
+    }
+}
+
+/// Callbacks for each part of a schema, as walked by [`visit`]
+///
+/// Each callback is given the path of [`Step`]s from the root. Every method does nothing by
+/// default, so only those of interest need be implemented.
+pub trait Visitor<'t> {
+    /// Called for every node (the root, every entry, and the body of every `:def`) before anything
+    /// beneath it, where `path` leads to the node itself
+    fn node(&mut self, path: &[Step<'t>], node: &SchemaNode<'t>) {
+        let _ = (path, node);
+    }
+
+    /// Called for each `:def` within a directory, where `path` leads to the directory
+    fn def(&mut self, path: &[Step<'t>], name: Identifier<'t>, node: &SchemaNode<'t>) {
+        let _ = (path, name, node);
+    }
+
+    /// Called for each entry within a directory, where `path` leads to the directory
+    fn entry(&mut self, path: &[Step<'t>], binding: &Binding<'t>, node: &SchemaNode<'t>) {
+        let _ = (path, binding, node);
+    }
+}
+
+/// Walks every node of a schema depth first, calling the `visitor` for each node, `:def` and
+/// entry found
+///
+/// Within a directory, its `:def`s are visited (in name order) before its entries (in the order
+/// given by [`DirectorySchema::entries`]). Definitions are visited where they are defined, not
+/// where they are `:use`d.
+///
+/// [`DirectorySchema::entries`]: crate::DirectorySchema::entries
+///
+/// Example:
+/// ```
+/// use diskplan_schema::{parse_schema, visit, SchemaNode, SchemaType, Step, Visitor};
+///
+/// struct CountFiles(usize);
+///
+/// impl<'t> Visitor<'t> for CountFiles {
+///     fn node(&mut self, _path: &[Step<'t>], node: &SchemaNode<'t>) {
+///         if let SchemaType::File(_) = node.schema {
+///             self.0 += 1;
+///         }
+///     }
+/// }
+///
+/// let schema = parse_schema(
+///     "
+///     :def readme/
+///         README
+///             :source /templates/readme
+///     $project/
+///         :use readme
+///         notes
+///             :source /dev/null
+///     ",
+/// )
+/// .unwrap();
+/// let mut count = CountFiles(0);
+/// visit(&schema, &mut count);
+/// assert_eq!(count.0, 2);
+/// ```
+pub fn visit<'t>(schema: &SchemaNode<'t>, visitor: &mut impl Visitor<'t>) {
+    visit_node(&mut Vec::new(), schema, visitor);
+}
+
+fn visit_node<'t>(path: &mut Vec<Step<'t>>, node: &SchemaNode<'t>, visitor: &mut impl Visitor<'t>) {
+    visitor.node(path, node);
+    let Some(directory) = node.schema.as_directory() else {
+        return;
+    };
+    let mut defs: Vec<_> = directory.defs().iter().collect();
+    defs.sort_by_key(|(name, _)| **name);
+    for (name, def) in defs {
+        visitor.def(path, *name, def);
+        path.push(Step::Def(*name));
+        visit_node(path, def, visitor);
+        path.pop();
+    }
+    for (binding, entry) in directory.entries() {
+        visitor.entry(path, binding, entry);
+        path.push(Step::Entry(binding.clone()));
+        visit_node(path, entry, visitor);
+        path.pop();
+    }
+}
@@ -0,0 +1,78 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+
+use diskplan_config::Config;
+use diskplan_filesystem::{Filesystem, MemoryFilesystem};
+
+use crate::{traverse, Extent, StackFrame, VariableSource, Visit};
+
+/// Lists every path the schema for `path` would create, given the values of `vars`, without
+/// touching the disk
+///
+/// The schema is traversed over an empty [`MemoryFilesystem`] holding only the configured roots,
+/// so the paths are those it could produce from nothing: fixed names, and variable ones only
+/// where a `:let` or `vars` gives them a value (with nothing on disk to match, dynamic bindings
+/// do not otherwise expand). Sources are not read, so files are listed whether or not their
+/// `:source` exists. The paths are returned sorted, and include symlinks and the targets
+/// created for them.
+///
+/// Example:
+/// ```
+/// use std::collections::HashMap;
+///
+/// use diskplan_config::Config;
+/// use diskplan_filesystem::Root;
+/// use diskplan_schema::parse_schema;
+/// use diskplan_traversal::enumerate;
+///
+/// let mut config = Config::new("/target", false);
+/// let schema = parse_schema(
+///     "
+///     $site/
+///         README
+///             :source /templates/readme
+///     projects/
+///         $project/
+///     ",
+/// )
+/// .unwrap();
+/// config.add_precached_stem(Root::try_from("/target").unwrap(), "/target", schema);
+///
+/// let vars = HashMap::from([("site".to_owned(), "north".to_owned())]);
+/// let paths = enumerate("/target", &config, vars).unwrap();
+/// assert_eq!(
+///     paths,
+///     ["/target/north", "/target/north/README", "/target/projects"]
+/// );
+/// ```
+pub fn enumerate<'g>(
+    path: impl AsRef<Utf8Path>,
+    config: &'g Config<'g>,
+    vars: HashMap<String, String>,
+) -> Result<Vec<Utf8PathBuf>> {
+    // Owners and groups need not exist on this machine to be enumerated
+    let mut fs = MemoryFilesystem::new().with_fake_ids(true);
+    for root in config.stem_roots() {
+        fs.create_directory_all(root.path(), Default::default())?;
+    }
+    let variables = match vars.is_empty() {
+        true => VariableSource::Empty,
+        false => VariableSource::Map(vars),
+    };
+    let created = Mutex::new(vec![]);
+    let collect = |visit: &Visit| {
+        if visit.created {
+            created.lock().unwrap().push(visit.path.to_owned());
+        }
+    };
+    let stack = StackFrame::stack(config, variables, "root", "root", None)
+        .with_observer(&collect)
+        .for_enumeration();
+    traverse(path, &stack, &mut fs, Extent::Full)?;
+
+    let mut created = created.into_inner().unwrap();
+    created.sort();
+    Ok(created)
+}
@@ -1,9 +1,9 @@
-use std::fmt::Display;
+use std::fmt::{Display, Write as _};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 
 use diskplan_filesystem::PlantedPath;
-use diskplan_schema::{Expression, Special, Token};
+use diskplan_schema::{Condition, Expression, Special, Token};
 
 use super::stack;
 
@@ -12,25 +12,89 @@ pub enum Value<'a> {
     String(&'a str),
 }
 
+/// Evaluates an expression to a string, substituting variables and special path tokens
+///
+/// Variables are resolved lazily through the stack, from the innermost frame outwards. That is, a
+/// name bound by a `$variable/` entry or set by a `:let` shadows any value given further out (each
+/// directory holding its names in a `VariableSource::Directory` frame), and the initial variable
+/// map (e.g. from `--vars`) is consulted last, unless the config says that it overrides `:let`
+/// variables. The expression found for a `:let` is itself evaluated at the point of use, so it may
+/// reference other variables and special tokens (such as `${NAME}`), which take the values of the
+/// `path` being evaluated. A variable whose value refers back to itself, directly or through
+/// others, produces an error.
 pub(super) fn evaluate(
     expr: &Expression<'_>,
     stack: &stack::StackFrame,
     path: &PlantedPath,
+) -> Result<String> {
+    evaluate_resolving(expr, stack, path, &mut Vec::new())
+}
+
+/// Evaluates both sides of a `:when` condition and compares them
+///
+/// The condition does not hold if either side refers directly to a variable (or environment
+/// variable) that is not set.
+pub(super) fn evaluate_condition(
+    condition: &Condition<'_>,
+    stack: &stack::StackFrame,
+    path: &PlantedPath,
+) -> Result<bool> {
+    let unset = [&condition.left, &condition.right]
+        .into_iter()
+        .flat_map(|expr| expr.tokens())
+        .any(|token| match token {
+            Token::Variable(var) => stack.lookup(var).is_none(),
+            Token::Environment(name) => stack.config.env_var(name.value()).is_none(),
+            Token::Text(_) | Token::Special(_) => false,
+        });
+    if unset {
+        return Ok(false);
+    }
+    let left = evaluate(&condition.left, stack, path)?;
+    let right = evaluate(&condition.right, stack, path)?;
+    Ok(condition.holds(&left, &right))
+}
+
+fn evaluate_resolving(
+    expr: &Expression<'_>,
+    stack: &stack::StackFrame,
+    path: &PlantedPath,
+    resolving: &mut Vec<String>,
 ) -> Result<String> {
     tracing::trace!(r#"Evaluating expression "{}""#, expr);
     let mut value = String::new();
-    for token in expr.tokens() {
+    for (index, token) in expr.tokens().iter().enumerate() {
         match token {
             Token::Text(text) => value.push_str(text),
             Token::Variable(var) => {
+                if resolving.iter().any(|id| id == var.value()) {
+                    let mut cycle = String::new();
+                    for id in resolving.iter().skip_while(|id| *id != var.value()) {
+                        write!(cycle, "${id} -> ")?;
+                    }
+                    bail!(
+                        "Cycle detected evaluating variable: {}${}{}",
+                        cycle,
+                        var,
+                        located(expr, index)
+                    );
+                }
                 let sub = stack.lookup(var).ok_or_else(|| {
-                    anyhow!(r#"Undefined variable "{}" in expression "{}""#, var, expr)
+                    anyhow!(
+                        r#"Undefined variable "{}" in expression "{}"{}"#,
+                        var,
+                        expr,
+                        located(expr, index)
+                    )
                 })?;
                 tracing::trace!(r#"Variable ${{{}}} = "{}""#, var, sub);
                 match sub {
                     Value::Expression(expr) => {
                         tracing::trace!("Going deeper...");
-                        value.push_str(&evaluate(expr, stack, path)?)
+                        resolving.push(var.value().to_owned());
+                        let sub_value = evaluate_resolving(expr, stack, path, resolving);
+                        resolving.pop();
+                        value.push_str(&sub_value?)
                     }
                     Value::String(s) => value.push_str(s),
                 }
@@ -39,7 +103,10 @@ pub(super) fn evaluate(
                 let it = match special {
                     Special::PathAbsolute => path.absolute().as_str(),
                     Special::PathRelative => path.relative().as_str(),
-                    Special::PathNameOnly => path.relative().file_name().unwrap(),
+                    Special::PathNameOnly => path
+                        .absolute()
+                        .file_name()
+                        .ok_or_else(|| anyhow!("Path has no name: {}", path.absolute()))?,
                     Special::ParentAbsolute => path
                         .absolute()
                         .parent()
@@ -57,16 +124,55 @@ pub(super) fn evaluate(
                         .and_then(|p| p.file_name())
                         .ok_or_else(|| anyhow!("Path has no parent: {}", path.relative()))?,
                     Special::RootPath => path.root().as_str(),
+                    Special::RootName => path
+                        .root()
+                        .file_name()
+                        .ok_or_else(|| anyhow!("Root has no name: {}", path.root()))?,
+                    Special::MatchedName => stack.matched_name().ok_or_else(|| {
+                        anyhow!(
+                            "${{{}}} used outside of any dynamic binding in expression \"{}\"",
+                            special,
+                            expr
+                        )
+                    })?,
+                    Special::MappedOwner => stack.owner(),
+                    Special::MappedGroup => stack.group(),
                 };
                 tracing::trace!(r#"Special {} = "{}""#, special, it);
                 value.push_str(it);
             }
+            Token::Environment(name) => {
+                let it = stack.config.env_var(name.value()).ok_or_else(|| {
+                    anyhow!(
+                        r#"Environment variable "{}" is not set, in expression "{}"{}"#,
+                        name,
+                        expr,
+                        located(expr, index)
+                    )
+                })?;
+                tracing::trace!(r#"Environment {} = "{}""#, name, it);
+                value.push_str(&it);
+            }
         }
     }
     tracing::trace!(r#"Expression "{}" fully evaluated as "{}""#, expr, value);
     Ok(value)
 }
 
+/// Describes where the token at `index` of `expr` was written in the schema, if known, with a
+/// caret beneath it
+fn located(expr: &Expression, index: usize) -> String {
+    match expr.position(index) {
+        Some(position) => format!(
+            " (line {}, byte {})\n{}",
+            position.line_number,
+            position.column,
+            position.to_string().trim_end()
+        ),
+        None => String::new(),
+    }
+}
+
 impl Display for Value<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -0,0 +1,79 @@
+//! Opt-in explanation of how each name within a directory was matched against its schema entries
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use diskplan_filesystem::PlantedPath;
+use diskplan_schema::{Binding, SchemaNode};
+
+use super::{
+    pattern::{CompiledPattern, Rejection},
+    Source,
+};
+
+/// The outcome of considering one schema entry for one name
+pub(super) enum Decision<'a> {
+    /// The entry was bound to this name
+    Matched,
+    /// The entry has a static name, and it is a different one
+    DifferentName,
+    /// The name failed the entry's `:match` or `:avoid` patterns
+    Rejected(Rejection, &'a CompiledPattern),
+    /// The entry is dynamic, and the name is reserved by a static entry in the same directory
+    Reserved,
+    /// The name matched, but so did an earlier entry, which is an error
+    Conflict,
+}
+
+/// Collects [`Decision`]s for the names within a directory, if enabled, to be logged together
+pub(super) struct Explanation {
+    names: Option<BTreeMap<String, String>>,
+}
+
+impl Explanation {
+    pub fn new(enabled: bool) -> Self {
+        Explanation {
+            names: enabled.then(BTreeMap::new),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        name: &str,
+        source: &Source,
+        binding: &Binding,
+        node: &SchemaNode,
+        decision: Decision,
+    ) {
+        let Some(names) = &mut self.names else { return };
+        let lines = names
+            .entry(name.to_owned())
+            .or_insert_with(|| format!("\n  \"{name}\" from {source}:"));
+        let _ = write!(lines, "\n    {binding} (line {}): ", node.line_number);
+        let _ = match decision {
+            Decision::Matched => write!(lines, "matched"),
+            Decision::DifferentName => write!(lines, "different name"),
+            Decision::Rejected(Rejection::Pattern, pattern) => {
+                write!(lines, "does not match {pattern}")
+            }
+            Decision::Rejected(Rejection::Avoided, pattern) => {
+                write!(lines, "avoided by {pattern}")
+            }
+            Decision::Reserved => write!(lines, "reserved by the static entry"),
+            Decision::Conflict => write!(lines, "matched, conflicting with an earlier match"),
+        };
+    }
+
+    /// Logs the explanation collected so far (if any) for the given directory
+    pub fn report(&self, directory_path: &PlantedPath) {
+        if let Some(names) = &self.names {
+            let mut report = format!("Matching names in {directory_path}:");
+            if names.is_empty() {
+                report.push_str(" (no names to match)");
+            }
+            for lines in names.values() {
+                report.push_str(lines);
+            }
+            tracing::info!("{}", report);
+        }
+    }
+}
@@ -4,23 +4,40 @@
 
 use std::{
     borrow::Cow,
-    collections::HashMap,
-    default,
-    fmt::{Display, Write as _},
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Display, Write as _},
 };
 
 use anyhow::{anyhow, bail, Context as _, Result};
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use rayon::prelude::*;
+use sha2::{Digest as _, Sha256};
 use tracing::{span, Level};
 
-use diskplan_filesystem::{Filesystem, PlantedPath, SetAttrs};
-use diskplan_schema::{Binding, DirectorySchema, SchemaNode, SchemaType};
+use diskplan_filesystem::{
+    expand_home, AttrDiff, Filesystem, NodeKind, PlantedPath, SetAttrs, DEFAULT_DIRECTORY_MODE,
+    DEFAULT_FILE_MODE,
+};
+use diskplan_schema::{
+    AttributeValue, Binding, DirectorySchema, Expression, FileSchema, SchemaNode, SchemaType,
+};
 
-use self::{eval::evaluate, pattern::CompiledPattern};
+use self::{
+    eval::{evaluate, evaluate_condition},
+    explain::{Decision, Explanation},
+};
 
+mod enumerate;
 mod eval;
+mod explain;
 mod pattern;
+mod preflight;
+mod route;
 mod stack;
+pub use enumerate::enumerate;
+pub use pattern::{Captures, CompiledPattern, PatternOptions, Rejection};
+pub use preflight::preflight;
+pub use route::{route, RouteStep};
 pub use stack::{StackFrame, VariableSource};
 
 /// Indicates whether to traverse the entire schema or a limited subset
@@ -31,8 +48,34 @@ pub enum Extent {
     Full,
     /// Only traverse the target path through the schema
     Restricted,
+    /// Only traverse the target path through the schema, then take all routes beneath it
+    Subtree,
 }
 
+/// An entry of the schema applied by the traversal, as told to an observer (see
+/// [`StackFrame::with_observer`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Visit<'a> {
+    /// The path of the entry (for a symlink, the path of the link itself)
+    pub path: &'a Utf8Path,
+    /// The kind of entry the schema gives
+    pub kind: NodeKind,
+    /// Whether the entry was newly created, rather than being present already
+    pub created: bool,
+    /// Whether anything was changed to apply the entry: it was created, or moved into place, or
+    /// had its attributes or content corrected
+    pub changed: bool,
+    /// The schema node giving the entry, or `None` for a file created from its directory's
+    /// `:source-glob`
+    pub node: Option<&'a SchemaNode<'a>>,
+    /// The variable bound to the entry's name and its value, if it was matched by a dynamic
+    /// binding
+    pub binding: Option<(&'a str, &'a str)>,
+}
+
+/// Errors recorded against the paths at which they occurred, rather than ending the traversal
+pub type TraversalErrors = Vec<(PlantedPath, anyhow::Error)>;
+
 /// Walks the schema and directory structure in concert, applying or reporting changes
 pub fn traverse<FS>(
     path: impl AsRef<Utf8Path>,
@@ -41,20 +84,58 @@ pub fn traverse<FS>(
     extent: Extent,
 ) -> Result<()>
 where
-    FS: Filesystem,
+    FS: Filesystem + Send,
 {
-    let path = path.as_ref();
+    traverse_recording(path.as_ref(), stack, filesystem, extent, &mut None)
+}
+
+/// Walks the schema and directory structure like [`traverse`], but on encountering an error
+/// within a directory entry, records it and continues with the entry's siblings
+///
+/// This is intended for reporting every problem at once (for example, during a dry run). The
+/// recorded errors are returned at the end; an error is only returned directly if the traversal
+/// cannot begin, or fails at the top level.
+pub fn traverse_best_effort<FS>(
+    path: impl AsRef<Utf8Path>,
+    stack: &StackFrame,
+    filesystem: &mut FS,
+    extent: Extent,
+) -> Result<TraversalErrors>
+where
+    FS: Filesystem + Send,
+{
+    let mut errors = Some(vec![]);
+    traverse_recording(path.as_ref(), stack, filesystem, extent, &mut errors)?;
+    Ok(errors.unwrap_or_default())
+}
+
+fn traverse_recording<FS>(
+    path: &Utf8Path,
+    stack: &StackFrame,
+    filesystem: &mut FS,
+    extent: Extent,
+    errors: &mut Option<TraversalErrors>,
+) -> Result<()>
+where
+    FS: Filesystem + Send,
+{
+    // Sibling subtrees are traversed within a pool of the configured size, where supported
+    let jobs = stack.config.jobs();
+    if jobs > 1 && rayon::current_thread_index().is_none() && filesystem.fork().is_some() {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+        return pool.install(|| traverse_recording(path, stack, filesystem, extent, errors));
+    }
+
+    let path = &expand_home(path)?;
     let span = span!(Level::DEBUG, "traverse", path = path.as_str());
     let _span = span.enter();
 
     if !path.is_absolute() {
         bail!("Path must be absolute: {}", path);
     }
-    let (schema_node, root) = stack.config.schema_for(path)?;
-    let start_path = PlantedPath::new(root, None)?;
-    let remaining_path = path
-        .strip_prefix(root.path())
-        .expect("Located root must prefix path");
+    let (schema_node, planted) = stack.config.planted_schema_for(path)?;
+    let start_path = planted.to_root();
+    let remaining_path = planted.relative();
     tracing::debug!(
         r#"Traversing root directory "{}" ("{}" relative path remains)"#,
         start_path,
@@ -67,6 +148,7 @@ where
         extent,
         stack,
         filesystem,
+        errors,
     )
     .with_context(|| {
         schema_context(
@@ -87,59 +169,185 @@ fn traverse_node<'a, FS>(
     extent: Extent,
     stack: &StackFrame<'a, '_, '_>,
     filesystem: &mut FS,
+    errors: &mut Option<TraversalErrors>,
 ) -> Result<()>
 where
-    FS: Filesystem,
+    FS: Filesystem + Send,
+{
+    let result = apply_node(
+        schema_node,
+        path,
+        remaining,
+        extent,
+        stack,
+        filesystem,
+        errors,
+    );
+    with_stack_dump(result, stack)
+}
+
+fn apply_node<'a, FS>(
+    schema_node: &'a SchemaNode<'a>,
+    path: &PlantedPath,
+    remaining: &Utf8Path,
+    extent: Extent,
+    stack: &StackFrame<'a, '_, '_>,
+    filesystem: &mut FS,
+    errors: &mut Option<TraversalErrors>,
+) -> Result<()>
+where
+    FS: Filesystem + Send,
 {
     let span = span!(Level::DEBUG, "traverse_node", node = schema_node.line);
     let _span = span.enter();
 
+    if let Some(max_depth) = stack.config.max_depth() {
+        if stack.directory_depth() > max_depth {
+            bail!(
+                "Maximum depth ({}) exceeded at {} (is the schema unintentionally recursive?)",
+                max_depth,
+                path
+            );
+        }
+    }
+
     let mut unresolved = if remaining == "" { None } else { Some(vec![]) };
-    let expanded = expand_uses(schema_node, stack)?;
+    let expanded = expand_uses(schema_node, stack, path)?;
+
+    // Skip this entry, and all beneath it, unless every condition (of this node and the
+    // definitions it uses) holds
+    for (usage, args) in &expanded {
+        if let Some(condition) = &usage.when {
+            if !evaluate_condition(condition, &stack.push(args.clone()), path)? {
+                tracing::info!("Skipping {} (not :when {})", path, condition);
+                return Ok(());
+            }
+        }
+    }
 
-    // Resolve attributes from all used definitions
+    // Resolve attributes from all used definitions (along with any arguments passed to them)
     let mut owner = None;
     let mut group = None;
     let mut mode = None;
-    for usage in std::iter::once(&schema_node).chain(expanded.iter()) {
-        owner = owner.or(usage.attributes.owner.as_ref());
-        group = group.or(usage.attributes.group.as_ref());
-        mode = mode.or(usage.attributes.mode);
+    let mut source = None;
+    for (usage, args) in &expanded {
+        owner = owner.or(usage.attributes.owner.as_ref().map(|expr| (expr, args)));
+        group = group.or(usage.attributes.group.as_ref().map(|expr| (expr, args)));
+        mode = mode.or(usage
+            .attributes
+            .mode
+            .map(|mode| (mode, usage.attributes.mode_initial)));
+        source = source.or(usage
+            .schema
+            .as_directory()
+            .and_then(DirectorySchema::source)
+            .map(|expr| (expr, args)));
     }
-    // Evaluate attribute expressions
+    // Only the attributes given here, or by an ancestor (whether inherited or seeded by
+    // :recursive), are corrected on an existing entry; any the schema leaves unset are kept
+    let given = stack.given();
+    let owner_given = matches!(owner, Some((AttributeValue::Explicit(_), _))) || given.owner;
+    let group_given = matches!(group, Some((AttributeValue::Explicit(_), _))) || given.group;
+    let mode_given = matches!(mode, Some((AttributeValue::Explicit(_), _))) || given.mode;
+    // Evaluate attribute expressions (the first found wins, though it may say to inherit)
     let evaluated_owner;
     let owner = match owner {
-        Some(expr) => {
-            evaluated_owner = evaluate(expr, stack, path)?;
+        Some((AttributeValue::Explicit(expr), args)) => {
+            evaluated_owner = evaluate(expr, &stack.push(args.clone()), path)?;
             Some(stack.config.map_user(&evaluated_owner))
         }
-        None => Some(stack.owner()),
+        Some((AttributeValue::Inherit, _)) | None => Some(stack.owner()),
     };
     let evaluated_group;
     let group = match group {
-        Some(expr) => {
-            evaluated_group = evaluate(expr, stack, path)?;
+        Some((AttributeValue::Explicit(expr), args)) => {
+            evaluated_group = evaluate(expr, &stack.push(args.clone()), path)?;
             Some(stack.config.map_group(&evaluated_group))
         }
-        None => Some(stack.group()),
+        Some((AttributeValue::Inherit, _)) | None => Some(stack.group()),
     };
-    let mode = Some(mode.map(Into::into).unwrap_or_else(|| stack.mode()));
+    // A mode given by :mode-initial is only set on creation, and not corrected thereafter
+    let mode_initial = matches!(mode, Some((_, true)));
+    // Without a mode given here or by an ancestor, use the default for this type of entry
+    let mode = Some(match mode.map(|(mode, _)| mode) {
+        Some(AttributeValue::Explicit(mode)) => mode.into(),
+        Some(AttributeValue::Inherit) | None => stack.mode().unwrap_or(match schema_node.schema {
+            SchemaType::Directory(_) => DEFAULT_DIRECTORY_MODE,
+            SchemaType::File(_) => DEFAULT_FILE_MODE,
+        }),
+    });
     let attrs = SetAttrs { owner, group, mode };
-
+    // An existing entry keeps any attribute not given, and any mode that was only for creation
+    let reconcile = SetAttrs {
+        owner: owner.filter(|_| owner_given),
+        group: group.filter(|_| group_given),
+        mode: mode.filter(|_| mode_given && !mode_initial),
+    };
+    // The owner and group are in scope for any source, as ${MAPPED_OWNER} and ${MAPPED_GROUP}
     let mut stack = stack.push(VariableSource::Empty);
-    if let Some(owner) = owner {
+    if let Some(owner) = owner.filter(|_| owner_given) {
         stack.put_owner(owner);
     }
-    if let Some(group) = group {
+    if let Some(group) = group.filter(|_| group_given) {
         stack.put_group(group);
     }
+    // A directory's source (to be copied from when it is created) is also taken from the first
+    // of this node and the definitions it uses to give one
+    let source = match source {
+        Some((expr, args)) => {
+            let source = evaluate(expr, &stack.push(args.clone()), path)?;
+            Some(stack.config.resolve_source(source))
+        }
+        None => None,
+    };
+
+    // Owner and group always carry down to descendants, but mode only does so if :recursive
+    let recursive = expanded.iter().any(|(usage, _)| {
+        usage
+            .schema
+            .as_directory()
+            .map(DirectorySchema::recursive)
+            .unwrap_or_default()
+    });
+    if let Some(mode) = mode.filter(|_| recursive && mode_given) {
+        stack.put_mode(mode);
+    }
     let stack = &stack;
 
-    for schema_node in expanded {
+    // Whether this entry is present already is noted for the observer, if any
+    let kind = match (&schema_node.symlink, &schema_node.schema) {
+        (Some(_), _) => NodeKind::Symlink,
+        (None, SchemaType::Directory(_)) => NodeKind::Directory,
+        (None, SchemaType::File(_)) => NodeKind::File,
+    };
+    let existed = filesystem.exists(path.absolute()) || filesystem.is_link(path.absolute());
+
+    let node = schema_node;
+    for (index, (schema_node, args)) in expanded.into_iter().enumerate() {
         tracing::debug!("Applying: {}", schema_node);
+        let stack = &stack.push(args);
         // Create this entry, following symlinks
-        create(schema_node, path, attrs.clone(), stack, filesystem)
-            .with_context(|| format!("Creating {}", &path))?;
+        let changed = create(
+            schema_node,
+            path,
+            attrs.clone(),
+            reconcile.clone(),
+            source.as_deref(),
+            stack,
+            filesystem,
+        );
+        let changed =
+            with_stack_dump(changed, stack).with_context(|| format!("Creating {}", &path))?;
+        if index == 0 {
+            stack.observe(&Visit {
+                path: path.absolute(),
+                kind,
+                created: !existed,
+                changed: !existed || changed,
+                node: Some(node),
+                binding: stack.entry_binding(),
+            });
+        }
 
         // Traverse over children
         if let SchemaType::Directory(ref directory_schema) = schema_node.schema {
@@ -151,14 +359,17 @@ where
                 extent,
                 stack,
                 filesystem,
-            )
-            .with_context(|| {
+                errors,
+            );
+            let directory_stack = stack.push(VariableSource::Directory(directory_schema));
+            let resolution = with_stack_dump(resolution, &directory_stack).with_context(|| {
+                // Report the directory's own variables too, as its entries were applied with them
                 schema_context(
                     "Applying directory schema",
                     schema_node,
                     path.absolute(),
                     remaining,
-                    stack,
+                    &stack.push(VariableSource::Directory(directory_schema)),
                 )
             })?;
             match resolution {
@@ -209,6 +420,7 @@ enum Source {
     Disk,
     Path,
     Schema,
+    Glob,
 }
 
 impl Display for Source {
@@ -217,6 +429,7 @@ impl Display for Source {
             Source::Disk => write!(f, "on disk"),
             Source::Path => write!(f, "the target path"),
             Source::Schema => write!(f, "the schema"),
+            Source::Glob => write!(f, ":source-glob"),
         }
     }
 }
@@ -228,16 +441,26 @@ fn schema_context(
     remaining: &Utf8Path,
     stack: &StackFrame,
 ) -> anyhow::Error {
+    let mut variables = String::from("Variables in scope:");
+    let in_scope = stack.variables_in_scope();
+    if in_scope.is_empty() {
+        variables.push_str("\n  (none)");
+    }
+    for (name, value) in in_scope {
+        let _ = write!(variables, "\n  ${name} = \"{value}\"");
+    }
     anyhow!(
-        "{}\n  To path: \"{}\" (\"{}\" remaining)\n  {}\n{}",
+        "{}\n  To path: \"{}\" (\"{}\" remaining)\n  {}\n{}\n{}",
         message,
         path,
         remaining,
         schema_node,
         stack,
+        variables,
     )
 }
 
+#[allow(clippy::too_many_arguments)]
 fn traverse_directory<'a, FS>(
     schema_node: &SchemaNode,
     directory_schema: &'a DirectorySchema,
@@ -246,13 +469,17 @@ fn traverse_directory<'a, FS>(
     extent: Extent,
     stack: &StackFrame<'a, '_, '_>,
     filesystem: &mut FS,
+    errors: &mut Option<TraversalErrors>,
 ) -> Result<Resolution>
 where
-    FS: Filesystem,
+    FS: Filesystem + Send,
 {
-    if let (Extent::Restricted, "") = (extent, remaining.as_ref()) {
-        return Ok(Resolution::FullyResolved);
-    }
+    let extent = match (extent, remaining.as_str()) {
+        (Extent::Restricted, "") => return Ok(Resolution::FullyResolved),
+        // Having reached the target of a subtree traversal, populate everything beneath it
+        (Extent::Subtree, "") => Extent::Full,
+        (extent, _) => extent,
+    };
     let stack = stack.push(VariableSource::Directory(directory_schema));
 
     // Pull the front off the relative remaining_path
@@ -266,34 +493,96 @@ where
             (Some(remaining.as_str()), Utf8Path::new(""))
         });
 
-    // Collect an unordered map of names (each mapped to None) for...
+    // Collect an ordered map of names (each mapped to None) for...
     //  - what's on disk
     //  - the next component of our intended path (sought)
     //  - any static bindings
     //  - any variable bindings for which we have a value from the stack
     //    and whose value matches the node's match pattern
     //
-    let mut names: HashMap<Cow<str>, (Source, Option<_>)> = HashMap::new();
+    let mut names: BTreeMap<Cow<str>, (Source, Option<_>)> = BTreeMap::new();
     let with_source = |src: Source| move |key| (key, (src, None));
+
+    // When ignoring case, names from the target path and schema take the spelling found on disk
+    let ignore_case = directory_schema.ignore_case();
+    let listing = match (extent, ignore_case) {
+        (Extent::Full, _) | (_, true) => filesystem
+            .list_directory(directory_path.absolute())
+            .unwrap_or_default(),
+        _ => vec![],
+    };
+    let disk_spelling: HashMap<String, String> = match ignore_case {
+        true => listing
+            .iter()
+            .map(|name| (name.to_lowercase(), name.clone()))
+            .collect(),
+        false => HashMap::new(),
+    };
+    let spelling = |name: &str| disk_spelling.get(&name.to_lowercase()).cloned();
+    let sought = sought.map(|name| match spelling(name) {
+        Some(on_disk) => Cow::Owned(on_disk),
+        None => Cow::Borrowed(name),
+    });
+    let sought = sought.as_deref();
+
     if let Extent::Full = extent {
         names.extend(
-            filesystem
-                .list_directory(directory_path.absolute())
-                .unwrap_or_default()
+            listing
                 .into_iter()
                 .map(Cow::Owned)
                 .map(with_source(Source::Disk)),
         );
     }
     names.extend(sought.map(Cow::Borrowed).map(with_source(Source::Path)));
+
+    // Files matching the directory's :source-glob are candidates for a file of the same name
+    let globbed = match directory_schema.source_glob() {
+        Some(pattern) if !stack.enumerating() => {
+            glob_sources(pattern, &stack, directory_path, filesystem)?
+        }
+        _ => BTreeMap::new(),
+    };
+    for name in globbed.keys() {
+        if matches!(extent, Extent::Full) || sought == Some(name.as_str()) {
+            names
+                .entry(Cow::Owned(name.clone()))
+                .or_insert((Source::Glob, None));
+        }
+    }
+
+    // Static names are reserved for their own entries, so are never candidates for a dynamic
+    // binding, sparing the schema author an `:avoid` for each of them
+    let static_names: Vec<&str> = directory_schema
+        .entries()
+        .iter()
+        .filter_map(|(binding, _)| match binding {
+            Binding::Static(name) => Some(*name),
+            Binding::Dynamic(_) => None,
+        })
+        .collect();
+    let is_reserved = |name: &str| {
+        static_names
+            .iter()
+            .any(|reserved| same_name(reserved, name, ignore_case))
+    };
+
     let mut compiled_schema_entries = Vec::with_capacity(directory_schema.entries().len());
     for (binding, child_node) in directory_schema.entries() {
-        // Note: Since we don't know the name of the thing we're matching yet, any path
-        // variable (e.g. SAME_PATH_NAME) used in the pattern expression will be evaluated
-        // using the parent directory
+        // Variables in the pattern take their values from this directory's stack, which holds
+        // the names bound by it and its ancestors. Since we don't know the name of the thing
+        // we're matching yet, any path variable (e.g. SAME_PATH_NAME) is evaluated using this
+        // (parent) directory too
         let pattern = CompiledPattern::compile(
-            child_node.match_pattern.as_ref(),
-            child_node.avoid_pattern.as_ref(),
+            child_node
+                .match_pattern
+                .as_ref()
+                .map(|pattern| (pattern, child_node.match_anchoring)),
+            &child_node.avoid_patterns,
+            PatternOptions {
+                avoid_hidden: child_node.avoid_hidden,
+                ignore_case,
+                match_path: child_node.match_path,
+            },
             &stack,
             directory_path,
         )?;
@@ -304,9 +593,16 @@ where
             Binding::Static(name) => Some(Cow::Borrowed(name)),
             Binding::Dynamic(var) => evaluate(&var.into(), &stack, directory_path)
                 .ok()
-                .filter(|name| pattern.matches(name))
+                .filter(|name| {
+                    pattern.matches(&pattern_text(name, child_node, directory_path))
+                        && !is_reserved(name)
+                })
                 .map(Cow::Owned),
         } {
+            let name = match spelling(&name) {
+                Some(on_disk) => Cow::Owned(on_disk),
+                None => name,
+            };
             names.insert(name, (Source::Schema, None));
         }
         compiled_schema_entries.push((binding, child_node, pattern));
@@ -317,56 +613,105 @@ where
     // Traverse the directory schema's sub-entries (static first, then variable), updating the
     // map of names so each matched name points to its binding and schema node.
     //
+    let mut explanation = Explanation::new(stack.config.explain());
     for (binding, child_node, pattern) in compiled_schema_entries {
         // Match this static/variable binding and schema against all names, flagging any conflicts
-        // with previously matched names. Since static bindings are ordered first, and static-
-        // then-variable conflicts explicitly ignored
-        for (name, (_, have_match)) in names.iter_mut() {
-            match binding {
+        // with previously matched names. Static names are excluded from variable bindings, so
+        // only variable-then-variable conflicts can arise
+        for (name, (source, have_match)) in names.iter_mut() {
+            let (decision, result) = match binding {
                 // Static binding produces a match for that name only
-                Binding::Static(bound_name) if bound_name == name => match have_match {
-                    // Didn't already have a match for this name
-                    None => {
-                        *have_match = Some((binding, child_node));
-                        Ok(())
-                    }
-                    // Somehow already had a match. This should be impossible
-                    Some((bound, _)) => Err(anyhow!(
-                        r#""{}" matches multiple static bindings "{}" and "{}""#,
-                        name,
-                        bound,
-                        binding
-                    )),
-                },
-                // Dynamic bindings must match their inner schema pattern
-                Binding::Dynamic(_) if pattern.matches(name) => {
+                Binding::Static(bound_name) if same_name(bound_name, name, ignore_case) => {
                     match have_match {
                         // Didn't already have a match for this name
                         None => {
                             *have_match = Some((binding, child_node));
-                            Ok(())
+                            (Decision::Matched, Ok(()))
                         }
-                        // Name and schema pattern matched. See if we had a conflicting match
-                        Some((bound, _)) => match bound {
-                            Binding::Static(_) => Ok(()), // Keep previous static binding
-                            Binding::Dynamic(_) => Err(anyhow!(
-                                r#""{}" matches multiple dynamic bindings "{}" and "{}" (latter matched: {})"#,
+                        // Somehow already had a match. This should be impossible
+                        Some((bound, _)) => (
+                            Decision::Conflict,
+                            Err(anyhow!(
+                                r#""{}" matches multiple static bindings "{}" and "{}""#,
                                 name,
                                 bound,
-                                binding,
-                                pattern,
+                                binding
                             )),
+                        ),
+                    }
+                }
+                Binding::Static(_) => (Decision::DifferentName, Ok(())),
+                // Dynamic bindings never take a name reserved by a static binding
+                Binding::Dynamic(_) if is_reserved(name) => (Decision::Reserved, Ok(())),
+                // Dynamic bindings must match their inner schema pattern
+                Binding::Dynamic(_) => {
+                    match pattern.rejection(&pattern_text(name, child_node, directory_path)) {
+                        Some(rejection) => (Decision::Rejected(rejection, &pattern), Ok(())),
+                        None => match have_match {
+                            // Didn't already have a match for this name
+                            None => {
+                                *have_match = Some((binding, child_node));
+                                (Decision::Matched, Ok(()))
+                            }
+                            // Name and schema pattern matched. See if we had a conflicting match
+                            Some((bound, bound_node)) => match bound {
+                                // Keep previous static binding (though reserved names are
+                                // already excluded above)
+                                Binding::Static(_) => (Decision::Reserved, Ok(())),
+                                Binding::Dynamic(_) => (
+                                    Decision::Conflict,
+                                    Err(anyhow!(
+                                        concat!(
+                                            r#""{}" matches multiple dynamic bindings "{}" and "{}" (latter matched: {})"#,
+                                            "\n  Line {}: {}",
+                                            "\n  Line {}: {}",
+                                        ),
+                                        name,
+                                        bound,
+                                        binding,
+                                        pattern,
+                                        bound_node.line_number,
+                                        bound_node.line,
+                                        child_node.line_number,
+                                        child_node.line,
+                                    )),
+                                ),
+                            },
                         },
                     }
                 }
-                _ => Ok(()),
-            }?;
+            };
+            explanation.record(name, source, binding, child_node, decision);
+            if result.is_err() {
+                explanation.report(directory_path);
+            }
+            result?;
+        }
+    }
+    explanation.report(directory_path);
+    if ignore_case {
+        // Matched names that differ only by case would refer to the same entry
+        let mut matched: HashMap<String, &str> = HashMap::new();
+        for (name, _) in names
+            .iter()
+            .filter(|(_, (_, have_match))| have_match.is_some())
+        {
+            if let Some(other) = matched.insert(name.to_lowercase(), name) {
+                bail!(
+                    r#""{}" and "{}" differ only by case, which is ambiguous with :ignore-case"#,
+                    other,
+                    name
+                );
+            }
         }
     }
 
-    // Report
+    // Report (in order of name, so the output is stable from one run to the next)
     for (name, (source, have_match)) in names.iter() {
         match have_match {
+            None if globbed.contains_key(name.as_ref()) => {
+                tracing::trace!(r#""{}" from {} matches :source-glob"#, name, source)
+            }
             None => tracing::warn!(
                 r#""{}" from {} has no match in "{}" under {}"#,
                 name,
@@ -386,14 +731,34 @@ where
             ),
         }
     }
+    if stack.config.fail_on_unmatched() {
+        let unmatched: Vec<_> = names
+            .iter()
+            .filter(|(name, (_, have_match))| {
+                have_match.is_none() && !globbed.contains_key(name.as_ref())
+            })
+            .map(|(name, _)| format!("{name:?}"))
+            .collect();
+        if !unmatched.is_empty() {
+            bail!(
+                r#"No match in "{}" under {} for: {}"#,
+                directory_path,
+                schema_node,
+                unmatched.join(", ")
+            );
+        }
+    }
 
     // Consider nothing to seek as if it were found
     let mut sought_matched = sought.is_none();
 
-    for (name, (_, matched)) in names {
-        let Some((binding, child_schema)) = matched else { continue };
+    let mut children = Vec::new();
+    for (name, (_, matched)) in names.iter() {
+        let Some((binding, child_schema)) = *matched else {
+            continue;
+        };
         let name = name.as_ref();
-        let child_path = directory_path.join(name)?;
+        let child_path = directory_path.join_component(name)?;
 
         // If this name is part of the target path, record that we found a match and keep
         // traversing that path. If it is not, we're no longer completing the target path
@@ -402,61 +767,84 @@ where
             sought_matched = true;
             remaining
         } else {
-            if let Extent::Restricted = extent {
+            if !matches!(extent, Extent::Full) {
                 continue;
             }
             Utf8Path::new("")
         };
+        children.push((name, binding, child_schema, child_path, remaining));
+    }
 
-        match binding {
-            Binding::Static(s) => {
-                tracing::debug!(
-                    r#"Traversing static directory entry "{}" at {} ("{}" relative path remains)"#,
-                    s,
-                    &child_path,
-                    remaining,
-                );
-                traverse_node(
-                    child_schema,
-                    &child_path,
-                    remaining,
-                    extent,
-                    &stack,
-                    filesystem,
+    // Sibling entries are independent, so may be traversed in parallel if the filesystem allows
+    let forks = match stack.config.jobs() > 1 && children.len() > 1 {
+        true => children
+            .iter()
+            .map(|_| filesystem.fork())
+            .collect::<Option<Vec<_>>>(),
+        false => None,
+    };
+    match forks {
+        Some(forks) => {
+            let results: Vec<_> = children
+                .into_par_iter()
+                .zip(forks)
+                .map(
+                    |((name, binding, child_schema, child_path, remaining), mut filesystem)| {
+                        let mut child_errors = errors.as_ref().map(|_| vec![]);
+                        let result = traverse_entry(
+                            name,
+                            binding,
+                            child_schema,
+                            &child_path,
+                            remaining,
+                            extent,
+                            &stack,
+                            &mut filesystem,
+                            &mut child_errors,
+                        );
+                        (child_path, result, child_errors)
+                    },
                 )
-                .with_context(|| format!("Processing path {}", &child_path))?;
+                .collect();
+            // Results are gathered in order, so are reported as they would be if serial
+            for (child_path, result, child_errors) in results {
+                if let (Some(errors), Some(child_errors)) = (errors.as_mut(), child_errors) {
+                    errors.extend(child_errors);
+                }
+                record_or_return(result, child_path, errors)?;
             }
-            Binding::Dynamic(var) => {
-                tracing::debug!(
-                    r#"Traversing variable directory entry ${}="{}" at {} ("{}" relative path remains)"#,
-                    var,
+        }
+        None => {
+            for (name, binding, child_schema, child_path, remaining) in children {
+                let result = traverse_entry(
                     name,
-                    &child_path,
-                    remaining,
-                );
-                let stack = StackFrame::push(&stack, VariableSource::Binding(var, name.into()));
-                traverse_node(
+                    binding,
                     child_schema,
                     &child_path,
                     remaining,
                     extent,
                     &stack,
                     filesystem,
-                )
-                .with_context(|| {
-                    format!(
-                        r#"Processing path {} (with {})"#,
-                        &child_path,
-                        &stack
-                            .variables()
-                            .as_binding()
-                            .map(|(var, value)| format!("${var} = {value}"))
-                            .unwrap_or_else(|| "<no binding>".into()),
-                    )
-                })?;
+                    errors,
+                );
+                record_or_return(result, child_path, errors)?;
             }
         }
     }
+
+    // Files from the :source-glob are created only where no entry of the schema claims the name
+    for (name, (_, matched)) in names.iter() {
+        let Some(source) = globbed.get(name.as_ref()).filter(|_| matched.is_none()) else {
+            continue;
+        };
+        if sought == Some(name.as_ref()) && remaining == "" {
+            sought_matched = true;
+        }
+        let child_path = directory_path.join_component(name)?;
+        let result = create_globbed_file(source, &child_path, &stack, filesystem)
+            .with_context(|| format!("Processing path {child_path}"));
+        record_or_return(result, child_path, errors)?;
+    }
     if !sought_matched {
         let unresolved = Utf8PathBuf::from(format!("{}/{}", sought.unwrap(), remaining));
         Ok(Resolution::Unresolved(unresolved))
@@ -465,15 +853,87 @@ where
     }
 }
 
+/// Traverses a single matched entry of a directory, binding its name if the binding is dynamic
+#[allow(clippy::too_many_arguments)]
+fn traverse_entry<'a, FS>(
+    name: &str,
+    binding: &'a Binding,
+    child_schema: &'a SchemaNode,
+    child_path: &PlantedPath,
+    remaining: &Utf8Path,
+    extent: Extent,
+    stack: &StackFrame<'a, '_, '_>,
+    filesystem: &mut FS,
+    errors: &mut Option<TraversalErrors>,
+) -> Result<()>
+where
+    FS: Filesystem + Send,
+{
+    match binding {
+        Binding::Static(s) => {
+            tracing::debug!(
+                r#"Traversing static directory entry "{}" at {} ("{}" relative path remains)"#,
+                s,
+                child_path,
+                remaining,
+            );
+            traverse_node(
+                child_schema,
+                child_path,
+                remaining,
+                extent,
+                stack,
+                filesystem,
+                errors,
+            )
+            .with_context(|| format!("Processing path {child_path}"))
+        }
+        Binding::Dynamic(var) => {
+            tracing::debug!(
+                r#"Traversing variable directory entry ${}="{}" at {} ("{}" relative path remains)"#,
+                var,
+                name,
+                child_path,
+                remaining,
+            );
+            let stack = StackFrame::push(stack, VariableSource::Binding(var, name.into()));
+            traverse_node(
+                child_schema,
+                child_path,
+                remaining,
+                extent,
+                &stack,
+                filesystem,
+                errors,
+            )
+            .with_context(|| {
+                format!(
+                    r#"Processing path {} (with {})"#,
+                    child_path,
+                    &stack
+                        .variables()
+                        .as_binding()
+                        .map(|(var, value)| format!("${var} = {value}"))
+                        .unwrap_or_else(|| "<no binding>".into()),
+                )
+            })
+        }
+    }
+}
+
+/// Creates the entry given by `schema_node`, or corrects it if present already, returning
+/// whether anything was changed
 fn create<FS>(
     schema_node: &SchemaNode,
     path: &PlantedPath,
     attrs: SetAttrs,
+    reconcile: SetAttrs,
+    directory_source: Option<&Utf8Path>,
     stack: &StackFrame,
     filesystem: &mut FS,
-) -> Result<()>
+) -> Result<bool>
 where
-    FS: Filesystem,
+    FS: Filesystem + Send,
 {
     let span = span!(
         Level::DEBUG,
@@ -489,44 +949,55 @@ where
     let link_path;
     let link_target;
 
+    let mut changed = false;
     let to_create;
+    let root;
     if let Some(expr) = &schema_node.symlink {
         link_str = evaluate(expr, stack, path)?;
         link_path = Utf8Path::new(&link_str);
         tracing::info!("Creating {} -> {}", path, link_path);
 
-        // Allow relative symlinks only if there is no schema to apply to the target (allowing us
-        // to create it and return early)
-        if !link_path.is_absolute() {
-            if schema_node.attributes.is_empty()
-                && schema_node.uses.is_empty()
-                && schema_node
-                    .schema
-                    .as_directory()
-                    .map(|d| d.entries().is_empty())
-                    .unwrap_or_default()
-            {
-                filesystem
-                    .create_symlink(path.absolute(), link_path)
-                    .context("As symlink")?;
-                return Ok(());
+        // Relative symlinks are resolved against the link's parent directory so the schema
+        // covering the target can be found and applied, just as for absolute links
+        let resolved_path;
+        let target_path = if link_path.is_absolute() {
+            link_path
+        } else {
+            resolved_path = resolve_relative(path.absolute(), link_path)?;
+            resolved_path.as_path()
+        };
+
+        // Without a root covering the target, allow relative symlinks only if there is no
+        // schema to apply to the target (allowing us to create it and return early)
+        if !link_path.is_absolute()
+            && !stack
+                .config
+                .stem_roots()
+                .any(|root| target_path.starts_with(root.path()))
+        {
+            if is_trivial_link_target(schema_node) {
+                let created = create_symlink(path.absolute(), link_path, filesystem)?;
+                let set = apply_link_attributes(schema_node, path, stack, filesystem)?;
+                return Ok(created || set);
             } else {
                 bail!(concat!(
                     "Relative paths in symlinks are only supported for directories whose schema ",
-                    "nodes have no attributes, use statements, or child entries"
+                    "nodes have no attributes (other than inherit), use statements, sources or ",
+                    "child entries, unless the target is within a configured root"
                 ));
             }
         }
 
-        let (_, link_root) = stack.config.schema_for(link_path).with_context(|| {
-            anyhow!(
-                "No schema found for symlink target {} -> {}",
-                path,
-                link_path
-            )
-        })?;
-        link_target = PlantedPath::new(link_root, Some(link_path))
-            .with_context(|| format!("Following symlink {path} -> {link_path}"))?;
+        (_, link_target) = stack
+            .config
+            .planted_schema_for(target_path)
+            .with_context(|| {
+                anyhow!(
+                    "No schema found for symlink target {} -> {}",
+                    path,
+                    target_path
+                )
+            })?;
 
         // Create the link target (using its own schema to build it)
         if !filesystem.exists(link_target.absolute()) {
@@ -536,54 +1007,533 @@ where
                 filesystem,
                 Extent::Restricted,
             )?;
-            assert!(filesystem.exists(link_target.absolute()));
+            // The target's own schema may have skipped it (by :when), leaving nothing to link to
+            if !filesystem.exists(link_target.absolute()) {
+                bail!(
+                    "Symlink target {} -> {} was skipped by :when (so was not created)",
+                    path,
+                    link_target
+                );
+            }
         }
-        // Create the symlink pointing to the target
-        filesystem
-            .create_symlink(path.absolute(), link_target.absolute())
-            .context("As symlink")?;
+        // Create the symlink pointing to the target (as written, so relative links stay relative)
+        changed |= create_symlink(path.absolute(), link_path, filesystem)?;
+        changed |= apply_link_attributes(schema_node, path, stack, filesystem)?;
         // Use the target path for creation. Further traversal will use the original
         // path, and resolve canonical paths through the symlink
         to_create = link_target.absolute();
+        root = link_target.root();
     } else {
         tracing::info!("Creating {}", path);
         to_create = path.absolute();
+        root = path.root();
+    }
+
+    // Move any existing entry from its old location (relative to this entry's parent), which
+    // must lie within the same root
+    if let Some(expr) = &schema_node.rename_from {
+        let old = evaluate(expr, stack, path)?;
+        let old = match to_create.parent() {
+            Some(parent) => parent.join(old),
+            None => old.into(),
+        };
+        let old = match normalize(&old) {
+            Some(old) if old.starts_with(root) => old,
+            _ => bail!(
+                "Cannot rename {} to {}: it lies outside the root {}",
+                old,
+                to_create,
+                root
+            ),
+        };
+        if filesystem.exists(&old) || filesystem.is_link(&old) {
+            if filesystem.exists(to_create) || filesystem.is_link(to_create) {
+                bail!(
+                    "Cannot rename {} to {}: both exist (remove or merge one first)",
+                    old,
+                    to_create
+                );
+            }
+            tracing::info!("Renaming {} to {}", old, to_create);
+            filesystem
+                .rename(&old, to_create)
+                .with_context(|| format!("Renaming from {old}"))?;
+            changed = true;
+        }
     }
 
+    // A single look at any existing entry gives both its type and how its attributes differ
+    let existing = filesystem
+        .metadata(to_create)
+        .map(|existing| (existing.kind, existing.attrs.diff(&reconcile)));
     match &schema_node.schema {
         SchemaType::Directory(_) => {
-            if !filesystem.is_directory(to_create) {
+            if let Some((NodeKind::Directory, diff)) = existing {
+                changed |= reconcile_attributes(to_create, diff, filesystem)?;
+            } else {
                 tracing::debug!("Make directory: {}", to_create);
                 filesystem
-                    .create_directory(to_create, attrs)
+                    .create_directory(to_create, attrs.clone())
                     .context("As directory")?;
-            } else {
-                let dir_attrs = filesystem.attributes(to_create)?;
-                if !attrs.matches(&dir_attrs) {
-                    filesystem.set_attributes(to_create, attrs)?;
+                changed = true;
+                // The copy is made before any child entries are applied, so they may refine it
+                if let Some(source) = directory_source.filter(|_| !stack.enumerating()) {
+                    check_source_present(source, to_create, stack, filesystem)?;
+                    tracing::info!("Copying {} into {}", source, to_create);
+                    filesystem
+                        .copy_tree(source, to_create, attrs)
+                        .with_context(|| format!("Copying from source directory {source}"))?;
                 }
             }
         }
         SchemaType::File(file) => {
-            if !filesystem.is_file(to_create) {
-                let source = evaluate(file.source(), stack, path)?;
-                let content = filesystem.read_file(source)?;
-                filesystem
-                    .create_file(to_create, attrs, content)
+            let existing_diff = match existing {
+                Some((NodeKind::File, diff)) => Some(diff),
+                _ => None,
+            };
+            // Files are only ever created if absent, so one made elsewhere in the meantime (by
+            // another process, or another path to the same target) is left as it is
+            if existing_diff.is_none() && stack.enumerating() {
+                changed |= filesystem
+                    .create_file_if_absent(to_create, attrs, vec![])
                     .context("As file")?;
+            } else if let Some(diff) = existing_diff {
+                // An existing file with unexpected content is repaired from its source, but only
+                // if the source itself has the expected content
+                if let Some(expected) = file.sha256() {
+                    if check_sha256(filesystem, to_create, expected).is_err() {
+                        let source = file_source(file, stack, path, filesystem)?;
+                        check_sha256(filesystem, &source, expected).with_context(|| {
+                            format!("Cannot repair {to_create} from its source")
+                        })?;
+                        let content = filesystem.read_file_bytes(&source)?;
+                        tracing::info!(
+                            "Rewriting {} from {} (checksum mismatch)",
+                            to_create,
+                            source
+                        );
+                        filesystem
+                            .write_file_bytes(to_create, content)
+                            .context("As file")?;
+                        changed = true;
+                    }
+                }
+                changed |= reconcile_attributes(to_create, diff, filesystem)?;
+            } else {
+                let source = file_source(file, stack, path, filesystem)?;
+                check_source_present(&source, to_create, stack, filesystem)?;
+                if let Some(expected) = file.sha256() {
+                    check_sha256(filesystem, &source, expected)?;
+                }
+                if filesystem
+                    .copy_if_absent(&source, to_create, attrs)
+                    .context("As file")?
+                {
+                    changed = true;
+                } else {
+                    tracing::debug!("File {} was created elsewhere meanwhile", to_create);
+                }
             }
         }
     }
+    Ok(changed)
+}
+
+/// Sets on an existing entry those attributes found to differ from its own, leaving the rest,
+/// returning whether any were set
+fn reconcile_attributes<FS>(path: &Utf8Path, diff: AttrDiff, filesystem: &mut FS) -> Result<bool>
+where
+    FS: Filesystem,
+{
+    if diff.is_empty() {
+        return Ok(false);
+    }
+    tracing::info!("Setting {} on {}", diff, path);
+    filesystem.set_attributes(path, diff.into())?;
+    Ok(true)
+}
+
+/// Lists the files matching a directory's `:source-glob` pattern (after evaluation and
+/// resolution), mapping the name of each to its path
+fn glob_sources<FS>(
+    pattern: &Expression,
+    stack: &StackFrame,
+    path: &PlantedPath,
+    filesystem: &FS,
+) -> Result<BTreeMap<String, Utf8PathBuf>>
+where
+    FS: Filesystem,
+{
+    let pattern = stack.config.resolve_source(evaluate(pattern, stack, path)?);
+    let (Some(directory), Some(name_pattern)) = (pattern.parent(), pattern.file_name()) else {
+        bail!("Invalid :source-glob pattern: {}", pattern);
+    };
+    if directory.as_str().contains(['*', '?']) {
+        bail!(
+            "Only the last component of a :source-glob pattern may contain wildcards: {}",
+            pattern
+        );
+    }
+    let mut regex = String::from("^");
+    for ch in name_pattern.chars() {
+        match ch {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            ch => regex.push_str(&regex::escape(ch.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    let regex = regex::Regex::new(&regex)?;
+    // As in the shell, wildcards do not match the leading dot of a hidden file
+    let hidden_allowed = name_pattern.starts_with('.');
+
+    let mut found = BTreeMap::new();
+    for name in filesystem
+        .list_directory(directory)
+        .with_context(|| format!("Listing {directory} for :source-glob {name_pattern}"))?
+    {
+        let source = directory.join(&name);
+        if (hidden_allowed || !name.starts_with('.'))
+            && regex.is_match(&name)
+            && filesystem.is_file(&source)
+        {
+            found.insert(name, source);
+        }
+    }
+    Ok(found)
+}
+
+/// Creates a file copied from a match of its directory's `:source-glob`, unless it is present
+/// already, giving it the attributes a file entry without tags would have
+fn create_globbed_file<FS>(
+    source: &Utf8Path,
+    path: &PlantedPath,
+    stack: &StackFrame,
+    filesystem: &mut FS,
+) -> Result<()>
+where
+    FS: Filesystem,
+{
+    let attrs = SetAttrs {
+        owner: Some(stack.owner()),
+        group: Some(stack.group()),
+        mode: Some(stack.mode().unwrap_or(DEFAULT_FILE_MODE)),
+    };
+    let created = filesystem
+        .copy_if_absent(source, path.absolute(), attrs)
+        .context("As file")?;
+    if created {
+        tracing::info!("Created {} from {}", path, source);
+    }
+    stack.observe(&Visit {
+        path: path.absolute(),
+        kind: NodeKind::File,
+        created,
+        changed: created,
+        node: None,
+        binding: None,
+    });
+    Ok(())
+}
+
+/// Returns the first of the file's `:source` candidates to exist (after evaluation and
+/// resolution), or the only one given whether or not it exists
+fn file_source<FS>(
+    file: &FileSchema,
+    stack: &StackFrame,
+    path: &PlantedPath,
+    filesystem: &FS,
+) -> Result<Utf8PathBuf>
+where
+    FS: Filesystem,
+{
+    let mut candidates = Vec::with_capacity(file.sources().len());
+    for expr in file.sources() {
+        let source = stack.config.resolve_source(evaluate(expr, stack, path)?);
+        if filesystem.exists(&source) {
+            return Ok(source);
+        }
+        candidates.push(source);
+    }
+    match candidates.len() {
+        1 => Ok(candidates.remove(0)),
+        _ => {
+            let candidates: Vec<_> = candidates.iter().map(|c| c.as_str()).collect();
+            Err(anyhow!(
+                "None of the sources for {} exist: {}",
+                path,
+                candidates.join(", ")
+            ))
+        }
+    }
+}
+
+/// Creates a symlink at `path` pointing to `target`, unless it is already present, returning
+/// whether it was created
+///
+/// An existing symlink to the same target is left alone. Anything else found at `path`, be it a
+/// symlink to another target or a file or directory, is an error, and is never replaced.
+fn create_symlink<FS>(path: &Utf8Path, target: &Utf8Path, filesystem: &mut FS) -> Result<bool>
+where
+    FS: Filesystem,
+{
+    if filesystem.is_link(path) {
+        let existing = filesystem.read_link(path)?;
+        if existing != target {
+            bail!(
+                concat!(
+                    "Cannot create symlink {} -> {}: it exists already, pointing to {} ",
+                    "(remove it to have it replaced)"
+                ),
+                path,
+                target,
+                existing
+            );
+        }
+        tracing::debug!("Symlink {} -> {} already exists", path, target);
+        return Ok(false);
+    }
+    if filesystem.exists(path) {
+        let kind = match filesystem.is_directory(path) {
+            true => "directory",
+            false => "file",
+        };
+        bail!(
+            concat!(
+                "Cannot create symlink {} -> {}: a {} exists there already ",
+                "(remove or move it to have it replaced)"
+            ),
+            path,
+            target,
+            kind
+        );
+    }
+    filesystem
+        .create_symlink(path, target)
+        .context("As symlink")?;
+    Ok(true)
+}
+
+/// Sets the owner and group of the symlink at `path` itself, as given by `:link-owner` and
+/// `:link-group`, where these differ from those it has, returning whether any were set
+fn apply_link_attributes<FS>(
+    schema_node: &SchemaNode,
+    path: &PlantedPath,
+    stack: &StackFrame,
+    filesystem: &mut FS,
+) -> Result<bool>
+where
+    FS: Filesystem,
+{
+    if schema_node.link_owner.is_none() && schema_node.link_group.is_none() {
+        return Ok(false);
+    }
+    let owner = schema_node
+        .link_owner
+        .as_ref()
+        .map(|expr| evaluate(expr, stack, path))
+        .transpose()?;
+    let group = schema_node
+        .link_group
+        .as_ref()
+        .map(|expr| evaluate(expr, stack, path))
+        .transpose()?;
+    let attrs = SetAttrs {
+        owner: owner.as_deref().map(|owner| stack.config.map_user(owner)),
+        group: group.as_deref().map(|group| stack.config.map_group(group)),
+        mode: None,
+    };
+    let diff = filesystem.link_attributes(path.absolute())?.diff(&attrs);
+    if diff.is_empty() {
+        return Ok(false);
+    }
+    filesystem
+        .set_link_attributes(path.absolute(), diff.into())
+        .context("As symlink")?;
+    Ok(true)
+}
+
+/// Returns the text against which a `name` in `directory_path` is tested by the patterns of
+/// `child_node`: the name itself or, with `:match-path`, its path relative to the root
+fn pattern_text<'n>(
+    name: &'n str,
+    child_node: &SchemaNode,
+    directory_path: &PlantedPath,
+) -> Cow<'n, str> {
+    match child_node.match_path {
+        true => Cow::Owned(directory_path.relative().join(name).into_string()),
+        false => Cow::Borrowed(name),
+    }
+}
+
+/// Checks that the `source` to be copied into `to_create` exists, explaining the dependency if
+/// it is absent but lies within a root, as it may be one that diskplan itself has yet to create
+fn check_source_present(
+    source: &Utf8Path,
+    to_create: &Utf8Path,
+    stack: &StackFrame,
+    filesystem: &impl Filesystem,
+) -> Result<()> {
+    if filesystem.exists(source) {
+        return Ok(());
+    }
+    if let Some(root) = stack
+        .config
+        .stem_roots()
+        .filter(|root| source.starts_with(root.path()))
+        .max_by_key(|root| root.path().as_str().len())
+    {
+        bail!(
+            concat!(
+                "Source \"{}\" for \"{}\" does not exist yet, but lies within root {}, so may ",
+                "be one that diskplan has still to create; it must be created first (for example, ",
+                "by applying a schema to \"{}\" before this one)",
+            ),
+            source,
+            to_create,
+            root.path(),
+            source,
+        );
+    }
+    Ok(())
+}
+
+/// Returns true if nothing would be done at the target of a symlink given by `schema_node`
+/// beyond creating it as a directory
+///
+/// Tags that only affect how the link itself is matched (such as `:match`), and attributes that
+/// are only inherited, leave nothing to be done.
+fn is_trivial_link_target(schema_node: &SchemaNode) -> bool {
+    schema_node.attributes.is_inherited()
+        && schema_node.uses.is_empty()
+        && schema_node
+            .schema
+            .as_directory()
+            .map(|d| d.entries().is_empty() && d.source().is_none())
+            .unwrap_or_default()
+}
+
+/// Checks that the SHA-256 digest of the file at `path` is the `expected` one, reading it as a
+/// stream so that large files are not held in memory
+fn check_sha256<FS>(filesystem: &FS, path: &Utf8Path, expected: &str) -> Result<()>
+where
+    FS: Filesystem,
+{
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut filesystem.open_file(path)?, &mut hasher)
+        .with_context(|| format!("Reading {path}"))?;
+    let actual = format!("{:x}", hasher.finalize());
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!(
+            "Checksum mismatch for {}: expected sha256 {}, found {}",
+            path,
+            expected,
+            actual
+        );
+    }
     Ok(())
 }
 
+/// Resolves a relative symlink target against the parent directory of the link, normalizing
+/// any `.` and `..` components
+fn resolve_relative(link: &Utf8Path, target: &Utf8Path) -> Result<Utf8PathBuf> {
+    let parent = link
+        .parent()
+        .ok_or_else(|| anyhow!("Symlink has no parent directory: {}", link))?;
+    normalize(&parent.join(target))
+        .ok_or_else(|| anyhow!("Symlink target {} escapes the root from {}", target, link))
+}
+
+/// Normalizes any `.` and `..` components of an absolute path (without following symlinks), or
+/// returns `None` if a `..` would go above `/`
+fn normalize(path: &Utf8Path) -> Option<Utf8PathBuf> {
+    let mut normalized = Utf8PathBuf::new();
+    for component in path.components() {
+        match component {
+            Utf8Component::CurDir => {}
+            Utf8Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    Some(normalized)
+}
+
+fn same_name(a: &str, b: &str, ignore_case: bool) -> bool {
+    match ignore_case {
+        true => a.to_lowercase() == b.to_lowercase(),
+        false => a == b,
+    }
+}
+
+/// A dump of the stack at the point of an error, attached to it as context
+struct StackDump(String);
+
+impl Display for StackDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Debug for StackDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Attaches a dump of the `stack` to any error, if the config asks for one and the error does
+/// not carry one already (from deeper in the traversal, where the stack was more complete)
+fn with_stack_dump<T>(result: Result<T>, stack: &StackFrame) -> Result<T> {
+    match result {
+        Err(error)
+            if stack.config.dump_stack_on_error()
+                && error.downcast_ref::<StackDump>().is_none() =>
+        {
+            Err(error.context(StackDump(stack.dump())))
+        }
+        result => result,
+    }
+}
+
+/// Passes on the result of traversing `path`, unless this is a best-effort traversal, in which
+/// case any error is recorded against the path so that traversal can continue
+fn record_or_return(
+    result: Result<()>,
+    path: PlantedPath,
+    errors: &mut Option<TraversalErrors>,
+) -> Result<()> {
+    match (result, errors) {
+        (Err(error), Some(errors)) => {
+            tracing::warn!("Continuing past error at {}: {}", path, error);
+            errors.push((path, error));
+            Ok(())
+        }
+        (result, _) => result,
+    }
+}
+
 fn expand_uses<'a>(
     schema_node: &'a SchemaNode<'_>,
     stack: &StackFrame<'a, '_, '_>,
-) -> Result<Vec<&'a SchemaNode<'a>>> {
-    // Expand `schema_node` to itself and any `:use`s within
+    path: &PlantedPath,
+) -> Result<Vec<(&'a SchemaNode<'a>, VariableSource<'a>)>> {
+    // Expand `schema_node` to itself and any `:use`s within, each with the variables (from any
+    // arguments) to be bound while applying it
     let mut use_schemas = Vec::with_capacity(1 + schema_node.uses.len());
-    use_schemas.push(schema_node);
+    use_schemas.push((schema_node, VariableSource::Empty));
+    expand_uses_into(schema_node, stack, path, &mut use_schemas)?;
+    Ok(use_schemas)
+}
+
+fn expand_uses_into<'a>(
+    schema_node: &'a SchemaNode<'_>,
+    stack: &StackFrame<'a, '_, '_>,
+    path: &PlantedPath,
+    use_schemas: &mut Vec<(&'a SchemaNode<'a>, VariableSource<'a>)>,
+) -> Result<()> {
     // Include schema_node itself and its :defs in the stack frame
     let stack = stack.push(match schema_node {
         SchemaNode {
@@ -594,13 +1544,47 @@ fn expand_uses<'a>(
     });
     for used in &schema_node.uses {
         tracing::trace!("Seeking definition of '{}'", used);
-        use_schemas.push(
-            stack
-                .find_definition(used)
-                .ok_or_else(|| anyhow!("No definition (:def) found for \"{}\"", used))?,
-        );
+        let definition = stack
+            .find_definition(&used.name)
+            .ok_or_else(|| anyhow!("No definition (:def) found for \"{}\"", used.name))?;
+        if definition.params.len() != used.args.len() {
+            bail!(
+                "Definition \"{}\" takes {} argument(s) but {} given in \":use {}\"",
+                used.name,
+                definition.params.len(),
+                used.args.len(),
+                used,
+            );
+        }
+        let expanding = stack.expanding();
+        if let Some(start) = expanding.iter().position(|name| *name == used.name) {
+            let mut cycle = String::new();
+            for name in &expanding[start..] {
+                write!(cycle, "{name} -> ")?;
+            }
+            bail!(
+                "Cycle detected in :use of definitions: {}{}",
+                cycle,
+                used.name
+            );
+        }
+        let args = if used.args.is_empty() {
+            VariableSource::Empty
+        } else {
+            let mut map = HashMap::with_capacity(used.args.len());
+            for (param, arg) in definition.params.iter().zip(&used.args) {
+                map.insert(param.value().to_owned(), evaluate(arg, &stack, path)?);
+            }
+            VariableSource::Map(map)
+        };
+        use_schemas.push((definition, args.clone()));
+
+        // Expand any further definitions used by this one (with its arguments in scope)
+        let mut stack = stack.push(args);
+        stack.put_expanding(used.name);
+        expand_uses_into(definition, &stack, path, use_schemas)?;
     }
-    Ok(use_schemas)
+    Ok(())
 }
 
 #[cfg(test)]
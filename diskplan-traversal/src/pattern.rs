@@ -1,20 +1,82 @@
-use std::fmt::Display;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Mutex, OnceLock},
+};
 
 use anyhow::Result;
+pub use regex::Captures;
 use regex::Regex;
 
 use diskplan_filesystem::PlantedPath;
-use diskplan_schema::Expression;
+use diskplan_schema::{Anchoring, Expression};
 
 use super::{eval::evaluate, stack};
 
+/// The `:match` and `:avoid` patterns of a schema node, evaluated and compiled, against which
+/// names are tested exactly as they are during traversal
+///
+/// Patterns are anchored at both ends, so a name matches only if the whole of it does: a
+/// `:match` of `[a-z]+` is tested as `^(?:[a-z]+)$` and does not match `abc1`. The same is true
+/// of each `:avoid` pattern. A `:match-prefix` is anchored only at the start (`^(?:[a-z]+)`) and
+/// a `:match-contains` not at all. Without a `:match`, any name matches (unless avoided). Where the
+/// patterns are compiled for `:match-path`, the text tested is instead the path relative to the
+/// root (for example `area/name`), so a pattern may depend on ancestry.
+///
+/// Example:
+/// ```
+/// use diskplan_config::Config;
+/// use diskplan_filesystem::{PlantedPath, Root};
+/// use diskplan_schema::{Anchoring, Expression, Token};
+/// use diskplan_traversal::{CompiledPattern, PatternOptions, StackFrame};
+///
+/// let config = Config::new("/target", false);
+/// let stack = StackFrame::stack(&config, Default::default(), "root", "root", Some(0o755.into()));
+/// let path = PlantedPath::new(&Root::try_from("/target").unwrap(), None).unwrap();
+///
+/// let match_pattern = Expression::from(vec![Token::Text("zone_([a-z])")]);
+/// let avoid_patterns = [Expression::from(vec![Token::Text("zone_x")])];
+/// let match_pattern = Some((&match_pattern, Anchoring::Full));
+/// let options = PatternOptions::default();
+/// let pattern =
+///     CompiledPattern::compile(match_pattern, &avoid_patterns, options, &stack, &path).unwrap();
+/// assert!(pattern.matches("zone_a"));
+/// assert!(!pattern.matches("zone_ab")); // Anchored to the end of the name
+/// assert!(!pattern.matches("zone_x")); // Avoided
+/// assert_eq!(&pattern.captures("zone_b").unwrap()[1], "b");
+/// ```
 #[derive(Debug)]
-pub(super) enum CompiledPattern {
+pub enum CompiledPattern {
+    /// Any name matches
     Any,
+    /// Names matching the (anchored) regular expression match
     Regex(regex::Regex),
+    /// Names matching the first (anchored) regular expression match, unless they also match the
+    /// second
     RegexWithExclusions(regex::Regex, regex::Regex),
 }
 
+/// How the patterns of a [`CompiledPattern`] are applied, all off by default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PatternOptions {
+    /// Whether hidden names (those starting with a dot) are avoided too
+    pub avoid_hidden: bool,
+    /// Whether patterns match without regard to case
+    pub ignore_case: bool,
+    /// Whether patterns are tested against the path relative to the root instead of the name (in
+    /// which case a hidden name is one whose last component starts with a dot)
+    pub match_path: bool,
+}
+
+/// Why a name failed to match a [`CompiledPattern`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The name did not match the `:match` pattern
+    Pattern,
+    /// The name matched one of the `:avoid` patterns
+    Avoided,
+}
+
 impl Display for CompiledPattern {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -28,38 +90,109 @@ impl Display for CompiledPattern {
 }
 
 impl CompiledPattern {
+    /// Evaluates the given `:match` (with its anchoring) and `:avoid` pattern expressions (for the
+    /// given `path`) and compiles them as the given options direct
     pub fn compile(
-        match_pattern: Option<&Expression>,
-        avoid_pattern: Option<&Expression>,
+        match_pattern: Option<(&Expression, Anchoring)>,
+        avoid_patterns: &[Expression],
+        options: PatternOptions,
         stack: &stack::StackFrame,
         path: &PlantedPath,
     ) -> Result<CompiledPattern> {
-        let match_pattern = match match_pattern {
-            Some(expr) => Some(evaluate(expr, stack, path)?),
-            None => None,
+        let regexes = stack.regexes();
+        let flags = if options.ignore_case { "(?i)" } else { "" };
+        let (match_pattern, (start, end)) = match match_pattern {
+            Some((expr, anchoring)) => (
+                Some(evaluate(expr, stack, path)?),
+                match anchoring {
+                    Anchoring::Full => ("^", "$"),
+                    Anchoring::Prefix => ("^", ""),
+                    Anchoring::Contains => ("", ""),
+                },
+            ),
+            None => (None, ("^", "$")),
         };
-        let avoid_pattern = match avoid_pattern {
-            Some(expr) => Some(evaluate(expr, stack, path)?),
-            None => None,
-        };
-        Ok(match (&match_pattern, &avoid_pattern) {
-            (None, None) => CompiledPattern::Any,
-            (Some(pattern), None) => {
-                Regex::new(pattern)?; // Ensure it's valid before encasing to avoid injection
-                CompiledPattern::Regex(Regex::new(&format!("^(?:{pattern})$"))?)
+        let mut avoiding = Vec::with_capacity(avoid_patterns.len());
+        for expr in avoid_patterns {
+            let pattern = evaluate(expr, stack, path)?;
+            regexes.get(&pattern)?; // Ensure each is valid before combining
+            avoiding.push(format!("(?:{pattern})"));
+        }
+        match (options.avoid_hidden, options.match_path) {
+            (false, _) => {}
+            (true, false) => avoiding.push(r"(?:\..*)".to_owned()),
+            (true, true) => avoiding.push(r"(?:(?:.*/)?\.[^/]*)".to_owned()),
+        }
+        Ok(match (&match_pattern, avoiding.is_empty()) {
+            (None, true) => CompiledPattern::Any,
+            (Some(pattern), true) => {
+                regexes.get(pattern)?; // Ensure it's valid before encasing to avoid injection
+                CompiledPattern::Regex(regexes.get(&format!("{flags}{start}(?:{pattern}){end}"))?)
             }
-            (_, Some(avoiding)) => {
+            (_, false) => {
                 let pattern = match_pattern.as_deref().unwrap_or(".*");
-                Regex::new(pattern)?;
-                Regex::new(avoiding)?;
+                regexes.get(pattern)?;
+                // A name is excluded if it matches any of the avoid patterns
+                let avoiding = avoiding.join("|");
                 CompiledPattern::RegexWithExclusions(
-                    Regex::new(&format!("^(?:{pattern})$"))?,
-                    Regex::new(&format!("^(?:{avoiding})$"))?,
+                    regexes.get(&format!("{flags}{start}(?:{pattern}){end}"))?,
+                    regexes.get(&format!("{flags}^(?:{avoiding})$"))?,
                 )
             }
         })
     }
 
+    /// Returns the reason the given text does not match, or `None` if it does
+    pub fn rejection(&self, text: &str) -> Option<Rejection> {
+        match self {
+            Self::Any => None,
+            Self::Regex(ref regex) => (!regex.is_match(text)).then_some(Rejection::Pattern),
+            Self::RegexWithExclusions(ref regex, ref excl) => {
+                if !regex.is_match(text) {
+                    Some(Rejection::Pattern)
+                } else if excl.is_match(text) {
+                    Some(Rejection::Avoided)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Returns true if the given text matches (and is not avoided)
+    ///
+    /// How much of the text the `:match` pattern must cover depends on its anchoring: all of it
+    /// for a `:match`, only its start for a `:match-prefix`, and any part of it for a
+    /// `:match-contains`. An `:avoid` pattern must always cover the whole text.
+    ///
+    /// Example:
+    /// ```
+    /// use diskplan_config::Config;
+    /// use diskplan_filesystem::{PlantedPath, Root};
+    /// use diskplan_schema::{Anchoring, Expression, Token};
+    /// use diskplan_traversal::{CompiledPattern, StackFrame};
+    ///
+    /// let config = Config::new("/target", false);
+    /// let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    /// let path = PlantedPath::new(&Root::try_from("/target").unwrap(), None).unwrap();
+    /// let compile = |anchoring| {
+    ///     let expr = Expression::from(vec![Token::Text("log")]);
+    ///     let avoid = [Expression::from(vec![Token::Text("catalog")])];
+    ///     let options = Default::default();
+    ///     CompiledPattern::compile(Some((&expr, anchoring)), &avoid, options, &stack, &path)
+    ///         .unwrap()
+    /// };
+    ///
+    /// let prefix = compile(Anchoring::Prefix);
+    /// assert!(prefix.matches("logs"));
+    /// assert!(!prefix.matches("blog"));
+    /// assert_eq!(&prefix.captures("logs").unwrap()[0], "log");
+    ///
+    /// let contains = compile(Anchoring::Contains);
+    /// assert!(contains.matches("blogs"));
+    /// assert!(!contains.matches("catalog")); // Avoided, as the whole name
+    /// assert!(contains.matches("catalogs"));
+    /// ```
     pub fn matches(&self, text: &str) -> bool {
         match self {
             Self::Any => true,
@@ -69,4 +202,50 @@ impl CompiledPattern {
             }
         }
     }
+
+    /// Returns the groups captured by the `:match` pattern if the given text matches (and is not
+    /// avoided), or `None` if it does not
+    ///
+    /// The text matches as it would for [`CompiledPattern::matches`], so with a `:match-prefix`
+    /// or `:match-contains`, group 0 is only the part of the text the pattern covered. Otherwise,
+    /// including where there is no `:match` (when it is the only group), group 0 is the whole
+    /// text.
+    pub fn captures<'t>(&self, text: &'t str) -> Option<Captures<'t>> {
+        match self {
+            Self::Any => {
+                static ANY: OnceLock<Regex> = OnceLock::new();
+                ANY.get_or_init(|| Regex::new("(?s)^.*$").expect("Valid pattern"))
+                    .captures(text)
+            }
+            Self::Regex(ref regex) => regex.captures(text),
+            Self::RegexWithExclusions(ref regex, ref excl) => {
+                regex.captures(text).filter(|_| !excl.is_match(text))
+            }
+        }
+    }
+}
+
+/// Compiled regular expressions, kept for reuse by a single traversal (held by its outermost
+/// [`StackFrame`](stack::StackFrame))
+///
+/// Patterns are compiled for each child entry of a schema each time its directory is entered, so
+/// a schema applied across many dynamic directories would otherwise compile the same (evaluated)
+/// patterns over and over. With 3000 sibling directories each holding three patterned entries,
+/// caching reduces traversal time from around 3.5s to 0.2s (release build, in memory). Cloning a
+/// compiled [`Regex`] is cheap, as its internals are shared. The cache is dropped with the stack,
+/// so a long-running process applying many schemas does not accumulate every pattern it has seen.
+#[derive(Default)]
+pub(crate) struct RegexCache(Mutex<HashMap<String, Regex>>);
+
+impl RegexCache {
+    /// Compiles the given regular expression, or reuses an earlier compilation of the same pattern
+    pub fn get(&self, pattern: &str) -> Result<Regex> {
+        let mut cache = self.0.lock().expect("Lock poisoned");
+        if let Some(regex) = cache.get(pattern) {
+            return Ok(regex.clone());
+        }
+        let regex = Regex::new(pattern)?;
+        cache.insert(pattern.to_owned(), regex.clone());
+        Ok(regex)
+    }
 }
@@ -0,0 +1,63 @@
+use std::fmt::Write as _;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+
+use diskplan_filesystem::{Filesystem, RecordingFilesystem};
+
+use crate::{traverse_best_effort, Extent, StackFrame};
+
+/// Checks that applying the schema to `path` would succeed, before anything is changed
+///
+/// The traversal is made in full, as by [`traverse_best_effort`], but over a
+/// [`RecordingFilesystem`] so that the real filesystem is read and never changed. Every `:source`
+/// reachable is evaluated and checked to exist, as is a schema for every symlink target, and
+/// every problem found is listed in a single error. The observer of `stack`, if any, is not told
+/// of the entries visited.
+///
+/// Afterwards, `filesystem` holds the changes that would be made, so that the preflight of one
+/// target takes account of those before it (as when producing several in turn).
+///
+/// Example:
+/// ```
+/// use diskplan_config::Config;
+/// use diskplan_filesystem::{Filesystem, MemoryFilesystem, RecordingFilesystem, Root};
+/// use diskplan_schema::parse_schema;
+/// use diskplan_traversal::{preflight, StackFrame};
+///
+/// let mut fs = MemoryFilesystem::new();
+/// fs.create_directory("/target", Default::default()).unwrap();
+///
+/// let mut config = Config::new("/target", false);
+/// let schema = parse_schema("a\n    :source /missing/a\nb\n    :source /missing/b\n").unwrap();
+/// config.add_precached_stem(Root::try_from("/target").unwrap(), "/target", schema);
+/// let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+///
+/// let mut recording = RecordingFilesystem::new(fs);
+/// let error = preflight("/target", &stack, &mut recording, Default::default()).unwrap_err();
+/// assert!(error.to_string().starts_with("Preflight found 2 problem(s)"));
+/// assert!(!recording.into_inner().exists("/target/a"));
+/// ```
+pub fn preflight<FS>(
+    path: impl AsRef<Utf8Path>,
+    stack: &StackFrame,
+    filesystem: &mut RecordingFilesystem<FS>,
+    extent: Extent,
+) -> Result<()>
+where
+    FS: Filesystem + Send,
+{
+    let stack = stack.push_unobserved();
+    let errors = traverse_best_effort(path, &stack, filesystem, extent)?;
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let mut message = format!(
+        "Preflight found {} problem(s), so nothing was changed:",
+        errors.len()
+    );
+    for (path, error) in &errors {
+        write!(message, "\n  At {path}: {error:#}")?;
+    }
+    Err(anyhow!(message))
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+
+use diskplan_config::Config;
+use diskplan_filesystem::{Filesystem, MemoryFilesystem, NodeKind};
+use diskplan_schema::{SchemaNode, SchemaType};
+
+use crate::{traverse, Extent, StackFrame, VariableSource, Visit};
+
+/// An entry along the route through the schema to a path, as found by [`route`]
+///
+/// This displays over several lines, giving the path, then (indented) the schema line and any
+/// binding, symlink target and source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteStep {
+    /// The path of the entry
+    pub path: Utf8PathBuf,
+    /// The kind of entry the schema gives
+    pub kind: NodeKind,
+    /// The number and text (trimmed) of the schema line giving the entry, where the root of a
+    /// schema is line zero, or `None` for a file created by its directory's `:source-glob`
+    pub line: Option<(usize, String)>,
+    /// The variable bound to the entry's name and its value, if it was matched by a dynamic
+    /// binding
+    pub binding: Option<(String, String)>,
+    /// The path a symlink points to, as it would be created
+    pub target: Option<Utf8PathBuf>,
+    /// The `:source` of the entry, as written in the schema
+    pub source: Option<String>,
+}
+
+impl Display for RouteStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path)?;
+        match &self.line {
+            Some((0, _)) => write!(f, "\n    Schema: (root)")?,
+            Some((number, line)) => write!(f, "\n    Schema line {number}: {line}")?,
+            None => write!(f, "\n    Schema: (from :source-glob)")?,
+        }
+        if let Some((var, value)) = &self.binding {
+            write!(f, "\n    Binding: ${var} = {value}")?;
+        }
+        if let Some(target) = &self.target {
+            write!(f, "\n    Target: {target}")?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, "\n    Source: {source}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Traces the route through the schema to `path`, given the values of `vars`, returning each
+/// entry along it from the root of the schema down to the path itself, without touching the disk
+///
+/// The route is found by a restricted traversal (see [`Extent::Restricted`]) over a
+/// [`MemoryFilesystem`] holding only the configured roots, as [`enumerate`](crate::enumerate)
+/// does, so it shows how the path would be produced from nothing. It is an error if the schema
+/// cannot produce the path.
+///
+/// Example:
+/// ```
+/// use std::collections::HashMap;
+///
+/// use diskplan_config::Config;
+/// use diskplan_filesystem::Root;
+/// use diskplan_schema::parse_schema;
+/// use diskplan_traversal::route;
+///
+/// let mut config = Config::new("/target", false);
+/// let schema = parse_schema(
+///     "
+///     $zone/
+///         admin/
+///     ",
+/// )
+/// .unwrap();
+/// config.add_precached_stem(Root::try_from("/target").unwrap(), "/target", schema);
+///
+/// let steps = route("/target/zone_a/admin", &config, HashMap::new()).unwrap();
+/// let paths: Vec<_> = steps.iter().map(|step| step.path.as_str()).collect();
+/// assert_eq!(paths, ["/target", "/target/zone_a", "/target/zone_a/admin"]);
+/// assert_eq!(
+///     steps[1].binding,
+///     Some(("zone".to_owned(), "zone_a".to_owned()))
+/// );
+/// ```
+pub fn route<'g>(
+    path: impl AsRef<Utf8Path>,
+    config: &'g Config<'g>,
+    vars: HashMap<String, String>,
+) -> Result<Vec<RouteStep>> {
+    let path = path.as_ref();
+    // Owners and groups need not exist on this machine to be traced
+    let mut fs = MemoryFilesystem::new().with_fake_ids(true);
+    for root in config.stem_roots() {
+        fs.create_directory_all(root.path(), Default::default())?;
+    }
+    let variables = match vars.is_empty() {
+        true => VariableSource::Empty,
+        false => VariableSource::Map(vars),
+    };
+    // Only entries on the way to the path are kept (not those of any symlink target, say), and
+    // each only as first visited
+    let steps = Mutex::new(BTreeMap::new());
+    let collect = |visit: &Visit| {
+        if path.starts_with(visit.path) {
+            steps
+                .lock()
+                .unwrap()
+                .entry(visit.path.to_owned())
+                .or_insert_with(|| step(visit));
+        }
+    };
+    let stack = StackFrame::stack(config, variables, "root", "root", None)
+        .with_observer(&collect)
+        .for_enumeration();
+    traverse(path, &stack, &mut fs, Extent::Restricted)?;
+
+    // Ancestors sort before their descendants
+    let mut steps: Vec<RouteStep> = steps.into_inner().unwrap().into_values().collect();
+    for step in &mut steps {
+        if step.kind == NodeKind::Symlink {
+            step.target = Some(fs.read_link(&step.path)?);
+        }
+    }
+    Ok(steps)
+}
+
+fn step(visit: &Visit) -> RouteStep {
+    RouteStep {
+        path: visit.path.to_owned(),
+        kind: visit.kind,
+        line: visit
+            .node
+            .map(|node| (node.line_number, node.line.trim().to_owned())),
+        binding: visit
+            .binding
+            .map(|(var, value)| (var.to_owned(), value.to_owned())),
+        target: None,
+        source: visit.node.and_then(source),
+    }
+}
+
+fn source(node: &SchemaNode) -> Option<String> {
+    match &node.schema {
+        SchemaType::Directory(directory) => directory.source().map(ToString::to_string),
+        SchemaType::File(file) => {
+            let sources: Vec<_> = file.sources().iter().map(ToString::to_string).collect();
+            Some(sources.join(", "))
+        }
+    }
+}
@@ -1,12 +1,14 @@
 use std::{
-    collections::HashMap,
-    fmt::{Debug, Display},
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Display, Write as _},
 };
 
-use crate::eval::Value;
+use anyhow::Result;
+
+use crate::{eval::Value, pattern::RegexCache, Visit};
 use diskplan_config::Config;
-use diskplan_filesystem::Mode;
-use diskplan_schema::{DirectorySchema, Identifier, SchemaNode};
+use diskplan_filesystem::{Mode, PlantedPath};
+use diskplan_schema::{DirectorySchema, Expression, Identifier, SchemaNode};
 
 /// Keeps track of variables and provides access to definitions from parent
 /// nodes
@@ -42,30 +44,124 @@ where
     /// Collection of variables and values at this level of the stack
     variables: VariableSource<'g>,
 
+    /// The definition being expanded (by `:use`) at this level of the stack, if any
+    expanding: Option<Identifier<'g>>,
+
     /// The owner (after mapping) of this level, inherited by children
     owner: &'l str,
     /// The group (after mapping) of this level, inherited by children
     group: &'l str,
-    /// The mode of this level, inherited by children
-    mode: Mode,
+    /// The mode of this level, if any has been set, inherited by children
+    mode: Option<Mode>,
+    /// Which of the owner, group and mode have been put by the schema (at this level or an outer
+    /// one), rather than being the defaults the stack was constructed with
+    given: Given,
+
+    /// A callback told of each entry applied by the traversal, if any
+    observer: Option<&'l (dyn Fn(&Visit) + Sync)>,
+
+    /// Whether the traversal only enumerates paths, creating files empty rather than reading
+    /// their sources
+    enumerating: bool,
+
+    /// The regular expressions compiled for the traversal, held by the outermost frame only
+    regexes: Option<RegexCache>,
 }
 
 impl<'g, 'p, 'l> StackFrame<'g, 'p, 'l> {
     /// Constructs a new stack
+    ///
+    /// Without a `mode`, anything the schema does not give a mode to is given the default mode
+    /// for its type (see [`DEFAULT_DIRECTORY_MODE`] and [`DEFAULT_FILE_MODE`])
+    ///
+    /// [`DEFAULT_DIRECTORY_MODE`]: diskplan_filesystem::DEFAULT_DIRECTORY_MODE
+    /// [`DEFAULT_FILE_MODE`]: diskplan_filesystem::DEFAULT_FILE_MODE
     pub fn stack(
         config: &'g Config<'g>,
         variables: VariableSource<'g>,
         owner: &'l str,
         group: &'l str,
-        mode: Mode,
+        mode: Option<Mode>,
     ) -> Self {
         StackFrame {
             parent: None,
             config,
             variables,
+            expanding: None,
             owner,
             group,
             mode,
+            given: Given::default(),
+            observer: None,
+            enumerating: false,
+            regexes: Some(RegexCache::default()),
+        }
+    }
+
+    /// Returns this stack with an `observer`, to be called with each entry of the schema applied
+    /// during traversal once it has been created (or found to be present already)
+    ///
+    /// This allows progress to be followed, or metrics collected, without inspecting the logs.
+    /// Entries skipped by `:when` are not reported. Subtrees may be traversed in parallel (see
+    /// [`Config::set_jobs`]), so the observer may be called from several threads.
+    ///
+    /// Example:
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// use diskplan_config::Config;
+    /// use diskplan_filesystem::{Filesystem, MemoryFilesystem, NodeKind, Root};
+    /// use diskplan_schema::parse_schema;
+    /// use diskplan_traversal::{traverse, StackFrame, Visit};
+    ///
+    /// let mut fs = MemoryFilesystem::new();
+    /// fs.create_directory("/target", Default::default()).unwrap();
+    /// fs.create_directory("/target/existing", Default::default()).unwrap();
+    ///
+    /// let mut config = Config::new("/target", false);
+    /// let schema = parse_schema("existing/\nnew/\n    inner/\n").unwrap();
+    /// config.add_precached_stem(Root::try_from("/target").unwrap(), "/target", schema);
+    ///
+    /// let (created, present) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    /// let count = |visit: &Visit| {
+    ///     assert_eq!(visit.kind, NodeKind::Directory);
+    ///     match visit.created {
+    ///         true => created.fetch_add(1, Ordering::Relaxed),
+    ///         false => present.fetch_add(1, Ordering::Relaxed),
+    ///     };
+    /// };
+    /// let stack = StackFrame::stack(&config, Default::default(), "root", "root", None)
+    ///     .with_observer(&count);
+    /// traverse("/target", &stack, &mut fs, Default::default()).unwrap();
+    ///
+    /// // The root and "existing" were already present
+    /// assert_eq!(created.into_inner(), 2);
+    /// assert_eq!(present.into_inner(), 2);
+    /// ```
+    pub fn with_observer(self, observer: &'l (dyn Fn(&Visit) + Sync)) -> Self {
+        StackFrame {
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    /// Returns this stack for enumerating paths only (see [`enumerate`](crate::enumerate))
+    pub(crate) fn for_enumeration(self) -> Self {
+        StackFrame {
+            enumerating: true,
+            ..self
+        }
+    }
+
+    /// Whether the traversal only enumerates paths, without reading any sources
+    pub(crate) fn enumerating(&self) -> bool {
+        self.enumerating
+    }
+
+    /// Tells the observer, if any, of an entry applied by the traversal
+    pub(crate) fn observe(&self, visit: &Visit) {
+        if let Some(observer) = self.observer {
+            observer(visit);
         }
     }
 
@@ -78,21 +174,66 @@ impl<'g, 'p, 'l> StackFrame<'g, 'p, 'l> {
         StackFrame {
             parent: Some(self),
             variables,
+            expanding: None,
             owner: self.owner,
             group: self.group,
             mode: self.mode,
+            given: self.given,
+            observer: self.observer,
+            enumerating: self.enumerating,
+            regexes: None,
             config: self.config,
         }
     }
 
+    /// Adds a new (empty) scope onto the stack, in which the observer, if any, is told of nothing
+    pub(crate) fn push_unobserved(&self) -> StackFrame<'g, '_, '_> {
+        StackFrame {
+            observer: None,
+            ..self.push(VariableSource::Empty)
+        }
+    }
+
+    /// Returns the regular expressions compiled so far by the traversal using this stack
+    pub(crate) fn regexes(&self) -> &RegexCache {
+        match (&self.regexes, self.parent) {
+            (Some(regexes), _) => regexes,
+            (None, Some(parent)) => parent.regexes(),
+            (None, None) => unreachable!("Outermost frame holds the cache"),
+        }
+    }
+
     /// Changes the owner in the current scope
     pub fn put_owner(&mut self, owner: &'l str) {
         self.owner = owner;
+        self.given.owner = true;
     }
 
     /// Changes the group in the current scope
     pub fn put_group(&mut self, group: &'l str) {
         self.group = group;
+        self.given.group = true;
+    }
+
+    /// Changes the UNIX permissions in the current scope
+    pub fn put_mode(&mut self, mode: Mode) {
+        self.mode = Some(mode);
+        self.given.mode = true;
+    }
+
+    /// Records that the given definition is being expanded (by `:use`) in the current scope
+    pub fn put_expanding(&mut self, definition: Identifier<'g>) {
+        self.expanding = Some(definition);
+    }
+
+    /// Returns the chain of definitions currently being expanded, outermost first
+    pub fn expanding(&self) -> Vec<Identifier<'g>> {
+        let mut chain = self
+            .parent
+            .map(|parent| parent.expanding())
+            .unwrap_or_default();
+        chain.extend(self.expanding);
+        chain
     }
 
     /// Returns the owner in the current scope
@@ -105,21 +246,77 @@ impl<'g, 'p, 'l> StackFrame<'g, 'p, 'l> {
         self.group
     }
 
-    /// Returns the UNIX permissions set for the current scope
-    pub fn mode(&self) -> Mode {
+    /// Returns the UNIX permissions set for the current scope, if any
+    pub fn mode(&self) -> Option<Mode> {
         self.mode
     }
 
+    /// Returns which of the owner, group and mode in the current scope were put there (rather
+    /// than being the defaults the stack was constructed with)
+    pub(crate) fn given(&self) -> Given {
+        self.given
+    }
+
     /// Provides access to variables in the current scope
     pub fn variables(&self) -> &VariableSource<'l> {
         &self.variables
     }
 
+    /// Returns the number of directory scopes in the current and parent scope(s)
+    pub fn directory_depth(&self) -> usize {
+        let here = matches!(self.variables, VariableSource::Directory(_)) as usize;
+        here + self
+            .parent
+            .map(|parent| parent.directory_depth())
+            .unwrap_or(0)
+    }
+
+    /// Evaluates an expression to a string, as it would be for the given `path` during traversal
+    ///
+    /// Variables are looked up through this stack (see [`StackFrame::lookup`]) and special
+    /// variables, such as `${NAME}`, take the values of `path`.
+    ///
+    /// Example:
+    /// ```
+    /// use std::collections::HashMap;
+    ///
+    /// use diskplan_config::Config;
+    /// use diskplan_filesystem::{PlantedPath, Root};
+    /// use diskplan_schema::{Expression, Identifier, Special, Token};
+    /// use diskplan_traversal::StackFrame;
+    ///
+    /// let config = Config::new("/target", false);
+    /// let variables = HashMap::from([("var".to_owned(), "value".to_owned())]);
+    /// let stack = StackFrame::stack(&config, variables.into(), "root", "root", Some(0o755.into()));
+    ///
+    /// // The equivalent of "${var}/suffix/${NAME}" in a schema
+    /// let expr = Expression::from(vec![
+    ///     Token::Variable(Identifier::new("var")),
+    ///     Token::Text("/suffix/"),
+    ///     Token::Special(Special::PathNameOnly),
+    /// ]);
+    /// let root = Root::try_from("/target").unwrap();
+    /// let path = PlantedPath::new(&root, Some("/target/name".into())).unwrap();
+    /// assert_eq!(stack.evaluate(&expr, &path).unwrap(), "value/suffix/name");
+    /// ```
+    pub fn evaluate(&self, expr: &Expression, path: &PlantedPath) -> Result<String> {
+        crate::eval::evaluate(expr, self, path)
+    }
+
     /// Looks up the value of a variable in the current or parent scope(s)
+    ///
+    /// The innermost scope to set the variable gives its value, except that a variable given by
+    /// a map (such as from `--vars`) overrides a `:let` if the config says that it should (see
+    /// [`Config::set_vars_override_lets`])
     pub fn lookup<'a>(&'a self, var: &Identifier<'a>) -> Option<Value<'a>> {
         match &self.variables {
             VariableSource::Empty => None,
-            VariableSource::Directory(directory) => directory.get_var(var).map(Value::Expression),
+            VariableSource::Directory(directory) => match directory.get_var(var) {
+                Some(expr) if self.config.vars_override_lets() => {
+                    self.lookup_map(var).or(Some(Value::Expression(expr)))
+                }
+                found => found.map(Value::Expression),
+            },
             VariableSource::Binding(bind, ref value) => {
                 if *bind == var {
                     Some(Value::String(value))
@@ -132,6 +329,106 @@ impl<'g, 'p, 'l> StackFrame<'g, 'p, 'l> {
         .or_else(|| self.parent.and_then(|parent| parent.lookup(var)))
     }
 
+    /// Looks up the value of a variable given by a map in the current or parent scope(s), ignoring
+    /// any set by the schema
+    fn lookup_map<'a>(&'a self, var: &Identifier<'a>) -> Option<Value<'a>> {
+        match &self.variables {
+            VariableSource::Map(map) => map.get(var.value()).map(|s| Value::String(s.as_str())),
+            _ => None,
+        }
+        .or_else(|| self.parent.and_then(|parent| parent.lookup_map(var)))
+    }
+
+    /// Collects every variable visible from the current scope, sorted by name, with its value
+    ///
+    /// Where a name is set at more than one level, only the innermost (which [`StackFrame::lookup`]
+    /// would find) is given. The values of `:let` variables are given as written, unevaluated.
+    pub fn variables_in_scope(&self) -> Vec<(String, String)> {
+        let mut in_scope = BTreeMap::new();
+        let mut frame = Some(self);
+        while let Some(current) = frame {
+            let mut add = |name: &str, value: String| {
+                in_scope.entry(name.to_owned()).or_insert(value);
+            };
+            match &current.variables {
+                VariableSource::Empty => {}
+                VariableSource::Directory(directory) => {
+                    for (ident, expr) in directory.vars() {
+                        // A variable from a map that overrides this will be found further out
+                        if !(self.config.vars_override_lets()
+                            && current.lookup_map(ident).is_some())
+                        {
+                            add(ident.value(), expr.to_string());
+                        }
+                    }
+                }
+                VariableSource::Binding(ident, value) => add(ident.value(), value.clone()),
+                VariableSource::Map(map) => {
+                    for (key, value) in map {
+                        add(key, value.clone());
+                    }
+                }
+            }
+            frame = current.parent;
+        }
+        in_scope.into_iter().collect()
+    }
+
+    /// Returns the name bound by the nearest dynamic binding in the current or parent scope(s)
+    pub fn matched_name(&self) -> Option<&str> {
+        match &self.variables {
+            VariableSource::Binding(_, value) => Some(value.as_str()),
+            _ => None,
+        }
+        .or_else(|| self.parent.and_then(|parent| parent.matched_name()))
+    }
+
+    /// Returns the variable bound to the name of the entry being applied, and its value, if the
+    /// entry was matched by a dynamic binding (looking no further out than its directory)
+    pub(crate) fn entry_binding(&self) -> Option<(&str, &str)> {
+        match &self.variables {
+            VariableSource::Binding(var, value) => Some((var.value(), value.as_str())),
+            VariableSource::Directory(_) => None,
+            _ => self.parent.and_then(|parent| parent.entry_binding()),
+        }
+    }
+
+    /// Describes every frame of the stack over several lines, outermost first
+    ///
+    /// Each frame gives the kind and contents of its [`VariableSource`] (as displayed for the
+    /// frame alone), the definition it is expanding, if any, and the owner, group and mode it
+    /// passes on. This is intended for debugging schemas, where an error's context is not enough.
+    pub fn dump(&self) -> String {
+        let mut frames = vec![];
+        let mut frame = Some(self);
+        while let Some(current) = frame {
+            frames.push(current);
+            frame = current.parent;
+        }
+        let mut dump = String::from("Stack (outermost first):");
+        for (index, frame) in frames.into_iter().rev().enumerate() {
+            let variables = match frame.variables {
+                VariableSource::Empty => "No variables".to_owned(),
+                _ => frame.to_string(),
+            };
+            let _ = write!(dump, "\n  #{index} {}", variables.replace('\n', "\n     "));
+            if let Some(definition) = frame.expanding {
+                let _ = write!(dump, "\n     Expanding: {definition}");
+            }
+            let _ = write!(
+                dump,
+                "\n     Owner: {}, group: {}, mode: {}",
+                frame.owner,
+                frame.group,
+                frame
+                    .mode
+                    .map(|mode| mode.to_string())
+                    .unwrap_or_else(|| "(default)".to_owned()),
+            );
+        }
+        dump
+    }
+
     /// Looks up the definition of a sub-schema in the current or parent scope(s)
     pub fn find_definition<'a>(&self, var: &Identifier<'a>) -> Option<&'a SchemaNode<'g>> {
         match self.variables {
@@ -142,8 +439,16 @@ impl<'g, 'p, 'l> StackFrame<'g, 'p, 'l> {
     }
 }
 
+/// Which of the owner, group and mode of a [`StackFrame`] have been put by the schema
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Given {
+    pub owner: bool,
+    pub group: bool,
+    pub mode: bool,
+}
+
 /// Ways in which variables may be provided by the current scope
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub enum VariableSource<'a> {
     /// No available variables
     #[default]
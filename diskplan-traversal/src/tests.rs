@@ -9,6 +9,10 @@ macro_rules! assert_effect_of {
         onto:
             $path:literal
         $(
+        extent:
+            $extent:ident
+        )?
+        $(
         with:
             $(directories:
                 $($in_d_path:literal $([
@@ -37,7 +41,9 @@ macro_rules! assert_effect_of {
                     $(group = $out_f_group:literal)?
                     $(mode = $out_f_mode:expr)? ])+ )?
             $(symlinks:
-                $($link:literal -> $target:literal)+ )?
+                $($link:literal -> $target:literal $([
+                    $(owner = $out_l_owner:literal)?
+                    $(group = $out_l_group:literal)? ])? )+ )?
     } => {{
         use std::collections::HashSet;
 
@@ -66,7 +72,7 @@ macro_rules! assert_effect_of {
 
         // onto:
         let path = Utf8Path::new($path);
-        let stack = StackFrame::stack(&config, Default::default(), "root", "root", 0o755.into());
+        let stack = StackFrame::stack(&config, Default::default(), "root", "root", Some(0o755.into()));
 
         $(
         // with:
@@ -86,23 +92,24 @@ macro_rules! assert_effect_of {
             // files:
             #[allow(unused_mut)]
             let mut attrs = SetAttrs::default();
-            $(
-                $(attrs.owner = Some($in_f_owner);)?
-                $(attrs.group = Some($in_f_group);)?
-                $(attrs.mode = Some($in_f_mode.into());)?
-            )?
+            $(attrs.owner = Some($in_f_owner);)?
+            $(attrs.group = Some($in_f_group);)?
+            $(attrs.mode = Some($in_f_mode.into());)?
             fs.create_file(Utf8Path::new($in_f_path), attrs, String::from($in_content))?;
             expected_paths.insert(Utf8Path::new($in_f_path));
         )+)?
         $($(
             // symlinks:
             fs.create_symlink(Utf8Path::new($in_l_path), Utf8Path::new($in_l_target))?;
-            expected_paths.insert(Utf8PathBuf::from($in_l_path));
+            expected_paths.insert(Utf8Path::new($in_l_path));
         )+)?
         )?
 
         // yields:
-        crate::traverse(path, &stack, &mut fs, Default::default())?;
+        #[allow(unused_mut, unused_assignments)]
+        let mut extent = crate::Extent::default();
+        $(extent = crate::Extent::$extent;)?
+        crate::traverse(path, &stack, &mut fs, extent)?;
         expected_paths.insert(Utf8Path::new("/"));
         expected_paths.insert(Utf8Path::new(root.path()));
         $($(
@@ -119,12 +126,11 @@ macro_rules! assert_effect_of {
         $($(
             // files:
             assert!(fs.is_file($out_f_path), "Expected file at: {}", $out_f_path);
-            $(
-                let attrs = fs.attributes(Utf8Path::new($out_f_path))?;
-                $(assert_eq!(attrs.owner.as_ref(), $out_f_owner);)?
-                $(assert_eq!(attrs.group.as_ref(), $out_f_group);)?
-                $(assert_eq!(attrs.mode, $out_f_mode.into());)?
-            )?
+            #[allow(unused_variables)]
+            let attrs = fs.attributes(Utf8Path::new($out_f_path))?;
+            $(assert_eq!(attrs.owner.as_ref(), $out_f_owner);)?
+            $(assert_eq!(attrs.group.as_ref(), $out_f_group);)?
+            $(assert_eq!(attrs.mode, $out_f_mode.into());)?
             assert_eq!(&fs.read_file(Utf8Path::new($out_f_path))?, $content);
             expected_paths.insert(Utf8Path::new($out_f_path));
         )+)?
@@ -132,6 +138,11 @@ macro_rules! assert_effect_of {
             // symlinks:
             assert!(fs.is_link(Utf8Path::new($link)), "Expected symlink at: {}", $link);
             assert_eq!(&fs.read_link(Utf8Path::new($link))?, $target, "Expected symlink: {} -> {}", $link, $target);
+            $(
+                let attrs = fs.link_attributes(Utf8Path::new($link))?;
+                $(assert_eq!(attrs.owner.as_ref(), $out_l_owner);)?
+                $(assert_eq!(attrs.group.as_ref(), $out_l_group);)?
+            )?
             expected_paths.insert(Utf8Path::new($link));
         )+)?
         let actual_paths = fs.to_path_set();
@@ -143,6 +154,30 @@ macro_rules! assert_effect_of {
     }};
 }
 
+use anyhow::{anyhow, Result};
+use diskplan_config::Config;
+use diskplan_filesystem::{Filesystem, Root};
+use diskplan_schema::parse_schema;
+
+use crate::{traverse, StackFrame};
+
+/// Returns a config targeting the root at `path`, with the schema text held as though it were
+/// loaded from a file at that same path
+fn config_for<'t>(path: &str, schema: &'t str) -> Result<Config<'t>> {
+    let mut config = Config::new(path, false);
+    let schema = parse_schema(schema).map_err(|e| anyhow!("{}", e))?;
+    config.add_precached_stem(Root::try_from(path)?, path, schema);
+    Ok(config)
+}
+
+/// Applies the schema text to the root at `path` of the given filesystem, as the root user and
+/// with no default mode given for the run
+fn apply_schema(fs: &mut (impl Filesystem + Send), path: &str, schema: &str) -> Result<()> {
+    let config = config_for(path, schema)?;
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    traverse(path, &stack, fs, Default::default())
+}
+
 mod attributes;
 mod comments;
 mod creation;
@@ -1,5 +1,9 @@
 use anyhow::Result;
-use diskplan_filesystem::DEFAULT_DIRECTORY_MODE;
+use diskplan_filesystem::{
+    Filesystem, MemoryFilesystem, DEFAULT_DIRECTORY_MODE, DEFAULT_FILE_MODE,
+};
+
+use super::apply_schema;
 
 #[test]
 #[should_panic]
@@ -130,3 +134,229 @@ fn inherited_attributes() -> Result<()> {
                     mode = DEFAULT_DIRECTORY_MODE]
     }
 }
+
+#[test]
+fn recursive_mode() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            private/
+                :mode 700
+                :recursive
+                inner/
+                    deeper/
+                explicit/
+                    :mode 750
+                    below_explicit/
+                inherits/
+                    :mode inherit
+            public/
+                :mode 700
+                inner/
+            "
+        onto: "/target"
+        yields:
+            directories:
+                "/target/private" [mode = 0o700]
+                "/target/private/inner" [mode = 0o700]
+                "/target/private/inner/deeper" [mode = 0o700]
+                "/target/private/explicit" [mode = 0o750]
+                "/target/private/explicit/below_explicit" [mode = 0o700]
+                "/target/private/inherits" [mode = 0o700]
+                "/target/public" [mode = 0o700]
+                "/target/public/inner" [mode = DEFAULT_DIRECTORY_MODE]
+    }
+}
+
+#[test]
+fn owner_from_own_name() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            :def named/
+                :owner $NAME
+            daemon/
+                :owner $NAME
+                :group ${NAME}
+            bin/
+                :use named
+            $user/
+                :owner ${user}
+                :group $NAME
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/sys"
+        yields:
+            directories:
+                "/target/daemon" [
+                    owner = "daemon"
+                    group = "daemon"
+                    mode = DEFAULT_DIRECTORY_MODE]
+                "/target/bin" [
+                    owner = "bin"
+                    mode = DEFAULT_DIRECTORY_MODE]
+                "/target/sys" [
+                    owner = "sys"
+                    group = "sys"
+                    mode = DEFAULT_DIRECTORY_MODE]
+    }
+}
+
+#[test]
+fn default_mode_by_type() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    fs.create_file("/source", Default::default(), "content".into())?;
+    // No default mode is given for the run
+    apply_schema(
+        &mut fs,
+        "/target",
+        "
+        plain/
+            file
+                :source /source
+        locked/
+            :mode 750
+            :recursive
+            file
+                :source /source
+        explicit
+            :source /source
+            :mode 600
+        ",
+    )?;
+
+    let mode = |path| -> Result<u16> { Ok(fs.attributes(path)?.mode.value()) };
+    assert_eq!(mode("/target/plain")?, DEFAULT_DIRECTORY_MODE.value());
+    assert_eq!(mode("/target/plain/file")?, DEFAULT_FILE_MODE.value());
+    assert_eq!(mode("/target/locked")?, 0o750);
+    assert_eq!(mode("/target/locked/file")?, 0o750);
+    assert_eq!(mode("/target/explicit")?, 0o600);
+    Ok(())
+}
+
+#[test]
+fn link_owner_and_group() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            store/
+            link/ -> /target/store
+                :link-owner bin
+                :link-group sys
+            "
+        onto: "/target"
+        yields:
+            // The link itself takes the link owner and group, leaving its target as it was
+            directories:
+                "/target/store" [owner = "root" group = "root"]
+            symlinks:
+                "/target/link" -> "/target/store" [owner = "bin" group = "sys"]
+    }
+}
+
+#[test]
+fn mode_initial_on_creation() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            private/
+                :mode-initial 700
+            enforced/
+                :mode 700
+            "
+        onto: "/target"
+        yields:
+            directories:
+                "/target/private" [mode = 0o700]
+                "/target/enforced" [mode = 0o700]
+    }
+}
+
+#[test]
+fn mode_initial_only_on_creation() -> Result<()> {
+    // An operator loosens both, but only the enforced mode is corrected on the next run
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            private/
+                :mode-initial 700
+            enforced/
+                :mode 700
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/private" [mode = 0o750]
+                "/target/enforced" [mode = 0o750]
+        yields:
+            directories:
+                "/target/private" [mode = 0o750]
+                "/target/enforced" [mode = 0o700]
+    }
+}
+
+#[test]
+fn only_given_attributes_reconciled() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            shared
+                :group sys
+                :source /dev/null
+            dir/
+                :group sys
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/dir" [owner = "daemon" group = "daemon" mode = 0o600]
+            files:
+                "/target/shared" ["" owner = "daemon" group = "daemon" mode = 0o600]
+        yields:
+            directories:
+                "/target/dir" [owner = "daemon" group = "sys" mode = 0o600]
+            files:
+                "/target/shared" ["" owner = "daemon" group = "sys" mode = 0o600]
+    }
+}
+
+#[test]
+fn recursive_and_inherited_attributes_reconciled() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            top/
+                :owner daemon
+                :group sys
+                :mode 750
+                :recursive
+                sub/
+                    deeper/
+            shared/
+                :owner daemon
+                below/
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/top" [owner = "bin" group = "bin" mode = 0o700]
+                "/target/top/sub" [owner = "bin" group = "bin" mode = 0o700]
+                "/target/shared" [owner = "bin" group = "bin" mode = 0o700]
+                "/target/shared/below" [owner = "bin" group = "bin" mode = 0o700]
+        yields:
+            directories:
+                "/target/top" [owner = "daemon" group = "sys" mode = 0o750]
+                "/target/top/sub" [owner = "daemon" group = "sys" mode = 0o750]
+                "/target/top/sub/deeper" [owner = "daemon" group = "sys" mode = 0o750]
+                // Only the inherited owner is given, so the group and mode are left alone
+                "/target/shared" [owner = "daemon" group = "bin" mode = 0o700]
+                "/target/shared/below" [owner = "daemon" group = "bin" mode = 0o700]
+    }
+}
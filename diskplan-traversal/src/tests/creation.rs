@@ -1,4 +1,16 @@
+use std::{collections::HashMap, sync::Mutex};
+
 use anyhow::Result;
+use camino::Utf8Path;
+
+use diskplan_config::Config;
+use diskplan_filesystem::{
+    DiskFilesystem, Filesystem, MemoryFilesystem, NodeKind, RecordingFilesystem, Root,
+};
+use diskplan_schema::parse_schema;
+
+use super::{apply_schema, config_for};
+use crate::{preflight, traverse, traverse_best_effort, StackFrame, Visit};
 
 #[test]
 fn create_directory() -> Result<()> {
@@ -115,6 +127,61 @@ fn create_symlink_using_target() -> Result<()> {
     }
 }
 
+#[test]
+fn existing_symlinks_are_kept() -> Result<()> {
+    // Applying again finds the links in place, and leaves them
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            store/
+            link/ -> /target/store
+            relative/ -> store
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/store"
+            symlinks:
+                "/target/link" -> "/target/store"
+                "/target/relative" -> "store"
+        yields:
+            symlinks:
+                "/target/link" -> "/target/store"
+                "/target/relative" -> "store"
+    }
+}
+
+#[test]
+fn other_entries_are_not_replaced_by_symlinks() -> Result<()> {
+    let apply = |fs: &mut MemoryFilesystem| {
+        apply_schema(
+            fs,
+            "/target",
+            "
+            store/
+            link/ -> /target/store
+            ",
+        )
+    };
+
+    // A link elsewhere, or a real directory, is never replaced
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    fs.create_symlink("/target/link", "/elsewhere")?;
+    let error = format!("{:?}", apply(&mut fs).unwrap_err());
+    assert!(error.contains("it exists already, pointing to /elsewhere"));
+    assert_eq!(fs.read_link("/target/link")?, "/elsewhere");
+
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    fs.create_directory("/target/link", Default::default())?;
+    let error = format!("{:?}", apply(&mut fs).unwrap_err());
+    assert!(error.contains("a directory exists there already"));
+    assert!(fs.is_directory("/target/link") && !fs.is_link("/target/link"));
+    Ok(())
+}
+
 #[test]
 fn create_relative_symlink() -> Result<()> {
     assert_effect_of! {
@@ -134,6 +201,69 @@ fn create_relative_symlink() -> Result<()> {
     }
 }
 
+#[test]
+fn create_relative_symlink_with_schema() -> Result<()> {
+    assert_effect_of! {
+        under: "/local"
+        applying: "
+            $zone/
+                storage/ -> ../../pool/$zone
+                    :group adm
+                    data/
+            "
+
+        under: "/pool"
+        applying: "
+            $zone/
+            "
+
+        onto: "/local/example"
+        yields:
+            directories:
+                "/local"
+                "/local/example"
+                "/pool"
+                "/pool/example" [ group = "adm" ]
+                "/pool/example/data"
+            symlinks:
+                "/local/example/storage" -> "../../pool/example"
+    }
+}
+
+#[test]
+fn relative_symlink_outside_roots_only_if_trivial() -> Result<()> {
+    fn attempt(schema: &'static str) -> Result<MemoryFilesystem> {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/local", Default::default())?;
+        let config = config_for("/local", schema)?;
+        let variables = HashMap::from([("name".to_owned(), "xray".to_owned())]);
+        let stack = StackFrame::stack(&config, variables.into(), "root", "root", None);
+        traverse("/local", &stack, &mut fs, Default::default())?;
+        Ok(fs)
+    }
+
+    // Genuinely empty
+    let fs = attempt("link/ -> ../elsewhere")?;
+    assert_eq!(fs.read_link("/local/link")?, "../elsewhere");
+
+    // Only matched by pattern, and inheriting attributes
+    let fs = attempt(
+        "
+        $name/ -> ../elsewhere/$name
+            :match x.*
+            :owner inherit
+            :mode inherit
+        ",
+    )?;
+    assert_eq!(fs.read_link("/local/xray")?, "../elsewhere/xray");
+
+    // Work remains at the target
+    assert!(attempt("link/ -> ../elsewhere\n    :mode 700").is_err());
+    assert!(attempt("link/ -> ../elsewhere\n    inner/").is_err());
+    assert!(attempt("link/ -> ../elsewhere\n    :source /content").is_err());
+    Ok(())
+}
+
 #[test]
 fn symlink_two_schemas() -> Result<()> {
     assert_effect_of! {
@@ -160,3 +290,650 @@ fn symlink_two_schemas() -> Result<()> {
                 "/local/example" -> "/remote/example"
     }
 }
+
+#[test]
+fn creation_order_is_sorted() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    for name in ["delta", "alpha", "charlie"] {
+        fs.create_directory(Utf8Path::new("/target").join(name), Default::default())?;
+    }
+    apply_schema(
+        &mut fs,
+        "/target",
+        "
+        $existing/
+            inner/
+        echo/
+        bravo/
+        ",
+    )?;
+
+    // Pre-existing entries remain in their original order, with new ones created in sorted order
+    assert_eq!(
+        fs.list_directory("/target")?,
+        vec!["delta", "alpha", "charlie", "bravo", "echo"]
+    );
+    Ok(())
+}
+
+#[test]
+fn source_falls_back_to_later_candidates() -> Result<()> {
+    assert_effect_of! {
+        under: "/primary"
+        applying: "
+            :let site = north
+            motd
+                :source /sites/$site/motd, /default/motd
+            issue
+                :source /sites/${site}/issue,/default/issue
+            "
+        onto: "/primary"
+        with:
+            directories:
+                "/sites"
+                "/sites/north"
+                "/default"
+            files:
+                "/sites/north/issue" ["NORTH ISSUE"]
+                "/default/motd" ["DEFAULT MOTD"]
+                "/default/issue" ["DEFAULT ISSUE"]
+        yields:
+            files:
+                "/primary/motd" ["DEFAULT MOTD"]
+                "/primary/issue" ["NORTH ISSUE"]
+    }
+}
+
+#[test]
+fn preflight_lists_missing_sources_without_changes() -> Result<()> {
+    let schema = "
+        present
+            :source /templates/present
+        sub/
+            absent
+                :source /templates/absent
+            copied/
+                :source /templates/tree
+        link/ -> /elsewhere/path
+        ";
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory_all("/templates", Default::default())?;
+    fs.create_file("/templates/present", Default::default(), "here".into())?;
+    fs.create_directory("/target", Default::default())?;
+    fs.create_directory("/elsewhere", Default::default())?;
+
+    let config = config_for("/target", schema)?;
+    let visits = Mutex::new(0);
+    let count = |_: &Visit| *visits.lock().unwrap() += 1;
+    let stack =
+        StackFrame::stack(&config, Default::default(), "root", "root", None).with_observer(&count);
+
+    let before = fs.snapshot();
+    let mut recording = RecordingFilesystem::new(fs);
+    let error = preflight("/target", &stack, &mut recording, Default::default()).unwrap_err();
+    let message = error.to_string();
+    assert!(message.starts_with("Preflight found 3 problem(s), so nothing was changed:"));
+    assert!(message.contains("\n  At /target/sub/absent: "));
+    assert!(message.contains("/templates/absent"));
+    assert!(message.contains("\n  At /target/sub/copied: "));
+    assert!(message.contains("\n  At /target/link: "));
+    assert_eq!(*visits.lock().unwrap(), 0);
+    let mut fs = recording.into_inner();
+    assert_eq!(fs.snapshot(), before);
+
+    // Once every input is in place, the preflight passes and the traversal itself can follow
+    fs.create_file("/templates/absent", Default::default(), "".into())?;
+    fs.create_directory("/templates/tree", Default::default())?;
+    let mut config = config_for("/target", schema)?;
+    config.add_precached_stem(
+        Root::try_from("/elsewhere")?,
+        "/elsewhere",
+        parse_schema("path/")?,
+    );
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    let mut recording = RecordingFilesystem::new(fs);
+    preflight("/target", &stack, &mut recording, Default::default())?;
+    let mut fs = recording.into_inner();
+    traverse("/target", &stack, &mut fs, Default::default())?;
+    assert_eq!(fs.read_file("/target/present")?, "here");
+    assert!(fs.is_file("/target/sub/absent"));
+    assert_eq!(fs.read_link("/target/link")?, "/elsewhere/path");
+    Ok(())
+}
+
+#[test]
+fn source_glob_creates_a_file_per_match() -> Result<()> {
+    // Explicit entries take precedence over the files matched
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            conf/
+                :owner daemon
+                :source-glob /templates/*.conf
+                b.conf
+                    :source /override
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/templates"
+            files:
+                "/templates/a.conf" ["A"]
+                "/templates/b.conf" ["B"]
+                "/templates/.hidden.conf" ["HIDDEN"]
+                "/templates/readme.txt" ["README"]
+                "/override" ["OVERRIDE"]
+        yields:
+            directories:
+                "/target/conf"
+            files:
+                "/target/conf/a.conf" ["A" owner = "daemon"]
+                "/target/conf/b.conf" ["OVERRIDE" owner = "daemon"]
+    }
+}
+
+#[test]
+fn source_glob_keeps_existing_files() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            conf/
+                :source-glob /templates/*.conf
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/templates"
+                "/target"
+                "/target/conf"
+            files:
+                "/templates/a.conf" ["A"]
+                "/target/conf/a.conf" ["CHANGED"]
+        yields:
+            files:
+                "/target/conf/a.conf" ["CHANGED"]
+    }
+}
+
+#[test]
+fn source_candidates_must_not_all_be_missing() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    let schema = "file
+    :source /missing/a, /missing/b
+";
+    let error = apply_schema(&mut fs, "/target", schema).unwrap_err();
+    assert!(error.chain().any(|cause| cause.to_string()
+        == "None of the sources for /target/file exist: /missing/a, /missing/b"));
+    Ok(())
+}
+
+#[test]
+fn source_within_a_root_must_be_created_first() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    let schema = "
+        consumer
+            :source /target/producer/output
+        ";
+    let error = apply_schema(&mut fs, "/target", schema).unwrap_err();
+    assert!(error
+        .chain()
+        .any(|cause| cause.to_string().starts_with(concat!(
+            r#"Source "/target/producer/output" for "/target/consumer" does not exist yet, "#,
+            "but lies within root /target"
+        ))));
+
+    fs.create_directory("/target/producer", Default::default())?;
+    fs.create_file("/target/producer/output", Default::default(), "made".into())?;
+    apply_schema(&mut fs, "/target", schema)?;
+    assert_eq!(fs.read_file("/target/consumer")?, "made");
+    Ok(())
+}
+
+#[test]
+fn observer_is_told_of_each_entry() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    fs.create_directory("/target/present", Default::default())?;
+    fs.create_directory("/resource", Default::default())?;
+    fs.create_file("/resource/file", Default::default(), "".into())?;
+    let config = config_for(
+        "/target",
+        "
+        present/
+            file
+                :source /resource/file
+        link/ -> /target/present
+        skipped/
+            :when a == b
+        ",
+    )?;
+    let visits = Mutex::new(Vec::new());
+    let record = |visit: &Visit| {
+        visits
+            .lock()
+            .unwrap()
+            .push((visit.path.to_string(), visit.kind, visit.created))
+    };
+    let stack =
+        StackFrame::stack(&config, Default::default(), "root", "root", None).with_observer(&record);
+    traverse("/target", &stack, &mut fs, Default::default())?;
+
+    let mut visits = visits.into_inner().unwrap();
+    visits.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        visits,
+        vec![
+            ("/target".into(), NodeKind::Directory, false),
+            ("/target/link".into(), NodeKind::Symlink, true),
+            ("/target/present".into(), NodeKind::Directory, false),
+            ("/target/present/file".into(), NodeKind::File, true),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn source_relative_to_schema_directory() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/schemas", Default::default())?;
+    fs.create_directory("/schemas/content", Default::default())?;
+    fs.create_file(
+        "/schemas/content/relative",
+        Default::default(),
+        "rel".into(),
+    )?;
+    fs.create_file("/absolute", Default::default(), "abs".into())?;
+    fs.create_directory("/target", Default::default())?;
+
+    let mut config = config_for(
+        "/target",
+        "
+        from_relative
+            :source content/relative
+        from_absolute
+            :source /absolute
+        ",
+    )?;
+    config.set_schema_directory("/schemas");
+    config.set_relative_sources(true);
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    traverse("/target", &stack, &mut fs, Default::default())?;
+
+    assert_eq!(fs.read_file("/target/from_relative")?, "rel");
+    assert_eq!(fs.read_file("/target/from_absolute")?, "abs");
+    Ok(())
+}
+
+#[test]
+fn rename_from_moves_existing() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            new_name/
+                :rename-from old_name
+                :mode 700
+            new_file
+                :rename-from /target/old_file
+                :source /nowhere
+            fresh/
+                :rename-from never_existed
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/old_name"
+            files:
+                "/target/old_name/kept" ["kept"]
+                "/target/old_file" ["old"]
+        yields:
+            directories:
+                "/target/new_name" [mode = 0o700]
+                "/target/fresh"
+            files:
+                "/target/new_name/kept" ["kept"]
+                "/target/new_file" ["old"]
+    }
+}
+
+#[test]
+fn rename_from_when_both_exist() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    fs.create_directory("/target/old_name", Default::default())?;
+    fs.create_directory("/target/new_name", Default::default())?;
+
+    let schema = "
+        new_name/
+            :rename-from old_name
+        ";
+    let error = apply_schema(&mut fs, "/target", schema).unwrap_err();
+    assert!(format!("{error:?}")
+        .contains("Cannot rename /target/old_name to /target/new_name: both exist"));
+    Ok(())
+}
+
+#[test]
+fn rename_from_must_stay_within_the_root() -> Result<()> {
+    for old_name in [
+        "/elsewhere/old_name",
+        "../elsewhere/old_name",
+        "inner/../../etc",
+    ] {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/target", Default::default())?;
+        fs.create_directory("/elsewhere", Default::default())?;
+        fs.create_directory("/elsewhere/old_name", Default::default())?;
+
+        let schema = format!("new_name/\n    :rename-from {old_name}\n");
+        let error = apply_schema(&mut fs, "/target", &schema).unwrap_err();
+        assert!(
+            format!("{error:?}").contains("to /target/new_name: it lies outside the root /target"),
+            "{old_name}: {error:?}"
+        );
+        assert!(fs.is_directory("/elsewhere/old_name"));
+        assert!(!fs.exists("/target/new_name"));
+    }
+    Ok(())
+}
+
+#[test]
+fn best_effort_continues_past_errors() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    let config = config_for(
+        "/target",
+        "
+        first
+            :source /missing
+        second/
+        third
+            :source /also_missing
+        ",
+    )?;
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+
+    // By default, the first error ends the traversal
+    assert!(traverse("/target", &stack, &mut fs, Default::default()).is_err());
+
+    let errors = traverse_best_effort("/target", &stack, &mut fs, Default::default())?;
+    let paths: Vec<_> = errors.iter().map(|(path, _)| path.absolute()).collect();
+    assert_eq!(paths, vec!["/target/first", "/target/third"]);
+    assert!(fs.is_directory("/target/second"));
+    Ok(())
+}
+
+#[test]
+fn directory_source_copies_then_refines() -> Result<()> {
+    // Modes are copied, and ownership taken from the schema, before child entries refine them
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            project/
+                :source /templates/project
+                :owner daemon
+                docs/
+                    :mode 700
+                extra/
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/templates"
+                "/templates/project"
+                "/templates/project/docs"
+                "/templates/project/src" [mode = 0o750]
+            files:
+                "/templates/project/README" ["TEMPLATE README" mode = 0o600]
+                "/templates/project/src/main" ["TEMPLATE MAIN"]
+            symlinks:
+                "/templates/project/latest" -> "src"
+        yields:
+            directories:
+                "/target/project"
+                "/target/project/docs" [mode = 0o700]
+                "/target/project/extra"
+                "/target/project/src" [owner = "daemon" mode = 0o750]
+            files:
+                "/target/project/README" ["TEMPLATE README" owner = "daemon" mode = 0o600]
+                "/target/project/src/main" ["TEMPLATE MAIN"]
+            symlinks:
+                "/target/project/latest" -> "src"
+    }
+}
+
+#[test]
+fn directory_source_from_definition_only_when_created() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            :def templated/
+                :source /templates
+            fresh/
+                :use templated
+            existing/
+                :use templated
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/templates"
+                "/target"
+                "/target/existing"
+            files:
+                "/templates/file" ["template"]
+        yields:
+            directories:
+                "/target/fresh"
+            files:
+                "/target/fresh/file" ["template"]
+    }
+}
+
+const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+#[test]
+fn sha256_checks_and_repairs_files() -> Result<()> {
+    // The checksum of "hello", in either case
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            created
+                :source /good
+                :sha256 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824
+            intact
+                :source /nowhere
+                :sha256 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824
+            tampered
+                :source /good
+                :sha256 2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+            files:
+                "/good" ["hello"]
+                "/target/intact" ["hello"]
+                "/target/tampered" ["tampered"]
+        yields:
+            files:
+                "/target/created" ["hello"]
+                "/target/intact" ["hello"]
+                "/target/tampered" ["hello"]
+    }
+}
+
+#[test]
+fn sha256_mismatched_source_is_an_error() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    fs.create_file("/bad", Default::default(), "tampered".into())?;
+    fs.create_file("/target/existing", Default::default(), "tampered".into())?;
+
+    let text = format!(
+        "
+        new
+            :source /bad
+            :sha256 {HELLO_SHA256}
+        existing
+            :source /bad
+            :sha256 {HELLO_SHA256}
+        "
+    );
+    let config = config_for("/target", &text)?;
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    let errors = traverse_best_effort("/target", &stack, &mut fs, Default::default())?;
+
+    assert_eq!(errors.len(), 2);
+    let existing = format!("{:?}", errors[0].1);
+    assert!(existing.contains("Cannot repair /target/existing from its source"));
+    assert!(existing.contains(&format!(
+        "Checksum mismatch for /bad: expected sha256 {HELLO_SHA256}"
+    )));
+    assert!(format!("{:?}", errors[1].1).contains("Checksum mismatch for /bad"));
+    assert!(!fs.exists("/target/new"));
+    assert_eq!(fs.read_file("/target/existing")?, "tampered");
+    Ok(())
+}
+
+#[test]
+fn parallel_traversal_on_disk() -> Result<()> {
+    let dir = Utf8Path::from_path(&std::env::temp_dir())
+        .expect("UTF-8 temp dir")
+        .join(format!("diskplan-jobs-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut fs = DiskFilesystem::new();
+    fs.create_directory(&dir, Default::default())?;
+    fs.create_directory(dir.join("users"), Default::default())?;
+    for existing in ["alpha", "beta", "gamma"] {
+        fs.create_directory(dir.join("users").join(existing), Default::default())?;
+    }
+    std::fs::write(dir.join("content"), "CONTENT")?;
+    // Apply as whoever already owns the directory, so no privileges are needed
+    let attrs = fs.attributes(&dir)?;
+    let (owner, group) = (attrs.owner.into_owned(), attrs.group.into_owned());
+
+    let text = format!(
+        "
+        users/
+            $user/
+                home/
+                    file
+                        :source {dir}/content
+        first
+            :source {dir}/missing
+        second/
+            nested/
+        third
+            :source {dir}/also_missing
+        "
+    );
+    let mut config = Config::new(dir.as_str(), true);
+    config.set_jobs(4);
+    let schema = parse_schema(&text).map_err(|e| anyhow::anyhow!("{}", e))?;
+    config.add_precached_stem(Root::try_from(dir.as_str())?, "/schema", schema);
+    let stack = StackFrame::stack(
+        &config,
+        Default::default(),
+        &owner,
+        &group,
+        Some(0o755.into()),
+    );
+
+    let errors = traverse_best_effort(&dir, &stack, &mut fs, Default::default())?;
+    let paths: Vec<_> = errors.iter().map(|(path, _)| path.absolute()).collect();
+    // Errors are reported in the same order as a serial traversal would report them
+    assert_eq!(paths, vec![dir.join("first"), dir.join("third")]);
+    for user in ["alpha", "beta", "gamma"] {
+        let file = dir.join("users").join(user).join("home/file");
+        assert_eq!(std::fs::read_to_string(file)?, "CONTENT");
+    }
+    assert!(fs.is_directory(dir.join("second/nested")));
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn enumerate_paths_without_disk() -> Result<()> {
+    let config = config_for(
+        "/target",
+        "
+        :let team = ops
+        $team/
+            checked
+                :source /nowhere/checked
+                :sha256 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+            copied/
+                :source /nowhere/template
+            link/ -> /target/shared/$team
+        shared/
+            $dest/
+        ",
+    )?;
+
+    // Variable entries expand for values given by :let or by the variables passed in
+    let vars = HashMap::from([("dest".to_owned(), "given".to_owned())]);
+    assert_eq!(
+        crate::enumerate("/target", &config, vars)?,
+        [
+            "/target/ops",
+            "/target/ops/checked",
+            "/target/ops/copied",
+            "/target/ops/link",
+            "/target/shared",
+            "/target/shared/given",
+            "/target/shared/ops",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn route_traces_bindings_and_targets() -> Result<()> {
+    let mut config = Config::new("/local", false);
+    let local = parse_schema(
+        "
+        $zone/
+            admin/ -> /remote/$zone/admin
+                storage
+                    :source /templates/storage
+        other/
+        ",
+    )?;
+    let remote = parse_schema(
+        "
+        $zone/
+            admin/
+        ",
+    )?;
+    config.add_precached_stem(Root::try_from("/local")?, "/local.diskplan", local);
+    config.add_precached_stem(Root::try_from("/remote")?, "/remote.diskplan", remote);
+
+    let steps = crate::route("/local/zone_a/admin/storage", &config, HashMap::new())?;
+    let described: Vec<_> = steps.iter().map(|step| step.to_string()).collect();
+    assert_eq!(
+        described,
+        [
+            "/local\n    Schema: (root)",
+            "/local/zone_a\n    Schema line 2: $zone/\n    Binding: $zone = zone_a",
+            concat!(
+                "/local/zone_a/admin\n    Schema line 3: admin/ -> /remote/$zone/admin",
+                "\n    Target: /remote/zone_a/admin"
+            ),
+            concat!(
+                "/local/zone_a/admin/storage\n    Schema line 4: storage",
+                "\n    Source: /templates/storage"
+            ),
+        ]
+    );
+    assert_eq!(steps[2].kind, NodeKind::Symlink);
+
+    let error = crate::route("/local/other/missing", &config, HashMap::new()).unwrap_err();
+    assert!(format!("{error:?}").contains("was able to produce"));
+    Ok(())
+}
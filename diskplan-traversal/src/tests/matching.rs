@@ -1,4 +1,15 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
 use anyhow::Result;
+use camino::Utf8Path;
+
+use diskplan_filesystem::{Filesystem, MemoryFilesystem};
+
+use super::{apply_schema, config_for};
+use crate::{traverse, StackFrame};
 
 #[test]
 fn binding_static_beats_dynamic() -> Result<()> {
@@ -42,6 +53,72 @@ fn binding_static_beats_dynamic_reordered() -> Result<()> {
     }
 }
 
+#[test]
+fn binding_dynamic_excludes_static_names() -> Result<()> {
+    assert_effect_of! {
+        under: "/"
+        applying: "
+            admin/
+                ADMIN_ONLY/
+            $project/
+                PROJECT_ONLY/
+            "
+        onto: "/"
+        with:
+            directories:
+                "/admin"
+                "/alpha"
+        yields:
+            directories:
+                "/admin/ADMIN_ONLY"
+                "/alpha/PROJECT_ONLY"
+    }
+}
+
+#[test]
+fn binding_dynamic_overlap_only_on_static_name() -> Result<()> {
+    // Both variables could match "admin", but neither is offered it, so there is no conflict
+    assert_effect_of! {
+        under: "/"
+        applying: "
+            admin/
+            $starts/
+                :match a.*
+                STARTS/
+            $ends/
+                :match .*n
+                ENDS/
+            "
+        onto: "/"
+        with:
+            directories:
+                "/admin"
+                "/alpha"
+                "/fin"
+        yields:
+            directories:
+                "/alpha/STARTS"
+                "/fin/ENDS"
+    }
+}
+
+#[test]
+fn binding_dynamic_value_excludes_static_names() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            :let project = admin
+            admin/
+            $project/
+                PROJECT_ONLY/
+            "
+        onto: "/target"
+        yields:
+            directories:
+                "/target/admin"
+    }
+}
+
 #[test]
 #[should_panic(
     expected = r#""existing" matches multiple dynamic bindings "$variable_a" and "$variable_b""#
@@ -191,6 +268,51 @@ fn match_pattern_start_or_end_collision() {
     .unwrap();
 }
 
+#[test]
+fn match_anchoring_modes() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            full/
+                $name/
+                    :match foo
+                    MARKER/
+            prefix/
+                $name/
+                    :match-prefix foo
+                    MARKER/
+            contains/
+                $name/
+                    :match-contains foo
+                    MARKER/
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/full"
+                "/target/full/foo"
+                "/target/full/foobar"
+                "/target/full/seafood"
+                "/target/prefix"
+                "/target/prefix/foo"
+                "/target/prefix/foobar"
+                "/target/prefix/seafood"
+                "/target/contains"
+                "/target/contains/foo"
+                "/target/contains/foobar"
+                "/target/contains/seafood"
+        yields:
+            directories:
+                "/target/full/foo/MARKER"
+                "/target/prefix/foo/MARKER"
+                "/target/prefix/foobar/MARKER"
+                "/target/contains/foo/MARKER"
+                "/target/contains/foobar/MARKER"
+                "/target/contains/seafood/MARKER"
+    }
+}
+
 #[test]
 fn match_variable_inherited() -> Result<()> {
     assert_effect_of! {
@@ -215,6 +337,62 @@ fn match_variable_inherited() -> Result<()> {
     }
 }
 
+#[test]
+fn match_from_parent_binding() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            $project/
+                :let prefix = ${project}_rel
+                $version/
+                    :match ${project}_[0-9]+
+                    marked/
+                $release/
+                    :match ${prefix}[0-9]+
+                    released/
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/alpha"
+                "/target/alpha/alpha_1"
+                "/target/alpha/alpha_rel2"
+                "/target/alpha/beta_2"
+                "/target/beta"
+                "/target/beta/alpha_1"
+                "/target/beta/beta_22"
+        yields:
+            directories:
+                "/target/alpha/alpha_1/marked"
+                "/target/alpha/alpha_rel2/released"
+                "/target/beta/beta_22/marked"
+                // And not: /target/alpha/beta_2/marked, /target/beta/alpha_1/marked
+    }
+}
+
+#[test]
+fn match_from_parent_binding_on_new_path() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            $project/
+                $version/
+                    :match ${project}_[0-9]+
+                    marked/
+            "
+        onto: "/target/gamma/gamma_3"
+        with:
+            directories:
+                "/target"
+        yields:
+            directories:
+                "/target/gamma"
+                "/target/gamma/gamma_3"
+                "/target/gamma/gamma_3/marked"
+    }
+}
+
 #[test]
 fn match_let() -> Result<()> {
     assert_effect_of! {
@@ -313,3 +491,328 @@ fn match_categories() -> Result<()> {
                 "/target/chicken/ANIMAL"
     }
 }
+
+#[test]
+fn match_multiple_avoids() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            $name/
+                :avoid \\..*
+                :avoid .*~
+                :avoid .*\\.tmp
+                INSIDE/
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/.hidden"
+                "/target/backup~"
+                "/target/scratch.tmp"
+                "/target/normal"
+        yields:
+            directories:
+                "/target/normal/INSIDE"
+    }
+}
+
+#[test]
+fn avoid_hidden_composes_with_avoid() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            .static/
+            $name/
+                :avoid-hidden
+                :avoid .*~
+                INSIDE/
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/.git"
+                "/target/backup~"
+                "/target/normal"
+        yields:
+            directories:
+                "/target/.static"
+                "/target/normal/INSIDE"
+    }
+}
+
+#[test]
+fn subtree_extent_skips_siblings() -> Result<()> {
+    assert_effect_of! {
+        under: "/local"
+        applying: "
+            $zone/
+                admin/
+                    sub/
+                other/
+            "
+        onto: "/local/zone_a/admin"
+        extent: Subtree
+        with:
+            directories:
+                "/local"
+                "/local/zone_a"
+                "/local/zone_b"
+        yields:
+            directories:
+                "/local/zone_a/admin"
+                "/local/zone_a/admin/sub"
+                // And not: /local/zone_a/other or /local/zone_b/admin
+    }
+}
+
+#[test]
+fn subtree_extent_binds_from_path() -> Result<()> {
+    assert_effect_of! {
+        under: "/local"
+        applying: "
+            $zone/
+                admin/
+                    $zone/
+            "
+        onto: "/local/zone_a/admin"
+        extent: Subtree
+        yields:
+            directories:
+                "/local/zone_a"
+                "/local/zone_a/admin"
+                "/local/zone_a/admin/zone_a"
+    }
+}
+
+#[test]
+fn binding_multiple_variable_error_reports_lines() {
+    let error = (|| -> Result<()> {
+        assert_effect_of! {
+            under: "/"
+            applying: "
+                $variable_a/
+                    :match .*
+
+                $variable_b/
+                    :match .*
+                "
+            onto: "/"
+            with:
+                directories:
+                    "/existing"
+            yields:
+        }
+    })()
+    .unwrap_err();
+    let message = format!("{error:?}");
+    assert!(message.contains("Line 2: $variable_a/"), "{message}");
+    assert!(message.contains("Line 5: $variable_b/"), "{message}");
+}
+
+#[test]
+fn ignore_case_uses_disk_spelling() -> Result<()> {
+    assert_effect_of! {
+        under: "/"
+        applying: "
+            zones/
+                :ignore-case
+                zone_a/
+                    inner/
+                $other/
+                    :match ZONE_[A-Z]
+                    matched/
+            "
+        onto: "/"
+        with:
+            directories:
+                "/zones"
+                "/zones/Zone_A"
+                "/zones/Zone_B"
+        yields:
+            directories:
+                "/zones/Zone_A/inner"
+                "/zones/Zone_B/matched"
+    }
+}
+
+#[test]
+fn ignore_case_conflict() {
+    let error = (|| -> Result<()> {
+        assert_effect_of! {
+            under: "/"
+            applying: "
+                zones/
+                    :ignore-case
+                    zone_a/
+                "
+            onto: "/"
+            with:
+                directories:
+                    "/zones"
+                    "/zones/Zone_A"
+                    "/zones/zone_a"
+            yields:
+                directories:
+                    "/zones"
+        }
+    })()
+    .unwrap_err();
+    assert!(format!("{error:?}").contains(
+        r#""Zone_A" and "zone_a" differ only by case, which is ambiguous with :ignore-case"#
+    ));
+}
+
+/// Collects log output written by a tracing subscriber
+#[derive(Clone, Default)]
+struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn unmatched_warnings_are_sorted() -> Result<()> {
+    let capture = LogCapture::default();
+    let writer = capture.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .with_max_level(tracing::Level::WARN)
+        .finish();
+    tracing::subscriber::with_default(subscriber, || -> Result<()> {
+        assert_effect_of! {
+            under: "/"
+            applying: "
+                dir/
+                "
+            onto: "/"
+            with:
+                directories:
+                    "/dir"
+                    "/dir/zeta"
+                    "/dir/alpha"
+                    "/dir/mid"
+            yields:
+                directories:
+                    "/dir"
+        }
+    })?;
+
+    let logs = String::from_utf8(capture.0.lock().unwrap().clone())?;
+    let unmatched: Vec<_> = logs
+        .lines()
+        .filter_map(|line| line.split_once(r#"" from on disk has no match"#))
+        .filter_map(|(start, _)| start.rsplit_once('"'))
+        .map(|(_, name)| name)
+        .collect();
+    assert_eq!(unmatched, vec!["alpha", "mid", "zeta"]);
+    Ok(())
+}
+
+#[test]
+fn explain_reports_each_decision() -> Result<()> {
+    let capture = LogCapture::default();
+    let writer = capture.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .with_max_level(tracing::Level::INFO)
+        .finish();
+    tracing::subscriber::with_default(subscriber, || -> Result<()> {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/target", Default::default())?;
+        for name in ["fixed", "x-ray", "keep", "LOUD"] {
+            fs.create_directory(Utf8Path::new("/target").join(name), Default::default())?;
+        }
+        let mut config = config_for(
+            "/target",
+            "
+            fixed/
+            $lower/
+                :match [a-z-]+
+                :avoid x-.*
+            ",
+        )?;
+        config.set_explain(true);
+        let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+        traverse("/target", &stack, &mut fs, Default::default())
+    })?;
+
+    let logs = String::from_utf8(capture.0.lock().unwrap().clone())?;
+    assert!(logs.contains("Matching names in /target:"));
+    assert!(logs.contains(r#""fixed" from the schema:"#));
+    assert!(logs.contains("fixed (line 2): matched"));
+    assert!(logs.contains("$lower (line 3): reserved by the static entry"));
+    assert!(logs.contains("fixed (line 2): different name"));
+    assert!(logs.contains("avoided by"));
+    assert!(logs.contains(r#""LOUD" from on disk:"#));
+    assert!(logs.contains("does not match"));
+    Ok(())
+}
+
+#[test]
+fn fail_on_unmatched_lists_names() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    for path in [
+        "/target",
+        "/target/known",
+        "/target/stray",
+        "/target/.hidden",
+    ] {
+        fs.create_directory(path, Default::default())?;
+    }
+    let schema = "
+        known/
+            inner/
+        ";
+
+    // Only warned about by default
+    apply_schema(&mut fs, "/target", schema)?;
+    assert!(fs.is_directory("/target/known/inner"));
+
+    let mut config = config_for("/target", schema)?;
+    config.set_fail_on_unmatched(true);
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    let error = traverse("/target", &stack, &mut fs, Default::default()).unwrap_err();
+    assert!(error
+        .chain()
+        .any(|cause| cause.to_string().ends_with(r#"for: ".hidden", "stray""#)));
+    Ok(())
+}
+
+#[test]
+fn match_path_depends_on_ancestry() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            $area/
+                $item/
+                    :match-path
+                    :match public/.*|private/secret_.*
+                    :avoid-hidden
+                    MARKER/
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/target"
+                "/target/public"
+                "/target/public/notes"
+                "/target/public/.cache"
+                "/target/private"
+                "/target/private/secret_plans"
+                "/target/private/notes"
+        yields:
+            directories:
+                "/target/public/notes/MARKER"
+                "/target/private/secret_plans/MARKER"
+    }
+}
@@ -1,5 +1,10 @@
 use anyhow::Result;
 
+use diskplan_filesystem::{Filesystem, MemoryFilesystem, DEFAULT_DIRECTORY_MODE};
+
+use super::config_for;
+use crate::{traverse, StackFrame};
+
 #[test]
 fn def_use_simple() -> Result<()> {
     assert_effect_of! {
@@ -146,6 +151,86 @@ fn owner_inheritance() -> Result<()> {
     }
 }
 
+#[test]
+fn inherit_overrides_use() -> Result<()> {
+    assert_effect_of! {
+        under: "/"
+        applying: "
+            :def private/
+                :owner sys
+                :group sys
+                :mode 700
+            :def via_private/
+                :use private
+            :def inheriting/
+                :mode inherit
+
+            parent/
+                :owner daemon
+                :mode 750
+
+                from_use/
+                    :use via_private
+                inherit_all/
+                    :owner inherit
+                    :group inherit
+                    :mode inherit
+                    :use private
+                inherit_mode_only/
+                    :mode inherit
+                    :use via_private
+                first_use_inherits/
+                    :use inheriting
+                    :use private
+            "
+        onto: "/"
+        yields:
+            directories:
+                "/parent" [owner = "daemon" group = "root" mode = 0o750]
+                "/parent/from_use" [owner = "sys" group = "sys" mode = 0o700]
+                "/parent/inherit_all" [
+                    owner = "daemon"
+                    group = "root"
+                    mode = DEFAULT_DIRECTORY_MODE]
+                "/parent/inherit_mode_only" [
+                    owner = "sys"
+                    group = "sys"
+                    mode = DEFAULT_DIRECTORY_MODE]
+                "/parent/first_use_inherits" [
+                    owner = "sys"
+                    group = "sys"
+                    mode = DEFAULT_DIRECTORY_MODE]
+    }
+}
+
+#[test]
+fn dash_clears_attribute_from_use() -> Result<()> {
+    assert_effect_of! {
+        under: "/"
+        applying: "
+            :def service/
+                :owner sys
+                :group sys
+                :mode 700
+
+            parent/
+                :owner daemon
+
+                service_owned/
+                    :use service
+                parent_owned/
+                    :use service
+                    :owner -
+            "
+        onto: "/"
+        yields:
+            directories:
+                "/parent" [owner = "daemon"]
+                "/parent/service_owned" [owner = "sys" group = "sys" mode = 0o700]
+                "/parent/parent_owned" [owner = "daemon" group = "sys" mode = 0o700]
+    }
+}
+
 #[test]
 #[should_panic(expected = r#"Error: :match cannot be used in definition"#)]
 fn disallow_match_in_definition() {
@@ -168,3 +253,163 @@ fn disallow_match_in_definition() {
     })()
     .unwrap();
 }
+
+#[test]
+fn use_with_arguments() -> Result<()> {
+    assert_effect_of! {
+        under: "/"
+        applying: "
+            :let area = shared
+
+            :def project(owner, quota_dir)/
+                :owner $owner
+                $quota_dir/
+
+            alice/
+                :use project(root, ${area}_scratch)
+            bob/
+                :use project(sys, home)
+            "
+        onto: "/"
+        yields:
+            directories:
+                "/alice" [owner = "root"]
+                "/alice/shared_scratch" [owner = "root"]
+                "/bob" [owner = "sys"]
+                "/bob/home" [owner = "sys"]
+    }
+}
+
+#[test]
+#[should_panic(expected = r#"Definition "project" takes 2 argument(s) but 1 given"#)]
+fn use_with_wrong_number_of_arguments() {
+    (|| -> Result<()> {
+        assert_effect_of! {
+            under: "/"
+            applying: "
+                :def project(owner, quota_dir)/
+
+                alice/
+                    :use project(root)
+                "
+            onto: "/"
+            yields:
+                directories:
+                    "/alice"
+        }
+    })()
+    .unwrap();
+}
+
+#[test]
+fn def_use_transitive() -> Result<()> {
+    assert_effect_of! {
+        under: "/"
+        applying: "
+            :def outer/
+                :use inner
+                outer_sub/
+            :def inner/
+                inner_sub/
+
+            usage/
+                :use outer
+            "
+        onto: "/"
+        yields:
+            directories:
+                "/usage"
+                "/usage/outer_sub"
+                "/usage/inner_sub"
+    }
+}
+
+#[test]
+#[should_panic(expected = "Cycle detected in :use of definitions: first -> second -> first")]
+fn def_use_cycle() {
+    (|| -> Result<()> {
+        assert_effect_of! {
+            under: "/"
+            applying: "
+                :def first/
+                    :use second
+                :def second/
+                    :use first
+
+                usage/
+                    :use first
+                "
+            onto: "/"
+            yields:
+                directories:
+                    "/usage"
+        }
+    })()
+    .unwrap();
+}
+
+#[test]
+fn recursion_limited_by_max_depth() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory_all("/target/a/b", Default::default())?;
+    let mut config = config_for(
+        "/target",
+        "
+        :def tree/
+            $child/
+                :use tree
+        a/
+            :use tree
+        ",
+    )?;
+    config.set_max_depth(Some(3));
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+
+    // The variable bound to "b" is carried deeper, so this would otherwise recurse without end
+    let error = traverse("/target", &stack, &mut fs, Default::default()).unwrap_err();
+    assert!(format!("{error:?}").contains("Maximum depth (3) exceeded at /target/a/b/b/b"));
+    Ok(())
+}
+
+#[test]
+fn stack_dumped_on_error() -> Result<()> {
+    let error_message = |dump_stack_on_error| -> Result<String> {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory_all("/target/a/b", Default::default())?;
+        let mut config = config_for(
+            "/target",
+            "
+            :def tree/
+                $child/
+                    :use tree
+            a/
+                :use tree
+            ",
+        )?;
+        config.set_max_depth(Some(3));
+        config.set_dump_stack_on_error(dump_stack_on_error);
+        let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+        let error = traverse("/target", &stack, &mut fs, Default::default()).unwrap_err();
+        Ok(format!("{error:?}"))
+    };
+    assert!(!error_message(false)?.contains("Stack (outermost first):"));
+
+    // Only the innermost stack is dumped, with every frame from the outermost in
+    let message = error_message(true)?;
+    assert_eq!(message.matches("Stack (outermost first):").count(), 1);
+    let lines: Vec<_> = message.lines().map(str::trim).collect();
+    let first = lines
+        .iter()
+        .position(|line| *line == "#0 No variables")
+        .unwrap();
+    assert_eq!(
+        lines[first + 1],
+        "Owner: root, group: root, mode: (default)"
+    );
+    let binding = lines
+        .iter()
+        .position(|line| *line == "#7 Schema binding:")
+        .unwrap();
+    assert_eq!(lines[binding + 1], "$child = \"b\"");
+    Ok(())
+}
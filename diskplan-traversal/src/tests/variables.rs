@@ -1,4 +1,11 @@
-use anyhow::Result;
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use diskplan_filesystem::{Filesystem, MemoryFilesystem, Root};
+use diskplan_schema::parse_schema;
+
+use super::config_for;
+use crate::{traverse, StackFrame};
 
 #[test]
 fn match_binds_for_reuse() -> Result<()> {
@@ -191,3 +198,350 @@ fn repeat_variable_binding() -> Result<()> {
                 "/aaa/VAR_A"
     )
 }
+
+#[test]
+fn let_references_let() -> Result<()> {
+    assert_effect_of!(
+        under: "/"
+        applying: "
+            :let remote_disk = remote
+            :let full = ${remote_disk}_resources
+            $full/
+            "
+        onto: "/"
+        yields:
+            directories:
+                "/remote_resources"
+    )
+}
+
+#[test]
+fn let_references_special() -> Result<()> {
+    assert_effect_of!(
+        under: "/root"
+        applying: "
+            :let here = ${PATH}_x
+            sub/
+                $here/
+            "
+        onto: "/root"
+        yields:
+            directories:
+                "/root/sub"
+                "/root/sub/sub_x"
+    )
+}
+
+#[test]
+#[should_panic(expected = "Cycle detected evaluating variable: $a -> $b -> $a")]
+fn let_cycle_is_an_error() {
+    (|| -> Result<()> {
+        assert_effect_of!(
+            under: "/"
+            applying: "
+                :let a = ${b}
+                :let b = x${a}
+                dir/
+                    :owner $a
+                "
+            onto: "/"
+            yields:
+        )
+    })()
+    .unwrap();
+}
+
+#[test]
+fn matched_name_from_nearest_binding() -> Result<()> {
+    assert_effect_of!(
+        under: "/"
+        applying: "
+            $user/
+                :let data = ${MATCHED_NAME}_data
+                inner/
+                    $data/
+            "
+        onto: "/alice"
+        yields:
+            directories:
+                "/alice"
+                "/alice/inner"
+                "/alice/inner/alice_data"
+    )
+}
+
+#[test]
+#[should_panic(expected = "${MATCHED_NAME} used outside of any dynamic binding")]
+fn matched_name_without_binding_is_an_error() {
+    (|| -> Result<()> {
+        assert_effect_of!(
+            under: "/"
+            applying: "
+                dir/
+                    :owner $MATCHED_NAME
+                "
+            onto: "/"
+            yields:
+        )
+    })()
+    .unwrap();
+}
+
+#[test]
+fn root_name_in_let_and_source() -> Result<()> {
+    assert_effect_of!(
+        under: "/net/remote"
+        applying: "
+            :let site = ${ROOT_NAME}_site
+            $site/
+            config
+                :source /resources/${ROOT_NAME}.conf
+            "
+        onto: "/net/remote"
+        with:
+            directories:
+                "/net"
+                "/resources"
+            files:
+                "/resources/remote.conf" ["remote config"]
+        yields:
+            directories:
+                "/net/remote/remote_site"
+            files:
+                "/net/remote/config" ["remote config"]
+    )
+}
+
+#[test]
+fn undefined_variable_error_points_to_it() {
+    let error = (|| -> Result<()> {
+        assert_effect_of!(
+            under: "/"
+            applying: "
+                file
+                    :source ${remote_disk}/x
+                "
+            onto: "/"
+            yields:
+        )
+    })()
+    .unwrap_err();
+    let message = error.root_cause().to_string();
+    assert!(
+        message.contains(r#"Undefined variable "remote_disk" in expression "${remote_disk}/x""#)
+    );
+
+    // The caret sits beneath the "$" of the variable on the numbered line above it
+    let lines: Vec<&str> = message.lines().collect();
+    let caret = lines.iter().position(|line| line.ends_with('^')).unwrap();
+    let source_line = lines[caret - 1];
+    assert!(source_line.starts_with("   3 | "));
+    assert_eq!(lines[caret].find('^'), source_line.find("${remote_disk}"),);
+    assert!(message.contains(&format!(
+        "(line 3, byte {})",
+        source_line.find("${remote_disk}").unwrap() - "   3 | ".len()
+    )));
+}
+
+#[test]
+fn escaped_dollar_is_literal() -> Result<()> {
+    assert_effect_of! {
+        under: "/target"
+        applying: "
+            :let var = value
+            price_list
+                :source /src/price_$$5
+            literal
+                :source /src/$$var
+            "
+        onto: "/target"
+        with:
+            directories:
+                "/src"
+            files:
+                "/src/price_$5" ["FIVE"]
+                "/src/$var" ["LITERAL"]
+                "/src/value" ["VALUE"]
+        yields:
+            files:
+                "/target/literal" ["LITERAL"]
+                "/target/price_list" ["FIVE"]
+    }
+}
+
+#[test]
+fn when_conditions_select_entries() -> Result<()> {
+    assert_effect_of!(
+        under: "/"
+        applying: "
+            :let env = prod
+            :def staging_only/
+                :when $env == staging
+            production/
+                :when $env == prod
+                logs/
+            staging/
+                :use staging_only
+            not_staging/
+                :when ${env} != staging
+            $name/
+                :when $NAME != skipped
+                inner/
+            unset_equal/
+                :when $missing == prod
+            unset_not_equal/
+                :when $missing != prod
+            "
+        onto: "/"
+        with:
+            directories:
+                "/kept"
+                "/skipped"
+        yields:
+            directories:
+                "/production"
+                "/production/logs"
+                "/not_staging"
+                "/kept/inner"
+    )
+}
+
+#[test]
+fn symlink_to_target_skipped_by_when() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/links", Default::default())?;
+    fs.create_directory("/targets", Default::default())?;
+    let mut config = config_for("/links", "link/ -> /targets/thing")?;
+    let schema = parse_schema("thing/\n    :when $env == prod\n").map_err(|e| anyhow!("{}", e))?;
+    config.add_precached_stem(Root::try_from("/targets")?, "/targets", schema);
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    let error = traverse("/links/link", &stack, &mut fs, Default::default()).unwrap_err();
+    assert!(error.chain().any(|cause| {
+        cause.to_string()
+        == "Symlink target /links/link -> /targets/thing was skipped by :when (so was not created)"
+    }));
+    assert!(!fs.is_link("/links/link"));
+    assert!(!fs.exists("/targets/thing"));
+    Ok(())
+}
+
+#[test]
+fn errors_list_variables_in_scope() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    let config = config_for(
+        "/target",
+        "
+        :let site = north
+        $zone/
+            :let data = /missing/${zone}
+            config
+                :source ${data}
+        ",
+    )?;
+    let variables = HashMap::from([("site".to_owned(), "ignored".to_owned())]);
+    let stack = StackFrame::stack(&config, variables.into(), "root", "root", None);
+    let error = traverse("/target/zone_a", &stack, &mut fs, Default::default()).unwrap_err();
+    assert!(error
+        .chain()
+        .any(|cause| cause.to_string().ends_with(concat!(
+            "Variables in scope:\n",
+            "  $data = \"/missing/${zone}\"\n",
+            "  $site = \"north\"\n",
+            "  $zone = \"zone_a\"",
+        ))));
+    Ok(())
+}
+
+#[test]
+fn environment_variables() -> Result<()> {
+    let mut fs = MemoryFilesystem::new();
+    fs.create_directory("/target", Default::default())?;
+    let environment = HashMap::from([("SITE".to_owned(), "north".to_owned())]);
+    let mut config = config_for(
+        "/target",
+        "
+        :let SITE = shadowed
+        :let name = $ENV{SITE}_$SITE
+        $name/
+        skipped/
+            :when $ENV{MISSING} == x
+        ",
+    )?;
+    config.set_environment(environment.clone());
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    traverse("/target", &stack, &mut fs, Default::default())?;
+    assert_eq!(fs.list_directory("/target")?, vec!["north_shadowed"]);
+
+    let mut config = config_for("/target", "sub/\n    :owner $ENV{MISSING}")?;
+    config.set_environment(environment);
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    let error = traverse("/target", &stack, &mut fs, Default::default()).unwrap_err();
+    assert!(error.chain().any(|cause| cause.to_string().starts_with(
+        r#"Environment variable "MISSING" is not set, in expression "$ENV{MISSING}" (line 2"#
+    )));
+    Ok(())
+}
+
+#[test]
+fn vars_override_lets_when_asked() -> Result<()> {
+    let schema = "
+        :let site = north
+        :let zone = zone_$site
+        $zone/
+            $site/
+        ";
+    let vars = HashMap::from([("site".to_owned(), "south".to_owned())]);
+    for (vars_override_lets, site) in [(false, "north"), (true, "south")] {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_directory("/target", Default::default())?;
+        fs.create_directory("/target/zone_disk", Default::default())?;
+        let mut config = config_for("/target", schema)?;
+        config.set_vars_override_lets(vars_override_lets);
+        let stack = StackFrame::stack(&config, vars.clone().into(), "root", "root", None);
+        traverse("/target", &stack, &mut fs, Default::default())?;
+        assert_eq!(
+            fs.list_directory("/target")?,
+            vec!["zone_disk", &format!("zone_{site}")]
+        );
+        // Names bound by entries are unaffected
+        assert_eq!(fs.list_directory("/target/zone_disk")?, vec![site]);
+    }
+    Ok(())
+}
+
+#[test]
+fn mapped_owner_and_group_in_source() -> Result<()> {
+    let mut fs = MemoryFilesystem::new().with_fake_ids(true);
+    fs.create_directory_all("/templates/admin", Default::default())?;
+    fs.create_directory_all("/templates/root", Default::default())?;
+    fs.create_file(
+        "/templates/admin/profile",
+        Default::default(),
+        "admin".into(),
+    )?;
+    fs.create_file("/templates/root/profile", Default::default(), "root".into())?;
+    fs.create_directory("/target", Default::default())?;
+
+    let mut config = config_for(
+        "/target",
+        "
+        home/
+            :owner root
+            :group staff
+            :let team = ${MAPPED_GROUP}_team
+            .profile
+                :source /templates/${MAPPED_OWNER}/profile
+            $team/
+        ",
+    )?;
+    config.apply_user_map(HashMap::from([("root".to_owned(), "admin".to_owned())]));
+    config.apply_group_map(HashMap::from([("staff".to_owned(), "ops".to_owned())]));
+    let stack = StackFrame::stack(&config, Default::default(), "root", "root", None);
+    traverse("/target", &stack, &mut fs, Default::default())?;
+
+    assert_eq!(fs.read_file("/target/home/.profile")?, "admin");
+    assert_eq!(fs.attributes("/target/home/.profile")?.owner, "admin");
+    assert!(fs.is_directory("/target/home/ops_team"));
+    Ok(())
+}
@@ -1,28 +1,124 @@
-use std::{collections::HashMap, ops::Deref};
+use std::{collections::HashMap, fs, io::BufRead, ops::Deref};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 use camino::Utf8PathBuf;
 use clap::Parser;
+use diskplan_schema::{parse_schema, Token};
 
 /// Command line arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct CommandLineArgs {
     /// The directory to produce. This must be absolute and begin with one of the configured roots
-    pub target: Utf8PathBuf,
+    #[arg(
+        value_parser = parse_path,
+        required_unless_present_any = ["config_check", "targets_from_stdin"],
+        conflicts_with = "targets_from_stdin"
+    )]
+    pub target: Option<Utf8PathBuf>,
+
+    /// Read the targets to produce from standard input (one per line) instead, loading the config
+    /// and schemas only once, and report whether each succeeded
+    #[arg(long)]
+    pub targets_from_stdin: bool,
 
     /// The path to the diskplan.toml config file
-    #[arg(short, long, default_value = "diskplan.toml")]
+    #[arg(short, long, default_value = "diskplan.toml", value_parser = parse_path)]
     pub config_file: Utf8PathBuf,
 
+    /// Check that every configured stem's schema loads and parses, then exit (no target needed)
+    #[arg(long)]
+    pub config_check: bool,
+
+    /// Print the schema for the target's root, with any includes merged, in canonical form and
+    /// exit without traversing
+    #[arg(long)]
+    pub print_schema: bool,
+
+    /// Trace the route through the schema to the target, printing each entry along it with the
+    /// schema line, binding, symlink target and source giving it, and exit without changing
+    /// anything
+    #[arg(long, conflicts_with_all = ["apply", "plan", "targets_from_stdin"])]
+    pub explain_route: bool,
+
     /// Whether to apply the changes (otherwise, only simulate and print)
     #[arg(long)]
     pub apply: bool,
 
+    /// Before applying, check that every target would be produced in full (with every :source
+    /// present, for example), listing all problems found and changing nothing if there are any
+    #[arg(long, requires = "apply")]
+    pub preflight: bool,
+
+    /// List the changes that --apply would make, reading the real filesystem but changing nothing
+    #[arg(long, conflicts_with = "apply")]
+    pub plan: bool,
+
+    /// Show the tree as --apply would leave it, reading the real filesystem but changing nothing,
+    /// with only the entries that would be created (+) or modified (~) and the directories
+    /// leading to them; anything already correct is collapsed into "..."
+    #[arg(long, conflicts_with_all = ["apply", "plan"])]
+    pub diff_only_changed: bool,
+
+    /// Create the target's root directory, and any missing parents, before applying (or
+    /// planning) changes, giving them the default owner, group and mode
+    #[arg(long)]
+    pub mkroots: bool,
+
+    /// Only follow the route to the target (and everything beneath it), without walking siblings
+    #[arg(long)]
+    pub only: bool,
+
+    /// Continue past errors in independent parts of the tree, reporting them all at the end
+    #[arg(long)]
+    pub best_effort: bool,
+
+    /// Fail if any name in a directory matches none of the schema's entries for it (these are
+    /// otherwise only warned about)
+    #[arg(long)]
+    pub fail_on_unmatched: bool,
+
+    /// Limit the depth of directories traversed beneath a root (unlimited by default)
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+
+    /// Traverse independent subtrees with up to this many threads (only when applying changes to
+    /// disk; simulations and plans are always serial)
+    #[arg(short, long, default_value_t = 1)]
+    pub jobs: usize,
+
+    /// Explain how each name in a directory was matched, or not, against the schema (implies at
+    /// least info-level logging)
+    #[arg(long)]
+    pub explain: bool,
+
+    /// On error, also print every frame of the traversal stack where it occurred (its variables,
+    /// bindings, definitions being expanded and attributes), for debugging schemas
+    #[arg(long)]
+    pub dump_stack_on_error: bool,
+
     /// Increase logging verbosity level (0: warn; 1: info; 2: debug; 3: trace)
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
 
+    /// Log only errors, for scripts that rely on the exit code alone
+    #[arg(short, long, conflicts_with_all = ["verbose", "explain"])]
+    pub quiet: bool,
+
+    /// The owner of anything the schema does not give an :owner (the current user by default)
+    #[arg(long)]
+    pub default_owner: Option<String>,
+
+    /// The group of anything the schema does not give a :group (the current user's group by
+    /// default)
+    #[arg(long)]
+    pub default_group: Option<String>,
+
+    /// The mode, in octal, of anything the schema does not give a :mode (by default, 755 for
+    /// directories and 644 for files)
+    #[arg(long, value_parser = parse_mode)]
+    pub default_mode: Option<u16>,
+
     /// Map user names, for example "root:admin,janine:jfu"
     #[arg(long, value_parser = parse_name_map)]
     pub usermap: Option<NameMap>,
@@ -34,12 +130,89 @@ pub struct CommandLineArgs {
     /// Set variables that may be used by the schema "variable:value,variable2:value2,..."
     #[arg(long, value_parser = parse_name_map)]
     pub vars: Option<NameMap>,
+
+    /// Let the variables given by --vars, --vars-file or the config file override the schema's own
+    /// :let variables of the same name (which otherwise take precedence)
+    #[arg(long)]
+    pub vars_override_lets: bool,
+
+    /// Read variables from a file of "variable = value" lines (values given by --vars take
+    /// precedence over those read from the file)
+    #[arg(long, value_parser = read_vars_file)]
+    pub vars_file: Option<NameMap>,
+}
+
+fn parse_path(value: &str) -> Result<Utf8PathBuf> {
+    diskplan_filesystem::expand_home(value)
+}
+
+/// Reads target paths, one per line, ignoring blank lines and comments
+pub fn read_targets(input: impl BufRead) -> Result<Vec<Utf8PathBuf>> {
+    let mut targets = vec![];
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        targets.push(parse_path(line).with_context(|| format!("Reading target {}", index + 1))?);
+    }
+    Ok(targets)
+}
+
+fn parse_mode(value: &str) -> Result<u16> {
+    let mode = u16::from_str_radix(value, 8).with_context(|| format!("Invalid octal: {value}"))?;
+    if mode > 0o7777 {
+        bail!("Mode out of range: {}", value);
+    }
+    Ok(mode)
 }
 
 fn parse_name_map(value: &str) -> Result<NameMap> {
     NameMap::try_from(value)
 }
 
+fn read_vars_file(path: &str) -> Result<NameMap> {
+    let path = parse_path(path)?;
+    let text = fs::read_to_string(&path).with_context(|| format!("Reading {path}"))?;
+    parse_vars(&text).with_context(|| format!("Parsing {path}"))
+}
+
+/// Parses lines of `variable = value`, which follow the grammar of the `:let` tag in schemas
+///
+/// Blank lines and comments are allowed. Values are taken literally, since there are no other
+/// variables in scope to refer to.
+fn parse_vars(text: &str) -> Result<NameMap> {
+    // Present the assignments to the schema parser as :let lines, leaving others untouched so
+    // that line numbers in any errors still match the file
+    let schema_text: String = text
+        .lines()
+        .map(|line| match line.trim_start() {
+            "" => format!("{line}\n"),
+            trimmed if trimmed.starts_with('#') => format!("{line}\n"),
+            trimmed => format!(":let {trimmed}\n"),
+        })
+        .collect();
+    let schema = parse_schema(&schema_text).map_err(|e| anyhow!("{}", e))?;
+    let mut map = HashMap::new();
+    let vars = schema.schema.as_directory().map(|d| d.vars());
+    for (name, expr) in vars.into_iter().flatten() {
+        let mut value = String::new();
+        for token in expr.tokens() {
+            match token {
+                Token::Text(text) => value.push_str(text),
+                _ => bail!(
+                    "Value of {} must not refer to other variables: {}",
+                    name,
+                    expr
+                ),
+            }
+        }
+        map.insert(name.value().to_owned(), value);
+    }
+    Ok(NameMap(map))
+}
+
 /// A string-to-string mapping of names to new names that can be parsed
 /// from string form `"name1:newname1,name2:newname2"` and used as a lookup
 #[derive(Debug, Default, Clone)]
@@ -80,3 +253,41 @@ impl From<NameMap> for HashMap<String, String> {
         name_map.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mode, parse_vars, read_targets};
+
+    #[test]
+    fn default_mode() {
+        assert_eq!(parse_mode("750").unwrap(), 0o750);
+        assert_eq!(parse_mode("2775").unwrap(), 0o2775);
+        assert!(parse_mode("8").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn targets() {
+        let input = "/local/zone_a\n\n  # Comment\n  /local/zone_b  \n";
+        assert_eq!(
+            read_targets(input.as_bytes()).unwrap(),
+            ["/local/zone_a", "/local/zone_b"]
+        );
+    }
+
+    #[test]
+    fn vars_file() {
+        let vars = parse_vars(
+            "# Site settings\n\nsite = north_wing\n  owner=alice\nlabel = a b # trailing comment\n",
+        )
+        .unwrap();
+        assert_eq!(vars.map("site"), "north_wing");
+        assert_eq!(vars.map("owner"), "alice");
+        assert_eq!(vars.map("label"), "a b");
+        assert_eq!(vars.0.len(), 3);
+
+        assert!(parse_vars("site = ${other}").is_err());
+        assert!(parse_vars("site = a\nsite = b").is_err());
+        assert!(parse_vars("not an assignment").is_err());
+    }
+}
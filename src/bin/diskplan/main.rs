@@ -1,22 +1,29 @@
 #![doc = include_str!("../../../README.md")]
 
-use anyhow::{anyhow, Result};
-use camino::Utf8Path;
+use std::{
+    collections::BTreeMap,
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{anyhow, bail, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use tracing::{span, Level};
 
 mod args;
 use args::CommandLineArgs;
 use diskplan_config::Config;
-use diskplan_filesystem::{self as filesystem, Filesystem};
-use diskplan_traversal::{self as traversal, StackFrame, VariableSource};
+use diskplan_filesystem::{self as filesystem, ChangeKind, Filesystem, NodeKind};
+use diskplan_traversal::{self as traversal, Extent, StackFrame, VariableSource, Visit};
 
-fn init_logger(verbosity: u8) {
+fn init_logger(verbosity: u8, quiet: bool) {
     let sub = tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
         .with_file(false)
         .with_line_number(false);
     let (level, pretty) = match verbosity {
+        _ if quiet => (Level::ERROR, false),
         0 => (Level::WARN, false),
         1 => (Level::INFO, false),
         2 => (Level::INFO, true),
@@ -31,23 +38,73 @@ fn init_logger(verbosity: u8) {
     }
 }
 
-fn main() -> Result<()> {
+/// Exits with 0 if nothing needed changing, 1 if anything was changed (or, without `--apply`,
+/// would be), or 2 on error
+fn main() -> ExitCode {
+    match run() {
+        Ok(false) => ExitCode::SUCCESS,
+        Ok(true) => ExitCode::from(1),
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Runs as given by the command line, returning whether anything was changed (or would be)
+fn run() -> Result<bool> {
     let CommandLineArgs {
         target,
+        targets_from_stdin,
         config_file,
+        config_check,
+        print_schema,
+        explain_route,
         apply,
+        preflight,
+        plan,
+        diff_only_changed,
+        mkroots,
+        only,
+        best_effort,
+        fail_on_unmatched,
+        max_depth,
+        jobs,
+        explain,
+        dump_stack_on_error,
         verbose,
+        quiet,
+        default_owner,
+        default_group,
+        default_mode,
         usermap,
         groupmap,
         vars,
+        vars_override_lets,
+        vars_file,
     } = CommandLineArgs::parse();
 
-    init_logger(verbose);
+    init_logger(verbose.max(explain as u8), quiet);
+    let target = target.unwrap_or_default();
     let span = span!(Level::DEBUG, "main", target = target.as_str());
     let _guard = span.enter();
 
     let mut config = Config::new(target, apply);
     config.load(config_file)?;
+    if config_check {
+        return check_config(&config).map(|()| false);
+    }
+    if print_schema {
+        let (schema, _) = config.schema_for(config.target_path())?;
+        print!("{}", diskplan_schema::format_schema(schema));
+        return Ok(false);
+    }
+    config.set_max_depth(max_depth);
+    config.set_jobs(jobs);
+    config.set_explain(explain);
+    config.set_dump_stack_on_error(dump_stack_on_error);
+    config.set_fail_on_unmatched(fail_on_unmatched);
+    config.set_vars_override_lets(vars_override_lets);
 
     if let Some(usermap) = usermap {
         config.apply_user_map(usermap.into())
@@ -55,50 +112,332 @@ fn main() -> Result<()> {
     if let Some(groupmap) = groupmap {
         config.apply_group_map(groupmap.into())
     }
+    // Inline --vars take precedence over those from --vars-file, which take precedence over any
+    // given by the config file
+    if let Some(vars_file) = vars_file {
+        config.apply_vars(vars_file.into());
+    }
+    if let Some(vars) = vars {
+        config.apply_vars(vars.into());
+    }
+    if explain_route {
+        for step in traversal::route(config.target_path(), &config, config.vars().clone())? {
+            println!("{step}");
+        }
+        return Ok(false);
+    }
 
-    let owner = users::get_current_username().unwrap();
-    let owner = owner.to_string_lossy();
+    // Defaults for anything the schema leaves unset, which are also subject to the maps
+    let owner = default_owner.unwrap_or_else(|| {
+        let owner = users::get_current_username().unwrap();
+        owner.to_string_lossy().into_owned()
+    });
     let owner = config.map_user(&owner);
-    let group = users::get_current_groupname().unwrap();
-    let group = group.to_string_lossy();
+    let group = default_group.unwrap_or_else(|| {
+        let group = users::get_current_groupname().unwrap();
+        group.to_string_lossy().into_owned()
+    });
     let group = config.map_group(&group);
-    let mode = 0o755.into();
-    let variables = vars
-        .map(|vars| VariableSource::Map(vars.into()))
-        .unwrap_or_default();
-    let stack = StackFrame::stack(&config, variables, owner, group, mode);
+    let mode = default_mode.map(Into::into);
+    let variables = config.vars().clone();
+    let variables = match variables.is_empty() {
+        true => VariableSource::Empty,
+        false => VariableSource::Map(variables),
+    };
+    let changed = AtomicBool::new(false);
+    let observe = |visit: &Visit| {
+        if visit.changed {
+            changed.store(true, Ordering::Relaxed);
+        }
+    };
+    let stack = StackFrame::stack(&config, variables, owner, group, mode).with_observer(&observe);
+    let extent = if only { Extent::Subtree } else { Extent::Full };
+    let batch = match targets_from_stdin {
+        true => Some(args::read_targets(std::io::stdin().lock())?),
+        false => None,
+    };
+    let batch = batch.as_deref();
 
     if config.will_apply() {
+        if preflight {
+            check_targets(batch, &stack, mkroots, extent)?;
+        }
         let mut fs = filesystem::DiskFilesystem::new();
-        traversal::traverse(config.target_path(), &stack, &mut fs, Default::default())?;
+        if produce(batch, &stack, &mut fs, mkroots, extent, best_effort)? {
+            changed.store(true, Ordering::Relaxed);
+        }
+    } else if plan || diff_only_changed {
+        let mut fs = filesystem::RecordingFilesystem::new(filesystem::DiskFilesystem::new());
+        if produce(batch, &stack, &mut fs, mkroots, extent, best_effort)? {
+            changed.store(true, Ordering::Relaxed);
+        }
+        if plan {
+            for operation in fs.operations() {
+                println!("{operation}");
+            }
+        } else {
+            let changes = fs.changes();
+            for root in config.stem_roots() {
+                if fs.is_directory(root.path()) {
+                    println!("\n[Root: {}]", root.path());
+                    print_changes(root.path(), root.path(), &fs, &changes)?;
+                }
+            }
+        }
     } else {
         tracing::warn!("Simulating in memory only, use --apply to apply to disk");
-        let mut fs = filesystem::MemoryFilesystem::new();
+        // Owners and groups need not exist on this machine to be simulated
+        let mut fs = filesystem::MemoryFilesystem::new().with_fake_ids(true);
         for root in config.stem_roots() {
             fs.create_directory_all(root.path(), Default::default())?;
         }
         fs.create_directory("/dev", Default::default())?;
         fs.create_file("/dev/null", Default::default(), "".to_owned())?;
-        traversal::traverse(config.target_path(), &stack, &mut fs, Default::default())?;
+        produce(batch, &stack, &mut fs, false, extent, best_effort)?;
         tracing::warn!("Displaying in-memory filesystem...");
         for root in config.stem_roots() {
             println!("\n[Root: {}]", root.path());
-            print_tree(root.path(), &fs, 0)?;
+            print_tree(root.path(), &fs)?;
+        }
+    }
+    Ok(changed.load(Ordering::Relaxed))
+}
+
+/// Loads the schema of every configured stem, reporting any that fail to load or parse
+fn check_config<'t>(config: &'t Config<'t>) -> Result<()> {
+    let mut roots: Vec<_> = config.stem_roots().collect();
+    roots.sort_by_key(|root| root.path());
+    let mut failed = 0;
+    for root in &roots {
+        match config.schema_for(root.path()) {
+            Ok(_) => println!("OK    {}", root.path()),
+            Err(error) => {
+                println!("ERROR {}", root.path());
+                tracing::error!("{:?}", error);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(anyhow!(
+            "Config check failed for {} of {} stem(s)",
+            failed,
+            roots.len()
+        )),
+    }
+}
+
+/// Produces the target given on the command line or, in a batch, each of the given targets in
+/// turn (reporting whether each succeeded, and failing at the end if any did not), returning
+/// whether any root was made (any other change being told to the stack's observer)
+fn produce<FS>(
+    batch: Option<&[Utf8PathBuf]>,
+    stack: &StackFrame,
+    fs: &mut FS,
+    mkroots: bool,
+    extent: Extent,
+    best_effort: bool,
+) -> Result<bool>
+where
+    FS: Filesystem + Send,
+{
+    let mut made_root = false;
+    let mut produce_one = |target: &Utf8Path| {
+        if mkroots {
+            made_root |= make_root(target, stack, fs)?;
+        }
+        traverse(target, stack, fs, extent, best_effort)
+    };
+    let Some(targets) = batch else {
+        produce_one(stack.config.target_path())?;
+        return Ok(made_root);
+    };
+    let mut failed = 0;
+    for target in targets {
+        match produce_one(target) {
+            Ok(()) => println!("OK    {target}"),
+            Err(error) => {
+                println!("ERROR {target}");
+                tracing::error!("{:?}", error);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(made_root),
+        failed => Err(anyhow!(
+            "Failed to produce {} of {} target(s)",
+            failed,
+            targets.len()
+        )),
+    }
+}
+
+/// Checks that the target given on the command line or each of those in a batch would be
+/// produced in full, reading the disk but recording any changes (so that each target is checked
+/// as it would be found after those before it)
+///
+/// Every target is checked, and the problems with all of those that fail reported together.
+fn check_targets(
+    batch: Option<&[Utf8PathBuf]>,
+    stack: &StackFrame,
+    mkroots: bool,
+    extent: Extent,
+) -> Result<()> {
+    let mut fs = filesystem::RecordingFilesystem::new(filesystem::DiskFilesystem::new());
+    let single = [stack.config.target_path().to_owned()];
+    let targets = batch.unwrap_or(&single);
+    let mut failures = Vec::new();
+    for target in targets {
+        let mut check = || -> Result<()> {
+            if mkroots {
+                make_root(target, stack, &mut fs)?;
+            }
+            traversal::preflight(target, stack, &mut fs, extent)
+        };
+        if let Err(error) = check() {
+            failures.push(format!("Preflight of {target}: {error:#}"));
+        }
+    }
+    if !failures.is_empty() {
+        bail!(
+            "Preflight failed for {} of {} target(s):\n{}",
+            failures.len(),
+            targets.len(),
+            failures.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Creates the root of the target, along with any missing parents, using the stack's defaults,
+/// returning whether it was created
+fn make_root<FS>(target: &Utf8Path, stack: &StackFrame, fs: &mut FS) -> Result<bool>
+where
+    FS: Filesystem,
+{
+    let (_, root) = stack.config.schema_for(target)?;
+    if !fs.is_directory(root.path()) {
+        tracing::info!("Creating root {}", root.path());
+        let attrs = filesystem::SetAttrs {
+            owner: Some(stack.owner()),
+            group: Some(stack.group()),
+            mode: stack.mode(),
+        };
+        fs.create_directory_all(root.path(), attrs)?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+fn traverse<FS>(
+    target: &Utf8Path,
+    stack: &StackFrame,
+    fs: &mut FS,
+    extent: Extent,
+    best_effort: bool,
+) -> Result<()>
+where
+    FS: Filesystem + Send,
+{
+    if !best_effort {
+        return traversal::traverse(target, stack, fs, extent);
+    }
+    let errors = traversal::traverse_best_effort(target, stack, fs, extent)?;
+    for (path, error) in &errors {
+        tracing::error!("At {}: {:?}", path, error);
+    }
+    match errors.len() {
+        0 => Ok(()),
+        count => Err(anyhow!("Encountered {} error(s)", count)),
+    }
+}
+
+fn print_tree<FS>(root: &Utf8Path, fs: &FS) -> Result<()>
+where
+    FS: filesystem::Filesystem,
+{
+    for entry in fs.walk(root) {
+        let (path, kind, attrs) = entry?;
+        print_entry(root, &path, kind, &attrs, fs)?;
+    }
+    Ok(())
+}
+
+/// Prints the entries beneath `root` that would be created (marked `+`) or modified (`~`),
+/// along with the directories leading to them, collapsing each run of entries that would be left
+/// as they are (and everything beneath them) into a `...` marker
+fn print_changes<FS>(
+    root: &Utf8Path,
+    path: &Utf8Path,
+    fs: &FS,
+    changes: &BTreeMap<Utf8PathBuf, ChangeKind>,
+) -> Result<()>
+where
+    FS: filesystem::Filesystem,
+{
+    print!(
+        "{} ",
+        match changes.get(path) {
+            Some(ChangeKind::Created) => '+',
+            Some(ChangeKind::Modified) => '~',
+            None => ' ',
         }
+    );
+    let (kind, attrs) = if fs.is_link(path) {
+        (NodeKind::Symlink, fs.link_attributes(path)?)
+    } else if fs.is_directory(path) {
+        (NodeKind::Directory, fs.attributes(path)?)
+    } else {
+        (NodeKind::File, fs.attributes(path)?)
+    };
+    print_entry(root, path, kind, &attrs, fs)?;
+    if kind != NodeKind::Directory {
+        return Ok(());
+    }
+    let depth = path.strip_prefix(root)?.components().count();
+    let unchanged = || println!("  {:33}{0:indent$}...", "", indent = (depth + 1) * 2);
+    let mut skipped = false;
+    for child in fs.list_directory_sorted(path)? {
+        let child = path.join(child);
+        // Paths sort just ahead of everything beneath them
+        let changed = changes
+            .range(child.clone()..)
+            .next()
+            .is_some_and(|(changed, _)| changed.starts_with(&child));
+        if changed {
+            if skipped {
+                unchanged();
+                skipped = false;
+            }
+            print_changes(root, &child, fs, changes)?;
+        } else {
+            skipped = true;
+        }
+    }
+    if skipped {
+        unchanged();
     }
     Ok(())
 }
 
-fn print_tree<FS>(path: impl AsRef<Utf8Path>, fs: &FS, depth: usize) -> Result<()>
+/// Prints a line for the entry at `path`, indented by its depth beneath `root`
+fn print_entry<FS>(
+    root: &Utf8Path,
+    path: &Utf8Path,
+    kind: NodeKind,
+    attrs: &filesystem::Attrs,
+    fs: &FS,
+) -> Result<()>
 where
     FS: filesystem::Filesystem,
 {
-    let path = path.as_ref();
+    let depth = path.strip_prefix(root)?.components().count();
     let name = path
         .file_name()
         .ok_or_else(|| anyhow!("No file name: {}", path))?;
     let dir = fs.is_directory(path);
-    let attrs = fs.attributes(path)?;
     print_perms(dir, attrs.mode.value());
     print!(
         " {owner:10} {group:10} {0:indent$}{name}{symbol}",
@@ -109,21 +448,10 @@ where
         name = if depth == 0 { path.as_str() } else { name },
         symbol = if dir { "/" } else { "" }
     );
-    if let Ok(target) = fs.read_link(path) {
-        println!(" -> {target}");
+    if kind == NodeKind::Symlink {
+        println!(" -> {}", fs.read_link(path)?);
     } else {
         println!();
-
-        if fs.is_directory(path) {
-            for child in {
-                let mut list = fs.list_directory(path)?;
-                list.sort();
-                list
-            } {
-                let child = path.join(&child);
-                print_tree(&child, fs, depth + 1)?;
-            }
-        }
     }
     Ok(())
 }
@@ -0,0 +1,35 @@
+use std::{fs, os::unix::fs::PermissionsExt, process::Command};
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+
+#[test]
+fn attribute_fix_counts_as_change() -> Result<()> {
+    let dir = Utf8PathBuf::try_from(std::env::temp_dir())?
+        .join(format!("diskplan-exit-codes-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let root = dir.join("root");
+    fs::create_dir_all(root.join("existing"))?;
+    fs::set_permissions(root.join("existing"), fs::Permissions::from_mode(0o700))?;
+    fs::write(dir.join("schema.diskplan"), "existing/\n    :mode 750\n")?;
+    fs::write(
+        dir.join("diskplan.toml"),
+        format!("[stems.main]\nroot = \"{root}\"\nschema = \"schema.diskplan\"\n"),
+    )?;
+
+    let apply = || {
+        Command::new(env!("CARGO_BIN_EXE_diskplan"))
+            .args(["--quiet", "--apply", "--config-file"])
+            .arg(dir.join("diskplan.toml"))
+            .arg(&root)
+            .status()
+    };
+    // Only the mode of the existing directory needs correcting, which is still a change
+    assert_eq!(apply()?.code(), Some(1));
+    let mode = fs::metadata(root.join("existing"))?.permissions().mode();
+    assert_eq!(mode & 0o7777, 0o750);
+    assert_eq!(apply()?.code(), Some(0));
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+
+#[test]
+fn preflight_reports_every_failing_target() -> Result<()> {
+    let dir = Utf8PathBuf::try_from(std::env::temp_dir())?
+        .join(format!("diskplan-preflight-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let root = dir.join("root");
+    fs::create_dir_all(&root)?;
+    fs::write(dir.join("present"), "")?;
+    fs::write(
+        dir.join("schema.diskplan"),
+        format!("$name/\n    file\n        :source {dir}/${{name}}\n"),
+    )?;
+    fs::write(
+        dir.join("diskplan.toml"),
+        format!("[stems.main]\nroot = \"{root}\"\nschema = \"schema.diskplan\"\n"),
+    )?;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_diskplan"))
+        .args([
+            "--quiet",
+            "--apply",
+            "--preflight",
+            "--only",
+            "--targets-from-stdin",
+        ])
+        .arg("--config-file")
+        .arg(dir.join("diskplan.toml"))
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let targets = format!("{root}/first\n{root}/present\n{root}/second\n");
+    child.stdin.take().unwrap().write_all(targets.as_bytes())?;
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8(output.stderr)?;
+
+    // Both failing targets are reported, and nothing is made for any of them
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr.contains("Preflight failed for 2 of 3 target(s)"),
+        "{stderr}"
+    );
+    assert!(stderr.contains(&format!("Preflight of {root}/first: ")));
+    assert!(stderr.contains(&format!("Preflight of {root}/second: ")));
+    assert!(!stderr.contains(&format!("Preflight of {root}/present: ")));
+    assert_eq!(fs::read_dir(&root)?.count(), 0);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}